@@ -1,284 +1,3226 @@
+use futures::{stream, StreamExt};
 use prettytable::Table;
-use std::path::{PathBuf, MAIN_SEPARATOR};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::fs;
 
-use clap::Subcommand;
-use indicatif::{ProgressBar, ProgressStyle};
-use sqlx::SqlitePool;
-use whip_core::{download::DownloadTask, downloader::Downloader, errors::WhipError};
-use whip_persistance::models::{DownloadFilter as Df, DownloadTaskEntity, DownloadTaskRepository};
+use chrono::{DateTime, Local, NaiveDate};
+use clap::{Args, Subcommand};
+use indicatif::{MultiProgress, ProgressBar};
+use tokio::{sync::mpsc, task::JoinHandle};
+use whip_core::{
+    checkpoint::{Checkpoint, Checkpointer, PartState},
+    checksum::{Checksum, HashAlgorithm},
+    control::DownloadControl,
+    dash,
+    download::{parse_header, DownloadTask, ProbeReport},
+    downloader::Downloader,
+    errors::WhipError,
+    extract::{self, ArchiveFormat},
+    hls,
+    hooks::{Hook, HookContext, HookEvent},
+    metalink::{self, MetalinkFile},
+    mirror::{Crawler, MirrorOptions},
+    schedule::{self, Bandwidth, Schedule, Scheduler, Window},
+    storage::{format_size, TaskLock},
+    stream::{StreamDownloader, VariantSelector},
+};
+use whip_persistance::{
+    checkpoint::spawn_checkpoint_writer,
+    errors::DatabaseError,
+    models::{DownloadFilter as Df, DownloadStatus, DownloadTaskEntity, DownloadTaskRepository},
+    repository::Repository,
+    search::{SortKey, TaskQuery},
+};
 
-#[cfg(target_family = "windows")]
-pub const TEMP_DIR: &str = ".\\temp";
-
-#[cfg(target_family = "unix")]
-pub const TEMP_DIR: &str = "./temp";
+use crate::{
+    config::{ConfigFile, Settings, Source},
+    exit_code::{report, summarize, ExitCode},
+    gc::{self, find_garbage, Orphan},
+    input_file::parse_input_file,
+    output::{self, Event, HookRunRecord, ProbeRecord, TaskRecord},
+    progress::PartBars,
+    signals::ActiveDownloads,
+    transfer::{self, Conflict, ExportFormat, ExportedTask},
+    tui::{self, TuiOptions},
+};
 
 #[derive(clap::ValueEnum, Clone)]
 pub enum DownloadFilter {
     Completed,
+    /// Neither completed, failed nor cancelled
     InProgress,
     All,
+    Queued,
+    Probing,
+    Downloading,
+    Paused,
+    Verifying,
+    Failed,
+    Cancelled,
 }
 
-impl Into<Df> for DownloadFilter {
-    fn into(self) -> Df {
-        match self {
+impl From<DownloadFilter> for Df {
+    fn from(val: DownloadFilter) -> Self {
+        match val {
             DownloadFilter::All => Df::All,
             DownloadFilter::Completed => Df::Completed,
             DownloadFilter::InProgress => Df::InProgress,
+            DownloadFilter::Queued => Df::Status(DownloadStatus::Queued),
+            DownloadFilter::Probing => Df::Status(DownloadStatus::Probing),
+            DownloadFilter::Downloading => Df::Status(DownloadStatus::Downloading),
+            DownloadFilter::Paused => Df::Status(DownloadStatus::Paused),
+            DownloadFilter::Verifying => Df::Status(DownloadStatus::Verifying),
+            DownloadFilter::Failed => Df::Status(DownloadStatus::Failed),
+            DownloadFilter::Cancelled => Df::Status(DownloadStatus::Cancelled),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum SortColumn {
+    Id,
+    Name,
+    Size,
+    Progress,
+    Status,
+    Date,
+    Host,
+    Url,
+    Queue,
+}
+
+impl From<SortColumn> for SortKey {
+    fn from(val: SortColumn) -> Self {
+        match val {
+            SortColumn::Id => SortKey::Id,
+            SortColumn::Name => SortKey::Name,
+            SortColumn::Size => SortKey::Size,
+            SortColumn::Progress => SortKey::Progress,
+            SortColumn::Status => SortKey::Status,
+            SortColumn::Date => SortKey::Date,
+            SortColumn::Host => SortKey::Host,
+            SortColumn::Url => SortKey::Url,
+            SortColumn::Queue => SortKey::Queue,
+        }
+    }
+}
+
+/// Which tasks show-downloads lists and in what order
+#[derive(Args)]
+pub struct TaskSearch {
+    #[clap(value_enum, long, short, default_value = "all")]
+    filter: DownloadFilter,
+    /// File name, with * and ? wildcards, or a part of it
+    #[clap(value_parser, long)]
+    name: Option<String>,
+    /// Part of the url
+    #[clap(value_parser, long)]
+    url: Option<String>,
+    /// Host of the url, its subdomains match too
+    #[clap(value_parser, long)]
+    host: Option<String>,
+    /// Content type or its start, like video/
+    #[clap(value_parser, long)]
+    content_type: Option<String>,
+    /// Created on or after this day, YYYY-MM-DD
+    #[clap(value_parser = parse_day, long)]
+    since: Option<String>,
+    /// Created on or before this day, YYYY-MM-DD
+    #[clap(value_parser = parse_day, long)]
+    until: Option<String>,
+    /// Smallest size, like 500K or 2G. Tasks of unknown size are left out
+    #[clap(value_parser = parse_size, long)]
+    min_size: Option<u64>,
+    /// Largest size, like 500K or 2G. Tasks of unknown size are left out
+    #[clap(value_parser = parse_size, long)]
+    max_size: Option<u64>,
+    #[clap(value_enum, long, default_value = "id")]
+    sort: SortColumn,
+    /// Sort from the highest to the lowest
+    #[clap(long, takes_value = false)]
+    desc: bool,
+    /// Show at most this many tasks
+    #[clap(value_parser, long)]
+    limit: Option<usize>,
+    /// Skip this many tasks first
+    #[clap(value_parser, long, default_value = "0")]
+    offset: usize,
+}
+
+impl From<TaskSearch> for TaskQuery {
+    fn from(val: TaskSearch) -> Self {
+        TaskQuery {
+            filter: val.filter.into(),
+            name: val.name,
+            url: val.url,
+            host: val.host,
+            content_type: val.content_type,
+            created_since: val.since,
+            created_until: val.until,
+            min_size: val.min_size,
+            max_size: val.max_size,
+            sort: val.sort.into(),
+            descending: val.desc,
+            offset: val.offset,
+            limit: val.limit,
         }
     }
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Show all the download task
+    /// Show the download tasks, filtered, sorted and paginated
     ShowDownloads {
-        #[clap(value_enum, long, short, default_value = "all")]
-        filter: DownloadFilter,
+        #[clap(flatten)]
+        search: TaskSearch,
     },
     /// Download a file
     Download {
+        #[clap(value_parser, required_unless_present = "input-file")]
+        url: Option<String>,
+        /// Defaults to output_dir of the configuration, or the current directory
         #[clap(value_parser)]
-        url: String,
-        #[clap(value_parser)]
-        output_dir: PathBuf,
+        output_dir: Option<PathBuf>,
+        /// Defaults to threads of the configuration, or 4
         #[clap(value_parser)]
-        max_threads: u64,
+        max_threads: Option<u32>,
         /// Option to store temp files in memory or on disk
         #[clap(takes_value = false, required = false)]
         in_memory: bool,
-        #[clap(value_parser, default_value = "3", long)]
-        max_retries: u8,
+        /// Defaults to retries of the configuration, or 3
+        #[clap(value_parser, long)]
+        max_retries: Option<u8>,
+        /// Highest rate of each download, like 500K (defaults to rate_limit of the configuration)
+        #[clap(value_parser = parse_rate_limit, long)]
+        rate_limit: Option<u64>,
+        /// Header to send with the requests of a new task, as "Name: value". Can be repeated,
+        /// they're added to the headers of the configuration
+        #[clap(value_parser = parse_header_flag, long)]
+        header: Vec<(String, String)>,
+        /// Number of parts to split the file into, downloaded max_threads at a time (defaults to max_threads)
+        #[clap(value_parser, long)]
+        parts: Option<u32>,
+        /// Reserve the disk space of the file before downloading it
+        #[clap(long, takes_value = false)]
+        preallocate: bool,
+        /// HLS/DASH only: pick the variant with the highest bandwidth not exceeding this value (bits/s)
+        #[clap(value_parser, long)]
+        bandwidth: Option<u64>,
+        /// HLS/DASH only: pick the variant with this resolution or the closest one below it (e.g. 1280x720)
+        #[clap(value_parser = parse_resolution, long)]
+        resolution: Option<(u32, u32)>,
+        /// Metalink only: prefer mirrors in this country (ISO 3166-1 alpha-2 code, e.g. de)
+        #[clap(value_parser, long)]
+        location: Option<String>,
+        /// Download the urls listed in this file instead, one per line with
+        /// indented dir=, out=, header=, checksum= and split= options (aria2 format)
+        #[clap(value_parser, long, short, conflicts_with = "url")]
+        input_file: Option<PathBuf>,
+        /// Input file only: how many files to download at the same time
+        #[clap(value_parser, long, short = 'j', default_value = "2")]
+        max_concurrent: usize,
+        /// Extract zip, tar, tar.gz, tar.xz and tar.zst files once downloaded. Split archives
+        /// (name.zip.001, name.zip.002...) are extracted once all their volumes are downloaded
+        #[clap(long, takes_value = false)]
+        extract: bool,
+        /// Directory to extract into (defaults to a directory named after the archive)
+        #[clap(value_parser, long, requires = "extract")]
+        extract_dir: Option<PathBuf>,
+        /// Delete the archive once extracted
+        #[clap(long, takes_value = false, requires = "extract")]
+        delete_archive: bool,
+        /// Wait until then to start: HH:MM (the next time it's that time), YYYY-MM-DD HH:MM or an RFC 3339 date
+        #[clap(value_parser = parse_start_after, long)]
+        start_after: Option<DateTime<Local>>,
+        /// Only download between these times of day, at full speed or at the given rate
+        /// (e.g. 22:00-06:00, 12:00-13:00=500K). Can be repeated, the first matching window applies
+        #[clap(value_parser = Window::parse, long)]
+        window: Vec<Window>,
+        /// What to do outside of the windows: pause, unlimited or a rate like 500K
+        #[clap(value_parser = Bandwidth::parse, long, default_value = "pause", requires = "window")]
+        outside_window: Bandwidth,
+        /// Command to run once the file is downloaded, it gets the task in WHIP_* environment variables
+        /// (WHIP_TASK_ID, WHIP_FILE_PATH, WHIP_URL, WHIP_FILE_SIZE, WHIP_CONTENT_TYPE, WHIP_HASH).
+        /// Defaults to the WHIP_ON_COMPLETE environment variable
+        #[clap(value_parser, long)]
+        on_complete: Option<String>,
+        /// Command to run when the download fails, WHIP_ERROR tells why. Defaults to the WHIP_ON_FAILURE environment variable
+        #[clap(value_parser, long)]
+        on_failure: Option<String>,
+        /// Seconds after which a hook still running is killed
+        #[clap(value_parser, long, default_value = "120")]
+        hook_timeout: u64,
+        /// Seconds between two saves of the progress to the database
+        #[clap(value_parser = parse_checkpoint_interval, long, default_value = "5")]
+        checkpoint_interval: u64,
+    },
+    /// Mirror a website or directory listing, preserving its layout
+    Mirror {
+        #[clap(value_parser)]
+        url: String,
+        #[clap(value_parser)]
+        output_dir: PathBuf,
+        /// How many links away from the start page to go
+        #[clap(value_parser, default_value = "5", long)]
+        depth: u32,
+        /// Other domains links may point to (the start url's domain is always allowed)
+        #[clap(value_parser, long)]
+        domain: Vec<String>,
+        /// Only follow links under this path (defaults to the start url's directory)
+        #[clap(value_parser, long)]
+        path_prefix: Option<String>,
+        /// Pause between two requests, in milliseconds
+        #[clap(value_parser, default_value = "500", long)]
+        delay: u64,
+        /// Don't fetch or apply robots.txt
+        #[clap(long, takes_value = false)]
+        ignore_robots: bool,
+        /// How many files to download at the same time
+        #[clap(value_parser, long, short = 'j', default_value = "2")]
+        max_concurrent: usize,
+        /// Defaults to threads of the configuration, or 4
+        #[clap(value_parser, long)]
+        max_threads: Option<u32>,
+        /// Defaults to retries of the configuration, or 3
+        #[clap(value_parser, long)]
+        max_retries: Option<u8>,
+        /// Command to run once the file is downloaded, it gets the task in WHIP_* environment variables
+        /// (WHIP_TASK_ID, WHIP_FILE_PATH, WHIP_URL, WHIP_FILE_SIZE, WHIP_CONTENT_TYPE, WHIP_HASH).
+        /// Defaults to the WHIP_ON_COMPLETE environment variable
+        #[clap(value_parser, long)]
+        on_complete: Option<String>,
+        /// Command to run when the download fails, WHIP_ERROR tells why. Defaults to the WHIP_ON_FAILURE environment variable
+        #[clap(value_parser, long)]
+        on_failure: Option<String>,
+        /// Seconds after which a hook still running is killed
+        #[clap(value_parser, long, default_value = "120")]
+        hook_timeout: u64,
+    },
+    /// Show what the server tells about a url without downloading it: where it
+    /// redirects, the file's size, type and name, and how it would be split
+    Info {
+        #[clap(value_parser)]
+        url: String,
+        /// Header to send with the request, as "Name: value". Can be repeated,
+        /// they're added to the headers of the configuration
+        #[clap(value_parser = parse_header_flag, long)]
+        header: Vec<(String, String)>,
+        /// Number of parts to split the file into (defaults to threads of the configuration)
+        #[clap(value_parser, long)]
+        parts: Option<u32>,
+    },
+    /// Resume a download task with the options it was created with
+    Resume {
+        #[clap(value_parser)]
+        id: i64,
+        /// Seconds between two saves of the progress to the database
+        #[clap(value_parser = parse_checkpoint_interval, long, default_value = "5")]
+        checkpoint_interval: u64,
+    },
+    /// Resume every failed download task
+    RetryFailed {
+        /// How many files to download at the same time
+        #[clap(value_parser, long, short = 'j', default_value = "2")]
+        max_concurrent: usize,
+    },
+    /// Throw away what was downloaded of a task and download it again
+    Restart {
+        #[clap(value_parser)]
+        id: i64,
+    },
+    /// Pause a download task run by another whip process, its other
+    /// downloads go on
+    Pause {
+        #[clap(value_parser)]
+        id: i64,
+    },
+    /// Write every task, with its parts, headers, checksum and hooks, to a JSON or CSV file
+    Export {
+        #[clap(value_parser)]
+        file: PathBuf,
+        /// Defaults to csv for files ending in .csv, json for the others
+        #[clap(value_enum, long)]
+        format: Option<ExportFormat>,
+        /// Also pack what the unfinished downloads received into this tar.gz
+        /// archive, so they can go on from there once imported
+        #[clap(value_parser, long)]
+        temp_data: Option<PathBuf>,
+    },
+    /// Load the tasks of a file written by export
+    Import {
+        #[clap(value_parser)]
+        file: PathBuf,
+        /// Defaults to csv for files ending in .csv, json for the others
+        #[clap(value_enum, long)]
+        format: Option<ExportFormat>,
+        /// What to do with tasks whose url is already a task
+        #[clap(value_enum, long, default_value = "skip")]
+        on_conflict: Conflict,
+        /// Archive written by export --temp-data, without it unfinished
+        /// downloads start over
+        #[clap(value_parser, long)]
+        temp_data: Option<PathBuf>,
+    },
+    /// Show the hooks that ran for a download task and what they printed
+    HookLog {
+        #[clap(value_parser)]
+        id: i64,
+    },
+    /// Find temporary data no task needs and completed tasks whose files are gone,
+    /// and remove them with --apply
+    Gc {
+        /// Remove what was found instead of only listing it
+        #[clap(long, takes_value = false)]
+        apply: bool,
+    },
+    /// Show or change the settings of config.toml
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Full screen dashboard of the downloads, it can add, pause, resume, reorder and
+    /// delete them, including the ones other whip processes are running
+    ///
+    /// There's no whip background service to attach to: the dashboard runs its
+    /// downloads as whip processes of its own and follows the others through
+    /// the database and the lock files they share. That's why it needs the
+    /// sqlite or json backend, the memory one isn't shared.
+    Tui {
+        /// How many downloads the dashboard runs at the same time
+        #[clap(value_parser, long, short = 'j', default_value = "2")]
+        max_concurrent: usize,
     },
     /// Delete a download task
     Delete {
         #[clap(value_parser)]
         id: i64,
+        /// Remove the downloaded file too, the temporary data always goes
         #[clap(required = false, takes_value = false)]
         remove_file: bool,
     },
 }
 
-pub async fn handle_delete(id: i64, remove_file: bool, db_pool: SqlitePool) -> Result<(), ()> {
+impl Commands {
+    /// Name of the command in JSON output
+    pub fn name(&self) -> &'static str {
+        match self {
+            Commands::ShowDownloads { .. } => "show-downloads",
+            Commands::Download { .. } => "download",
+            Commands::Mirror { .. } => "mirror",
+            Commands::Info { .. } => "info",
+            Commands::Resume { .. } => "resume",
+            Commands::RetryFailed { .. } => "retry-failed",
+            Commands::Restart { .. } => "restart",
+            Commands::Pause { .. } => "pause",
+            Commands::Export { .. } => "export",
+            Commands::Import { .. } => "import",
+            Commands::HookLog { .. } => "hook-log",
+            Commands::Gc { .. } => "gc",
+            Commands::Config { .. } => "config",
+            Commands::Tui { .. } => "tui",
+            Commands::Delete { .. } => "delete",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Show the settings in effect and where they come from
+    Show,
+    /// Set a setting in the configuration file, an empty value unsets it.
+    /// Headers are set as headers.NAME
+    Set {
+        #[clap(value_parser)]
+        key: String,
+        #[clap(value_parser)]
+        value: String,
+    },
+}
+
+fn parse_rate_limit(value: &str) -> Result<u64, String> {
+    if value.eq_ignore_ascii_case("unlimited") {
+        return Ok(0);
+    }
+    schedule::parse_rate(value)
+        .ok_or_else(|| String::from("Expected a rate like 500K or unlimited"))
+}
+
+fn parse_header_flag(value: &str) -> Result<(String, String), String> {
+    parse_header(value).ok_or_else(|| String::from("Headers must be in the form \"Name: value\""))
+}
+
+/// `defaults` with `own` added, which replace the defaults of the same name
+pub fn merge_headers(
+    defaults: &[(String, String)],
+    own: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let mut headers = defaults
+        .iter()
+        .filter(|(name, _)| !own.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.extend(own);
+    headers
+}
+
+fn parse_checkpoint_interval(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(seconds),
+        _ => Err(String::from(
+            "The interval must be a number of seconds above 0",
+        )),
+    }
+}
+
+fn parse_day(value: &str) -> Result<String, String> {
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(day) => Ok(day.format("%Y-%m-%d").to_string()),
+        Err(_) => Err(String::from("Expected a day like 2026-10-18")),
+    }
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    schedule::parse_rate(value).ok_or_else(|| String::from("Expected a size like 500K or 2G"))
+}
+
+fn parse_start_after(value: &str) -> Result<DateTime<Local>, String> {
+    schedule::parse_start_time(value, Local::now())
+}
+
+/// Builds the schedule of a download from its windows, None without any
+pub fn download_schedule(windows: Vec<Window>, outside_window: Bandwidth) -> Option<Schedule> {
+    if windows.is_empty() {
+        return None;
+    }
+    Some(Schedule {
+        windows,
+        otherwise: outside_window,
+    })
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    if let Some((width, height)) = value.split_once('x') {
+        if let (Ok(w), Ok(h)) = (width.parse(), height.parse()) {
+            return Ok((w, h));
+        }
+    }
+    Err(String::from("Resolution must be in the form WIDTHxHEIGHT"))
+}
+
+/// Settings of a download that don't change how the task is stored
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub max_retries: u8,
+    /// Name to save the file under instead of the one the server suggests
+    pub file_name: Option<String>,
+    /// Rendition to pick from HLS playlists and DASH manifests
+    pub variant_selector: VariantSelector,
+    /// Country of the metalink mirrors to prefer
+    pub location: Option<String>,
+    /// Extra headers sent with the requests of a new task
+    pub headers: Vec<(String, String)>,
+    /// Expected digest of the file of a new task
+    pub checksum: Option<Checksum>,
+    /// Number of parts to split a new task into instead of one per thread
+    pub part_count: Option<u32>,
+    /// Reserve the space of the final file before downloading
+    pub preallocate: bool,
+    /// Set when several downloads share the terminal
+    pub multi_progress: Option<MultiProgress>,
+    /// Command stored with the task and run once it's downloaded
+    pub on_complete: Option<String>,
+    /// Command stored with the task and run when it fails
+    pub on_failure: Option<String>,
+    /// Hooks still running after this are killed
+    pub hook_timeout: Duration,
+    /// Extract the file once downloaded if it's an archive
+    pub extract: bool,
+    /// Where to extract archives instead of a directory named after them
+    pub extract_dir: Option<PathBuf>,
+    /// Remove archives once extracted
+    pub delete_archive: bool,
+    /// Don't start before then
+    pub start_after: Option<DateTime<Local>>,
+    /// Bandwidth allowed through the day
+    pub schedule: Option<Schedule>,
+    /// How often the progress of a download is saved
+    pub checkpoint_interval: Duration,
+    /// Downloads of the process, stopped together when it's interrupted
+    pub active: ActiveDownloads,
+    /// Where new tasks get their temporary directory
+    pub temp_dir: PathBuf,
+    /// Highest rate of each download in bytes per second, 0 for none
+    pub rate_limit: u64,
+    /// The task was stored just before downloading it, by batch and mirror
+    /// downloads, so it starts rather than resumes
+    pub inserted: bool,
+}
+
+impl DownloadOptions {
+    pub fn new(max_retries: u8, temp_dir: PathBuf) -> Self {
+        DownloadOptions {
+            max_retries,
+            file_name: None,
+            variant_selector: VariantSelector::Highest,
+            location: None,
+            headers: Vec::new(),
+            checksum: None,
+            part_count: None,
+            preallocate: false,
+            multi_progress: None,
+            on_complete: None,
+            on_failure: None,
+            hook_timeout: Duration::from_secs(120),
+            extract: false,
+            extract_dir: None,
+            delete_archive: false,
+            start_after: None,
+            schedule: None,
+            checkpoint_interval: Duration::from_secs(5),
+            active: ActiveDownloads::new(),
+            temp_dir,
+            rate_limit: 0,
+            inserted: false,
+        }
+    }
+}
+
+/// Builds the HLS variant selector from the download options
+pub fn variant_selector(bandwidth: Option<u64>, resolution: Option<(u32, u32)>) -> VariantSelector {
+    match (resolution, bandwidth) {
+        (Some((w, h)), _) => VariantSelector::Resolution(w, h),
+        (None, Some(b)) => VariantSelector::MaxBandwidth(b),
+        (None, None) => VariantSelector::Highest,
+    }
+}
+
+pub async fn handle_delete(
+    id: i64,
+    remove_file: bool,
+    db_pool: Repository,
+) -> Result<(), ExitCode> {
     let task = match db_pool.get_task_by_id(id).await {
         Ok(task) => task,
         Err(e) => {
-            eprintln!("{}", e);
-            return Err(());
+            return Err(report(e));
         }
     };
 
     if let Some(t) = task {
+        // A task being downloaded would be recreated by its process
+        let lock = match lock_task(&t).await {
+            Ok(l) => l,
+            Err(e) => return Err(report(e)),
+        };
         if let Err(e) = db_pool.remove_task(id).await {
-            eprintln!("{}", e);
-            return Err(());
+            return Err(report(e));
         }
+        drop(lock);
+        remove_temp_dir(&t).await;
         if remove_file {
-            for i in 0..t.max_threads {
-                let f_name = format!("{tmp}{sep}{fn}.{id}", tmp = TEMP_DIR, sep = MAIN_SEPARATOR, fn=t.file_name.to_owned(), id=i);
-                if PathBuf::from(&f_name).is_file() {
-                    if let Err(e) = fs::remove_file(&f_name).await {
-                        eprintln!("{} : Path {}", e, f_name);
-                    }
-                } else {
-                    break;
-                }
-            }
-
-            let final_file = PathBuf::from(format!("{}{}", t.final_file_path, t.file_name));
+            let mut final_file = PathBuf::from(&t.final_file_path);
+            final_file.push(&t.file_name);
             if final_file.is_file() {
                 if let Err(e) = fs::remove_file(&final_file).await {
-                    eprintln!("{} : Path {}", e, final_file.to_string_lossy().to_string());
+                    eprintln!("{} : Path {}", e, final_file.to_string_lossy());
                 }
             }
         }
+        output::emit(Event::Deleted {
+            task_id: t.id,
+            file_removed: remove_file,
+        });
     } else {
-        println!("No task found");
+        output::info("No task found");
     };
 
     Ok(())
 }
 
-pub async fn handle_download(
-    url: String,
-    output_dir: PathBuf,
-    max_threads: u64,
-    in_memory: bool,
-    pool: SqlitePool,
-    max_retires: u8,
-) -> Result<(), ()> {
-    let download_task = match pool.get_task_by_url(&url).await {
-        Ok(task) => task,
-        Err(_) => None,
+/// Task `id`, reported when there's none
+async fn get_task(pool: &Repository, id: i64) -> Result<DownloadTaskEntity, ExitCode> {
+    match pool.get_task_by_id(id).await {
+        Ok(Some(t)) => Ok(t),
+        Ok(None) => {
+            let message = format!("No task with id {}", id);
+            if output::is_json() {
+                output::emit(Event::error(ExitCode::Failure, message));
+            } else {
+                eprintln!("{}", message);
+            }
+            Err(ExitCode::Failure)
+        }
+        Err(e) => Err(report(e)),
+    }
+}
+
+/// Resumes `task` where it was left, with the destination, threads,
+/// headers and hooks it was created with
+async fn resume_task(
+    task: DownloadTaskEntity,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let url = task.file_url.to_owned();
+    let output_dir = PathBuf::from(&task.final_file_path);
+    let max_threads = task.max_threads;
+    download(
+        url,
+        Some(task),
+        output_dir,
+        max_threads,
+        false,
+        pool,
+        options,
+    )
+    .await
+}
+
+pub async fn handle_resume(
+    id: i64,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let task = get_task(&pool, id).await?;
+    resume_task(task, pool, options).await
+}
+
+/// Resumes every failed task, `max_concurrent` at a time
+pub async fn handle_retry_failed(
+    max_concurrent: usize,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let tasks = match pool.get_tasks(Df::Status(DownloadStatus::Failed)).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
     };
+    if tasks.is_empty() {
+        output::info("No failed tasks");
+        return Ok(());
+    }
 
-    let mut dtask_entity: DownloadTaskEntity;
+    let multi_progress = MultiProgress::new();
+    let results = stream::iter(tasks)
+        .map(|task| {
+            let mut options = options.clone();
+            options.multi_progress = Some(multi_progress.clone());
+            let pool = pool.clone();
+            async move {
+                let name = format!("[{}] {}", task.id, task.file_name);
+                output::info(format!("Retrying {}", name));
+                (name, resume_task(task, pool, options).await)
+            }
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect::<Vec<(String, Result<(), ExitCode>)>>()
+        .await;
 
-    let pbr = ProgressBar::new(100);
-    pbr.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise:.green}] |{bar:40.blue/cyan}| {pos:0}% ● {binary_bytes_per_sec:.green} eta {eta:.blue}",
-        )
-        .unwrap()
-        .progress_chars("■▪▫"),
-    );
+    if options.active.is_stopping() {
+        output::info("\nPaused, run the same command again to resume");
+        return Err(ExitCode::Interrupted);
+    }
+    report_batch(results)
+}
+
+/// Sums up the downloads of a batch, which fails if any of them did
+fn report_batch(results: Vec<(String, Result<(), ExitCode>)>) -> Result<(), ExitCode> {
+    let mut succeeded = 0;
+    let mut paused = Vec::new();
+    let mut failed = Vec::new();
+    for (name, result) in results.into_iter() {
+        match result {
+            Ok(()) => succeeded += 1,
+            // Paused on its own by `whip pause`
+            Err(ExitCode::Interrupted) => paused.push(name),
+            Err(code) => failed.push((name, code)),
+        }
+    }
+    output::info(format!(
+        "\n{} succeeded, {} paused, {} failed",
+        succeeded,
+        paused.len(),
+        failed.len()
+    ));
+    for name in paused.iter() {
+        output::info(format!("  paused : {}", name));
+    }
+    for (name, _) in failed.iter() {
+        output::info(format!("  failed : {}", name));
+    }
+    if !failed.is_empty() {
+        return summarize(
+            &failed
+                .iter()
+                .map(|(_, code)| *code)
+                .collect::<Vec<ExitCode>>(),
+        );
+    }
+    if !paused.is_empty() {
+        return Err(ExitCode::Interrupted);
+    }
+    Ok(())
+}
+
+/// Throws away what was downloaded of task `id` and downloads it again
+pub async fn handle_restart(
+    id: i64,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let task = get_task(&pool, id).await?;
+    let lock = match lock_task(&task).await {
+        Ok(l) => l,
+        Err(e) => return Err(report(e)),
+    };
+    let mut task = match reset_progress(&pool, task).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
+    };
+    task.extracted_path = None;
+    task.extraction_result = None;
+    let task = match pool.update_task(task).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
+    };
+    remove_temp_dir(&task).await;
+    drop(lock);
+
+    output::info(format!("Restarting download : {}", task.file_name));
+    resume_task(task, pool, options).await
+}
 
-    let downloader;
+/// Sets the progress of `task` and its parts back to nothing
+async fn reset_progress(
+    pool: &Repository,
+    mut task: DownloadTaskEntity,
+) -> Result<DownloadTaskEntity, DatabaseError> {
+    // Parts are kept by the checkpoints, they start over like the rest
+    let parts = pool
+        .get_parts(task.id as i64)
+        .await?
+        .into_iter()
+        .map(|mut p| {
+            p.bytes_done = 0;
+            p.state = PartState::Pending;
+            p
+        })
+        .collect();
+    let checkpoint = Checkpoint {
+        task_id: task.id,
+        bytes_done: 0,
+        total_bytes: task.file_size,
+        speed: 0f64,
+        parts,
+    };
+    pool.save_checkpoints(&[checkpoint]).await?;
+    task.percentage_completed = 0f64;
+    task.bytes_done = 0;
+    pool.update_task(task).await
+}
+
+/// Writes every task with its parts to `file`, and the temporary files of
+/// the unfinished ones to the `temp_data` archive
+pub async fn handle_export(
+    file: PathBuf,
+    format: Option<ExportFormat>,
+    temp_data: Option<PathBuf>,
+    pool: Repository,
+) -> Result<(), ExitCode> {
+    let format = format.unwrap_or_else(|| ExportFormat::of(&file));
+    let mut tasks = match pool.get_tasks(Df::All).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
+    };
+    tasks.sort_by_key(|t| t.id);
 
-    let on_progress_changed = move |p: f64| {
-        if !pbr.is_finished() {
-            pbr.set_position(p.floor() as u64);
+    let mut exported = Vec::new();
+    let mut temp_files = BTreeMap::new();
+    for task in tasks.iter() {
+        let parts = match pool.get_parts(task.id as i64).await {
+            Ok(p) => p,
+            Err(e) => return Err(report(e)),
+        };
+        let record = ExportedTask::new(task, &parts);
+        if temp_data.is_some() && record.is_unfinished() {
+            temp_files.insert(task.id, temp_files_of(task).await);
         }
+        exported.push(record);
+    }
+
+    let content = match transfer::write(exported, format) {
+        Ok(c) => c,
+        Err(e) => return Err(report(WhipError::Parse(e))),
     };
-    let on_complete = |s: String| {
-        println!("\nFile downloaded successfully : {}", s);
+    if let Err(e) = fs::write(&file, content).await {
+        return Err(report(WhipError::io(file, e)));
+    }
+    output::info(format!(
+        "Exported {} tasks to {}",
+        tasks.len(),
+        file.to_string_lossy()
+    ));
+
+    let mut bundled = 0;
+    if let Some(archive) = &temp_data {
+        bundled = match transfer::write_bundle(archive, &temp_files) {
+            Ok(count) => count,
+            Err(e) => return Err(report(WhipError::io(archive.to_owned(), e))),
+        };
+        output::info(format!(
+            "Bundled {} temporary files into {}",
+            bundled,
+            archive.to_string_lossy()
+        ));
+    }
+    output::emit(Event::Exported {
+        path: file.to_string_lossy().to_string(),
+        tasks: tasks.len(),
+        temp_data: temp_data.map(|a| a.to_string_lossy().to_string()),
+        temp_files: bundled,
+    });
+    Ok(())
+}
+
+/// Temporary files a task downloaded so far, its lock file left out
+async fn temp_files_of(task: &DownloadTaskEntity) -> Vec<PathBuf> {
+    let lock_file = TaskLock::file_path(Path::new(&task.temp_files_path), task.id);
+    let mut files = Vec::new();
+    if has_own_temp_dir(task) {
+        if let Ok(mut entries) = fs::read_dir(&task.temp_files_path).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.is_file() && path != lock_file {
+                    files.push(path);
+                }
+            }
+        }
+    } else {
+        files = shared_temp_files(task).await;
+    }
+    files.sort();
+    files
+}
+
+/// Stores the tasks of an export, each one getting a temporary directory of
+/// its own where the files bundled in `temp_data` are put back
+pub async fn handle_import(
+    file: PathBuf,
+    format: Option<ExportFormat>,
+    on_conflict: Conflict,
+    temp_data: Option<PathBuf>,
+    temp_dir: PathBuf,
+    pool: Repository,
+) -> Result<(), ExitCode> {
+    let format = format.unwrap_or_else(|| ExportFormat::of(&file));
+    let content = match fs::read(&file).await {
+        Ok(c) => c,
+        Err(e) => return Err(report(WhipError::io(file, e))),
     };
-    let on_error = |e: WhipError| {
-        eprintln!("\n{}", e);
+    let exported = match transfer::read(&content, format) {
+        Ok(t) => t,
+        Err(e) => {
+            let e = WhipError::Parse(format!("{} : {}", file.to_string_lossy(), e));
+            return Err(report(e));
+        }
     };
 
-    if let Some(d_task) = download_task {
-        if d_task.percentage_completed >= 100f64 {
-            let mut path = PathBuf::new();
-            path.push(&d_task.final_file_path);
-            path.push(&d_task.file_name);
-            if path.is_file() {
-                if let Ok(metadata) = path.metadata() {
-                    if metadata.len() == d_task.file_size {
-                        println!(
-                            "File already downloaded : {}",
-                            path.to_string_lossy().to_string()
-                        );
-                        return Ok(());
+    let (mut imported, mut skipped, mut failures) = (0, 0, Vec::new());
+    // Unfinished tasks, by the id they were exported with
+    let mut unfinished = BTreeMap::new();
+    for record in exported.into_iter() {
+        let url = record.url.to_owned();
+        let existing = match pool.get_task_by_url(&url).await {
+            Ok(t) => t,
+            Err(e) => return Err(report(e)),
+        };
+        let mut action = "imported";
+        match (existing, on_conflict) {
+            (Some(task), Conflict::Skip) => {
+                output::info(format!("Skipped {}, it's task {}", url, task.id));
+                output::emit(Event::Imported {
+                    url,
+                    task_id: task.id,
+                    action: "skipped",
+                });
+                skipped += 1;
+                continue;
+            }
+            (Some(task), Conflict::Replace) => {
+                // Its process would store it again
+                let lock = match lock_task(&task).await {
+                    Ok(l) => l,
+                    Err(e) => {
+                        failures.push(report(e));
+                        continue;
                     }
+                };
+                if let Err(e) = pool.remove_task(task.id as i64).await {
+                    return Err(report(e));
                 }
-            } else {
-                println!(
-                    "Can't find full file : {}",
-                    path.to_string_lossy().to_string()
-                );
+                drop(lock);
+                remove_temp_dir(&task).await;
+                action = "replaced";
             }
+            _ => {}
         }
 
-        println!("Resuming download : {}", d_task.file_name);
-
-        downloader = Downloader::restore(
-            d_task.percentage_completed,
-            d_task.to_download_task(),
-            output_dir.to_string_lossy().to_string(),
-            d_task.temp_files_path.to_owned(),
-            on_progress_changed,
-            on_complete,
-            on_error,
-            in_memory,
-            d_task.max_threads as u8,
-            max_retires,
-        );
-        dtask_entity = d_task;
-        dtask_entity.final_file_path = output_dir.to_string_lossy().to_string();
-    } else {
-        println!("Profiling Download");
-        let download_task = match DownloadTask::new(url).await {
-            Ok(task) => task,
+        let is_unfinished = record.is_unfinished();
+        let exported_id = record.id;
+        let (task, parts) = match record.into_task() {
+            Ok(t) => t,
             Err(e) => {
-                eprintln!("{}", e);
-                return Err(());
+                let e = WhipError::Parse(format!("{} : {}", file.to_string_lossy(), e));
+                failures.push(report(e));
+                continue;
             }
         };
+        let id = match pool.import_task(&task, &parts).await {
+            Ok(id) => id,
+            Err(e) => return Err(report(e)),
+        };
+        let mut task = task;
+        task.id = id;
+        task.temp_files_path = task_temp_dir(&temp_dir, id).to_string_lossy().to_string();
+        let task = match pool.update_task(task).await {
+            Ok(t) => t,
+            Err(e) => return Err(report(e)),
+        };
+        if is_unfinished {
+            unfinished.insert(exported_id, task);
+        }
+        output::emit(Event::Imported {
+            url,
+            task_id: id,
+            action,
+        });
+        imported += 1;
+    }
 
-        match pool
-            .insert_task(
-                &download_task,
-                TEMP_DIR.to_owned(),
-                output_dir.to_string_lossy().to_string(),
-                max_threads.to_string(),
-            )
-            .await
-        {
-            Err(e) => {
-                eprintln!("{}", e);
-                return Err(());
-            }
-            Ok(id) => {
-                dtask_entity = pool.get_task_by_id(id as i64).await.unwrap().unwrap();
-            }
+    let mut restored = BTreeSet::new();
+    if let Some(archive) = &temp_data {
+        let dirs = unfinished
+            .iter()
+            .map(|(id, task)| (*id, PathBuf::from(&task.temp_files_path)))
+            .collect();
+        restored = match transfer::read_bundle(archive, &dirs) {
+            Ok(r) => r,
+            Err(e) => return Err(report(WhipError::io(archive.to_owned(), e))),
         };
+    }
+    // Without their files, unfinished downloads start over
+    for (exported_id, task) in unfinished.into_iter() {
+        if !restored.contains(&exported_id) && task.bytes_done > 0 {
+            if let Err(e) = reset_progress(&pool, task).await {
+                return Err(report(e));
+            }
+        }
+    }
 
-        println!("Starting download : {}", download_task.meta.file_name);
+    output::info(format!(
+        "Imported {} tasks, skipped {}, {} with their temporary files",
+        imported,
+        skipped,
+        restored.len()
+    ));
+    summarize(&failures)
+}
 
-        match Downloader::new(
-            download_task,
-            output_dir.to_string_lossy().to_string(),
-            TEMP_DIR.to_string(),
-            on_progress_changed,
-            on_complete,
-            on_error,
-            in_memory,
-            max_threads as u8,
-            max_retires,
-        ) {
-            Ok(t) => {
-                downloader = t;
+/// Asks the process downloading task `id` to pause and waits for it to,
+/// queued tasks are taken out of the queue
+pub async fn handle_pause(id: i64, pool: Repository) -> Result<(), ExitCode> {
+    let task = get_task(&pool, id).await?;
+    let pid = match TaskLock::holder(Path::new(&task.temp_files_path), task.id) {
+        Some(pid) => pid,
+        None => {
+            if !task.status.can_become(DownloadStatus::Paused) {
+                output::info(format!(
+                    "{} isn't being downloaded, it's {}",
+                    task.file_name, task.status
+                ));
+                return Ok(());
             }
-            Err(e) => {
-                eprintln!("{}", e);
-                return Err(());
+            // Nothing runs it, only the status is left to change
+            return match set_status(&pool, task, DownloadStatus::Paused).await {
+                Ok(t) => {
+                    output::info(format!("Paused {}", t.file_name));
+                    Ok(())
+                }
+                Err(e) => Err(report(e)),
+            };
+        }
+    };
+
+    if let Err(e) = TaskLock::request_pause(Path::new(&task.temp_files_path), task.id) {
+        return Err(report(e));
+    }
+    output::info(format!("Asked process {} to pause {}", pid, task.file_name));
+    // It lets go of the task once its progress is saved
+    for _ in 0..150 {
+        if TaskLock::holder(Path::new(&task.temp_files_path), task.id).is_none() {
+            if let Ok(Some(t)) = pool.get_task_by_id(id).await {
+                output::emit(Event::Task(TaskRecord::from(&t)));
             }
+            output::info(format!("Paused {}", task.file_name));
+            return Ok(());
         }
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
+    output::info(format!("Process {} is still pausing", pid));
+    Err(ExitCode::Failure)
+}
 
-    match downloader.download().await {
-        Err(e) => {
-            eprintln!("{}", e);
-            return Err(());
+pub fn handle_config(command: ConfigCommand, config_path: &Path) -> Result<(), ExitCode> {
+    match command {
+        ConfigCommand::Show => {
+            let settings = match Settings::load(config_path) {
+                Ok(s) => s,
+                Err(e) => return Err(report(e)),
+            };
+            let state = if config_path.is_file() {
+                ""
+            } else {
+                " (doesn't exist)"
+            };
+            output::info(format!(
+                "Configuration file : {}{}",
+                config_path.to_string_lossy(),
+                state
+            ));
+            if output::is_json() {
+                for (key, value, source) in settings.values() {
+                    output::emit(Event::Setting {
+                        key: key.to_string(),
+                        value,
+                        source: source.to_string(),
+                    });
+                }
+            } else {
+                print!("{}", settings.describe());
+            }
         }
-        Ok(p) => {
-            dtask_entity.percentage_completed = (p / dtask_entity.file_size as f64) * 100f64;
-            if let Err(e) = pool.update_task(dtask_entity).await {
-                eprintln!("{}", e);
-                return Err(());
+        ConfigCommand::Set { key, value } => {
+            let mut file = match ConfigFile::load(config_path) {
+                Ok(f) => f,
+                Err(e) => return Err(report(e)),
             };
+            if let Err(e) = file.set(&key, &value) {
+                return Err(report(e));
+            }
+            if let Err(e) = file.save(config_path) {
+                return Err(report(e));
+            }
+            output::info(format!(
+                "Saved {} to {}",
+                key,
+                config_path.to_string_lossy()
+            ));
+            output::emit(Event::Setting {
+                key,
+                value,
+                source: Source::File.to_string(),
+            });
         }
-    };
-
-    return Ok(());
+    }
+    Ok(())
 }
 
-pub async fn handle_show_downloads(filter: DownloadFilter, pool: SqlitePool) -> Result<(), ()> {
-    let df = filter.into();
+pub async fn handle_tui(options: TuiOptions, pool: Repository) -> Result<(), ExitCode> {
+    if output::is_json() {
+        let message = String::from("The dashboard is interactive, it has no JSON output");
+        output::emit(Event::error(ExitCode::Failure, message));
+        return Err(ExitCode::Failure);
+    }
+    // Its downloads run in other processes, which wouldn't see these tasks
+    if let Repository::Memory(_) = pool {
+        eprintln!(
+            "The dashboard needs the sqlite or json backend, the downloads it starts can't share the memory one"
+        );
+        return Err(ExitCode::Config);
+    }
+    if let Err(e) = tui::run(pool, options).await {
+        eprintln!("{}", e);
+        return Err(ExitCode::Failure);
+    }
+    Ok(())
+}
 
-    let downloads = match pool.get_tasks(df).await {
-        Ok(res) => res,
-        Err(e) => {
-            eprintln!("{}", e);
-            return Err(());
-        }
+pub async fn handle_gc(apply: bool, temp_dir: PathBuf, pool: Repository) -> Result<(), ExitCode> {
+    let tasks = match pool.get_tasks(Df::All).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
     };
-
-    if downloads.is_empty() {
-        println!("You have no downloads");
+    let garbage = find_garbage(&tasks, &temp_dir);
+    if garbage.orphans.is_empty() && garbage.dangling.is_empty() {
+        output::info("Nothing to clean up");
         return Ok(());
     }
 
-    let mut table = Table::new();
-
-    table.add_row(row![bFg->"id", bFg->"File Name", bFg->"Status"]);
-
-    for (_, download) in downloads.iter().enumerate() {
-        table.add_row(row![
-            download.id,
-            download.file_name,
-            if download.percentage_completed >= 100f64 {
-                "Completed"
-            } else {
-                "In Progress"
-            }
+    let mut failures = Vec::new();
+    let mut reclaimed = 0;
+    let mut records = 0;
+    if !garbage.orphans.is_empty() {
+        output::info("Orphaned data :");
+    }
+    for orphan in garbage.orphans.iter() {
+        output::info(format!(
+            "  {} ({}) : {}",
+            orphan.path.to_string_lossy(),
+            format_size(orphan.size),
+            orphan.reason
+        ));
+        let mut removed = false;
+        if apply {
+            // Its task may have started again since it was found
+            match lock_orphan(orphan) {
+                Ok(lock) => {
+                    let result = if orphan.path.is_dir() {
+                        fs::remove_dir_all(&orphan.path).await
+                    } else {
+                        fs::remove_file(&orphan.path).await
+                    };
+                    drop(lock);
+                    match result {
+                        Ok(()) => {
+                            removed = true;
+                            reclaimed += orphan.size;
+                        }
+                        Err(e) => failures.push(report(WhipError::io(orphan.path.to_owned(), e))),
+                    }
+                }
+                Err(WhipError::Locked { task_id, .. }) => output::info(format!(
+                    "  [{}] is being downloaded, leaving it alone",
+                    task_id
+                )),
+                Err(e) => failures.push(report(e)),
+            }
+        }
+        output::emit(Event::Orphan {
+            path: orphan.path.to_string_lossy().to_string(),
+            size: orphan.size,
+            reason: orphan.reason.to_owned(),
+            removed,
+        });
+    }
+    if !garbage.dangling.is_empty() {
+        output::info("Completed tasks whose files are gone :");
+    }
+    for record in garbage.dangling.iter() {
+        output::info(format!(
+            "  [{}] {} : {} doesn't exist",
+            record.task_id,
+            record.file_name,
+            record.path.to_string_lossy()
+        ));
+        let mut removed = false;
+        if apply {
+            // A task another process is downloading isn't dangling anymore
+            if let Some(task) = tasks.iter().find(|t| t.id == record.task_id) {
+                match lock_task(task).await {
+                    Ok(lock) => {
+                        match pool.remove_task(record.task_id as i64).await {
+                            Ok(()) => {
+                                removed = true;
+                                records += 1;
+                            }
+                            Err(e) => failures.push(report(e)),
+                        }
+                        drop(lock);
+                        if removed {
+                            remove_temp_dir(task).await;
+                        }
+                    }
+                    Err(WhipError::Locked { .. }) => output::info(format!(
+                        "  [{}] is being downloaded, leaving it alone",
+                        record.task_id
+                    )),
+                    Err(e) => failures.push(report(e)),
+                }
+            }
+        }
+        output::emit(Event::DanglingRecord {
+            task_id: record.task_id,
+            file_name: record.file_name.to_owned(),
+            path: record.path.to_string_lossy().to_string(),
+            removed,
+        });
+    }
+
+    if apply {
+        output::info(format!(
+            "Reclaimed {}, removed {} records",
+            format_size(reclaimed),
+            records
+        ));
+    } else {
+        output::info(format!(
+            "{} can be reclaimed and {} records removed, run again with --apply to do it",
+            format_size(garbage.size()),
+            garbage.dangling.len()
+        ));
+    }
+    summarize(&failures)
+}
+
+pub async fn handle_download(
+    url: String,
+    output_dir: PathBuf,
+    max_threads: u32,
+    in_memory: bool,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    if options.active.is_stopping() {
+        return Err(ExitCode::Interrupted);
+    }
+    if metalink::is_metalink(&url) {
+        return handle_metalink_download(url, output_dir, max_threads, pool, options).await;
+    }
+
+    let download_task = pool.get_task_by_url(&url).await.unwrap_or_default();
+    download(
+        url,
+        download_task,
+        output_dir,
+        max_threads,
+        in_memory,
+        pool,
+        options,
+    )
+    .await
+}
+
+/// Downloads `url` as a new task, or resumes `known` when it's already one
+async fn download(
+    url: String,
+    known: Option<DownloadTaskEntity>,
+    output_dir: PathBuf,
+    max_threads: u32,
+    in_memory: bool,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let mut dtask_entity: DownloadTaskEntity;
+
+    let mut downloader;
+    // Bar of the whole file, the ones of the parts go with the downloader
+    let pbr;
+    // Held until the download ends so no other process works on the task
+    let _lock;
+
+    let on_complete = |s: String| {
+        output::info(format!("\nFile downloaded successfully : {}", s));
+    };
+    let on_error = |e: WhipError| {
+        if output::is_json() {
+            report(e);
+        } else {
+            eprintln!("\n{}", e);
+        }
+    };
+
+    if let Some(d_task) = known {
+        // Its pieces are spread over the mirrors the metalink file lists
+        if let Some(source) = d_task.source.to_owned() {
+            return resume_metalink_task(d_task, &source, output_dir, max_threads, pool, options)
+                .await;
+        }
+        if d_task.percentage_completed >= 100f64 {
+            let mut path = PathBuf::new();
+            path.push(&d_task.final_file_path);
+            path.push(&d_task.file_name);
+            if path.is_file() {
+                if let Ok(metadata) = path.metadata() {
+                    if metadata.len() == d_task.file_size {
+                        output::info(format!(
+                            "File already downloaded : {}",
+                            path.to_string_lossy()
+                        ));
+                        output::emit(Event::Task(TaskRecord::from(&d_task)));
+                        return Ok(());
+                    }
+                }
+            } else if let Some(extracted_path) = &d_task.extracted_path {
+                // The archive was deleted once extracted
+                if PathBuf::from(extracted_path).is_dir() {
+                    output::info(format!(
+                        "File already downloaded and extracted : {}",
+                        extracted_path
+                    ));
+                    output::emit(Event::Task(TaskRecord::from(&d_task)));
+                    return Ok(());
+                }
+                output::info(format!("Can't find full file : {}", path.to_string_lossy()));
+            } else {
+                output::info(format!("Can't find full file : {}", path.to_string_lossy()));
+            }
+        }
+
+        if options.inserted {
+            output::info(format!("Starting download : {}", d_task.file_name));
+        } else {
+            output::info(format!("Resuming download : {}", d_task.file_name));
+        }
+        _lock = match lock_task(&d_task).await {
+            Ok(l) => l,
+            Err(e) => return Err(report(e)),
+        };
+        let mut d_task = d_task;
+        if d_task.status == DownloadStatus::Downloading {
+            // Left downloading by a process that was killed, resuming is another attempt
+            if let Err(e) = d_task.set_status(DownloadStatus::Paused) {
+                return Err(report(e));
+            }
+        }
+        let d_task = match store_options(&pool, d_task, &options).await {
+            Ok(t) => t,
+            Err(e) => return Err(report(e)),
+        };
+
+        if hls::is_hls(&d_task.to_download_task()) || dash::is_dash(&d_task.to_download_task()) {
+            let mut d_task = d_task;
+            d_task.final_file_path = output_dir.to_string_lossy().to_string();
+            return handle_stream_download(d_task, options, pool).await;
+        }
+
+        let bars = PartBars::new(options.multi_progress.as_ref(), d_task.id);
+        pbr = bars.total_bar();
+        downloader = Downloader::restore(
+            // What the temporary parts hold is counted by the downloader
+            0f64,
+            d_task.to_download_task(),
+            output_dir.to_string_lossy().to_string(),
+            d_task.temp_files_path.to_owned(),
+            bars.callback(),
+            on_complete,
+            on_error,
+            in_memory,
+            d_task.max_threads,
+            d_task.part_count,
+            options.max_retries,
+        );
+        dtask_entity = d_task;
+        dtask_entity.final_file_path = output_dir.to_string_lossy().to_string();
+    } else {
+        output::info("Profiling Download");
+        let mut download_task = match DownloadTask::with_headers(url, options.headers.clone()).await
+        {
+            Ok(task) => task,
+            Err(e) => {
+                return Err(report(e));
+            }
+        };
+        download_task.checksum = options.checksum.clone();
+
+        if metalink::is_metalink_content_type(&download_task.meta.content_type) {
+            return handle_metalink_download(
+                download_task.file_url,
+                output_dir,
+                max_threads,
+                pool,
+                options,
+            )
+            .await;
+        }
+
+        let is_stream = if hls::is_hls(&download_task) {
+            download_task.meta.file_name = hls::output_file_name(&download_task.meta.file_name);
+            true
+        } else if dash::is_dash(&download_task) {
+            download_task.meta.file_name = dash::output_file_stem(&download_task.meta.file_name);
+            true
+        } else {
+            false
+        };
+
+        if let Some(file_name) = &options.file_name {
+            download_task.meta.file_name = file_name.to_owned();
+        }
+        let part_count = options.part_count.unwrap_or(max_threads);
+
+        dtask_entity = match insert_task(
+            &pool,
+            &download_task,
+            &output_dir,
+            &options.temp_dir,
+            max_threads,
+            part_count,
+        )
+        .await
+        {
+            Ok(t) => t,
+            Err(e) => return Err(report(e)),
+        };
+        dtask_entity = match store_options(&pool, dtask_entity, &options).await {
+            Ok(t) => t,
+            Err(e) => return Err(report(e)),
+        };
+        _lock = match lock_task(&dtask_entity).await {
+            Ok(l) => l,
+            Err(e) => return Err(report(e)),
+        };
+
+        output::info(format!(
+            "Starting download : {}",
+            download_task.meta.file_name
+        ));
+
+        if is_stream {
+            return handle_stream_download(dtask_entity, options, pool).await;
+        }
+
+        let bars = PartBars::new(options.multi_progress.as_ref(), dtask_entity.id);
+        pbr = bars.total_bar();
+        match Downloader::new(
+            download_task,
+            output_dir.to_string_lossy().to_string(),
+            dtask_entity.temp_files_path.to_owned(),
+            bars.callback(),
+            on_complete,
+            on_error,
+            in_memory,
+            max_threads,
+            part_count,
+            options.max_retries,
+        ) {
+            Ok(t) => {
+                downloader = t;
+            }
+            Err(e) => {
+                return Err(report(e));
+            }
+        }
+    }
+
+    downloader.preallocate = options.preallocate;
+    dtask_entity = match set_status(&pool, dtask_entity, DownloadStatus::Downloading).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
+    };
+    let (sender, receiver) = mpsc::unbounded_channel();
+    downloader.checkpointer = Some(Checkpointer::new(
+        dtask_entity.id,
+        options.checkpoint_interval,
+        sender,
+    ));
+    let writer = spawn_checkpoint_writer(pool.clone(), receiver, options.checkpoint_interval);
+    let parts = downloader.parts();
+    options.active.register(
+        dtask_entity.id,
+        &dtask_entity.file_name,
+        Path::new(&dtask_entity.temp_files_path),
+        downloader.control(),
+        Some(parts.clone()),
+    );
+    let scheduler = start_scheduler(&dtask_entity, downloader.control(), pbr, options.rate_limit);
+    let downloaded = downloader.download().await;
+    if let Some(s) = scheduler {
+        s.abort();
+    }
+    options.active.unregister(dtask_entity.id);
+    // The last checkpoint has to land before the task is updated
+    if let Err(e) = writer.await {
+        eprintln!("{}", e);
+    }
+    match downloaded {
+        Err(e) => {
+            // Keeps the progress of the last checkpoint
+            dtask_entity.bytes_done = parts.bytes_done();
+            if dtask_entity.file_size > 0 {
+                dtask_entity.percentage_completed =
+                    parts.bytes_done() as f64 / dtask_entity.file_size as f64 * 100f64;
+            }
+            let dtask_entity = stop_task(&pool, dtask_entity, &e).await;
+            if let Some(code) = report_paused(&dtask_entity, &e) {
+                return Err(code);
+            }
+            run_hook(&pool, &dtask_entity, HookEvent::Failure, Some(&e), &options).await;
+            return Err(report(e));
+        }
+        Ok(p) => {
+            // The server didn't tell the size of the file
+            if dtask_entity.file_size == 0 {
+                dtask_entity.file_size = p as u64;
+            }
+            dtask_entity.bytes_done = p as u64;
+            dtask_entity.percentage_completed = if dtask_entity.file_size == 0 {
+                100f64
+            } else {
+                (p / dtask_entity.file_size as f64) * 100f64
+            };
+            let extracted = if dtask_entity.percentage_completed >= 100f64 {
+                if let Err(e) = dtask_entity.set_status(DownloadStatus::Completed) {
+                    return Err(report(e));
+                }
+                extract_archive(&pool, &mut dtask_entity, &options).await
+            } else {
+                Ok(())
+            };
+            dtask_entity = match pool.update_task(dtask_entity).await {
+                Ok(t) => t,
+                Err(e) => return Err(report(e)),
+            };
+            if let Err(e) = save_progress(&pool, &dtask_entity).await {
+                return Err(report(e));
+            }
+            output::emit(Event::Task(TaskRecord::from(&dtask_entity)));
+            // Failed parts are reported through on_error, the download stays resumable
+            if dtask_entity.percentage_completed < 100f64 {
+                let e = WhipError::Unknown(String::from("Some parts failed to download"));
+                let dtask_entity = stop_task(&pool, dtask_entity, &e).await;
+                run_hook(&pool, &dtask_entity, HookEvent::Failure, Some(&e), &options).await;
+                return Err(ExitCode::Failure);
+            }
+            run_hook(&pool, &dtask_entity, HookEvent::Complete, None, &options).await;
+            remove_temp_dir(&dtask_entity).await;
+            if let Err(e) = extracted {
+                return Err(report(e));
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// Downloads the urls listed in an input file, `max_concurrent` at a time.
+/// Every url is profiled and stored as a task before any download starts,
+/// so the whole list can be resumed by running the same command again.
+pub async fn handle_batch_download(
+    input_file: PathBuf,
+    output_dir: PathBuf,
+    max_threads: u32,
+    max_concurrent: usize,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let content = match fs::read_to_string(&input_file).await {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(report(WhipError::io(input_file, e)));
+        }
+    };
+    let entries = match parse_input_file(&content) {
+        Ok(e) => e,
+        Err(e) => {
+            let e = WhipError::Parse(format!("{} : {}", input_file.to_string_lossy(), e));
+            return Err(report(e));
+        }
+    };
+    if entries.is_empty() {
+        output::info(format!("No urls in {}", input_file.to_string_lossy()));
+        return Ok(());
+    }
+
+    let mut downloads = Vec::new();
+    let mut failed = Vec::new();
+    for entry in entries.into_iter() {
+        let dir = entry.output_dir.unwrap_or_else(|| output_dir.clone());
+        let threads = entry.max_threads.unwrap_or(max_threads);
+        let headers = merge_headers(&options.headers, entry.headers);
+
+        let known = matches!(pool.get_task_by_url(&entry.url).await, Ok(Some(_)));
+        let mut inserted = false;
+        if !known && !metalink::is_metalink(&entry.url) {
+            output::info(format!("Profiling {}", entry.url));
+            let mut task =
+                match DownloadTask::with_headers(entry.url.to_owned(), headers.clone()).await {
+                    Ok(t) => t,
+                    Err(e) => {
+                        failed.push((entry.url, report(e)));
+                        continue;
+                    }
+                };
+            task.checksum = entry.checksum.clone();
+            if let Some(file_name) = &entry.file_name {
+                task.meta.file_name = file_name.to_owned();
+            }
+            // Playlists, manifests and metalink files get named and stored by handle_download
+            let is_plain_file = !hls::is_hls(&task)
+                && !dash::is_dash(&task)
+                && !metalink::is_metalink_content_type(&task.meta.content_type);
+            if is_plain_file {
+                let part_count = options.part_count.unwrap_or(threads);
+                if let Err(e) =
+                    insert_task(&pool, &task, &dir, &options.temp_dir, threads, part_count).await
+                {
+                    failed.push((entry.url, report(e)));
+                    continue;
+                }
+                inserted = true;
+            }
+        }
+
+        let mut options = options.clone();
+        options.inserted = inserted;
+        options.file_name = entry.file_name;
+        options.headers = headers;
+        options.checksum = entry.checksum;
+        downloads.push((entry.url, dir, threads, options));
+    }
+
+    let multi_progress = MultiProgress::new();
+    let results = stream::iter(downloads)
+        .map(|(url, dir, threads, mut options)| {
+            options.multi_progress = Some(multi_progress.clone());
+            let pool = pool.clone();
+            async move {
+                if let Err(e) = fs::create_dir_all(&dir).await {
+                    return (url, Err(report(WhipError::io(dir, e))));
+                }
+                let result =
+                    handle_download(url.to_owned(), dir, threads, false, pool, options).await;
+                (url, result)
+            }
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect::<Vec<(String, Result<(), ExitCode>)>>()
+        .await;
+
+    if options.active.is_stopping() {
+        output::info("\nPaused, run the same command again to resume");
+        return Err(ExitCode::Interrupted);
+    }
+    // Urls that couldn't be profiled come first, as they failed first
+    let results = failed
+        .into_iter()
+        .map(|(url, code)| (url, Err(code)))
+        .chain(results)
+        .collect();
+    report_batch(results)
+}
+
+/// Downloads every file listed in a metalink file (local path or url), each
+/// one as its own task. Pieces are spread over the mirrors, verified against
+/// their hashes as they land and only the bad ones are fetched again.
+async fn handle_metalink_download(
+    source: String,
+    output_dir: PathBuf,
+    max_threads: u32,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let metalink = match metalink::load(&source).await {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    // Tasks are resumed from their metalink file, wherever whip runs then
+    let source = match std::fs::canonicalize(&source) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => source,
+    };
+
+    let mut failures = Vec::new();
+    for file in metalink.files.iter() {
+        let download_task = file.to_download_task();
+        let mut dtask_entity = match pool.get_task_by_url(&download_task.file_url).await {
+            Ok(Some(entity)) => entity,
+            Ok(None) => {
+                let part_count = file.segments(None).len() as u32;
+                match insert_task(
+                    &pool,
+                    &download_task,
+                    &output_dir,
+                    &options.temp_dir,
+                    max_threads,
+                    part_count,
+                )
+                .await
+                {
+                    Ok(t) => t,
+                    Err(e) => {
+                        failures.push(report(e));
+                        continue;
+                    }
+                }
+            }
+            // Inserting it again would leave two tasks for the same file
+            Err(e) => {
+                failures.push(report(e));
+                continue;
+            }
+        };
+        dtask_entity.source = Some(source.to_owned());
+        match download_metalink_file(
+            file,
+            dtask_entity,
+            &output_dir,
+            max_threads,
+            &pool,
+            &options,
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(ExitCode::Interrupted) => return Err(ExitCode::Interrupted),
+            Err(code) => failures.push(code),
+        }
+    }
+
+    if !failures.is_empty() {
+        output::info(format!(
+            "{} of {} files failed",
+            failures.len(),
+            metalink.files.len()
+        ));
+    }
+    summarize(&failures)
+}
+
+/// Resumes a task listed in the metalink file it was created from, with
+/// the mirrors and piece hashes the file gives it
+async fn resume_metalink_task(
+    task: DownloadTaskEntity,
+    source: &str,
+    output_dir: PathBuf,
+    max_threads: u32,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let metalink = match metalink::load(source).await {
+        Ok(m) => m,
+        Err(e) => return Err(report(e)),
+    };
+    let file = match metalink
+        .files
+        .iter()
+        .find(|f| f.to_download_task().file_url == task.file_url)
+    {
+        Some(f) => f,
+        None => {
+            let e = WhipError::Parse(format!(
+                "{} doesn't list {} anymore",
+                source, task.file_name
+            ));
+            return Err(report(e));
+        }
+    };
+    download_metalink_file(file, task, &output_dir, max_threads, &pool, &options).await
+}
+
+/// Downloads a file of a metalink through its task, piece by piece from
+/// its mirrors. Pieces already in the temp directory are reused.
+async fn download_metalink_file(
+    file: &MetalinkFile,
+    mut dtask_entity: DownloadTaskEntity,
+    output_dir: &Path,
+    max_threads: u32,
+    pool: &Repository,
+    options: &DownloadOptions,
+) -> Result<(), ExitCode> {
+    dtask_entity = match store_options(pool, dtask_entity, options).await {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    dtask_entity.final_file_path = output_dir.to_string_lossy().to_string();
+
+    let mut f_path = output_dir.to_path_buf();
+    f_path.push(&file.name);
+    if dtask_entity.percentage_completed >= 100f64 && f_path.is_file() {
+        output::info(format!(
+            "File already downloaded : {}",
+            f_path.to_string_lossy()
+        ));
+        return Ok(());
+    }
+
+    output::info(format!("Starting download : {}", file.name));
+
+    let _lock = match lock_task(&dtask_entity).await {
+        Ok(l) => l,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    if dtask_entity.status == DownloadStatus::Downloading {
+        // Left downloading by a process that was killed, resuming is another attempt
+        if let Err(e) = dtask_entity.set_status(DownloadStatus::Paused) {
+            return Err(report(e));
+        }
+    }
+    dtask_entity = match set_status(pool, dtask_entity, DownloadStatus::Downloading).await {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    let bars = PartBars::new(options.multi_progress.as_ref(), dtask_entity.id);
+    let pbr = bars.total_bar();
+    let mut downloader = match StreamDownloader::new(
+        file.segments(options.location.as_deref()),
+        output_dir.to_string_lossy().to_string(),
+        dtask_entity.temp_files_path.to_owned(),
+        file.name.to_owned(),
+        bars.callback(),
+        max_threads,
+        options.max_retries,
+    ) {
+        Ok(d) => d,
+        Err(e) => {
+            stop_task(pool, dtask_entity, &e).await;
+            return Err(report(e));
+        }
+    };
+    let (sender, receiver) = mpsc::unbounded_channel();
+    downloader.checkpointer = Some(Checkpointer::new(
+        dtask_entity.id,
+        options.checkpoint_interval,
+        sender,
+    ));
+    downloader.total_bytes = dtask_entity.file_size;
+    let writer = spawn_checkpoint_writer(pool.clone(), receiver, options.checkpoint_interval);
+
+    options.active.register(
+        dtask_entity.id,
+        &file.name,
+        Path::new(&dtask_entity.temp_files_path),
+        downloader.control(),
+        None,
+    );
+    let scheduler = start_scheduler(&dtask_entity, downloader.control(), pbr, options.rate_limit);
+    let downloaded = downloader.download().await;
+    if let Some(s) = scheduler {
+        s.abort();
+    }
+    options.active.unregister(dtask_entity.id);
+    // The last checkpoint has to land before the task is updated
+    if let Err(e) = writer.await {
+        eprintln!("{}", e);
+    }
+    f_path = match downloaded {
+        Ok(p) => p,
+        Err(e) => {
+            let dtask_entity = stop_task(pool, dtask_entity, &e).await;
+            if let Some(code) = report_paused(&dtask_entity, &e) {
+                return Err(code);
+            }
+            eprintln!();
+            run_hook(pool, &dtask_entity, HookEvent::Failure, Some(&e), options).await;
+            return Err(report(e));
+        }
+    };
+
+    if let Some(checksum) = Checksum::strongest(&file.hashes) {
+        dtask_entity = match set_status(pool, dtask_entity, DownloadStatus::Verifying).await {
+            Ok(t) => t,
+            Err(e) => {
+                return Err(report(e));
+            }
+        };
+        match checksum.verify_file(&f_path).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let e = WhipError::ChecksumMismatch {
+                    algorithm: checksum.algorithm,
+                    resource: f_path.to_string_lossy().to_string(),
+                };
+                eprintln!();
+                let dtask_entity = stop_task(pool, dtask_entity, &e).await;
+                run_hook(pool, &dtask_entity, HookEvent::Failure, Some(&e), options).await;
+                return Err(report(e));
+            }
+            Err(e) => {
+                eprintln!();
+                stop_task(pool, dtask_entity, &e).await;
+                return Err(report(e));
+            }
+        }
+    }
+    output::info(format!(
+        "\nFile downloaded successfully : {}",
+        f_path.to_string_lossy()
+    ));
+
+    dtask_entity.bytes_done = dtask_entity.file_size;
+    dtask_entity.percentage_completed = 100f64;
+    if let Err(e) = dtask_entity.set_status(DownloadStatus::Completed) {
+        return Err(report(e));
+    }
+    let extracted = extract_archive(pool, &mut dtask_entity, options).await;
+    match pool.update_task(dtask_entity).await {
+        Ok(t) => {
+            if let Err(e) = save_progress(pool, &t).await {
+                return Err(report(e));
+            }
+            output::emit(Event::Task(TaskRecord::from(&t)));
+            run_hook(pool, &t, HookEvent::Complete, None, options).await;
+            remove_temp_dir(&t).await;
+        }
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    if let Err(e) = extracted {
+        return Err(report(e));
+    }
+    Ok(())
+}
+
+/// Downloads the segments of an HLS stream into a single `.ts` file, or
+/// the video and audio tracks of a DASH manifest into one file per track.
+/// Segments already in the temp directory are reused, so an interrupted
+/// stream resumes where it stopped.
+async fn handle_stream_download(
+    mut dtask_entity: DownloadTaskEntity,
+    options: DownloadOptions,
+    pool: Repository,
+) -> Result<(), ExitCode> {
+    dtask_entity = match set_status(&pool, dtask_entity, DownloadStatus::Probing).await {
+        Ok(t) => t,
+        Err(e) => return Err(report(e)),
+    };
+    // Every track sends its checkpoints to the same writer
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let writer = spawn_checkpoint_writer(pool.clone(), receiver, options.checkpoint_interval);
+    let downloaded = async {
+        let tracks = if dash::is_dash(&dtask_entity.to_download_task()) {
+            match dash::get_tracks(&dtask_entity.file_url, &options.variant_selector).await {
+                Ok(tracks) => tracks
+                    .into_iter()
+                    .map(|t| (t.file_name(&dtask_entity.file_name), t.segments))
+                    .collect(),
+                Err(e) => return Err(e),
+            }
+        } else {
+            match hls::get_segments(&dtask_entity.file_url, &options.variant_selector).await {
+                Ok(stream) => {
+                    // Streams with an initialization section are usually fMP4
+                    if options.file_name.is_none() {
+                        dtask_entity.file_name =
+                            hls::with_extension(&dtask_entity.file_name, stream.extension);
+                    }
+                    vec![(dtask_entity.file_name.to_owned(), stream.segments)]
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        if let Err(e) = dtask_entity.set_status(DownloadStatus::Downloading) {
+            return Err(WhipError::Unknown(e.to_string()));
+        }
+        dtask_entity = match pool.update_task(dtask_entity.clone()).await {
+            Ok(t) => t,
+            Err(e) => return Err(WhipError::Unknown(e.to_string())),
+        };
+
+        let mut file_size = 0;
+
+        for (file_name, segments) in tracks.into_iter() {
+            let mut f_path = PathBuf::new();
+            f_path.push(&dtask_entity.final_file_path);
+            f_path.push(&file_name);
+
+            // Assembled files only get their final name once complete
+            if !f_path.is_file() {
+                // Each track has bars of its own, they complete one after the other
+                let bars = PartBars::new(options.multi_progress.as_ref(), dtask_entity.id);
+                let pbr = bars.total_bar();
+                let mut downloader = match StreamDownloader::new(
+                    segments,
+                    dtask_entity.final_file_path.to_owned(),
+                    dtask_entity.temp_files_path.to_owned(),
+                    file_name,
+                    bars.callback(),
+                    dtask_entity.max_threads,
+                    options.max_retries,
+                ) {
+                    Ok(d) => d,
+                    Err(e) => return Err(e),
+                };
+                downloader.checkpointer = Some(Checkpointer::new(
+                    dtask_entity.id,
+                    options.checkpoint_interval,
+                    sender.clone(),
+                ));
+                downloader.bytes_before = file_size;
+
+                options.active.register(
+                    dtask_entity.id,
+                    &dtask_entity.file_name,
+                    Path::new(&dtask_entity.temp_files_path),
+                    downloader.control(),
+                    None,
+                );
+                let scheduler =
+                    start_scheduler(&dtask_entity, downloader.control(), pbr, options.rate_limit);
+                let downloaded = downloader.download().await;
+                if let Some(s) = scheduler {
+                    s.abort();
+                }
+                options.active.unregister(dtask_entity.id);
+                f_path = match downloaded {
+                    Ok(p) => p,
+                    Err(e) => {
+                        eprintln!();
+                        return Err(e);
+                    }
+                };
+            }
+            output::info(format!(
+                "\nFile downloaded successfully : {}",
+                f_path.to_string_lossy()
+            ));
+
+            if let Ok(metadata) = f_path.metadata() {
+                file_size += metadata.len();
+            }
+        }
+        Ok(file_size)
+    }
+    .await;
+    // The last checkpoint has to land before the task is updated
+    drop(sender);
+    if let Err(e) = writer.await {
+        eprintln!("{}", e);
+    }
+
+    let file_size = match downloaded {
+        Ok(size) => size,
+        Err(e) => {
+            let dtask_entity = stop_task(&pool, dtask_entity, &e).await;
+            if let Some(code) = report_paused(&dtask_entity, &e) {
+                return Err(code);
+            }
+            run_hook(&pool, &dtask_entity, HookEvent::Failure, Some(&e), &options).await;
+            return Err(report(e));
+        }
+    };
+
+    dtask_entity.file_size = file_size;
+    dtask_entity.bytes_done = file_size;
+    dtask_entity.percentage_completed = 100f64;
+    if let Err(e) = dtask_entity.set_status(DownloadStatus::Completed) {
+        return Err(report(e));
+    }
+    match pool.update_task(dtask_entity).await {
+        Ok(t) => {
+            if let Err(e) = save_progress(&pool, &t).await {
+                return Err(report(e));
+            }
+            output::emit(Event::Task(TaskRecord::from(&t)));
+            run_hook(&pool, &t, HookEvent::Complete, None, &options).await;
+            remove_temp_dir(&t).await;
+        }
+        Err(e) => return Err(report(e)),
+    };
+
+    Ok(())
+}
+
+/// Crawls a website within the given limits and downloads every file
+/// found as a regular task, under `output_dir/host/remote/path`,
+/// `max_concurrent` at a time. Pages fetched for their links are saved as
+/// they are and files keep what the crawl learned about them.
+pub async fn handle_mirror(
+    url: String,
+    output_dir: PathBuf,
+    mirror_options: MirrorOptions,
+    max_threads: u32,
+    max_concurrent: usize,
+    pool: Repository,
+    options: DownloadOptions,
+) -> Result<(), ExitCode> {
+    let delay = mirror_options.delay;
+    let crawler = match Crawler::new(&url, mirror_options) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+
+    output::info(format!("Crawling {}", url));
+    let resources = match crawler
+        .crawl(|r| output::info(format!("Found : {}", r.local_path.to_string_lossy())))
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+    let count = resources.len();
+    output::info(format!("{} files to download", count));
+
+    let mut failures = Vec::new();
+    let mut downloads = Vec::new();
+    for resource in resources.into_iter() {
+        let mut dir = output_dir.clone();
+        if let Some(parent) = resource.local_path.parent() {
+            dir.push(parent);
+        }
+        if let Err(e) = fs::create_dir_all(&dir).await {
+            failures.push(report(WhipError::io(dir, e)));
+            continue;
+        }
+        let file_name = resource
+            .local_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if let Some(page) = &resource.page {
+            let path = dir.join(&file_name);
+            match fs::write(&path, page).await {
+                Ok(()) => output::info(format!("Saved page : {}", path.to_string_lossy())),
+                Err(e) => failures.push(report(WhipError::io(path, e))),
+            }
+            continue;
+        }
+
+        let mut task = DownloadTask {
+            file_url: resource.url.to_owned(),
+            percentage_completed: 0f64,
+            meta: resource.meta,
+            headers: options.headers.clone(),
+            checksum: None,
+        };
+        task.meta.file_name = file_name.to_owned();
+        // Playlists, manifests and metalink files get named and stored by handle_download
+        let is_plain_file = !hls::is_hls(&task)
+            && !dash::is_dash(&task)
+            && !metalink::is_metalink_content_type(&task.meta.content_type);
+        let inserted = match pool.get_task_by_url(&resource.url).await {
+            Ok(None) if is_plain_file => {
+                let part_count = options.part_count.unwrap_or(max_threads);
+                if let Err(e) = insert_task(
+                    &pool,
+                    &task,
+                    &dir,
+                    &options.temp_dir,
+                    max_threads,
+                    part_count,
+                )
+                .await
+                {
+                    failures.push(report(e));
+                    continue;
+                }
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                failures.push(report(e));
+                continue;
+            }
+        };
+        downloads.push((resource.url, dir, file_name, inserted));
+    }
+
+    let multi_progress = MultiProgress::new();
+    let results = stream::iter(downloads)
+        // Downloads start as far apart as the requests of the crawl
+        .then(|download| async move {
+            tokio::time::sleep(delay).await;
+            download
+        })
+        .map(|(url, dir, file_name, inserted)| {
+            let mut options = options.clone();
+            options.file_name = Some(file_name);
+            options.inserted = inserted;
+            options.multi_progress = Some(multi_progress.clone());
+            handle_download(url, dir, max_threads, false, pool.clone(), options)
+        })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect::<Vec<Result<(), ExitCode>>>()
+        .await;
+
+    if options.active.is_stopping() {
+        output::info("\nPaused, run the same command again to resume");
+        return Err(ExitCode::Interrupted);
+    }
+    failures.extend(results.into_iter().filter_map(Result::err));
+    output::info(format!(
+        "Mirrored {} of {} files",
+        count - failures.len(),
+        count
+    ));
+    summarize(&failures)
+}
+
+/// Extracts a downloaded archive when asked to and records the outcome on
+/// the task, which the caller still has to save. Volumes of a split archive
+/// wait for all the others to be downloaded, the last one extracts them.
+async fn extract_archive(
+    pool: &Repository,
+    task: &mut DownloadTaskEntity,
+    options: &DownloadOptions,
+) -> Result<(), WhipError> {
+    if !options.extract {
+        return Ok(());
+    }
+
+    let mut path = PathBuf::from(&task.final_file_path);
+    path.push(&task.file_name);
+    if let Some((base, _)) = extract::volume_number(&task.file_name) {
+        let pending = match pool.get_tasks(Df::All).await {
+            Ok(tasks) => tasks.into_iter().any(|t| {
+                t.status != DownloadStatus::Completed
+                    && t.id != task.id
+                    && t.final_file_path == task.final_file_path
+                    && matches!(extract::volume_number(&t.file_name), Some((b, _)) if b == base)
+            }),
+            Err(e) => {
+                eprintln!("{}", e);
+                false
+            }
+        };
+        if pending {
+            output::info(format!(
+                "Waiting for the other volumes of {} to extract it",
+                base
+            ));
+            return Ok(());
+        }
+    }
+    let volumes = extract::volumes(&path);
+    let first_volume = match volumes.first() {
+        Some(v) => v.to_owned(),
+        None => {
+            let e = WhipError::Storage(format!(
+                "First volume of the archive is missing : {}",
+                path.to_string_lossy()
+            ));
+            task.extraction_result = Some(e.to_string());
+            return Err(e);
+        }
+    };
+
+    let format = match ArchiveFormat::of_file(&first_volume, &task.content_type) {
+        Ok(Some(f)) => f,
+        Ok(None) => {
+            output::info(format!(
+                "Not an archive, nothing to extract : {}",
+                path.to_string_lossy()
+            ));
+            task.extraction_result = Some(String::from("Not an archive"));
+            return Ok(());
+        }
+        Err(e) => {
+            task.extraction_result = Some(e.to_string());
+            return Err(e);
+        }
+    };
+    let target = match &options.extract_dir {
+        Some(dir) => dir.to_owned(),
+        None => {
+            let mut dir = PathBuf::from(&task.final_file_path);
+            dir.push(extract::extract_dir_name(&task.file_name));
+            dir
+        }
+    };
+
+    output::info(format!(
+        "Extracting {} archive to {}",
+        format,
+        target.to_string_lossy()
+    ));
+    let report = match extract::extract(volumes.clone(), format, target.clone()).await {
+        Ok(r) => r,
+        Err(e) => {
+            task.extracted_path = None;
+            task.extraction_result = Some(e.to_string());
+            return Err(e);
+        }
+    };
+
+    for name in report.skipped.iter() {
+        eprintln!("Skipped entry outside of the target directory : {}", name);
+    }
+    let mut result = format!(
+        "Extracted {} files ({}) to {}",
+        report.files,
+        format_size(report.bytes),
+        target.to_string_lossy()
+    );
+    if !report.skipped.is_empty() {
+        result.push_str(&format!(
+            ", skipped {} unsafe entries",
+            report.skipped.len()
+        ));
+    }
+    output::info(&result);
+    task.extracted_path = Some(target.to_string_lossy().to_string());
+    task.extraction_result = Some(result);
+
+    // The other volumes share the outcome, they aren't downloaded again once deleted
+    if let Some((base, _)) = extract::volume_number(&task.file_name) {
+        if let Ok(tasks) = pool.get_tasks(Df::All).await {
+            for mut t in tasks.into_iter().filter(|t| {
+                t.id != task.id
+                    && t.final_file_path == task.final_file_path
+                    && matches!(extract::volume_number(&t.file_name), Some((b, _)) if b == base)
+            }) {
+                t.extracted_path = task.extracted_path.clone();
+                t.extraction_result = task.extraction_result.clone();
+                if let Err(e) = pool.update_task(t).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+
+    if options.delete_archive {
+        for volume in volumes.iter() {
+            if let Err(e) = fs::remove_file(volume).await {
+                eprintln!("{} : Path {}", e, volume.to_string_lossy());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Temporary directory of the task `id`
+pub fn task_temp_dir(temp_dir: &Path, id: u64) -> PathBuf {
+    let mut dir = temp_dir.to_path_buf();
+    dir.push(id.to_string());
+    dir
+}
+
+/// Tasks created before they got a directory of their own keep their
+/// parts directly in the temporary directory
+fn has_own_temp_dir(task: &DownloadTaskEntity) -> bool {
+    Path::new(&task.temp_files_path).file_name() == Some(task.id.to_string().as_ref())
+}
+
+/// Stores a new task, its temporary files go to a directory named after its id
+async fn insert_task(
+    pool: &Repository,
+    task: &DownloadTask,
+    output_dir: &Path,
+    temp_dir: &Path,
+    max_threads: u32,
+    part_count: u32,
+) -> Result<DownloadTaskEntity, DatabaseError> {
+    let id = pool
+        .insert_task(
+            task,
+            temp_dir.to_string_lossy().to_string(),
+            output_dir.to_string_lossy().to_string(),
+            max_threads.to_string(),
+            part_count,
+        )
+        .await?;
+    let mut entity = match pool.get_task_by_id(id as i64).await? {
+        Some(t) => t,
+        None => {
+            return Err(DatabaseError::Operation(format!(
+                "Task {} can't be found once inserted",
+                id
+            )))
+        }
+    };
+    entity.temp_files_path = task_temp_dir(temp_dir, id).to_string_lossy().to_string();
+    pool.update_task(entity).await
+}
+
+/// Creates the temporary directory of a task and locks the task for this process
+async fn lock_task(task: &DownloadTaskEntity) -> Result<TaskLock, WhipError> {
+    let dir = PathBuf::from(&task.temp_files_path);
+    if let Err(e) = fs::create_dir_all(&dir).await {
+        return Err(WhipError::io(dir, e));
+    }
+    TaskLock::acquire(&dir, task.id)
+}
+
+/// Locks the task an orphan belongs to, None when it has none or its
+/// directory is gone, in which case nothing can hold it
+fn lock_orphan(orphan: &Orphan) -> Result<Option<TaskLock>, WhipError> {
+    match &orphan.lock {
+        Some((dir, id)) if dir.is_dir() => TaskLock::acquire(dir, *id).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Removes the temporary directory of a task along with what's left in it,
+/// or the parts and the lock file of tasks sharing the temporary directory
+async fn remove_temp_dir(task: &DownloadTaskEntity) {
+    if !has_own_temp_dir(task) {
+        let lock_file = TaskLock::file_path(Path::new(&task.temp_files_path), task.id);
+        for path in shared_temp_files(task).await.into_iter().chain([lock_file]) {
+            if !path.is_file() {
+                continue;
+            }
+            if let Err(e) = fs::remove_file(&path).await {
+                eprintln!("{} : Path {}", e, path.to_string_lossy());
+            }
+        }
+        return;
+    }
+    let dir = PathBuf::from(&task.temp_files_path);
+    if dir.is_dir() {
+        if let Err(e) = fs::remove_dir_all(&dir).await {
+            eprintln!("{} : Path {}", e, dir.to_string_lossy());
+        }
+    }
+}
+
+/// Parts and segments of a task sharing the temporary directory, they're
+/// named after its file whatever their number
+async fn shared_temp_files(task: &DownloadTaskEntity) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(&task.temp_files_path).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path.is_file() && gc::part_file_name(&name) == Some(task.file_name.as_str()) {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+/// Saves the progress of a task whose download ended, the size of the file
+/// may only be known now. Its parts are left as the last checkpoint has them.
+async fn save_progress(pool: &Repository, task: &DownloadTaskEntity) -> Result<(), DatabaseError> {
+    let checkpoint = Checkpoint {
+        task_id: task.id,
+        bytes_done: task.bytes_done,
+        total_bytes: task.file_size,
+        speed: 0f64,
+        parts: Vec::new(),
+    };
+    pool.save_checkpoints(&[checkpoint]).await
+}
+
+/// Moves the task to `status` and stores it
+async fn set_status(
+    pool: &Repository,
+    mut task: DownloadTaskEntity,
+    status: DownloadStatus,
+) -> Result<DownloadTaskEntity, DatabaseError> {
+    task.set_status(status)?;
+    let task = pool.update_task(task).await?;
+    output::emit(Event::Task(TaskRecord::from(&task)));
+    Ok(task)
+}
+
+/// Tells how to resume a task that `e` paused, an interrupt or a full
+/// disk, and returns the exit code. None when `e` failed the task, its
+/// failure hook is left to run.
+fn report_paused(task: &DownloadTaskEntity, e: &WhipError) -> Option<ExitCode> {
+    match e {
+        WhipError::Interrupted => {
+            output::info(format!(
+                "\nPaused {}, run whip resume {} to resume",
+                task.file_name, task.id
+            ));
+            Some(ExitCode::Interrupted)
+        }
+        WhipError::DiskFull { .. } => {
+            let code = ExitCode::from(e);
+            let message = format!(
+                "{}\nFree some space and run whip resume {} to resume",
+                e, task.id
+            );
+            if output::is_json() {
+                output::emit(Event::error(code, message));
+            } else {
+                eprintln!("{}", message);
+            }
+            Some(code)
+        }
+        _ => None,
+    }
+}
+
+/// Stores why the download of a task stopped. A full disk or an interrupt
+/// only pauses it, other errors fail it. Storage errors are printed, the
+/// task is returned as is.
+async fn stop_task(
+    pool: &Repository,
+    mut task: DownloadTaskEntity,
+    error: &WhipError,
+) -> DownloadTaskEntity {
+    let stopped = match error {
+        // Paused tasks keep why they stopped too
+        WhipError::DiskFull { .. } | WhipError::Interrupted => task
+            .set_status(DownloadStatus::Paused)
+            .map(|_| task.last_error = Some(error.to_string())),
+        _ => task.fail(&error.to_string()),
+    };
+    if let Err(e) = stopped {
+        eprintln!("{}", e);
+        return task;
+    }
+    let task = match pool.update_task(task.clone()).await {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            task
+        }
+    };
+    output::emit(Event::Task(TaskRecord::from(&task)));
+    task
+}
+
+/// Stores the hooks and schedule given for this download with the task,
+/// replacing the ones it had.
+async fn store_options(
+    pool: &Repository,
+    mut task: DownloadTaskEntity,
+    options: &DownloadOptions,
+) -> Result<DownloadTaskEntity, DatabaseError> {
+    if options.on_complete.is_none()
+        && options.on_failure.is_none()
+        && options.start_after.is_none()
+        && options.schedule.is_none()
+    {
+        return Ok(task);
+    }
+    if options.on_complete.is_some() {
+        task.on_complete_hook = options.on_complete.clone();
+    }
+    if options.on_failure.is_some() {
+        task.on_failure_hook = options.on_failure.clone();
+    }
+    if options.start_after.is_some() {
+        task.start_after = options.start_after;
+    }
+    if options.schedule.is_some() {
+        task.schedule = options.schedule.clone();
+    }
+    pool.update_task(task).await
+}
+
+/// Applies the start time and schedule of a task to its download until
+/// the returned task is aborted, None when the task has neither.
+fn start_scheduler(
+    task: &DownloadTaskEntity,
+    control: DownloadControl,
+    pbr: ProgressBar,
+    rate_limit: u64,
+) -> Option<JoinHandle<()>> {
+    let scheduler = Scheduler {
+        start_after: task.start_after,
+        schedule: task.schedule.clone(),
+        max_rate: rate_limit,
+    };
+    if scheduler.is_empty() {
+        return None;
+    }
+    if let Some(start_after) = task.start_after {
+        if start_after > Local::now() {
+            print_above(
+                &pbr,
+                format!(
+                    "Waiting until {} to start",
+                    start_after.format("%Y-%m-%d %H:%M")
+                ),
+            );
+        }
+    }
+
+    let mut first = true;
+    Some(scheduler.start(control, move |bandwidth| {
+        // Running at full speed from the start goes without saying
+        if !(first && bandwidth == Bandwidth::Unlimited) {
+            print_above(&pbr, schedule::describe(bandwidth));
+        }
+        first = false;
+    }))
+}
+
+/// Prints a message above a progress bar, which is hidden in JSON output
+fn print_above(pbr: &ProgressBar, message: String) {
+    if output::is_json() {
+        output::info(message);
+    } else {
+        pbr.println(message);
+    }
+}
+
+/// Runs the complete or failure hook of a task, its own one or else the
+/// global one (WHIP_ON_COMPLETE or WHIP_ON_FAILURE), and stores what it printed.
+async fn run_hook(
+    pool: &Repository,
+    task: &DownloadTaskEntity,
+    event: HookEvent,
+    error: Option<&WhipError>,
+    options: &DownloadOptions,
+) {
+    let (task_hook, global_var) = match event {
+        HookEvent::Complete => (&task.on_complete_hook, "WHIP_ON_COMPLETE"),
+        HookEvent::Failure => (&task.on_failure_hook, "WHIP_ON_FAILURE"),
+    };
+    let command = match task_hook
+        .to_owned()
+        .or_else(|| std::env::var(global_var).ok())
+    {
+        Some(c) if !c.trim().is_empty() => c,
+        _ => return,
+    };
+
+    let mut file_path = PathBuf::from(&task.final_file_path);
+    file_path.push(&task.file_name);
+    // Files without an expected digest are hashed for the hook
+    let checksum = match (event, &task.checksum) {
+        (HookEvent::Complete, Some(c)) => Some(c.clone()),
+        (HookEvent::Complete, None) => Checksum::of_file(HashAlgorithm::Sha256, &file_path)
+            .await
+            .ok(),
+        (HookEvent::Failure, _) => None,
+    };
+    let context = HookContext {
+        task_id: task.id,
+        file_path,
+        url: task.file_url.to_owned(),
+        file_size: task.file_size,
+        content_type: task.content_type.to_owned(),
+        checksum,
+        error: error.map(|e| e.to_string()),
+    };
+
+    let output = match Hook::new(&command, options.hook_timeout)
+        .run(event, &context)
+        .await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if output.timed_out {
+        eprintln!("Hook timed out : {}", command);
+    } else if !output.success() {
+        eprintln!(
+            "Hook failed (exit code {}) : {}",
+            output
+                .exit_code
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| String::from("none")),
+            command
+        );
+    }
+    if let Err(e) = pool
+        .insert_hook_run(task.id, event, &command, &output)
+        .await
+    {
+        eprintln!("{}", e);
+    }
+}
+
+pub async fn handle_info(
+    url: String,
+    headers: Vec<(String, String)>,
+    part_count: u32,
+) -> Result<(), ExitCode> {
+    let report = match DownloadTask::get_file_info(&url, &headers).await {
+        Ok(r) => r,
+        Err(e) => return Err(report(e)),
+    };
+    let mut task = DownloadTask::from_probe(report.clone(), headers);
+    // Named the way a download would name it
+    let kind = if metalink::is_metalink_content_type(&task.meta.content_type) {
+        "metalink"
+    } else if hls::is_hls(&task) {
+        task.meta.file_name = hls::output_file_name(&task.meta.file_name);
+        "hls"
+    } else if dash::is_dash(&task) {
+        task.meta.file_name = dash::output_file_stem(&task.meta.file_name);
+        "dash"
+    } else {
+        "file"
+    };
+    let parts = task.get_download_parts(part_count).len() as u32;
+
+    if output::is_json() {
+        let record = ProbeRecord::new(&report, &task.meta.file_name, kind, parts);
+        output::emit(Event::Probe(record));
+        return Ok(());
+    }
+    print_probe(&report, &task.meta.file_name, kind, parts);
+    Ok(())
+}
+
+fn print_probe(report: &ProbeReport, file_name: &str, kind: &str, parts: u32) {
+    let unknown = || String::from("-");
+    output::info(format!("Url           : {}", report.url));
+    for redirect in report.redirects.iter() {
+        output::info(format!(
+            "Redirected    : {} -> {}",
+            redirect.status, redirect.url
+        ));
+    }
+    if report.final_url != report.url {
+        output::info(format!("Final url     : {}", report.final_url));
+    }
+    let status = if report.head_supported {
+        report.status.to_string()
+    } else {
+        format!(
+            "{} (HEAD isn't supported, probed with a GET)",
+            report.status
+        )
+    };
+    output::info(format!("Status        : {}", status));
+    output::info(format!("File name     : {}", file_name));
+    let size = match report.meta.content_length {
+        0 => String::from("unknown"),
+        s => format!("{} ({} bytes)", format_size(s), s),
+    };
+    output::info(format!("Size          : {}", size));
+    let content_type = Some(report.meta.content_type.to_owned()).filter(|t| !t.is_empty());
+    output::info(format!(
+        "Content type  : {}",
+        content_type.unwrap_or_else(unknown)
+    ));
+    if kind != "file" {
+        output::info(format!("Kind          : {}", kind));
+    }
+    let ranges = match (report.meta.supports_resume, &report.accept_ranges) {
+        (true, Some(unit)) => format!("yes ({})", unit),
+        (true, None) => String::from("yes"),
+        (false, _) => String::from("no, the download can't be resumed or split"),
+    };
+    output::info(format!("Ranges        : {}", ranges));
+    output::info(format!(
+        "ETag          : {}",
+        report.etag.clone().unwrap_or_else(unknown)
+    ));
+    output::info(format!(
+        "Last modified : {}",
+        report.last_modified.clone().unwrap_or_else(unknown)
+    ));
+    output::info(format!(
+        "Server        : {}",
+        report.server.clone().unwrap_or_else(unknown)
+    ));
+    if kind == "file" {
+        output::info(format!("Parts         : {}", parts));
+    }
+}
+
+pub async fn handle_hook_log(id: i64, pool: Repository) -> Result<(), ExitCode> {
+    let runs = match pool.get_hook_runs(id).await {
+        Ok(runs) => runs,
+        Err(e) => return Err(report(e)),
+    };
+
+    if runs.is_empty() {
+        output::info("No hooks ran for this task");
+        return Ok(());
+    }
+
+    for run in runs.iter() {
+        if output::is_json() {
+            output::emit(Event::HookRun(HookRunRecord::from(run)));
+            continue;
+        }
+        let status = if run.timed_out {
+            String::from("timed out")
+        } else {
+            match run.exit_code {
+                Some(c) => format!("exit code {}", c),
+                None => String::from("killed"),
+            }
+        };
+        output::info(format!(
+            "[{}] {} : {} ({})",
+            run.date_created, run.event, run.command, status
+        ));
+        for line in run.output.lines() {
+            output::info(format!("    {}", line));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn handle_show_downloads(query: TaskQuery, pool: Repository) -> Result<(), ExitCode> {
+    let page = match pool.search_tasks(&query).await {
+        Ok(res) => res,
+        Err(e) => {
+            return Err(report(e));
+        }
+    };
+
+    if output::is_json() {
+        for download in page.tasks.iter() {
+            output::emit(Event::Task(TaskRecord::from(download)));
+        }
+        output::emit(Event::Page {
+            total: page.total,
+            offset: query.offset,
+            limit: query.limit,
+            returned: page.tasks.len(),
+        });
+        return Ok(());
+    }
+    if page.total == 0 {
+        output::info("No downloads found");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_titles(row![
+        bFg->"id",
+        bFg->"File Name",
+        bFg->"Status",
+        bFg->"Size",
+        bFg->"Progress",
+        bFg->"Created",
+        bFg->"Destination",
+        bFg->"Attempts",
+        bFg->"Last Error",
+    ]);
+
+    for download in page.tasks.iter() {
+        let size = match download.file_size {
+            0 => String::from("?"),
+            s => format_size(s),
+        };
+        let mut progress = format!("{:.1}%", download.percentage_completed);
+        if download.status == DownloadStatus::Downloading && download.speed > 0f64 {
+            progress.push_str(&format!(" ({}/s)", format_size(download.speed as u64)));
+        }
+        table.add_row(row![
+            r->download.id,
+            download.file_name,
+            download.status,
+            r->size,
+            r->progress,
+            download.date_created,
+            download.final_file_path,
+            r->download.attempt_count,
+            download.last_error.as_deref().unwrap_or_default(),
         ]);
     }
 
     table.printstd();
+    if page.tasks.len() < page.total {
+        output::info(format!(
+            "Showing {}-{} of {} tasks",
+            (query.offset + 1).min(page.total),
+            query.offset + page.tasks.len(),
+            page.total
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use whip_core::download::DownloadMeta;
+    use whip_persistance::memory::MemoryRepository;
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"1111222233";
+
+    /// Serves the byte ranges of CONTENT under /file.iso and a 404 anywhere
+    /// else, records the ranges it served. /slow.iso sends the first byte
+    /// of the range and nothing more until the client goes away.
+    fn serve(served: Arc<Mutex<Vec<String>>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let served = served.clone();
+                thread::spawn(move || {
+                    let mut stream = stream;
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_lowercase();
+                    let range = request
+                        .lines()
+                        .find_map(|l| l.strip_prefix("range: bytes="))
+                        .and_then(|r| r.split_once('-'))
+                        // Like servers do, ranges past the end stop at it
+                        .map(|(s, e)| {
+                            let end = e.parse::<usize>().unwrap().min(CONTENT.len() - 1);
+                            (s.parse::<usize>().unwrap(), end)
+                        });
+                    let response = match range {
+                        Some((start, end)) if request.starts_with("get /file.iso ") => {
+                            served.lock().unwrap().push(format!("{}-{}", start, end));
+                            let body = &CONTENT[start..=end];
+                            let mut response = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                                body.len(),
+                                start,
+                                end,
+                                CONTENT.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(body);
+                            response
+                        }
+                        Some((start, end)) if request.starts_with("get /slow.iso ") => {
+                            let header = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                                end - start + 1,
+                                start,
+                                end,
+                                CONTENT.len()
+                            );
+                            let _ = stream.write_all(header.as_bytes());
+                            let _ = stream.write_all(&CONTENT[start..=start]);
+                            while let Ok(n) = stream.read(&mut buffer) {
+                                if n == 0 {
+                                    break;
+                                }
+                            }
+                            return;
+                        }
+                        _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec(),
+                    };
+                    let _ = stream.write_all(&response);
+                });
+            }
+        });
+        port
+    }
+
+    /// Task downloading `path` of the test server, in one part as it's small
+    async fn file_task(pool: &Repository, port: u16, path: &str, dir: &Path) -> DownloadTaskEntity {
+        let task = DownloadTask {
+            file_url: format!("http://127.0.0.1:{}{}", port, path),
+            percentage_completed: 0f64,
+            meta: DownloadMeta {
+                content_length: CONTENT.len() as u64,
+                supports_resume: true,
+                content_type: String::new(),
+                file_name: path.trim_start_matches('/').to_string(),
+            },
+            headers: Vec::new(),
+            checksum: None,
+        };
+        insert_task(pool, &task, dir, &dir.join("temp"), 2, 2)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_restart_discards_parts() {
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let port = serve(served.clone());
+        let dir = std::env::temp_dir().join(format!("whip-restart-{}", std::process::id()));
+        let pool = Repository::Memory(MemoryRepository::new());
+        let mut task = file_task(&pool, port, "/file.iso", &dir).await;
+        task.set_status(DownloadStatus::Downloading).unwrap();
+        task.set_status(DownloadStatus::Failed).unwrap();
+        task.bytes_done = 4;
+        task.percentage_completed = 40f64;
+        let task = pool.update_task(task).await.unwrap();
+        // Resuming would keep them and append the rest
+        std::fs::create_dir_all(&task.temp_files_path).unwrap();
+        std::fs::write(Path::new(&task.temp_files_path).join("file.iso.0"), b"xxxx").unwrap();
+
+        let options = DownloadOptions::new(2, dir.join("temp"));
+        handle_restart(task.id as i64, pool.clone(), options)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(dir.join("file.iso")).unwrap(), CONTENT);
+        assert_eq!(*served.lock().unwrap(), vec!["0-9"]);
+        let task = pool.get_task_by_id(task.id as i64).await.unwrap().unwrap();
+        assert_eq!(task.status, DownloadStatus::Completed);
+        assert_eq!(task.percentage_completed, 100f64);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_only() {
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let port = serve(served.clone());
+        let dir = std::env::temp_dir().join(format!("whip-retry-{}", std::process::id()));
+        let pool = Repository::Memory(MemoryRepository::new());
+        let mut failed = file_task(&pool, port, "/file.iso", &dir).await;
+        failed.set_status(DownloadStatus::Downloading).unwrap();
+        failed.set_status(DownloadStatus::Failed).unwrap();
+        let failed = pool.update_task(failed).await.unwrap();
+        // Retrying them would fail them, the server doesn't have them
+        let mut paused = file_task(&pool, port, "/paused.iso", &dir).await;
+        paused.set_status(DownloadStatus::Downloading).unwrap();
+        paused.set_status(DownloadStatus::Paused).unwrap();
+        let paused = pool.update_task(paused).await.unwrap();
+        let queued = file_task(&pool, port, "/queued.iso", &dir).await;
 
-    return Ok(());
+        let options = DownloadOptions::new(2, dir.join("temp"));
+        handle_retry_failed(2, pool.clone(), options).await.unwrap();
+
+        assert_eq!(*served.lock().unwrap(), vec!["0-9"]);
+        let status = |task: Option<DownloadTaskEntity>| task.unwrap().status;
+        let get = |id: u64| pool.get_task_by_id(id as i64);
+        assert_eq!(
+            status(get(failed.id).await.unwrap()),
+            DownloadStatus::Completed
+        );
+        assert_eq!(
+            status(get(paused.id).await.unwrap()),
+            DownloadStatus::Paused
+        );
+        assert_eq!(
+            status(get(queued.id).await.unwrap()),
+            DownloadStatus::Queued
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pause_requested() {
+        let port = serve(Arc::new(Mutex::new(Vec::new())));
+        let dir = std::env::temp_dir().join(format!("whip-pause-{}", std::process::id()));
+        let pool = Repository::Memory(MemoryRepository::new());
+        let task = file_task(&pool, port, "/slow.iso", &dir).await;
+        let lock_dir = PathBuf::from(&task.temp_files_path);
+
+        let options = DownloadOptions::new(2, dir.join("temp"));
+        // What main does, the requests are checked in the background
+        crate::signals::listen(options.active.clone());
+        let (resumed, paused) = tokio::join!(
+            handle_resume(task.id as i64, pool.clone(), options),
+            async {
+                while TaskLock::holder(&lock_dir, task.id).is_none() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                handle_pause(task.id as i64, pool.clone()).await
+            }
+        );
+
+        assert_eq!(resumed, Err(ExitCode::Interrupted));
+        assert_eq!(paused, Ok(()));
+        assert!(!TaskLock::pause_requested(&lock_dir, task.id));
+        let task = pool.get_task_by_id(task.id as i64).await.unwrap().unwrap();
+        assert_eq!(task.status, DownloadStatus::Paused);
+        assert!(!dir.join("slow.iso").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_metalink_task() {
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let port = serve(served.clone());
+        let dir = std::env::temp_dir().join(format!("whip-metalink-{}", std::process::id()));
+        let temp_dir = dir.join("temp");
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        // The preferred mirror lost the file, the pieces come from the other one
+        let source = dir.join("file.meta4");
+        std::fs::write(
+            &source,
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="file.iso">
+    <size>10</size>
+    <hash type="sha-256">07e79afc33c931f735efa648026c57dfdb32522505668d8147b754b6438451de</hash>
+    <pieces length="4" type="sha-1">
+      <hash>011c945f30ce2cbafc452f39840f025693339c42</hash>
+      <hash>fea7f657f56a2a448da7d4b535ee5e279caf3d9a</hash>
+      <hash>b6692ea5df920cad691c20319a6fffd7a4a766b8</hash>
+    </pieces>
+    <url priority="1">http://127.0.0.1:{port}/gone.iso</url>
+    <url priority="2">http://127.0.0.1:{port}/file.iso</url>
+  </file>
+</metalink>"#,
+                port = port
+            ),
+        )
+        .unwrap();
+
+        // Paused once its first piece was in
+        let metalink = metalink::load(&source.to_string_lossy()).await.unwrap();
+        let file = &metalink.files[0];
+        let pool = Repository::Memory(MemoryRepository::new());
+        let mut task = insert_task(&pool, &file.to_download_task(), &dir, &temp_dir, 2, 3)
+            .await
+            .unwrap();
+        task.source = Some(source.to_string_lossy().to_string());
+        task.set_status(DownloadStatus::Downloading).unwrap();
+        task.set_status(DownloadStatus::Paused).unwrap();
+        let task = pool.update_task(task).await.unwrap();
+        std::fs::create_dir_all(&task.temp_files_path).unwrap();
+        std::fs::write(
+            Path::new(&task.temp_files_path).join("file.iso.seg0"),
+            &CONTENT[..4],
+        )
+        .unwrap();
+
+        let options = DownloadOptions::new(2, temp_dir);
+        handle_resume(task.id as i64, pool.clone(), options)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(dir.join("file.iso")).unwrap(), CONTENT);
+        let mut served = served.lock().unwrap().clone();
+        served.sort();
+        assert_eq!(served, vec!["4-7", "8-9"]);
+        let task = pool.get_task_by_id(task.id as i64).await.unwrap().unwrap();
+        assert_eq!(task.status, DownloadStatus::Completed);
+        assert!(!Path::new(&task.temp_files_path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_shared_temp_dir_task() {
+        let dir = std::env::temp_dir().join(format!("whip-delete-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = Repository::Memory(MemoryRepository::new());
+        let task = DownloadTask {
+            file_url: String::from("http://127.0.0.1/file.bin"),
+            percentage_completed: 0f64,
+            meta: DownloadMeta {
+                content_length: 10,
+                supports_resume: true,
+                content_type: String::new(),
+                file_name: String::from("file.bin"),
+            },
+            headers: Vec::new(),
+            checksum: None,
+        };
+        // Stored before tasks had a directory of their own
+        let dir_name = dir.to_string_lossy().to_string();
+        let id = pool
+            .insert_task(&task, dir_name.clone(), dir_name, String::from("2"), 2)
+            .await
+            .unwrap();
+        // Parts beyond the part count, from before it changed, go too
+        for name in [
+            "file.bin.0",
+            "file.bin.1",
+            "file.bin.5",
+            "file.bin.seg3",
+            "other.bin.0",
+        ] {
+            std::fs::write(dir.join(name), b"data").unwrap();
+        }
+
+        handle_delete(id as i64, false, pool.clone()).await.unwrap();
+
+        assert!(pool.get_task_by_id(id as i64).await.unwrap().is_none());
+        let left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(left, vec!["other.bin.0"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
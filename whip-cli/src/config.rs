@@ -0,0 +1,577 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use whip_core::schedule::parse_rate;
+use whip_persistance::repository::Backend;
+
+use crate::exit_code::ExitCode;
+
+/// Points to another configuration file than the default one
+pub const CONFIG_VAR: &str = "WHIP_CONFIG";
+
+const DEFAULT_THREADS: u32 = 4;
+const DEFAULT_RETRIES: u8 = 3;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        message: String,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    /// A setting has a value whip can't use
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, message } => {
+                write!(f, "{} : Path {}", message, path.to_string_lossy())
+            }
+            ConfigError::Parse { path, message } => {
+                write!(
+                    f,
+                    "Invalid configuration file {} : {}",
+                    path.to_string_lossy(),
+                    message
+                )
+            }
+            ConfigError::Invalid { key, message } => write!(f, "Invalid {} : {}", key, message),
+        }
+    }
+}
+
+impl From<&ConfigError> for ExitCode {
+    fn from(_: &ConfigError) -> Self {
+        ExitCode::Config
+    }
+}
+
+/// Settings read from config.toml, unset ones fall back to the defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Where tasks are kept: sqlite, json or memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// sqlite: url or path of the database, path of the file for json
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u8>,
+    /// Highest rate of each download, like 500K
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<String>,
+    /// Sent with the requests of every new task
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Keys of the file `whip config set` accepts, headers go as headers.NAME
+pub const KEYS: [&str; 8] = [
+    "backend",
+    "database",
+    "temp_dir",
+    "output_dir",
+    "threads",
+    "retries",
+    "rate_limit",
+    "headers",
+];
+
+impl ConfigFile {
+    /// Reads the file at `path`, an empty configuration when it doesn't exist
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ConfigFile::default()),
+            Err(e) => {
+                return Err(ConfigError::Io {
+                    path: path.to_path_buf(),
+                    message: e.to_string(),
+                })
+            }
+        };
+        toml::from_str(&content).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Writes the configuration to `path`, creating its directory
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let io_error = |e: std::io::Error| ConfigError::Io {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+        let content = match toml::to_string(self) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(ConfigError::Parse {
+                    path: path.to_path_buf(),
+                    message: e.to_string(),
+                })
+            }
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        fs::write(path, content).map_err(io_error)
+    }
+
+    /// Sets `key` from its text form, an empty value unsets it
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = |message: &str| ConfigError::Invalid {
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+        let value = value.trim();
+        let unset = value.is_empty();
+
+        if let Some(name) = key.strip_prefix("headers.") {
+            if name.is_empty() || name.contains(':') {
+                return Err(invalid("headers are set as headers.NAME"));
+            }
+            if unset {
+                self.headers.remove(name);
+            } else {
+                self.headers.insert(name.to_string(), value.to_string());
+            }
+            return Ok(());
+        }
+
+        match key {
+            "backend" if unset => self.backend = None,
+            "backend" => {
+                backend(key, value)?;
+                self.backend = Some(value.to_lowercase());
+            }
+            "database" => self.database = (!unset).then(|| value.to_string()),
+            "temp_dir" => self.temp_dir = (!unset).then(|| PathBuf::from(value)),
+            "output_dir" => self.output_dir = (!unset).then(|| PathBuf::from(value)),
+            "threads" if unset => self.threads = None,
+            "threads" => match value.parse::<u32>() {
+                Ok(t) if t > 0 => self.threads = Some(t),
+                _ => return Err(invalid("expected a number of threads above 0")),
+            },
+            "retries" if unset => self.retries = None,
+            "retries" => match value.parse::<u8>() {
+                Ok(r) => self.retries = Some(r),
+                Err(_) => return Err(invalid("expected a number of retries up to 255")),
+            },
+            "rate_limit" if unset => self.rate_limit = None,
+            "rate_limit" => {
+                rate_limit(key, value)?;
+                self.rate_limit = Some(value.to_string());
+            }
+            _ => {
+                return Err(ConfigError::Invalid {
+                    key: key.to_string(),
+                    message: format!("unknown setting, expected one of {}", KEYS.join(", ")),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a setting got its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    Env(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "config file"),
+            Source::Env(var) => write!(f, "{}", var),
+        }
+    }
+}
+
+/// Settings of whip once the defaults, the configuration file and the
+/// environment variables are layered, in that order. Command line flags
+/// are applied over them by the commands.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub backend: Backend,
+    /// Url of the sqlite database, path of the JSON file, empty in memory
+    pub database_url: String,
+    pub temp_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub threads: u32,
+    pub retries: u8,
+    /// Bytes per second, 0 for no limit
+    pub rate_limit: u64,
+    pub headers: Vec<(String, String)>,
+    /// Where each setting comes from, by key
+    pub sources: BTreeMap<&'static str, Source>,
+}
+
+impl Settings {
+    /// Layers `file` and the variables `env` returns over the defaults
+    pub fn resolve<F>(file: &ConfigFile, env: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut sources = BTreeMap::new();
+        let mut layer = |key: &'static str, vars: &[&'static str], from_file: Option<String>| {
+            let from_env = vars.iter().find_map(|v| {
+                env(v)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (*v, value))
+            });
+            let (source, value) = match (from_env, from_file) {
+                (Some((var, value)), _) => (Source::Env(var), Some(value)),
+                (None, Some(value)) => (Source::File, Some(value)),
+                (None, None) => (Source::Default, None),
+            };
+            sources.insert(key, source);
+            value
+        };
+
+        let backend = match layer("backend", &["WHIP_BACKEND"], file.backend.clone()) {
+            Some(b) => backend("backend", &b)?,
+            None => Backend::default(),
+        };
+        // DATABASE_URL of older setups is still read, below the file since
+        // it's set for other programs too
+        let legacy_url = env("DATABASE_URL").filter(|url| !url.is_empty());
+        let from_legacy = backend == Backend::Sqlite
+            && file.database.is_none()
+            && legacy_url.is_some()
+            && env("WHIP_DATABASE_URL")
+                .filter(|url| !url.is_empty())
+                .is_none();
+        let database_url = match backend {
+            Backend::Sqlite => match layer(
+                "database",
+                &["WHIP_DATABASE_URL"],
+                file.database.clone().or(legacy_url),
+            ) {
+                Some(d) => database_url(&d),
+                None => database_url(&data_dir().join("whip.db").to_string_lossy()),
+            },
+            Backend::Json => layer("database", &["WHIP_DATABASE_URL"], file.database.clone())
+                .unwrap_or_else(|| data_dir().join("whip.json").to_string_lossy().to_string()),
+            Backend::Memory => {
+                layer("database", &[], None);
+                String::new()
+            }
+        };
+        let temp_dir = layer(
+            "temp_dir",
+            &["WHIP_TEMP_DIR"],
+            file.temp_dir
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string()),
+        )
+        .map(PathBuf::from)
+        .unwrap_or_else(|| data_dir().join("temp"));
+        let output_dir = layer(
+            "output_dir",
+            &["WHIP_OUTPUT_DIR"],
+            file.output_dir
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string()),
+        )
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+
+        let threads = match layer(
+            "threads",
+            &["WHIP_THREADS"],
+            file.threads.map(|t| t.to_string()),
+        ) {
+            Some(t) => match t.parse::<u32>() {
+                Ok(t) if t > 0 => t,
+                _ => {
+                    return Err(ConfigError::Invalid {
+                        key: String::from("threads"),
+                        message: format!("{} isn't a number of threads above 0", t),
+                    })
+                }
+            },
+            None => DEFAULT_THREADS,
+        };
+        let retries = match layer(
+            "retries",
+            &["WHIP_RETRIES"],
+            file.retries.map(|r| r.to_string()),
+        ) {
+            Some(r) => match r.parse::<u8>() {
+                Ok(r) => r,
+                Err(_) => {
+                    return Err(ConfigError::Invalid {
+                        key: String::from("retries"),
+                        message: format!("{} isn't a number of retries up to 255", r),
+                    })
+                }
+            },
+            None => DEFAULT_RETRIES,
+        };
+        let rate_limit = match layer("rate_limit", &["WHIP_RATE_LIMIT"], file.rate_limit.clone()) {
+            Some(r) => rate_limit("rate_limit", &r)?,
+            None => 0,
+        };
+        if from_legacy {
+            sources.insert("database", Source::Env("DATABASE_URL"));
+        }
+        sources.insert(
+            "headers",
+            if file.headers.is_empty() {
+                Source::Default
+            } else {
+                Source::File
+            },
+        );
+
+        Ok(Settings {
+            backend,
+            database_url,
+            temp_dir,
+            output_dir,
+            threads,
+            retries,
+            rate_limit,
+            headers: file
+                .headers
+                .iter()
+                .map(|(n, v)| (n.to_owned(), v.to_owned()))
+                .collect(),
+            sources,
+        })
+    }
+
+    /// Reads the configuration file at `path` and the environment of the process
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let file = ConfigFile::load(path)?;
+        Settings::resolve(&file, |var| env::var(var).ok())
+    }
+
+    /// Each setting with its value and where it comes from
+    pub fn values(&self) -> Vec<(&'static str, String, Source)> {
+        let rate_limit = if self.rate_limit == 0 {
+            String::from("unlimited")
+        } else {
+            format!("{}/s", whip_core::storage::format_size(self.rate_limit))
+        };
+        let headers = self
+            .headers
+            .iter()
+            .map(|(n, v)| format!("{}: {}", n, v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let values = [
+            ("backend", self.backend.to_string()),
+            ("database", self.database_url.to_owned()),
+            ("temp_dir", self.temp_dir.to_string_lossy().to_string()),
+            ("output_dir", self.output_dir.to_string_lossy().to_string()),
+            ("threads", self.threads.to_string()),
+            ("retries", self.retries.to_string()),
+            ("rate_limit", rate_limit),
+            ("headers", headers),
+        ];
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.sources.get(key).copied().unwrap_or(Source::Default);
+                (key, value, source)
+            })
+            .collect()
+    }
+
+    /// Settings with their value and source, one per line
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for (key, value, source) in self.values() {
+            description.push_str(&format!("{:<10} = {} ({})\n", key, value, source));
+        }
+        description
+    }
+}
+
+/// Rate of a setting in bytes per second, 0 or unlimited for none
+fn rate_limit(key: &str, value: &str) -> Result<u64, ConfigError> {
+    if value.eq_ignore_ascii_case("unlimited") {
+        return Ok(0);
+    }
+    parse_rate(value).ok_or_else(|| ConfigError::Invalid {
+        key: key.to_string(),
+        message: format!("{} isn't a rate like 500K or unlimited", value),
+    })
+}
+
+fn backend(key: &str, value: &str) -> Result<Backend, ConfigError> {
+    Backend::parse(value).ok_or_else(|| ConfigError::Invalid {
+        key: key.to_string(),
+        message: format!("{} isn't sqlite, json or memory", value),
+    })
+}
+
+/// Makes a sqlite url from a path, urls are kept as they are
+fn database_url(value: &str) -> String {
+    if value.starts_with("sqlite:") {
+        value.to_string()
+    } else {
+        format!("sqlite:{}", value)
+    }
+}
+
+/// Path of the configuration file: WHIP_CONFIG, or else
+/// $XDG_CONFIG_HOME/whip/config.toml
+pub fn config_file() -> PathBuf {
+    match env::var_os(CONFIG_VAR) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => base_dir("XDG_CONFIG_HOME", "APPDATA", ".config").join("config.toml"),
+    }
+}
+
+/// Directory of the database and temporary files, $XDG_DATA_HOME/whip
+pub fn data_dir() -> PathBuf {
+    base_dir("XDG_DATA_HOME", "LOCALAPPDATA", ".local/share")
+}
+
+/// The whip directory under the XDG variable `xdg_var`, or its default
+/// under the home directory. Windows has its own variable for it.
+fn base_dir(xdg_var: &str, windows_var: &str, home_default: &str) -> PathBuf {
+    let absolute = |var: &str| {
+        env::var_os(var)
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+    };
+    let base = if cfg!(target_family = "windows") {
+        absolute(windows_var)
+    } else {
+        absolute(xdg_var).or_else(|| absolute("HOME").map(|home| home.join(home_default)))
+    };
+    // Without a home, whip keeps working from the current directory like it used to
+    match base {
+        Some(b) => b.join("whip"),
+        None => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layering() {
+        let mut file: ConfigFile = toml::from_str(
+            r#"
+            threads = 8
+            rate_limit = "500K"
+            temp_dir = "/var/tmp/whip"
+
+            [headers]
+            User-Agent = "whip"
+            "#,
+        )
+        .unwrap();
+        let env = |var: &str| match var {
+            "WHIP_THREADS" => Some(String::from("2")),
+            "DATABASE_URL" => Some(String::from("/tmp/whip.db")),
+            _ => None,
+        };
+
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.threads, 2);
+        assert_eq!(settings.sources["threads"], Source::Env("WHIP_THREADS"));
+        assert_eq!(settings.rate_limit, 500 * 1024);
+        assert_eq!(settings.temp_dir, PathBuf::from("/var/tmp/whip"));
+        assert_eq!(settings.retries, DEFAULT_RETRIES);
+        // DATABASE_URL is only read for the sqlite backend
+        #[cfg(feature = "sqlite")]
+        {
+            assert_eq!(settings.database_url, "sqlite:/tmp/whip.db");
+            assert_eq!(settings.sources["database"], Source::Env("DATABASE_URL"));
+        }
+        assert_eq!(
+            settings.headers,
+            vec![(String::from("User-Agent"), String::from("whip"))]
+        );
+
+        file.set("threads", "").unwrap();
+        file.set("headers.User-Agent", "").unwrap();
+        assert!(file.set("threads", "0").is_err());
+        assert!(file.set("speed", "1M").is_err());
+        let reloaded: ConfigFile = toml::from_str(&toml::to_string(&file).unwrap()).unwrap();
+        assert_eq!(reloaded, file);
+        assert_eq!(reloaded.threads, None);
+        assert!(reloaded.headers.is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_legacy_database_url() {
+        let mut file = ConfigFile::default();
+        file.set("database", "/srv/whip/whip.db").unwrap();
+        let env = |var: &str| match var {
+            "DATABASE_URL" => Some(String::from("sqlite:whip.db")),
+            _ => None,
+        };
+
+        // The file wins over DATABASE_URL, WHIP_DATABASE_URL over both
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.database_url, "sqlite:/srv/whip/whip.db");
+        assert_eq!(settings.sources["database"], Source::File);
+        let env = |var: &str| match var {
+            "DATABASE_URL" => Some(String::from("sqlite:whip.db")),
+            "WHIP_DATABASE_URL" => Some(String::from("/tmp/whip.db")),
+            _ => None,
+        };
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.database_url, "sqlite:/tmp/whip.db");
+        assert_eq!(
+            settings.sources["database"],
+            Source::Env("WHIP_DATABASE_URL")
+        );
+    }
+
+    #[test]
+    fn test_backend() {
+        let mut file = ConfigFile::default();
+        file.set("backend", "JSON").unwrap();
+        assert!(file.set("backend", "postgres").is_err());
+        let env = |var: &str| match var {
+            "DATABASE_URL" => Some(String::from("sqlite:whip.db")),
+            _ => None,
+        };
+
+        // The sqlite url of older setups isn't taken for the file
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.backend, Backend::Json);
+        assert!(settings.database_url.ends_with("whip.json"));
+        file.set("database", "/srv/whip/tasks.json").unwrap();
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.database_url, "/srv/whip/tasks.json");
+        assert_eq!(settings.sources["database"], Source::File);
+
+        let env = |var: &str| (var == "WHIP_BACKEND").then(|| String::from("memory"));
+        let settings = Settings::resolve(&file, env).unwrap();
+        assert_eq!(settings.backend, Backend::Memory);
+        assert_eq!(settings.sources["backend"], Source::Env("WHIP_BACKEND"));
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+
+use whip_core::errors::WhipError;
+use whip_persistance::errors::DatabaseError;
+
+use crate::output::{self, Event};
+
+/// Exit status of whip, one per kind of failure so scripts can react to
+/// them. 2 is left to invalid command line arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Unknown errors, or several downloads of one command failed
+    Failure = 1,
+    /// Timeout or connection failure, trying again later may work
+    Network = 3,
+    Dns = 4,
+    Tls = 5,
+    /// The server answered with an error status
+    Http = 6,
+    RangeNotSupported = 7,
+    LinkExpired = 8,
+    ChecksumMismatch = 9,
+    DiskFull = 10,
+    Storage = 11,
+    /// A playlist, manifest, metalink or input file couldn't be understood
+    Parse = 12,
+    Database = 13,
+    /// Another whip process is working on the task
+    Locked = 14,
+    /// The configuration file or a setting is invalid
+    Config = 15,
+    /// Stopped by SIGINT or SIGTERM, like shells report a Ctrl-C
+    Interrupted = 130,
+}
+
+impl ExitCode {
+    /// Name of the kind of failure in JSON output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExitCode::Failure => "failure",
+            ExitCode::Network => "network",
+            ExitCode::Dns => "dns",
+            ExitCode::Tls => "tls",
+            ExitCode::Http => "http",
+            ExitCode::RangeNotSupported => "range_not_supported",
+            ExitCode::LinkExpired => "link_expired",
+            ExitCode::ChecksumMismatch => "checksum_mismatch",
+            ExitCode::DiskFull => "disk_full",
+            ExitCode::Storage => "storage",
+            ExitCode::Parse => "parse",
+            ExitCode::Database => "database",
+            ExitCode::Locked => "locked",
+            ExitCode::Config => "config",
+            ExitCode::Interrupted => "interrupted",
+        }
+    }
+}
+
+impl From<&WhipError> for ExitCode {
+    fn from(e: &WhipError) -> Self {
+        match e {
+            WhipError::Http { .. } => ExitCode::Http,
+            WhipError::Timeout { .. } | WhipError::Connection { .. } => ExitCode::Network,
+            WhipError::Dns { .. } => ExitCode::Dns,
+            WhipError::Tls { .. } => ExitCode::Tls,
+            WhipError::RangeNotSupported { .. } => ExitCode::RangeNotSupported,
+            WhipError::LinkExpired { .. } => ExitCode::LinkExpired,
+            WhipError::ChecksumMismatch { .. } => ExitCode::ChecksumMismatch,
+            WhipError::DiskFull { .. } => ExitCode::DiskFull,
+            WhipError::Io { .. } | WhipError::Storage(_) => ExitCode::Storage,
+            WhipError::Parse(_) => ExitCode::Parse,
+            WhipError::Interrupted => ExitCode::Interrupted,
+            WhipError::Locked { .. } => ExitCode::Locked,
+            WhipError::Unknown(_) => ExitCode::Failure,
+        }
+    }
+}
+
+impl From<&DatabaseError> for ExitCode {
+    fn from(_: &DatabaseError) -> Self {
+        ExitCode::Database
+    }
+}
+
+/// Prints an error, or sends it as an event in JSON output, and returns
+/// the exit code of its kind
+pub fn report<E>(e: E) -> ExitCode
+where
+    E: fmt::Display,
+    for<'a> ExitCode: From<&'a E>,
+{
+    let code = ExitCode::from(&e);
+    if output::is_json() {
+        output::emit(Event::error(code, e.to_string()));
+    } else {
+        eprintln!("{}", e);
+    }
+    code
+}
+
+/// Exit code of a command whose steps failed with `codes`: the kind they
+/// share, or Failure when they differ
+pub fn summarize(codes: &[ExitCode]) -> Result<(), ExitCode> {
+    match codes.split_first() {
+        None => Ok(()),
+        Some((first, rest)) if rest.iter().all(|c| c == first) => Err(*first),
+        Some(_) => Err(ExitCode::Failure),
+    }
+}
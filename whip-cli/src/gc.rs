@@ -0,0 +1,337 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use whip_core::{dash, storage::TaskLock};
+use whip_persistance::models::{DownloadStatus, DownloadTaskEntity};
+
+/// Temporary or partial data nothing will use anymore
+#[derive(Debug, PartialEq, Eq)]
+pub struct Orphan {
+    pub path: PathBuf,
+    pub size: u64,
+    pub reason: String,
+    /// Directory holding the lock of the task it belongs to, and the task's
+    /// id. The lock is taken while it's removed, the task may start again.
+    pub lock: Option<(PathBuf, u64)>,
+}
+
+/// Completed task whose files are all gone
+#[derive(Debug, PartialEq, Eq)]
+pub struct DanglingRecord {
+    pub task_id: u64,
+    pub file_name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct Garbage {
+    pub orphans: Vec<Orphan>,
+    pub dangling: Vec<DanglingRecord>,
+}
+
+impl Garbage {
+    /// Bytes freed by removing the orphans
+    pub fn size(&self) -> u64 {
+        self.orphans.iter().map(|o| o.size).sum()
+    }
+}
+
+/// Looks for the data in `temp_dir` and in the output directories that
+/// no task needs anymore, and for the completed tasks whose files are gone.
+pub fn find_garbage(tasks: &[DownloadTaskEntity], temp_dir: &Path) -> Garbage {
+    let mut garbage = Garbage::default();
+
+    if let Ok(entries) = fs::read_dir(temp_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(reason) = temp_entry_orphaned(&name, path.is_dir(), tasks, temp_dir) {
+                garbage.orphans.push(Orphan {
+                    size: disk_usage(&path),
+                    lock: entry_lock(&name, &path, temp_dir),
+                    path,
+                    reason,
+                });
+            }
+        }
+    }
+
+    for task in tasks.iter() {
+        // Streams are assembled under a temporary name next to the final file
+        let mut partial = PathBuf::from(&task.final_file_path);
+        partial.push(format!("{}.part", task.file_name));
+        if partial.is_file() && !keeps_temp_data(task) && !is_running(task) {
+            garbage.orphans.push(Orphan {
+                size: disk_usage(&partial),
+                path: partial,
+                reason: format!("partial file of task {}, which is {}", task.id, task.status),
+                lock: Some((PathBuf::from(&task.temp_files_path), task.id)),
+            });
+        }
+
+        if task.status == DownloadStatus::Completed && !has_files(task) {
+            let mut path = PathBuf::from(&task.final_file_path);
+            path.push(&task.file_name);
+            garbage.dangling.push(DanglingRecord {
+                task_id: task.id,
+                file_name: task.file_name.to_owned(),
+                path,
+            });
+        }
+    }
+    garbage
+}
+
+/// Completed and cancelled tasks won't read their temporary data again,
+/// failed ones may be resumed
+fn keeps_temp_data(task: &DownloadTaskEntity) -> bool {
+    !matches!(
+        task.status,
+        DownloadStatus::Completed | DownloadStatus::Cancelled
+    )
+}
+
+/// Tells why an entry of the temporary directory is orphaned, None when a
+/// task still needs it. Only the task directories, parts, stream segments
+/// and lock files whip creates are considered, anything else is left alone.
+fn temp_entry_orphaned(
+    name: &str,
+    is_dir: bool,
+    tasks: &[DownloadTaskEntity],
+    temp_dir: &Path,
+) -> Option<String> {
+    let find = |id: u64| tasks.iter().find(|t| t.id == id);
+
+    if is_dir {
+        let id = task_id(name)?;
+        // A download may be running for a task its process hasn't saved yet
+        if TaskLock::holder(&temp_dir.join(name), id).is_some() {
+            return None;
+        }
+        return match find(id) {
+            None => Some(format!("no task {}", id)),
+            Some(t) if !keeps_temp_data(t) && !is_running(t) => {
+                Some(format!("task {} is {}", id, t.status))
+            }
+            Some(_) => None,
+        };
+    }
+
+    // Tasks created before they had a directory of their own share temp_dir
+    let shared = |t: &&DownloadTaskEntity| {
+        same_dir(Path::new(&t.temp_files_path), temp_dir) && (keeps_temp_data(t) || is_running(t))
+    };
+
+    if let Some(id) = name
+        .strip_prefix("task-")
+        .and_then(|n| n.strip_suffix(".lock"))
+    {
+        let id = task_id(id)?;
+        if TaskLock::holder(temp_dir, id).is_some() {
+            return None;
+        }
+        return match find(id).filter(shared) {
+            Some(_) => None,
+            None => Some(format!("lock file of task {}, which isn't running", id)),
+        };
+    }
+
+    let file_name = part_file_name(name)?;
+    if tasks
+        .iter()
+        .filter(shared)
+        .any(|t| t.file_name == file_name)
+    {
+        None
+    } else {
+        Some(String::from("no unfinished task uses it"))
+    }
+}
+
+/// Lock of the task an entry of the temporary directory belongs to, None
+/// for parts and segments, which tasks share by name
+fn entry_lock(name: &str, path: &Path, temp_dir: &Path) -> Option<(PathBuf, u64)> {
+    if path.is_dir() {
+        return task_id(name).map(|id| (path.to_path_buf(), id));
+    }
+    let id = name.strip_prefix("task-")?.strip_suffix(".lock")?;
+    task_id(id).map(|id| (temp_dir.to_path_buf(), id))
+}
+
+/// Id of a task directory or lock file, which is only made of digits
+fn task_id(name: &str) -> Option<u64> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// Name of the file a part (`name.N`) or a stream segment (`name.segN` or
+/// `name.segN.part`) is downloaded for
+pub fn part_file_name(name: &str) -> Option<&str> {
+    let (file_name, number) = match name.strip_suffix(".part") {
+        Some(segment) => {
+            let (file_name, suffix) = segment.rsplit_once('.')?;
+            (file_name, suffix.strip_prefix("seg")?)
+        }
+        None => {
+            let (file_name, suffix) = name.rsplit_once('.')?;
+            (file_name, suffix.strip_prefix("seg").unwrap_or(suffix))
+        }
+    };
+    if file_name.is_empty() || task_id(number).is_none() {
+        return None;
+    }
+    Some(file_name)
+}
+
+/// Whether another process is downloading the task right now
+fn is_running(task: &DownloadTaskEntity) -> bool {
+    TaskLock::holder(Path::new(&task.temp_files_path), task.id).is_some()
+}
+
+/// Whether both paths lead to the same directory, however they're spelt
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Whether the downloaded file, or what was extracted from it, is still there
+fn has_files(task: &DownloadTaskEntity) -> bool {
+    let mut path = PathBuf::from(&task.final_file_path);
+    path.push(&task.file_name);
+    if path.exists() {
+        return true;
+    }
+    if let Some(extracted) = &task.extracted_path {
+        if Path::new(extracted).exists() {
+            return true;
+        }
+    }
+    // DASH tracks are saved under names made from the task's
+    if dash::is_dash(&task.to_download_task()) {
+        if let Ok(entries) = fs::read_dir(&task.final_file_path) {
+            return entries
+                .flatten()
+                .any(|e| e.file_name().to_string_lossy().starts_with(&task.file_name));
+        }
+    }
+    false
+}
+
+/// Size of a file, or of everything under a directory
+fn disk_usage(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|e| disk_usage(&e.path())).sum(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(
+        id: u64,
+        file_name: &str,
+        temp_files_path: &str,
+        status: DownloadStatus,
+    ) -> DownloadTaskEntity {
+        DownloadTaskEntity {
+            id,
+            file_name: file_name.to_string(),
+            temp_files_path: temp_files_path.to_string(),
+            status,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_temp_entry_orphaned() {
+        let temp_dir = Path::new("./temp");
+        let tasks = vec![
+            task(1, "a.bin", "./temp/1", DownloadStatus::Paused),
+            task(2, "b.bin", "./temp/2", DownloadStatus::Completed),
+            task(3, "c.ts", "./temp", DownloadStatus::Failed),
+        ];
+        let orphaned = |name, is_dir| temp_entry_orphaned(name, is_dir, &tasks, temp_dir).is_some();
+
+        assert!(!orphaned("1", true));
+        assert!(orphaned("2", true));
+        assert!(orphaned("4", true));
+        assert!(!orphaned("c.ts.seg12", false));
+        assert!(!orphaned("c.ts.seg3.part", false));
+        assert!(!orphaned("task-3.lock", false));
+        assert!(orphaned("task-1.lock", false));
+        assert!(orphaned("a.bin.0", false));
+        assert!(orphaned("d.iso.seg0.part", false));
+        // Whip doesn't create these
+        assert!(!orphaned("c.ts.old", false));
+        assert!(!orphaned("a.bin.0.part", false));
+        assert!(!orphaned("notes", true));
+        assert!(!orphaned("+4", true));
+        assert!(!orphaned("task-x.lock", false));
+        assert!(!orphaned(".seg1", false));
+    }
+
+    #[test]
+    fn test_running_tasks_kept() {
+        let temp_dir = std::env::temp_dir().join(format!("whip-gc-{}", std::process::id()));
+        fs::create_dir_all(temp_dir.join("5")).unwrap();
+        // The same directory, spelt differently
+        let shared = temp_dir.join("5").join("..");
+        let tasks = vec![
+            task(
+                5,
+                "e.bin",
+                &temp_dir.join("5").to_string_lossy(),
+                DownloadStatus::Completed,
+            ),
+            task(
+                6,
+                "f.bin",
+                &shared.to_string_lossy(),
+                DownloadStatus::Paused,
+            ),
+        ];
+        let orphaned =
+            |name, is_dir| temp_entry_orphaned(name, is_dir, &tasks, &temp_dir).is_some();
+
+        assert!(orphaned("5", true));
+        assert!(!orphaned("f.bin.2", false));
+        assert!(!orphaned("task-6.lock", false));
+
+        let lock = TaskLock::acquire(&temp_dir.join("5"), 5).unwrap();
+        assert!(!orphaned("5", true));
+        drop(lock);
+        let lock = TaskLock::acquire(&temp_dir, 7).unwrap();
+        assert!(!orphaned("task-7.lock", false));
+        drop(lock);
+        assert!(orphaned("task-7.lock", false));
+
+        // Removing them takes the lock of their task
+        let dir = temp_dir.join("5");
+        assert_eq!(entry_lock("5", &dir, &temp_dir), Some((dir.clone(), 5)));
+        assert_eq!(
+            entry_lock("task-7.lock", &temp_dir.join("task-7.lock"), &temp_dir),
+            Some((temp_dir.clone(), 7))
+        );
+        assert_eq!(
+            entry_lock("f.bin.2", &temp_dir.join("f.bin.2"), &temp_dir),
+            None
+        );
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
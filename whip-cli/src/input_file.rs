@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use whip_core::{checksum::Checksum, download::parse_header};
+
+/// A download listed in an input file, with its own options.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InputEntry {
+    pub url: String,
+    pub output_dir: Option<PathBuf>,
+    pub file_name: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub checksum: Option<Checksum>,
+    pub max_threads: Option<u32>,
+}
+
+/// Parses an input file in the aria2 format: one url per line, followed
+/// by its options on indented `name=value` lines. Blank lines and lines
+/// starting with `#` are ignored.
+///
+/// ```text
+/// https://example.com/file.iso
+///   dir=./isos
+///   out=debian.iso
+///   header=Authorization: Bearer 1234
+///   checksum=sha-256=e3b0c442...
+///   split=8
+/// ```
+pub fn parse_input_file(content: &str) -> Result<Vec<InputEntry>, String> {
+    let mut entries: Vec<InputEntry> = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            // Tab separated mirrors of the same file aren't supported, keep the first one
+            let url = line.split('\t').next().unwrap_or("").trim();
+            entries.push(InputEntry {
+                url: url.to_string(),
+                output_dir: None,
+                file_name: None,
+                headers: Vec::new(),
+                checksum: None,
+                max_threads: None,
+            });
+            continue;
+        }
+
+        let entry = match entries.last_mut() {
+            Some(e) => e,
+            None => return Err(format!("Line {} : option before any url", line_number)),
+        };
+        let (name, value) = match line.trim().split_once('=') {
+            Some((n, v)) => (n.trim(), v.trim()),
+            None => return Err(format!("Line {} : expected name=value", line_number)),
+        };
+        match name {
+            "dir" => entry.output_dir = Some(PathBuf::from(value)),
+            "out" => entry.file_name = Some(value.to_string()),
+            "header" => match parse_header(value) {
+                Some(h) => entry.headers.push(h),
+                None => return Err(format!("Line {} : invalid header {}", line_number, value)),
+            },
+            "checksum" => match Checksum::parse(value) {
+                Some(c) => entry.checksum = Some(c),
+                None => return Err(format!("Line {} : invalid checksum {}", line_number, value)),
+            },
+            "split" => match value.parse() {
+                Ok(n) if n > 0 => entry.max_threads = Some(n),
+                _ => return Err(format!("Line {} : invalid split {}", line_number, value)),
+            },
+            _ => return Err(format!("Line {} : unknown option {}", line_number, name)),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input_file() {
+        let content = "# nightly builds
+https://example.com/a.zip
+  dir=./out
+  out=b.zip
+  header=Cookie: session=42
+\tchecksum=sha-1=A9993E364706816ABA3E25717850C26C9CD0D89D
+
+https://example.com/c.iso\thttps://mirror.example.com/c.iso
+  split=8
+";
+
+        let entries = parse_input_file(content).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].output_dir, Some(PathBuf::from("./out")));
+        assert_eq!(entries[0].file_name, Some(String::from("b.zip")));
+        assert_eq!(
+            entries[0].headers,
+            vec![(String::from("Cookie"), String::from("session=42"))]
+        );
+        assert!(entries[0].checksum.as_ref().unwrap().verify(b"abc"));
+        assert_eq!(entries[1].url, "https://example.com/c.iso");
+        assert_eq!(entries[1].max_threads, Some(8));
+    }
+
+    #[test]
+    fn test_parse_input_file_errors() {
+        assert!(parse_input_file("  dir=./out\nhttps://example.com/a.zip").is_err());
+        assert!(parse_input_file("https://example.com/a.zip\n  speed=10").is_err());
+        assert!(parse_input_file("https://example.com/a.zip\n  checksum=crc32=1").is_err());
+    }
+}
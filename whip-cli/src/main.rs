@@ -1,93 +1,289 @@
 #[macro_use]
 extern crate prettytable;
 use clap::Parser;
-use commands::{handle_delete, handle_download, handle_show_downloads, Commands, TEMP_DIR};
-use dotenv::dotenv;
-use sqlx::SqlitePool;
-use std::{
-    path::{Path, PathBuf},
-    process,
+use commands::{
+    download_schedule, handle_batch_download, handle_config, handle_delete, handle_download,
+    handle_export, handle_gc, handle_hook_log, handle_import, handle_info, handle_mirror,
+    handle_pause, handle_restart, handle_resume, handle_retry_failed, handle_show_downloads,
+    handle_tui, merge_headers, variant_selector, Commands, DownloadOptions,
 };
+use config::Settings;
+use exit_code::{report, ExitCode};
+use output::{Event, OutputFormat};
+use std::{path::PathBuf, process, time::Duration};
 use tokio::fs;
-use whip_persistance::{errors::DatabaseError, get_database_pool};
+use tui::TuiOptions;
+use whip_core::{errors::WhipError, mirror::MirrorOptions};
+use whip_persistance::{
+    errors::DatabaseError,
+    repository::{Backend, Repository},
+};
 
 pub mod commands;
+pub mod config;
+pub mod exit_code;
+pub mod gc;
+pub mod input_file;
+pub mod output;
+pub mod progress;
+pub mod signals;
+pub mod transfer;
+pub mod tui;
 
 #[derive(Parser)]
 #[clap(subcommand_required = true)]
 struct Whip {
+    /// Configuration file to use instead of $XDG_CONFIG_HOME/whip/config.toml
+    /// (defaults to the WHIP_CONFIG environment variable)
+    #[clap(value_parser, long, global = true)]
+    config: Option<PathBuf>,
+    /// json writes one JSON event per line on stdout instead of tables,
+    /// progress bars and messages
+    #[clap(value_enum, long, global = true, default_value = "text")]
+    output: OutputFormat,
     #[clap(subcommand)]
     commands: Commands,
 }
 
 #[tokio::main]
 async fn main() {
-    dotenv().ok();
+    let whip = Whip::parse();
+    let config_path = whip.config.unwrap_or_else(config::config_file);
+    output::set_format(whip.output);
+    let command = whip.commands.name();
 
-    if !Path::new(TEMP_DIR).is_dir() {
-        if let Err(e) = fs::create_dir(TEMP_DIR).await {
-            eprintln!("{}", e);
-            process::exit(1);
-        }
+    // Settings can be fixed without a database, even when they're invalid
+    if let Commands::Config { command: config } = whip.commands {
+        return finish(command, handle_config(config, &config_path));
     }
 
-    let database_url =
-        dotenv::var("DATABASE_URL").expect("DATABASE_URL environment variable has to be set");
+    let settings = match Settings::load(&config_path) {
+        Ok(s) => s,
+        Err(e) => return finish(command, Err(report(e))),
+    };
 
-    let db_pool = match setup_database(database_url).await {
-        Ok(pool) => pool,
-        Err(e) => {
-            eprintln!("{}", e);
-            process::exit(1);
+    if !settings.temp_dir.is_dir() {
+        if let Err(e) = fs::create_dir_all(&settings.temp_dir).await {
+            let e = WhipError::io(settings.temp_dir, e);
+            return finish(command, Err(report(e)));
         }
-    };
+    }
 
-    let whip = Whip::parse();
-    let successful: bool;
+    let db_pool = match setup_database(settings.backend, settings.database_url.clone()).await {
+        Ok(pool) => pool,
+        Err(e) => return finish(command, Err(report(e))),
+    };
 
-    match whip.commands {
-        Commands::ShowDownloads { filter } => {
-            successful = handle_show_downloads(filter, db_pool).await.is_ok();
-        }
+    let result = match whip.commands {
+        Commands::ShowDownloads { search } => handle_show_downloads(search.into(), db_pool).await,
         Commands::Download {
             url,
             output_dir,
             max_threads,
             in_memory,
             max_retries,
+            rate_limit,
+            header,
+            parts,
+            preallocate,
+            bandwidth,
+            resolution,
+            location,
+            input_file,
+            max_concurrent,
+            on_complete,
+            on_failure,
+            hook_timeout,
+            extract,
+            extract_dir,
+            delete_archive,
+            start_after,
+            window,
+            outside_window,
+            checkpoint_interval,
+        } => {
+            let mut options = DownloadOptions::new(
+                max_retries.unwrap_or(settings.retries),
+                settings.temp_dir.clone(),
+            );
+            options.rate_limit = rate_limit.unwrap_or(settings.rate_limit);
+            options.headers = merge_headers(&settings.headers, header);
+            // Only downloads are paused on Ctrl-C, the other commands quit
+            signals::listen(options.active.clone());
+            options.on_complete = on_complete;
+            options.on_failure = on_failure;
+            options.hook_timeout = Duration::from_secs(hook_timeout);
+            options.checkpoint_interval = Duration::from_secs(checkpoint_interval);
+            options.extract = extract;
+            options.extract_dir = extract_dir;
+            options.delete_archive = delete_archive;
+            options.start_after = start_after;
+            options.schedule = download_schedule(window, outside_window);
+            options.part_count = parts;
+            options.preallocate = preallocate;
+            options.variant_selector = variant_selector(bandwidth, resolution);
+            options.location = location;
+            let output_dir = output_dir.unwrap_or(settings.output_dir);
+            let max_threads = max_threads.unwrap_or(settings.threads);
+            match (url, input_file) {
+                (_, Some(input_file)) => {
+                    handle_batch_download(
+                        input_file,
+                        output_dir,
+                        max_threads,
+                        max_concurrent,
+                        db_pool,
+                        options,
+                    )
+                    .await
+                }
+                (Some(url), None) => {
+                    handle_download(url, output_dir, max_threads, in_memory, db_pool, options).await
+                }
+                (None, None) => Err(ExitCode::Failure),
+            }
+        }
+        Commands::Mirror {
+            url,
+            output_dir,
+            depth,
+            domain,
+            path_prefix,
+            delay,
+            ignore_robots,
+            max_concurrent,
+            max_threads,
+            max_retries,
+            on_complete,
+            on_failure,
+            hook_timeout,
         } => {
-            successful = handle_download(
+            let mut options = DownloadOptions::new(
+                max_retries.unwrap_or(settings.retries),
+                settings.temp_dir.clone(),
+            );
+            options.rate_limit = settings.rate_limit;
+            options.headers = settings.headers.clone();
+            // Only downloads are paused on Ctrl-C, the other commands quit
+            signals::listen(options.active.clone());
+            options.on_complete = on_complete;
+            options.on_failure = on_failure;
+            options.hook_timeout = Duration::from_secs(hook_timeout);
+            handle_mirror(
                 url,
                 output_dir,
-                max_threads,
-                in_memory,
+                MirrorOptions {
+                    max_depth: depth,
+                    allowed_domains: domain,
+                    path_prefix,
+                    delay: Duration::from_millis(delay),
+                    respect_robots: !ignore_robots,
+                },
+                max_threads.unwrap_or(settings.threads),
+                max_concurrent,
                 db_pool,
-                max_retries,
+                options,
             )
             .await
-            .is_ok();
         }
-        Commands::Delete { id, remove_file } => {
-            successful = handle_delete(id, remove_file, db_pool).await.is_ok();
+        Commands::Info { url, header, parts } => {
+            let headers = merge_headers(&settings.headers, header);
+            handle_info(url, headers, parts.unwrap_or(settings.threads)).await
         }
-    }
+        Commands::Resume {
+            id,
+            checkpoint_interval,
+        } => {
+            let mut options = task_options(&settings);
+            options.checkpoint_interval = Duration::from_secs(checkpoint_interval);
+            handle_resume(id, db_pool, options).await
+        }
+        Commands::RetryFailed { max_concurrent } => {
+            handle_retry_failed(max_concurrent, db_pool, task_options(&settings)).await
+        }
+        Commands::Restart { id } => handle_restart(id, db_pool, task_options(&settings)).await,
+        Commands::Pause { id } => handle_pause(id, db_pool).await,
+        Commands::Export {
+            file,
+            format,
+            temp_data,
+        } => handle_export(file, format, temp_data, db_pool).await,
+        Commands::Import {
+            file,
+            format,
+            on_conflict,
+            temp_data,
+        } => {
+            handle_import(
+                file,
+                format,
+                on_conflict,
+                temp_data,
+                settings.temp_dir,
+                db_pool,
+            )
+            .await
+        }
+        Commands::HookLog { id } => handle_hook_log(id, db_pool).await,
+        Commands::Tui { max_concurrent } => {
+            handle_tui(
+                TuiOptions {
+                    config_path,
+                    max_concurrent,
+                },
+                db_pool,
+            )
+            .await
+        }
+        Commands::Gc { apply } => handle_gc(apply, settings.temp_dir, db_pool).await,
+        Commands::Delete { id, remove_file } => handle_delete(id, remove_file, db_pool).await,
+        Commands::Config { .. } => Ok(()),
+    };
+
+    finish(command, result);
+}
+
+/// Options of the downloads of stored tasks, which keep their own
+/// destination, threads, headers and hooks
+fn task_options(settings: &Settings) -> DownloadOptions {
+    let mut options = DownloadOptions::new(settings.retries, settings.temp_dir.clone());
+    options.rate_limit = settings.rate_limit;
+    // Only downloads are paused on Ctrl-C, the other commands quit
+    signals::listen(options.active.clone());
+    options
+}
 
-    if !successful {
-        process::exit(1);
+/// Sends the outcome of `command` in JSON output, then exits with its code
+/// if it failed
+fn finish(command: &'static str, result: Result<(), ExitCode>) {
+    output::emit(Event::result(command, result));
+    if let Err(code) = result {
+        process::exit(code as i32);
     }
 }
 
-async fn setup_database(database_url: String) -> Result<SqlitePool, DatabaseError> {
+async fn setup_database(
+    backend: Backend,
+    database_url: String,
+) -> Result<Repository, DatabaseError> {
+    // The JSON file is created by its repository
+    if backend != Backend::Sqlite {
+        return Repository::open(backend, &database_url).await;
+    }
     let db_file_path = database_url.replace("sqlite:", "");
 
     let db_path = PathBuf::from(db_file_path);
 
+    if let Some(dir) = db_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(dir).await {
+            return Err(DatabaseError::Operation(e.to_string()));
+        }
+    }
     if !db_path.is_file() {
         if let Err(e) = fs::File::create(db_path).await {
             return Err(DatabaseError::Operation(e.to_string()));
         };
     }
 
-    let db_pool = get_database_pool(database_url).await?;
-    Ok(db_pool)
+    Repository::open(backend, &database_url).await
 }
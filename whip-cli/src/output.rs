@@ -0,0 +1,461 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use whip_core::{
+    download::{ProbeReport, Redirect},
+    progress::Progress,
+};
+use whip_persistance::models::{encode_time, DownloadTaskEntity, HookRunEntity};
+
+use crate::exit_code::ExitCode;
+
+/// Version of the JSON events, raised when a field goes away or changes
+/// meaning. Fields may be added to the events without raising it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Tables, progress bars and messages
+    Text,
+    /// One JSON event per line on stdout, messages go to stderr
+    Json,
+}
+
+/// Set once at start, everything that prints reads it
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints a message meant for people, on stderr when stdout carries JSON
+pub fn info<M: fmt::Display>(message: M) {
+    if is_json() {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Writes `event` on its own line of stdout, nothing is written in text output
+pub fn emit(event: Event) {
+    if !is_json() {
+        return;
+    }
+    let line = match serde_json::to_string(&event) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // A closed pipe isn't worth failing the download for
+    let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+}
+
+/// What `--output json` writes, one event per line with its kind in "type".
+/// Every command ends with a `result` event. docs/json-output.md documents
+/// them, keep it in step.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// State of a task. Downloads send it when they start and stop,
+    /// show-downloads once per task.
+    Task(TaskRecord),
+    /// Tasks listed by show-downloads, sent after their `task` events
+    Page {
+        /// Tasks matching the filters, before the offset and limit
+        total: usize,
+        offset: usize,
+        /// Null when every task after the offset is listed
+        limit: Option<usize>,
+        /// Task events sent
+        returned: usize,
+    },
+    /// Progress of a download, about once a second while it runs and
+    /// once when it stops
+    Progress(ProgressRecord),
+    /// Something failed, `code` is the exit code of its kind
+    Error {
+        code: i32,
+        kind: &'static str,
+        message: String,
+    },
+    /// A setting in effect, by `config show` and `config set`
+    Setting {
+        key: String,
+        value: String,
+        /// default, config file or the environment variable it comes from
+        source: String,
+    },
+    /// A task and its files removed by `delete`
+    Deleted { task_id: u64, file_removed: bool },
+    /// What the server tells about a url, by info
+    Probe(ProbeRecord),
+    /// Tasks written by export
+    Exported {
+        path: String,
+        tasks: usize,
+        /// Archive of the temporary files, if asked for
+        temp_data: Option<String>,
+        temp_files: usize,
+    },
+    /// A task of the file loaded by import
+    Imported {
+        url: String,
+        /// Id of the new task, or of the one kept when skipped
+        task_id: u64,
+        /// imported, replaced or skipped
+        action: &'static str,
+    },
+    /// A hook that ran for a task, by hook-log
+    HookRun(HookRunRecord),
+    /// Temporary data no task needs, found by gc
+    Orphan {
+        path: String,
+        size: u64,
+        reason: String,
+        removed: bool,
+    },
+    /// Completed task whose files are gone, found by gc
+    DanglingRecord {
+        task_id: u64,
+        file_name: String,
+        path: String,
+        removed: bool,
+    },
+    /// End of the command
+    Result {
+        command: &'static str,
+        success: bool,
+        exit_code: i32,
+        schema_version: u32,
+    },
+}
+
+impl Event {
+    pub fn error(code: ExitCode, message: String) -> Self {
+        Event::Error {
+            code: code as i32,
+            kind: code.kind(),
+            message,
+        }
+    }
+
+    pub fn result(command: &'static str, result: Result<(), ExitCode>) -> Self {
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(code) => code as i32,
+        };
+        Event::Result {
+            command,
+            success: result.is_ok(),
+            exit_code,
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskRecord {
+    pub id: u64,
+    pub url: String,
+    pub file_name: String,
+    /// Where the file is, or will be once downloaded
+    pub path: String,
+    /// None when the server didn't tell it
+    pub file_size: Option<u64>,
+    pub content_type: String,
+    /// queued, probing, downloading, paused, verifying, completed, failed or cancelled
+    pub status: String,
+    pub percentage: f64,
+    /// Bytes received as of the last checkpoint
+    pub bytes_done: u64,
+    pub part_count: u32,
+    pub max_threads: u32,
+    pub attempt_count: u32,
+    pub last_error: Option<String>,
+    pub extracted_path: Option<String>,
+    pub queue_position: i64,
+    pub date_created: String,
+    /// RFC 3339
+    pub started_at: Option<String>,
+    /// RFC 3339
+    pub finished_at: Option<String>,
+}
+
+impl From<&DownloadTaskEntity> for TaskRecord {
+    fn from(task: &DownloadTaskEntity) -> Self {
+        let mut path = PathBuf::from(&task.final_file_path);
+        path.push(&task.file_name);
+        let time = |t| Some(encode_time(t)).filter(|t| !t.is_empty());
+        TaskRecord {
+            id: task.id,
+            url: task.file_url.to_owned(),
+            file_name: task.file_name.to_owned(),
+            path: path.to_string_lossy().to_string(),
+            file_size: Some(task.file_size).filter(|s| *s > 0),
+            content_type: task.content_type.to_owned(),
+            status: task.status.to_string(),
+            percentage: task.percentage_completed,
+            bytes_done: task.bytes_done,
+            part_count: task.part_count,
+            max_threads: task.max_threads,
+            attempt_count: task.attempt_count,
+            last_error: task.last_error.clone(),
+            extracted_path: task.extracted_path.clone(),
+            queue_position: task.queue_position,
+            date_created: task.date_created.to_owned(),
+            started_at: time(&task.started_at),
+            finished_at: time(&task.finished_at),
+        }
+    }
+}
+
+/// Fields a download can't tell are null
+#[derive(Debug, Serialize)]
+pub struct ProgressRecord {
+    pub task_id: u64,
+    /// None when the size is unknown
+    pub percentage: Option<f64>,
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+    /// Bytes per second over the last few seconds
+    pub speed: f64,
+    /// Bytes per second since the download started
+    pub average_speed: f64,
+    pub eta_seconds: Option<u64>,
+    pub elapsed_seconds: u64,
+    /// Segments of streams and pieces of metalink files are parts too
+    pub parts: Vec<PartRecord>,
+}
+
+impl ProgressRecord {
+    pub fn new(task_id: u64, progress: &Progress) -> Self {
+        ProgressRecord {
+            task_id,
+            percentage: progress.percentage(),
+            downloaded_bytes: progress.downloaded_bytes,
+            total_bytes: progress.total_bytes,
+            speed: progress.speed,
+            average_speed: progress.average_speed,
+            eta_seconds: progress.eta.map(|e| e.as_secs()),
+            elapsed_seconds: progress.elapsed.as_secs(),
+            parts: progress
+                .parts
+                .iter()
+                .map(|p| PartRecord {
+                    id: p.id,
+                    start_byte: p.start_byte,
+                    end_byte: p.end_byte,
+                    size: Some(p.size).filter(|s| *s > 0),
+                    bytes_done: p.bytes_done,
+                    state: p.state.to_string(),
+                    speed: p.speed,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Sends the progress of a download at most once a second, the last
+/// one held back is sent when dropped
+#[derive(Debug, Default)]
+pub struct ProgressEvents {
+    sent_at: Option<Instant>,
+    pending: Option<ProgressRecord>,
+}
+
+impl ProgressEvents {
+    pub fn new() -> Self {
+        ProgressEvents::default()
+    }
+
+    /// `force` sends it even if the previous one was sent less than a second ago
+    pub fn send(&mut self, record: ProgressRecord, force: bool) {
+        let due = match self.sent_at {
+            Some(at) => at.elapsed() >= Duration::from_secs(1),
+            None => true,
+        };
+        if force || due {
+            self.pending = None;
+            self.sent_at = Some(Instant::now());
+            emit(Event::Progress(record));
+        } else {
+            self.pending = Some(record);
+        }
+    }
+}
+
+impl Drop for ProgressEvents {
+    fn drop(&mut self) {
+        if let Some(record) = self.pending.take() {
+            emit(Event::Progress(record));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartRecord {
+    pub id: u32,
+    pub start_byte: u64,
+    pub end_byte: u64,
+    /// None when the size of the download is unknown
+    pub size: Option<u64>,
+    pub bytes_done: u64,
+    /// pending, downloading, completed or failed
+    pub state: String,
+    /// Bytes per second of the connection of the part
+    pub speed: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeRecord {
+    pub url: String,
+    pub final_url: String,
+    pub redirects: Vec<RedirectRecord>,
+    pub status: u16,
+    /// False when the server refused the HEAD request, the file was
+    /// probed with a GET of its first byte
+    pub head_supported: bool,
+    /// Name the file would be saved under
+    pub file_name: String,
+    /// None when the server didn't tell it
+    pub file_size: Option<u64>,
+    pub content_type: String,
+    /// file, hls, dash or metalink
+    pub kind: String,
+    /// Whether the download can be resumed and split
+    pub supports_ranges: bool,
+    pub accept_ranges: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub server: Option<String>,
+    /// Parts the file would be split into
+    pub part_count: u32,
+}
+
+impl ProbeRecord {
+    pub fn new(report: &ProbeReport, file_name: &str, kind: &str, part_count: u32) -> Self {
+        ProbeRecord {
+            url: report.url.to_owned(),
+            final_url: report.final_url.to_owned(),
+            redirects: report.redirects.iter().map(RedirectRecord::from).collect(),
+            status: report.status,
+            head_supported: report.head_supported,
+            file_name: file_name.to_owned(),
+            file_size: Some(report.meta.content_length).filter(|s| *s > 0),
+            content_type: report.meta.content_type.to_owned(),
+            kind: kind.to_owned(),
+            supports_ranges: report.meta.supports_resume,
+            accept_ranges: report.accept_ranges.clone(),
+            etag: report.etag.clone(),
+            last_modified: report.last_modified.clone(),
+            server: report.server.clone(),
+            part_count,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RedirectRecord {
+    pub status: u16,
+    /// Where it redirected to
+    pub url: String,
+}
+
+impl From<&Redirect> for RedirectRecord {
+    fn from(redirect: &Redirect) -> Self {
+        RedirectRecord {
+            status: redirect.status,
+            url: redirect.url.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HookRunRecord {
+    pub task_id: u64,
+    /// complete or failure
+    pub event: String,
+    pub command: String,
+    /// None when the command was killed
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub output: String,
+    pub date_created: String,
+}
+
+impl From<&HookRunEntity> for HookRunRecord {
+    fn from(run: &HookRunEntity) -> Self {
+        HookRunRecord {
+            task_id: run.task_id,
+            event: run.event.to_owned(),
+            command: run.command.to_owned(),
+            exit_code: run.exit_code,
+            timed_out: run.timed_out,
+            output: run.output.to_owned(),
+            date_created: run.date_created.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_schema() {
+        let event = Event::error(ExitCode::Http, String::from("404 Not Found"));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"error","code":6,"kind":"http","message":"404 Not Found"}"#
+        );
+
+        let event = Event::result("download", Err(ExitCode::Interrupted));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"result","command":"download","success":false,"exit_code":130,"schema_version":1}"#
+        );
+
+        let event = Event::Page {
+            total: 42,
+            offset: 20,
+            limit: Some(10),
+            returned: 10,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"page","total":42,"offset":20,"limit":10,"returned":10}"#
+        );
+
+        let progress = Progress {
+            downloaded_bytes: 50,
+            total_bytes: None,
+            speed: 10f64,
+            average_speed: 10f64,
+            elapsed: std::time::Duration::from_secs(5),
+            eta: None,
+            parts: Vec::new(),
+        };
+        let event = Event::Progress(ProgressRecord::new(3, &progress));
+        let value: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["type"], "progress");
+        assert_eq!(value["task_id"], 3);
+        assert_eq!(value["downloaded_bytes"], 50);
+        assert!(value["total_bytes"].is_null());
+        assert!(value["percentage"].is_null());
+    }
+}
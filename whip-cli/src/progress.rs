@@ -0,0 +1,174 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use whip_core::{checkpoint::PartState, progress::Progress, storage::format_size};
+
+use crate::output::{self, ProgressEvents, ProgressRecord};
+
+/// Most parts drawn with a bar each
+pub const MAX_PART_BARS: usize = 32;
+
+/// Bars of a download split into parts: one for the whole file and one per
+/// part under it. Downloads of a single part only get the first one, streams
+/// with more segments than [`MAX_PART_BARS`] only get bars for the ones in
+/// flight. In JSON output they're hidden and the progress is sent as events.
+pub struct PartBars {
+    task_id: u64,
+    events: ProgressEvents,
+    multi: MultiProgress,
+    total: ProgressBar,
+    parts: Vec<ProgressBar>,
+    /// Whether the total bar shows a known size
+    sized: Option<bool>,
+}
+
+impl PartBars {
+    /// Adds the bars of the task `task_id` to `multi`, shared by several
+    /// downloads, or to a view of their own
+    pub fn new(multi: Option<&MultiProgress>, task_id: u64) -> Self {
+        let multi = match multi {
+            Some(m) => m.clone(),
+            None => MultiProgress::new(),
+        };
+        let total = if output::is_json() {
+            ProgressBar::hidden()
+        } else {
+            multi.add(ProgressBar::new(0))
+        };
+        PartBars {
+            task_id,
+            events: ProgressEvents::new(),
+            multi,
+            total,
+            parts: Vec::new(),
+            sized: None,
+        }
+    }
+
+    /// Bar of the whole file, to print messages above
+    pub fn total_bar(&self) -> ProgressBar {
+        self.total.clone()
+    }
+
+    pub fn update(&mut self, progress: &Progress) {
+        if output::is_json() {
+            let record = ProgressRecord::new(self.task_id, progress);
+            self.events.send(record, progress.is_complete());
+            return;
+        }
+        if self.total.is_finished() {
+            return;
+        }
+        let sized = progress.total_bytes.is_some();
+        if self.sized != Some(sized) {
+            self.total.set_style(total_style(sized));
+            self.sized = Some(sized);
+        }
+        // Finishing moves the bar to its length
+        self.total
+            .set_length(progress.total_bytes.unwrap_or(progress.downloaded_bytes));
+        self.total.set_position(progress.downloaded_bytes);
+        let mut message = format!("{}/s", format_size(progress.speed as u64));
+        if !sized && progress.parts.len() > 1 {
+            let done = progress
+                .parts
+                .iter()
+                .filter(|p| p.state == PartState::Completed)
+                .count();
+            message.push_str(&format!(" ● {}/{} parts", done, progress.parts.len()));
+        }
+        if let Some(eta) = progress.eta {
+            message.push_str(&format!(" eta {}", format_eta(eta.as_secs())));
+        }
+        self.total.set_message(message);
+        if !sized {
+            self.total.tick();
+        }
+
+        if progress.parts.len() > 1 {
+            let shown: Vec<_> = if progress.parts.len() > MAX_PART_BARS {
+                progress
+                    .parts
+                    .iter()
+                    .filter(|p| p.state == PartState::Downloading)
+                    .collect()
+            } else {
+                progress.parts.iter().collect()
+            };
+            while self.parts.len() < shown.len() {
+                let after = self.parts.last().unwrap_or(&self.total).clone();
+                let bar = self.multi.insert_after(&after, ProgressBar::new(0));
+                bar.set_style(part_style());
+                self.parts.push(bar);
+            }
+            // Bars are reused by position as segments come and go
+            for bar in self.parts.drain(shown.len()..) {
+                bar.finish_and_clear();
+                self.multi.remove(&bar);
+            }
+            for (bar, part) in self.parts.iter().zip(shown) {
+                bar.set_prefix(part.id.to_string());
+                bar.set_length(part.size);
+                bar.set_position(part.bytes_done);
+                bar.set_message(match part.state {
+                    PartState::Downloading => format!("{}/s", format_size(part.speed as u64)),
+                    state => state.to_string(),
+                });
+            }
+        }
+
+        if progress.is_complete() {
+            self.clear_parts();
+            self.total.finish();
+        }
+    }
+
+    /// Removes the bars of the parts, the total one says it all
+    fn clear_parts(&mut self) {
+        for bar in self.parts.drain(..) {
+            bar.finish_and_clear();
+            self.multi.remove(&bar);
+        }
+    }
+
+    /// Callback for Downloader and StreamDownloader
+    pub fn callback(mut self) -> impl FnMut(&Progress) + Send + Sync + 'static {
+        move |p: &Progress| self.update(p)
+    }
+}
+
+impl Drop for PartBars {
+    /// An unfinished download leaves its total bar where it stopped
+    fn drop(&mut self) {
+        self.clear_parts();
+        if !self.total.is_finished() {
+            self.total.abandon();
+        }
+    }
+}
+
+fn total_style(sized: bool) -> ProgressStyle {
+    let template = if sized {
+        "[{elapsed_precise:.green}] |{bar:40.blue/cyan}| {percent}% ● {binary_bytes}/{binary_total_bytes} ● {msg:.green}"
+    } else {
+        "[{elapsed_precise:.green}] {spinner:.blue} {binary_bytes} ● {msg:.green}"
+    };
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("■▪▫")
+}
+
+fn part_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "  part {prefix:>2} |{bar:30.cyan/blue}| {binary_bytes:>10}/{binary_total_bytes:<10} {msg}",
+    )
+    .unwrap()
+    .progress_chars("■▪▫")
+}
+
+/// Time left, like 1h02m or 45s
+pub fn format_eta(seconds: u64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
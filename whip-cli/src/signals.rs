@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use whip_core::{
+    checkpoint::PartTracker,
+    control::DownloadControl,
+    storage::{format_size, TaskLock},
+};
+
+use crate::exit_code::ExitCode;
+
+struct ActiveDownload {
+    file_name: String,
+    /// Where `whip pause` leaves its requests
+    temp_dir: PathBuf,
+    control: DownloadControl,
+    /// Streams are downloaded by segment, they have no parts
+    parts: Option<PartTracker>,
+}
+
+#[derive(Default)]
+struct Registry {
+    downloads: BTreeMap<u64, ActiveDownload>,
+    /// Set by the first interrupt, downloads starting afterwards stop right away
+    stopping: bool,
+}
+
+/// Downloads running in this process, by task id. They're all stopped on
+/// SIGINT or SIGTERM so they save what they received before exiting, one
+/// of them when `whip pause` asks for it.
+#[derive(Clone, Default)]
+pub struct ActiveDownloads {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl ActiveDownloads {
+    pub fn new() -> Self {
+        ActiveDownloads::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        match self.inner.lock() {
+            Ok(r) => r,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    pub fn register(
+        &self,
+        task_id: u64,
+        file_name: &str,
+        temp_dir: &Path,
+        control: DownloadControl,
+        parts: Option<PartTracker>,
+    ) {
+        let mut registry = self.lock();
+        if registry.stopping {
+            control.stop();
+        }
+        registry.downloads.insert(
+            task_id,
+            ActiveDownload {
+                file_name: file_name.to_string(),
+                temp_dir: temp_dir.to_path_buf(),
+                control,
+                parts,
+            },
+        );
+    }
+
+    pub fn unregister(&self, task_id: u64) {
+        self.lock().downloads.remove(&task_id);
+    }
+
+    /// Whether the process was interrupted
+    pub fn is_stopping(&self) -> bool {
+        self.lock().stopping
+    }
+
+    fn stop_all(&self) {
+        let mut registry = self.lock();
+        registry.stopping = true;
+        for download in registry.downloads.values() {
+            download.control.stop();
+        }
+    }
+
+    /// Stops the downloads whose pause was requested
+    fn stop_requested(&self) {
+        let registry = self.lock();
+        for (id, download) in registry.downloads.iter() {
+            if !download.control.is_stopped() && TaskLock::pause_requested(&download.temp_dir, *id)
+            {
+                download.control.stop();
+            }
+        }
+    }
+
+    /// What each download is doing, part by part
+    fn status(&self) -> String {
+        let registry = self.lock();
+        if registry.downloads.is_empty() {
+            return String::from("No active downloads");
+        }
+        let mut status = String::new();
+        for (id, download) in registry.downloads.iter() {
+            let state = if download.control.is_stopped() {
+                "stopping"
+            } else if download.control.is_held() {
+                "held"
+            } else {
+                "running"
+            };
+            status.push_str(&format!("[{}] {} : {}", id, download.file_name, state));
+            let limit = download.control.rate_limit();
+            if limit > 0 {
+                status.push_str(&format!(", limited to {}/s", format_size(limit)));
+            }
+            status.push('\n');
+            if let Some(parts) = &download.parts {
+                for part in parts.snapshot() {
+                    status.push_str(&format!(
+                        "  part {} : bytes {}-{}, {} received, {}\n",
+                        part.id,
+                        part.start_byte,
+                        part.end_byte,
+                        format_size(part.bytes_done),
+                        part.state
+                    ));
+                }
+            }
+        }
+        status
+    }
+}
+
+/// Handles the signals of the process in the background: the first SIGINT
+/// or SIGTERM stops the downloads so they pause cleanly, a second one quits
+/// right away. SIGUSR1 prints the state of the downloads. Pause requests
+/// are checked every half second.
+pub fn listen(active: ActiveDownloads) {
+    let downloads = active.clone();
+    let requested = active.clone();
+    tokio::spawn(async move {
+        let mut check = tokio::time::interval(Duration::from_millis(500));
+        loop {
+            check.tick().await;
+            requested.stop_requested();
+        }
+    });
+    tokio::spawn(async move {
+        let mut interrupted = false;
+        loop {
+            if !wait_interrupt().await {
+                return;
+            }
+            if interrupted {
+                eprintln!("\nQuitting, the progress since the last checkpoint is lost");
+                process::exit(ExitCode::Interrupted as i32);
+            }
+            interrupted = true;
+            eprintln!("\nPausing downloads, press Ctrl-C again to quit right away");
+            downloads.stop_all();
+        }
+    });
+
+    #[cfg(target_family = "unix")]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut status_requests = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Can't listen for SIGUSR1 : {}", e);
+                return;
+            }
+        };
+        while status_requests.recv().await.is_some() {
+            eprintln!("\n{}", active.status());
+        }
+    });
+    #[cfg(not(target_family = "unix"))]
+    drop(active);
+}
+
+/// Waits for SIGINT or SIGTERM, false if they can't be listened for
+#[cfg(target_family = "unix")]
+pub async fn wait_interrupt() -> bool {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Can't listen for SIGTERM : {}", e);
+            return tokio::signal::ctrl_c().await.is_ok();
+        }
+    };
+    tokio::select! {
+        interrupt = tokio::signal::ctrl_c() => interrupt.is_ok(),
+        _ = terminate.recv() => true,
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+pub async fn wait_interrupt() -> bool {
+    tokio::signal::ctrl_c().await.is_ok()
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::Local;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use whip_core::checkpoint::{PartCheckpoint, PartState};
+use whip_persistance::models::{
+    decode_checksum, decode_headers, decode_schedule, decode_time, encode_checksum,
+    encode_schedule, encode_time, DownloadStatus, DownloadTaskEntity,
+};
+
+/// Version of export files, raised when a field goes away or changes meaning
+pub const EXPORT_VERSION: u32 = 1;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    /// One task per row, headers and parts packed in a column each
+    Csv,
+}
+
+impl ExportFormat {
+    /// CSV for files ending in .csv, JSON for the others
+    pub fn of(path: &Path) -> Self {
+        match path.extension() {
+            Some(e) if e.eq_ignore_ascii_case("csv") => ExportFormat::Csv,
+            _ => ExportFormat::Json,
+        }
+    }
+}
+
+/// What import does with a task whose url is already one
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the task already there
+    Skip,
+    /// Delete the task already there along with its temporary data
+    Replace,
+    /// Import it as another task
+    KeepBoth,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportFile {
+    version: u32,
+    exported_at: String,
+    tasks: Vec<ExportedTask>,
+}
+
+/// A task as it's written to export files
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportedTask {
+    /// Id in the database it was exported from, the bundle of temporary
+    /// data files its parts under it
+    pub id: u64,
+    pub url: String,
+    pub file_name: String,
+    /// 0 when unknown
+    pub file_size: u64,
+    pub content_type: String,
+    pub supports_resume: bool,
+    /// Directory of the downloaded file
+    pub destination: String,
+    pub max_threads: u32,
+    pub part_count: u32,
+    pub percentage: f64,
+    pub bytes_done: u64,
+    pub status: String,
+    pub date_created: String,
+    /// "Name: value" each
+    pub headers: Vec<String>,
+    /// algorithm:digest
+    pub checksum: Option<String>,
+    pub on_complete_hook: Option<String>,
+    pub on_failure_hook: Option<String>,
+    /// RFC 3339
+    pub start_after: Option<String>,
+    pub schedule: Option<String>,
+    pub extracted_path: Option<String>,
+    pub extraction_result: Option<String>,
+    pub last_error: Option<String>,
+    pub attempt_count: u32,
+    /// RFC 3339
+    pub started_at: Option<String>,
+    /// RFC 3339
+    pub finished_at: Option<String>,
+    pub queue_position: i64,
+    /// Metalink file the task was listed in
+    pub source: Option<String>,
+    pub parts: Vec<ExportedPart>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportedPart {
+    pub id: u32,
+    pub start_byte: u64,
+    pub end_byte: u64,
+    pub bytes_done: u64,
+    pub state: String,
+}
+
+/// A row of CSV exports, they can't nest so parts are written as
+/// id:start-end:bytes_done:state separated by spaces
+#[derive(Serialize, Deserialize)]
+struct CsvTask {
+    id: u64,
+    url: String,
+    file_name: String,
+    file_size: u64,
+    content_type: String,
+    supports_resume: bool,
+    destination: String,
+    max_threads: u32,
+    part_count: u32,
+    percentage: f64,
+    bytes_done: u64,
+    status: String,
+    date_created: String,
+    /// One per line
+    headers: String,
+    checksum: Option<String>,
+    on_complete_hook: Option<String>,
+    on_failure_hook: Option<String>,
+    start_after: Option<String>,
+    schedule: Option<String>,
+    extracted_path: Option<String>,
+    extraction_result: Option<String>,
+    last_error: Option<String>,
+    attempt_count: u32,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    queue_position: i64,
+    source: Option<String>,
+    parts: String,
+}
+
+fn optional(value: String) -> Option<String> {
+    Some(value).filter(|v| !v.is_empty())
+}
+
+impl ExportedTask {
+    pub fn new(task: &DownloadTaskEntity, parts: &[PartCheckpoint]) -> Self {
+        ExportedTask {
+            id: task.id,
+            url: task.file_url.to_owned(),
+            file_name: task.file_name.to_owned(),
+            file_size: task.file_size,
+            content_type: task.content_type.to_owned(),
+            supports_resume: task.supports_resume,
+            destination: task.final_file_path.to_owned(),
+            max_threads: task.max_threads,
+            part_count: task.part_count,
+            percentage: task.percentage_completed,
+            bytes_done: task.bytes_done,
+            status: task.status.to_string(),
+            date_created: task.date_created.to_owned(),
+            headers: task
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect(),
+            checksum: optional(encode_checksum(&task.checksum)),
+            on_complete_hook: task.on_complete_hook.clone(),
+            on_failure_hook: task.on_failure_hook.clone(),
+            start_after: optional(encode_time(&task.start_after)),
+            schedule: optional(encode_schedule(&task.schedule)),
+            extracted_path: task.extracted_path.clone(),
+            extraction_result: task.extraction_result.clone(),
+            last_error: task.last_error.clone(),
+            attempt_count: task.attempt_count,
+            started_at: optional(encode_time(&task.started_at)),
+            finished_at: optional(encode_time(&task.finished_at)),
+            queue_position: task.queue_position,
+            source: task.source.clone(),
+            parts: parts
+                .iter()
+                .map(|p| ExportedPart {
+                    id: p.id,
+                    start_byte: p.start_byte,
+                    end_byte: p.end_byte,
+                    bytes_done: p.bytes_done,
+                    state: p.state.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether the download was left before the end
+    pub fn is_unfinished(&self) -> bool {
+        !matches!(
+            DownloadStatus::parse(&self.status),
+            Some(DownloadStatus::Completed | DownloadStatus::Cancelled)
+        )
+    }
+
+    /// The task and its parts to store. Downloads that were running are
+    /// paused, nothing runs them once imported.
+    pub fn into_task(self) -> Result<(DownloadTaskEntity, Vec<PartCheckpoint>), String> {
+        let mut status = match DownloadStatus::parse(&self.status) {
+            Some(s) => s,
+            None => return Err(format!("{} : unknown status {}", self.url, self.status)),
+        };
+        if matches!(
+            status,
+            DownloadStatus::Probing | DownloadStatus::Downloading | DownloadStatus::Verifying
+        ) {
+            status = DownloadStatus::Paused;
+        }
+        let mut parts = Vec::new();
+        for part in self.parts.iter() {
+            let state = match PartState::parse(&part.state) {
+                // A part being downloaded stopped where its bytes end
+                Some(PartState::Downloading) => PartState::Pending,
+                Some(s) => s,
+                None => return Err(format!("{} : unknown part state {}", self.url, part.state)),
+            };
+            parts.push(PartCheckpoint {
+                id: part.id,
+                start_byte: part.start_byte,
+                end_byte: part.end_byte,
+                bytes_done: part.bytes_done,
+                state,
+            });
+        }
+        let time = |t: &Option<String>| decode_time(t.as_deref().unwrap_or(""));
+
+        let task = DownloadTaskEntity {
+            id: self.id,
+            file_name: self.file_name,
+            file_size: self.file_size,
+            file_url: self.url,
+            supports_resume: self.supports_resume,
+            temp_files_path: String::new(),
+            final_file_path: self.destination,
+            max_threads: self.max_threads,
+            part_count: self.part_count,
+            percentage_completed: self.percentage,
+            date_created: self.date_created,
+            content_type: self.content_type,
+            headers: decode_headers(&self.headers.join("\n")),
+            checksum: decode_checksum(self.checksum.as_deref().unwrap_or("")),
+            on_complete_hook: self.on_complete_hook,
+            on_failure_hook: self.on_failure_hook,
+            extracted_path: self.extracted_path,
+            extraction_result: self.extraction_result,
+            start_after: time(&self.start_after),
+            schedule: decode_schedule(self.schedule.as_deref().unwrap_or("")),
+            status,
+            last_error: self.last_error,
+            attempt_count: self.attempt_count,
+            started_at: time(&self.started_at),
+            finished_at: time(&self.finished_at),
+            bytes_done: self.bytes_done,
+            speed: 0f64,
+            checkpoint_at: None,
+            queue_position: self.queue_position,
+            source: self.source,
+        };
+        Ok((task, parts))
+    }
+}
+
+impl From<ExportedTask> for CsvTask {
+    fn from(task: ExportedTask) -> Self {
+        CsvTask {
+            id: task.id,
+            url: task.url,
+            file_name: task.file_name,
+            file_size: task.file_size,
+            content_type: task.content_type,
+            supports_resume: task.supports_resume,
+            destination: task.destination,
+            max_threads: task.max_threads,
+            part_count: task.part_count,
+            percentage: task.percentage,
+            bytes_done: task.bytes_done,
+            status: task.status,
+            date_created: task.date_created,
+            headers: task.headers.join("\n"),
+            checksum: task.checksum,
+            on_complete_hook: task.on_complete_hook,
+            on_failure_hook: task.on_failure_hook,
+            start_after: task.start_after,
+            schedule: task.schedule,
+            extracted_path: task.extracted_path,
+            extraction_result: task.extraction_result,
+            last_error: task.last_error,
+            attempt_count: task.attempt_count,
+            started_at: task.started_at,
+            finished_at: task.finished_at,
+            queue_position: task.queue_position,
+            source: task.source,
+            parts: task
+                .parts
+                .iter()
+                .map(|p| {
+                    format!(
+                        "{}:{}-{}:{}:{}",
+                        p.id, p.start_byte, p.end_byte, p.bytes_done, p.state
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+}
+
+impl TryFrom<CsvTask> for ExportedTask {
+    type Error = String;
+
+    fn try_from(task: CsvTask) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        for part in task.parts.split_whitespace() {
+            let invalid = || format!("{} : invalid part {}", task.url, part);
+            let fields = part.split(':').collect::<Vec<&str>>();
+            let (start_byte, end_byte) = match fields.get(1).and_then(|r| r.split_once('-')) {
+                Some(range) => range,
+                None => return Err(invalid()),
+            };
+            let number = |n: &str| n.parse::<u64>().map_err(|_| invalid());
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+            parts.push(ExportedPart {
+                id: fields[0].parse().map_err(|_| invalid())?,
+                start_byte: number(start_byte)?,
+                end_byte: number(end_byte)?,
+                bytes_done: number(fields[2])?,
+                state: fields[3].to_string(),
+            });
+        }
+        Ok(ExportedTask {
+            id: task.id,
+            url: task.url,
+            file_name: task.file_name,
+            file_size: task.file_size,
+            content_type: task.content_type,
+            supports_resume: task.supports_resume,
+            destination: task.destination,
+            max_threads: task.max_threads,
+            part_count: task.part_count,
+            percentage: task.percentage,
+            bytes_done: task.bytes_done,
+            status: task.status,
+            date_created: task.date_created,
+            headers: task.headers.lines().map(|h| h.to_string()).collect(),
+            checksum: task.checksum,
+            on_complete_hook: task.on_complete_hook,
+            on_failure_hook: task.on_failure_hook,
+            start_after: task.start_after,
+            schedule: task.schedule,
+            extracted_path: task.extracted_path,
+            extraction_result: task.extraction_result,
+            last_error: task.last_error,
+            attempt_count: task.attempt_count,
+            started_at: task.started_at,
+            finished_at: task.finished_at,
+            queue_position: task.queue_position,
+            source: task.source,
+            parts,
+        })
+    }
+}
+
+/// Writes `tasks` in `format`
+pub fn write(tasks: Vec<ExportedTask>, format: ExportFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ExportFormat::Json => {
+            let file = ExportFile {
+                version: EXPORT_VERSION,
+                exported_at: Local::now().to_rfc3339(),
+                tasks,
+            };
+            serde_json::to_vec_pretty(&file).map_err(|e| e.to_string())
+        }
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for task in tasks.into_iter() {
+                if let Err(e) = writer.serialize(CsvTask::from(task)) {
+                    return Err(e.to_string());
+                }
+            }
+            writer.into_inner().map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Reads the tasks of an export written in `format`
+pub fn read(content: &[u8], format: ExportFormat) -> Result<Vec<ExportedTask>, String> {
+    match format {
+        ExportFormat::Json => {
+            let file: ExportFile = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+            if file.version > EXPORT_VERSION {
+                return Err(format!(
+                    "Exported by a newer whip (version {}), this one reads up to version {}",
+                    file.version, EXPORT_VERSION
+                ));
+            }
+            Ok(file.tasks)
+        }
+        ExportFormat::Csv => {
+            let mut tasks = Vec::new();
+            for row in csv::Reader::from_reader(content).deserialize::<CsvTask>() {
+                let row = row.map_err(|e| e.to_string())?;
+                tasks.push(ExportedTask::try_from(row)?);
+            }
+            Ok(tasks)
+        }
+    }
+}
+
+/// Packs the temporary files of tasks, by task id, into a tar.gz archive
+/// where they're under a directory named after their task
+pub fn write_bundle(archive: &Path, files: &BTreeMap<u64, Vec<PathBuf>>) -> io::Result<usize> {
+    let encoder = GzEncoder::new(File::create(archive)?, Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut count = 0;
+    for (id, paths) in files.iter() {
+        for path in paths.iter() {
+            let name = match path.file_name() {
+                Some(n) => n,
+                None => continue,
+            };
+            let mut entry = PathBuf::from(id.to_string());
+            entry.push(name);
+            builder.append_path_with_name(path, &entry)?;
+            count += 1;
+        }
+    }
+    builder.into_inner()?.finish()?;
+    Ok(count)
+}
+
+/// Unpacks the files of a bundle into the temporary directories of the
+/// tasks they were exported with, by exported id. Returns the ids that had files.
+/// Only regular files are unpacked, links and other entries are skipped.
+pub fn read_bundle(archive: &Path, dirs: &BTreeMap<u64, PathBuf>) -> io::Result<BTreeSet<u64>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(archive)?));
+    let mut restored = BTreeSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // A link named like a part would have resume write through it
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_path_buf();
+        // Only task-id/file-name, nothing may land outside the directories
+        let components = path.components().collect::<Vec<Component>>();
+        let (id, name) = match components.as_slice() {
+            [Component::Normal(id), Component::Normal(name)] => (id.to_owned(), name.to_owned()),
+            _ => continue,
+        };
+        let id = match id.to_str().and_then(|i| i.parse::<u64>().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let dir = match dirs.get(&id) {
+            Some(d) => d,
+            None => continue,
+        };
+        fs::create_dir_all(dir)?;
+        entry.unpack(dir.join(name))?;
+        restored.insert(id);
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exported() -> ExportedTask {
+        ExportedTask {
+            id: 4,
+            url: String::from("https://hello.com/disk.img"),
+            file_name: String::from("disk.img"),
+            file_size: 3000000,
+            content_type: String::from("application/octet-stream"),
+            supports_resume: true,
+            destination: String::from("/downloads"),
+            max_threads: 3,
+            part_count: 3,
+            percentage: 50f64,
+            bytes_done: 1500000,
+            status: String::from("downloading"),
+            date_created: String::from("2026-10-18"),
+            headers: vec![String::from("Cookie: a=1; b=2")],
+            checksum: None,
+            on_complete_hook: Some(String::from("notify-send done")),
+            on_failure_hook: None,
+            start_after: None,
+            schedule: Some(String::from("22:00-06:00")),
+            extracted_path: None,
+            extraction_result: None,
+            last_error: None,
+            attempt_count: 1,
+            started_at: Some(String::from("2026-10-18T20:00:00+00:00")),
+            finished_at: None,
+            queue_position: 2,
+            source: None,
+            parts: vec![
+                ExportedPart {
+                    id: 0,
+                    start_byte: 0,
+                    end_byte: 1499999,
+                    bytes_done: 1500000,
+                    state: String::from("completed"),
+                },
+                ExportedPart {
+                    id: 1,
+                    start_byte: 1500000,
+                    end_byte: 3000000,
+                    bytes_done: 0,
+                    state: String::from("downloading"),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [ExportFormat::Json, ExportFormat::Csv] {
+            let content = write(vec![exported()], format).unwrap();
+            assert_eq!(read(&content, format).unwrap(), vec![exported()]);
+        }
+    }
+
+    #[test]
+    fn test_into_task() {
+        let (task, parts) = exported().into_task().unwrap();
+        assert_eq!(task.status, DownloadStatus::Paused);
+        assert_eq!(
+            task.headers,
+            vec![(String::from("Cookie"), String::from("a=1; b=2"))]
+        );
+        assert!(task.schedule.is_some());
+        assert!(task.started_at.is_some());
+        assert_eq!(parts[1].state, PartState::Pending);
+        assert_eq!(parts[0].bytes_done, 1500000);
+
+        let mut invalid = exported();
+        invalid.status = String::from("lost");
+        assert!(invalid.into_task().is_err());
+    }
+
+    #[test]
+    fn test_bundle_links_skipped() {
+        let dir = std::env::temp_dir().join(format!("whip-bundle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("bundle.tar.gz");
+        let encoder = GzEncoder::new(File::create(&archive).unwrap(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        builder
+            .append_link(&mut link, "4/disk.img.0", dir.join("target"))
+            .unwrap();
+        let mut hard = tar::Header::new_gnu();
+        hard.set_entry_type(tar::EntryType::Link);
+        hard.set_size(0);
+        builder
+            .append_link(&mut hard, "4/disk.img.1", "4/disk.img.2")
+            .unwrap();
+        let mut file = tar::Header::new_gnu();
+        file.set_size(5);
+        file.set_mode(0o644);
+        builder
+            .append_data(&mut file, "4/disk.img.2", "hello".as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let temp = dir.join("4");
+        let dirs = BTreeMap::from([(4, temp.clone())]);
+        assert_eq!(read_bundle(&archive, &dirs).unwrap(), BTreeSet::from([4]));
+        assert!(fs::symlink_metadata(temp.join("disk.img.0")).is_err());
+        assert!(fs::symlink_metadata(temp.join("disk.img.1")).is_err());
+        assert_eq!(fs::read(temp.join("disk.img.2")).unwrap(), b"hello");
+        assert!(!dir.join("target").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,798 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Stdout},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::StreamExt;
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Cell, Clear, Paragraph, Row, Table, TableState, Wrap},
+    Frame, Terminal,
+};
+use tokio::process::{Child, Command};
+use whip_core::{
+    checkpoint::{PartCheckpoint, PartState},
+    storage::{format_size, TaskLock},
+};
+use whip_persistance::{
+    models::{DownloadFilter, DownloadStatus, DownloadTaskEntity, DownloadTaskRepository},
+    repository::Repository,
+};
+
+use crate::{exit_code::ExitCode, progress::format_eta, signals};
+
+/// Settings of the dashboard
+pub struct TuiOptions {
+    /// Configuration file of the downloads the dashboard starts
+    pub config_path: PathBuf,
+    /// How many downloads the dashboard runs at the same time
+    pub max_concurrent: usize,
+}
+
+/// Download waiting for a free slot of the dashboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Job {
+    Url(String),
+    Task(u64),
+}
+
+enum Mode {
+    Normal,
+    /// Typing the url to add
+    Adding(String),
+    ConfirmDelete(u64),
+    Details,
+}
+
+/// whip processes started by the dashboard, by the url they download.
+/// Every download runs in its own process like `whip download` would, so
+/// they're paused the same way and other processes see them locked.
+struct Workers {
+    exe: PathBuf,
+    config_path: PathBuf,
+    children: BTreeMap<String, Child>,
+}
+
+impl Workers {
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.exe);
+        command
+            .arg("--config")
+            .arg(&self.config_path)
+            .stdin(Stdio::null());
+        command
+    }
+
+    /// Starts downloading `job`, stored tasks are resumed with the options
+    /// they were created with
+    fn spawn(&mut self, job: &Job, url: &str) -> io::Result<()> {
+        let mut command = self.command();
+        match job {
+            Job::Url(url) => command.args(["download", url]),
+            Job::Task(id) => command.args(["resume", &id.to_string()]),
+        };
+        let child = command
+            .args(["--checkpoint-interval", "1"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        self.children.insert(url.to_string(), child);
+        Ok(())
+    }
+
+    fn is_running(&self, url: &str) -> bool {
+        self.children.contains_key(url)
+    }
+
+    /// Forgets the workers that ended, returns them with their exit code
+    fn reap(&mut self) -> Vec<(String, Option<i32>)> {
+        let mut ended = Vec::new();
+        self.children.retain(|url, child| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                ended.push((url.to_owned(), status.code()));
+                false
+            }
+            Err(_) => {
+                ended.push((url.to_owned(), None));
+                false
+            }
+        });
+        ended
+    }
+
+    /// Asks the worker downloading `url` to pause `task`, false if there's
+    /// none. A worker that doesn't hold its task yet has nothing to save,
+    /// it's stopped.
+    fn pause(&mut self, url: &str, task: Option<&DownloadTaskEntity>) -> bool {
+        let child = match self.children.get_mut(url) {
+            Some(c) => c,
+            None => return false,
+        };
+        match task {
+            Some(t) if TaskLock::holder(Path::new(&t.temp_files_path), t.id).is_some() => {
+                TaskLock::request_pause(Path::new(&t.temp_files_path), t.id).is_ok()
+                    || child.start_kill().is_ok()
+            }
+            _ => child.start_kill().is_ok(),
+        }
+    }
+
+    /// Pauses every worker and waits for them to save their progress
+    async fn pause_all(&mut self, tasks: &[DownloadTaskEntity]) {
+        let urls = self.children.keys().cloned().collect::<Vec<_>>();
+        for url in urls.iter() {
+            self.pause(url, tasks.iter().find(|t| t.file_url == *url));
+        }
+        for (url, mut child) in std::mem::take(&mut self.children) {
+            if tokio::time::timeout(Duration::from_secs(15), child.wait())
+                .await
+                .is_err()
+            {
+                eprintln!("{} didn't pause in time, stopping it", url);
+                let _ = child.kill().await;
+            }
+        }
+    }
+}
+
+struct App {
+    pool: Repository,
+    /// Every task, in queue order
+    tasks: Vec<DownloadTaskEntity>,
+    table: TableState,
+    /// Parts of the selected task as of its last checkpoint
+    parts: Vec<PartCheckpoint>,
+    /// Process downloading the selected task
+    holder: Option<u32>,
+    mode: Mode,
+    message: String,
+    waiting: Vec<Job>,
+    /// Tasks deleted once their worker has paused, with whether their file goes too
+    deleting: BTreeMap<u64, bool>,
+    workers: Workers,
+    max_concurrent: usize,
+    quit: bool,
+}
+
+impl App {
+    fn selected(&self) -> Option<&DownloadTaskEntity> {
+        self.table.selected().and_then(|i| self.tasks.get(i))
+    }
+
+    fn task(&self, id: u64) -> Option<&DownloadTaskEntity> {
+        self.tasks.iter().find(|t| t.id == id)
+    }
+
+    fn name(&self, url: &str) -> String {
+        match self.tasks.iter().find(|t| t.file_url == url) {
+            Some(t) => t.file_name.to_owned(),
+            None => url.to_string(),
+        }
+    }
+
+    /// Reloads the tasks, collects the workers that ended and starts the
+    /// waiting downloads there's room for
+    async fn refresh(&mut self) {
+        let selected_id = self.selected().map(|t| t.id);
+        match self.pool.get_tasks(DownloadFilter::All).await {
+            Ok(mut tasks) => {
+                tasks.sort_by_key(|t| (t.queue_position, t.id));
+                self.tasks = tasks;
+            }
+            Err(e) => self.message = e.to_string(),
+        }
+        let index = selected_id
+            .and_then(|id| self.tasks.iter().position(|t| t.id == id))
+            .or_else(|| self.table.selected())
+            .map(|i| i.min(self.tasks.len().saturating_sub(1)));
+        self.table.select(if self.tasks.is_empty() {
+            None
+        } else {
+            index.or(Some(0))
+        });
+
+        let (parts, holder) = match self.selected() {
+            Some(t) => (
+                self.pool.get_parts(t.id as i64).await.unwrap_or_default(),
+                TaskLock::holder(Path::new(&t.temp_files_path), t.id),
+            ),
+            None => (Vec::new(), None),
+        };
+        self.parts = parts;
+        self.holder = holder;
+
+        for (url, code) in self.workers.reap() {
+            let outcome = match code {
+                Some(0) => "done",
+                Some(c) if c == ExitCode::Interrupted as i32 => "paused",
+                Some(c) if c == ExitCode::Locked as i32 => "downloaded by another process",
+                Some(_) => "failed, its details tell why",
+                None => "stopped",
+            };
+            self.message = format!("{} : {}", self.name(&url), outcome);
+            let deleted = self
+                .tasks
+                .iter()
+                .find(|t| t.file_url == url && self.deleting.contains_key(&t.id))
+                .map(|t| t.id);
+            if let Some(id) = deleted {
+                let remove_file = self.deleting.remove(&id).unwrap_or(false);
+                self.delete(id, remove_file).await;
+            }
+        }
+
+        self.start_waiting();
+    }
+
+    fn start_waiting(&mut self) {
+        // Tasks go by their place in the queue, new urls in the order they were added
+        let tasks = &self.tasks;
+        self.waiting.sort_by_key(|job| match job {
+            Job::Task(id) => tasks
+                .iter()
+                .find(|t| t.id == *id)
+                .map(|t| t.queue_position)
+                .unwrap_or(i64::MAX),
+            Job::Url(_) => i64::MAX,
+        });
+        while self.workers.children.len() < self.max_concurrent && !self.waiting.is_empty() {
+            let job = self.waiting.remove(0);
+            let url = match &job {
+                Job::Url(url) => url.to_owned(),
+                Job::Task(id) => match self.task(*id) {
+                    Some(t) => t.file_url.to_owned(),
+                    None => continue,
+                },
+            };
+            if let Err(e) = self.workers.spawn(&job, &url) {
+                self.message = format!("Can't start {} : {}", url, e);
+            }
+        }
+    }
+
+    async fn set_status(&mut self, id: u64, status: DownloadStatus) -> bool {
+        let mut task = match self.pool.get_task_by_id(id as i64).await {
+            Ok(Some(t)) => t,
+            Ok(None) => return false,
+            Err(e) => {
+                self.message = e.to_string();
+                return false;
+            }
+        };
+        if let Err(e) = task.set_status(status) {
+            self.message = e.to_string();
+            return false;
+        }
+        match self.pool.update_task(task).await {
+            Ok(_) => true,
+            Err(e) => {
+                self.message = e.to_string();
+                false
+            }
+        }
+    }
+
+    /// Pauses the selected task wherever it runs, or queues it
+    async fn toggle_pause(&mut self) {
+        let task = match self.selected() {
+            Some(t) => t.clone(),
+            None => return,
+        };
+        if self.workers.pause(&task.file_url, Some(&task)) {
+            self.message = format!("Pausing {}", task.file_name);
+        } else if self.waiting.contains(&Job::Task(task.id)) {
+            self.waiting.retain(|j| *j != Job::Task(task.id));
+            self.set_status(task.id, DownloadStatus::Paused).await;
+            self.message = format!("{} left the queue", task.file_name);
+        } else if let Some(pid) = self.holder {
+            self.message = match TaskLock::request_pause(Path::new(&task.temp_files_path), task.id)
+            {
+                Ok(()) => format!("Asked process {} to pause {}", pid, task.file_name),
+                Err(e) => format!("Can't pause {} : {}", task.file_name, e),
+            };
+        } else if task.status == DownloadStatus::Completed {
+            self.message = format!("{} is already downloaded", task.file_name);
+        } else if self.set_status(task.id, DownloadStatus::Queued).await {
+            self.waiting.push(Job::Task(task.id));
+            self.message = format!("{} queued", task.file_name);
+        }
+    }
+
+    /// Starts the selected task right away, whatever the queue
+    fn start_selected(&mut self) {
+        let task = match self.selected() {
+            Some(t) => t.clone(),
+            None => return,
+        };
+        if task.status == DownloadStatus::Completed {
+            self.message = format!("{} is already downloaded", task.file_name);
+            return;
+        }
+        if self.workers.is_running(&task.file_url) {
+            return;
+        }
+        if let Some(pid) = self.holder {
+            self.message = format!("{} is downloaded by process {}", task.file_name, pid);
+            return;
+        }
+        self.waiting.retain(|j| *j != Job::Task(task.id));
+        self.message = match self.workers.spawn(&Job::Task(task.id), &task.file_url) {
+            Ok(()) => format!("Starting {}", task.file_name),
+            Err(e) => format!("Can't start {} : {}", task.file_name, e),
+        };
+    }
+
+    async fn confirm_delete(&mut self, id: u64, remove_file: bool) {
+        let task = match self.task(id) {
+            Some(t) => t.clone(),
+            None => return,
+        };
+        self.waiting.retain(|j| *j != Job::Task(id));
+        if self.workers.pause(&task.file_url, Some(&task)) {
+            // Deleted once its worker has saved its progress and let go of it
+            self.deleting.insert(id, remove_file);
+            self.message = format!("Pausing {} to delete it", task.file_name);
+        } else if let Some(pid) = self.holder {
+            self.message = format!(
+                "{} is downloaded by process {}, pause it first",
+                task.file_name, pid
+            );
+        } else {
+            self.delete(id, remove_file).await;
+        }
+    }
+
+    /// Deletes a task like `whip delete` does
+    async fn delete(&mut self, id: u64, remove_file: bool) {
+        let mut command = self.workers.command();
+        command.args(["delete", &id.to_string()]);
+        if remove_file {
+            command.arg("remove-file");
+        }
+        self.message = match command.output().await {
+            Ok(output) if output.status.success() => format!("Deleted task {}", id),
+            Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            Err(e) => format!("Can't delete task {} : {}", id, e),
+        };
+    }
+
+    /// Moves the selected task `offset` places in the queue
+    async fn move_selected(&mut self, offset: isize) {
+        let from = match self.table.selected() {
+            Some(i) => i,
+            None => return,
+        };
+        let to = from as isize + offset;
+        if to < 0 || to as usize >= self.tasks.len() {
+            return;
+        }
+        self.tasks.swap(from, to as usize);
+        self.table.select(Some(to as usize));
+        // Renumbering also orders the tasks created before the queue existed
+        for (i, task) in self.tasks.iter_mut().enumerate() {
+            let position = i as i64 + 1;
+            if task.queue_position != position {
+                if let Err(e) = self.pool.set_queue_position(task.id as i64, position).await {
+                    self.message = e.to_string();
+                    return;
+                }
+                task.queue_position = position;
+            }
+        }
+    }
+
+    fn select(&mut self, offset: isize) {
+        if self.tasks.is_empty() {
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let next = (current + offset).clamp(0, self.tasks.len() as isize - 1);
+        self.table.select(Some(next as usize));
+    }
+
+    async fn on_key(&mut self, key: KeyEvent) {
+        let mode = std::mem::replace(&mut self.mode, Mode::Normal);
+        match mode {
+            Mode::Adding(mut url) => match key.code {
+                KeyCode::Enter => {
+                    let url = url.trim().to_string();
+                    if !url.is_empty() {
+                        self.message = format!("Queued {}", url);
+                        self.waiting.push(Job::Url(url));
+                        self.start_waiting();
+                    }
+                }
+                KeyCode::Esc => {}
+                KeyCode::Backspace => {
+                    url.pop();
+                    self.mode = Mode::Adding(url);
+                }
+                KeyCode::Char(c) => {
+                    url.push(c);
+                    self.mode = Mode::Adding(url);
+                }
+                _ => self.mode = Mode::Adding(url),
+            },
+            Mode::ConfirmDelete(id) => match key.code {
+                KeyCode::Char('y') => self.confirm_delete(id, false).await,
+                KeyCode::Char('f') => self.confirm_delete(id, true).await,
+                _ => self.message.clear(),
+            },
+            // Any key closes the details
+            Mode::Details => {}
+            Mode::Normal => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.quit = true
+                }
+                KeyCode::Up | KeyCode::Char('k') => self.select(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.select(1),
+                KeyCode::Char('K') => self.move_selected(-1).await,
+                KeyCode::Char('J') => self.move_selected(1).await,
+                KeyCode::Char('a') => self.mode = Mode::Adding(String::new()),
+                KeyCode::Char('s') => self.start_selected(),
+                KeyCode::Char('p') | KeyCode::Char(' ') => self.toggle_pause().await,
+                KeyCode::Char('d') => {
+                    if let Some(t) = self.selected() {
+                        self.mode = Mode::ConfirmDelete(t.id);
+                    }
+                }
+                KeyCode::Enter if self.selected().is_some() => self.mode = Mode::Details,
+                _ => {}
+            },
+        }
+    }
+}
+
+/// Runs the dashboard until it's quit, then pauses the downloads it started
+pub async fn run(pool: Repository, options: TuiOptions) -> io::Result<()> {
+    let mut app = App {
+        pool,
+        tasks: Vec::new(),
+        table: TableState::default(),
+        parts: Vec::new(),
+        holder: None,
+        mode: Mode::Normal,
+        message: String::new(),
+        waiting: Vec::new(),
+        deleting: BTreeMap::new(),
+        workers: Workers {
+            exe: std::env::current_exe()?,
+            config_path: options.config_path,
+            children: BTreeMap::new(),
+        },
+        max_concurrent: options.max_concurrent.max(1),
+        quit: false,
+    };
+
+    enable_raw_mode()?;
+    let screen = Screen;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    let result = event_loop(&mut terminal, &mut app).await;
+    drop(screen);
+    let _ = terminal.show_cursor();
+
+    if !app.workers.children.is_empty() {
+        println!(
+            "Pausing the {} downloads started from the dashboard",
+            app.workers.children.len()
+        );
+        app.workers.pause_all(&app.tasks).await;
+    }
+    result
+}
+
+/// Gives the terminal back as it was when dropped, even on panics
+struct Screen;
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+async fn event_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+) -> io::Result<()> {
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(Duration::from_millis(500));
+    // Ctrl-C is a key in raw mode, SIGINT and SIGTERM come from other processes
+    let interrupt = signals::wait_interrupt();
+    tokio::pin!(interrupt);
+    let mut listening = true;
+
+    while !app.quit {
+        terminal.draw(|f| draw(f, app))?;
+        tokio::select! {
+            interrupted = &mut interrupt, if listening => {
+                // Quitting pauses the downloads started here, like `whip download` does
+                app.quit = interrupted;
+                listening = false;
+            }
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    app.on_key(key).await
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            _ = refresh.tick() => app.refresh().await,
+        }
+    }
+    Ok(())
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let parts_height = if app.parts.len() > 1 {
+        (app.parts.len() as u16 + 2).min(12)
+    } else {
+        0
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Min(4),
+            Constraint::Length(parts_height),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+    let downloading = app
+        .tasks
+        .iter()
+        .filter(|t| t.status == DownloadStatus::Downloading)
+        .collect::<Vec<_>>();
+    let speed: f64 = downloading.iter().map(|t| t.speed).sum();
+    let summary = format!(
+        " whip : {} tasks, {} downloading at {}/s, {} started here, {} waiting",
+        app.tasks.len(),
+        downloading.len(),
+        format_size(speed as u64),
+        app.workers.children.len(),
+        app.waiting.len()
+    );
+    f.render_widget(
+        Paragraph::new(summary).style(Style::default().add_modifier(Modifier::BOLD)),
+        chunks[0],
+    );
+
+    let header = Row::new(["Id", "Name", "Status", "Progress", "Size", "Speed", "ETA"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = app.tasks.iter().map(|t| {
+        let waiting = app.waiting.contains(&Job::Task(t.id));
+        Row::new(vec![
+            Cell::from(t.id.to_string()),
+            Cell::from(t.file_name.to_owned()),
+            Cell::from(if waiting {
+                String::from("waiting")
+            } else {
+                t.status.to_string()
+            })
+            .style(status_style(t.status)),
+            Cell::from(task_progress(t)),
+            Cell::from(if t.file_size > 0 {
+                format_size(t.file_size)
+            } else {
+                String::from("?")
+            }),
+            Cell::from(task_speed(t)),
+            Cell::from(task_eta(t)),
+        ])
+    });
+    let widths = [
+        Constraint::Length(5),
+        Constraint::Percentage(40),
+        Constraint::Length(11),
+        Constraint::Length(28),
+        Constraint::Length(10),
+        Constraint::Length(12),
+        Constraint::Length(9),
+    ];
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(" Downloads "))
+        .widths(&widths)
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(table, chunks[1], &mut app.table);
+
+    if parts_height > 0 {
+        let lines = app
+            .parts
+            .iter()
+            .map(|p| Spans::from(part_line(p)))
+            .collect::<Vec<_>>();
+        let title = match app.selected() {
+            Some(t) => format!(" Parts of {} ", t.file_name),
+            None => String::from(" Parts "),
+        };
+        f.render_widget(
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)),
+            chunks[2],
+        );
+    }
+
+    let footer = match &app.mode {
+        Mode::Adding(url) => format!("Url to download : {}", url),
+        Mode::ConfirmDelete(id) => format!(
+            "Delete task {} ? y : keep the file, f : delete the file too, any other key : cancel",
+            id
+        ),
+        _ if !app.message.is_empty() => app.message.to_owned(),
+        _ => String::from(
+            "a add  s start  p pause/resume  d delete  K/J move up/down  enter details  q quit",
+        ),
+    };
+    f.render_widget(Paragraph::new(footer), chunks[3]);
+    if let Mode::Adding(url) = &app.mode {
+        let x = chunks[3].x + "Url to download : ".len() as u16 + url.chars().count() as u16;
+        f.set_cursor(x.min(chunks[3].right().saturating_sub(1)), chunks[3].y);
+    }
+
+    if let Mode::Details = app.mode {
+        if let Some(task) = app.selected() {
+            let area = centered(f.size(), 80, 70);
+            let lines = details(task, app.holder)
+                .into_iter()
+                .map(|(key, value)| {
+                    Spans::from(vec![
+                        Span::styled(
+                            format!("{:<14}", key),
+                            Style::default().add_modifier(Modifier::BOLD),
+                        ),
+                        Span::raw(value),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            f.render_widget(Clear, area);
+            f.render_widget(
+                Paragraph::new(lines)
+                    .wrap(Wrap { trim: false })
+                    .block(Block::default().borders(Borders::ALL).title(" Details ")),
+                area,
+            );
+        }
+    }
+}
+
+fn status_style(status: DownloadStatus) -> Style {
+    let color = match status {
+        DownloadStatus::Downloading | DownloadStatus::Probing | DownloadStatus::Verifying => {
+            Color::Green
+        }
+        DownloadStatus::Paused => Color::Yellow,
+        DownloadStatus::Failed => Color::Red,
+        DownloadStatus::Completed => Color::Blue,
+        DownloadStatus::Queued | DownloadStatus::Cancelled => Color::Gray,
+    };
+    Style::default().fg(color)
+}
+
+/// Bar and percentage, or the bytes received when the size is unknown
+fn task_progress(task: &DownloadTaskEntity) -> String {
+    if task.file_size == 0 {
+        return format_size(task.bytes_done);
+    }
+    let percentage = task.percentage_completed.clamp(0f64, 100f64);
+    format!("{} {:>5.1}%", text_bar(percentage / 100f64, 20), percentage)
+}
+
+fn task_speed(task: &DownloadTaskEntity) -> String {
+    if task.status == DownloadStatus::Downloading && task.speed > 0f64 {
+        format!("{}/s", format_size(task.speed as u64))
+    } else {
+        String::new()
+    }
+}
+
+fn task_eta(task: &DownloadTaskEntity) -> String {
+    if task.status != DownloadStatus::Downloading || task.speed <= 0f64 || task.file_size == 0 {
+        return String::new();
+    }
+    let remaining = task.file_size.saturating_sub(task.bytes_done);
+    format_eta((remaining as f64 / task.speed).ceil() as u64)
+}
+
+fn part_line(part: &PartCheckpoint) -> String {
+    let length = (part.end_byte.saturating_sub(part.start_byte) + 1).max(1);
+    let ratio = if part.state == PartState::Completed {
+        1f64
+    } else {
+        (part.bytes_done as f64 / length as f64).min(1f64)
+    };
+    format!(
+        "{:>3} {} {:>5.1}% {:>10} of {:<10} {}",
+        part.id,
+        text_bar(ratio, 30),
+        ratio * 100f64,
+        format_size(part.bytes_done),
+        format_size(length),
+        part.state
+    )
+}
+
+fn details(task: &DownloadTaskEntity, holder: Option<u32>) -> Vec<(&'static str, String)> {
+    let time = |t: &Option<chrono::DateTime<chrono::Local>>| match t {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => String::new(),
+    };
+    let mut path = PathBuf::from(&task.final_file_path);
+    path.push(&task.file_name);
+    vec![
+        ("Id", task.id.to_string()),
+        ("Url", task.file_url.to_owned()),
+        ("File", path.to_string_lossy().to_string()),
+        ("Size", format_size(task.file_size)),
+        ("Content type", task.content_type.to_owned()),
+        ("Status", task.status.to_string()),
+        ("Received", format_size(task.bytes_done)),
+        ("Parts", task.part_count.to_string()),
+        ("Threads", task.max_threads.to_string()),
+        ("Attempts", task.attempt_count.to_string()),
+        ("Last error", task.last_error.to_owned().unwrap_or_default()),
+        ("Created", task.date_created.to_owned()),
+        ("Started", time(&task.started_at)),
+        ("Finished", time(&task.finished_at)),
+        ("Temp dir", task.temp_files_path.to_owned()),
+        (
+            "Process",
+            holder.map(|pid| pid.to_string()).unwrap_or_default(),
+        ),
+        (
+            "Headers",
+            task.headers
+                .iter()
+                .map(|(n, v)| format!("{}: {}", n, v))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    ]
+}
+
+/// Area of `percent_x` by `percent_y` percent of `area` at its center
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let width = area.width * percent_x / 100;
+    let height = area.height * percent_y / 100;
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Progress bar made of `width` characters
+fn text_bar(ratio: f64, width: usize) -> String {
+    let filled = ((ratio.clamp(0f64, 1f64) * width as f64).round() as usize).min(width);
+    format!("{}{}", "█".repeat(filled), "░".repeat(width - filled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_bar() {
+        assert_eq!(text_bar(0.5, 4), "██░░");
+        assert_eq!(text_bar(2f64, 2), "██");
+        assert_eq!(format_eta(3725), "1h02m");
+        assert_eq!(format_eta(61), "1m01s");
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartState {
+    Pending,
+    Downloading,
+    Completed,
+    Failed,
+}
+
+impl PartState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PartState::Pending => "pending",
+            PartState::Downloading => "downloading",
+            PartState::Completed => "completed",
+            PartState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(PartState::Pending),
+            "downloading" => Some(PartState::Downloading),
+            "completed" => Some(PartState::Completed),
+            "failed" => Some(PartState::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PartState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Where a part of a download stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartCheckpoint {
+    pub id: u32,
+    pub start_byte: u64,
+    pub end_byte: u64,
+    /// Bytes of the part received so far, resumed ones included
+    pub bytes_done: u64,
+    pub state: PartState,
+}
+
+/// Progress of the parts of a download, shared with whoever watches it
+#[derive(Debug, Clone, Default)]
+pub struct PartTracker {
+    inner: Arc<Mutex<BTreeMap<u32, PartCheckpoint>>>,
+}
+
+impl PartTracker {
+    pub fn new() -> Self {
+        PartTracker::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, PartCheckpoint>> {
+        match self.inner.lock() {
+            Ok(p) => p,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Replaces the tracked parts
+    pub fn reset(&self, parts: Vec<PartCheckpoint>) {
+        *self.lock() = parts.into_iter().map(|p| (p.id, p)).collect();
+    }
+
+    /// Changes the part `id` if it's tracked
+    pub fn update<F>(&self, id: u32, change: F)
+    where
+        F: FnOnce(&mut PartCheckpoint),
+    {
+        if let Some(p) = self.lock().get_mut(&id) {
+            change(p);
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<PartCheckpoint> {
+        self.lock().get(&id).cloned()
+    }
+
+    /// The parts ordered by id
+    pub fn snapshot(&self) -> Vec<PartCheckpoint> {
+        self.lock().values().cloned().collect()
+    }
+
+    pub fn bytes_done(&self) -> u64 {
+        self.lock().values().map(|p| p.bytes_done).sum()
+    }
+}
+
+/// Snapshot of a running download, sent at a regular interval so its
+/// progress survives the process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub task_id: u64,
+    pub bytes_done: u64,
+    /// 0 when the size is unknown
+    pub total_bytes: u64,
+    /// Bytes per second since the previous checkpoint
+    pub speed: f64,
+    pub parts: Vec<PartCheckpoint>,
+}
+
+impl Checkpoint {
+    pub fn percentage(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0f64;
+        }
+        (self.bytes_done as f64 / self.total_bytes as f64 * 100f64).min(100f64)
+    }
+}
+
+/// Sends the checkpoints of a download every `interval`. Downloads send a
+/// last one when they end, then drop the sender.
+#[derive(Debug, Clone)]
+pub struct Checkpointer {
+    pub task_id: u64,
+    pub interval: Duration,
+    sender: UnboundedSender<Checkpoint>,
+}
+
+impl Checkpointer {
+    pub fn new(task_id: u64, interval: Duration, sender: UnboundedSender<Checkpoint>) -> Self {
+        Checkpointer {
+            task_id,
+            interval,
+            sender,
+        }
+    }
+
+    /// Returns false once nobody listens anymore
+    pub fn send(&self, checkpoint: Checkpoint) -> bool {
+        self.sender.send(checkpoint).is_ok()
+    }
+}
+
+/// Measures the speed between two readings of a byte count
+#[derive(Debug, Default)]
+pub struct SpeedMeter {
+    last: Option<(Instant, u64)>,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        SpeedMeter::default()
+    }
+
+    /// Bytes per second since the previous reading, 0 on the first one
+    pub fn sample(&mut self, bytes_done: u64) -> f64 {
+        self.sample_at(Instant::now(), bytes_done)
+    }
+
+    fn sample_at(&mut self, now: Instant, bytes_done: u64) -> f64 {
+        let speed = match self.last {
+            Some((at, bytes)) if now > at => {
+                bytes_done.saturating_sub(bytes) as f64 / (now - at).as_secs_f64()
+            }
+            _ => 0f64,
+        };
+        self.last = Some((now, bytes_done));
+        speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_meter() {
+        let start = Instant::now();
+        let mut meter = SpeedMeter::new();
+
+        assert_eq!(meter.sample_at(start, 1000), 0f64);
+        assert_eq!(
+            meter.sample_at(start + Duration::from_secs(2), 5000),
+            2000f64
+        );
+        // Rolled back progress doesn't make the speed negative
+        assert_eq!(meter.sample_at(start + Duration::from_secs(3), 0), 0f64);
+    }
+}
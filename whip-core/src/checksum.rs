@@ -0,0 +1,186 @@
+use std::{fmt, path::Path};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::errors::WhipError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Parses the names used by metalink files and aria2 input
+    /// files (sha-256, sha256, SHA-256...).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace('-', "").as_str() {
+            "md5" => Some(HashAlgorithm::Md5),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Stronger algorithms first
+    fn strength(&self) -> u8 {
+        match self {
+            HashAlgorithm::Md5 => 0,
+            HashAlgorithm::Sha1 => 1,
+            HashAlgorithm::Sha256 => 2,
+            HashAlgorithm::Sha512 => 3,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "md5"),
+            HashAlgorithm::Sha1 => write!(f, "sha-1"),
+            HashAlgorithm::Sha256 => write!(f, "sha-256"),
+            HashAlgorithm::Sha512 => write!(f, "sha-512"),
+        }
+    }
+}
+
+/// An expected digest of some data, stored as lower case hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub value: String,
+}
+
+/// Incremental hasher for any of the supported algorithms.
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Md5(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha1(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("{:x}", h.finalize()),
+        }
+    }
+}
+
+impl Checksum {
+    pub fn new(algorithm: HashAlgorithm, value: &str) -> Self {
+        Checksum {
+            algorithm,
+            value: value.trim().to_lowercase(),
+        }
+    }
+
+    /// Parses a "type=digest" pair (e.g. sha-256=e3b0c442...)
+    pub fn parse(value: &str) -> Option<Self> {
+        let (algorithm, digest) = value.split_once('=')?;
+        Some(Checksum::new(HashAlgorithm::from_name(algorithm)?, digest))
+    }
+
+    /// Picks the strongest checksum of a list
+    pub fn strongest(checksums: &[Checksum]) -> Option<&Checksum> {
+        checksums.iter().max_by_key(|c| c.algorithm.strength())
+    }
+
+    pub fn verify(&self, data: &[u8]) -> bool {
+        let mut hasher = Hasher::new(self.algorithm);
+        hasher.update(data);
+        hasher.finalize() == self.value
+    }
+
+    /// Hashes a file on disk and compares it to the expected digest
+    pub async fn verify_file(&self, path: &Path) -> Result<bool, WhipError> {
+        let checksum = Checksum::of_file(self.algorithm, path).await?;
+        Ok(checksum.value == self.value)
+    }
+
+    /// Hashes a file on disk
+    pub async fn of_file(algorithm: HashAlgorithm, path: &Path) -> Result<Self, WhipError> {
+        let mut file = match fs::File::open(path).await {
+            Ok(f) => f,
+            Err(e) => return Err(WhipError::io(path, e)),
+        };
+        let mut hasher = Hasher::new(algorithm);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => hasher.update(&buffer[..n]),
+                Err(e) => return Err(WhipError::io(path, e)),
+            }
+        }
+        Ok(Checksum {
+            algorithm,
+            value: hasher.finalize(),
+        })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.algorithm, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let checksum = Checksum::parse(
+            "sha-256=BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+        )
+        .unwrap();
+
+        assert_eq!(checksum.algorithm, HashAlgorithm::Sha256);
+        assert!(checksum.verify(b"abc"));
+        assert!(!checksum.verify(b"abd"));
+    }
+
+    #[test]
+    fn test_strongest() {
+        let checksums = vec![
+            Checksum::new(HashAlgorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+            Checksum::new(
+                HashAlgorithm::Sha1,
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+        ];
+
+        let strongest = Checksum::strongest(&checksums).unwrap();
+        assert_eq!(strongest.algorithm, HashAlgorithm::Sha1);
+        assert!(checksums.iter().all(|c| c.verify(b"abc")));
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+/// Handle to steer a running download from outside of it. Holding it
+/// stops the transfers until it's released, parts that were cut resume
+/// from what they wrote. Stopping it ends the download for good, with what
+/// was written saved so a later session resumes from it. A rate limit caps
+/// the speed of all the parts together.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadControl {
+    inner: Arc<ControlState>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    held: AtomicBool,
+    stopped: AtomicBool,
+    /// Bytes per second, 0 for no limit
+    rate_limit: AtomicU64,
+    /// When the bandwidth used so far is paid off
+    next_slot: Mutex<Option<Instant>>,
+    released: Notify,
+    stopped_notify: Notify,
+}
+
+impl DownloadControl {
+    pub fn new() -> Self {
+        DownloadControl::default()
+    }
+
+    pub fn hold(&self) {
+        self.inner.held.store(true, Ordering::SeqCst);
+    }
+
+    pub fn release(&self) {
+        self.inner.held.store(false, Ordering::SeqCst);
+        self.inner.released.notify_waiters();
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.inner.held.load(Ordering::SeqCst)
+    }
+
+    /// Ends the download, parts in flight save what they received
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        self.inner.stopped_notify.notify_waiters();
+        // Held downloads have to wake up to end
+        self.inner.released.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Caps the speed to `bytes_per_second`, 0 removes the limit
+    pub fn set_rate_limit(&self, bytes_per_second: u64) {
+        self.inner
+            .rate_limit
+            .store(bytes_per_second, Ordering::SeqCst);
+    }
+
+    pub fn rate_limit(&self) -> u64 {
+        self.inner.rate_limit.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the control is released or stopped
+    pub async fn wait_released(&self) {
+        loop {
+            // Registered before the check so a release in between isn't missed
+            let released = self.inner.released.notified();
+            if !self.is_held() || self.is_stopped() {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// Waits until the control is stopped
+    pub async fn wait_stopped(&self) {
+        let stopped = self.inner.stopped_notify.notified();
+        if self.is_stopped() {
+            return;
+        }
+        stopped.await;
+    }
+
+    /// Waits as long as receiving `bytes` takes under the rate limit
+    pub async fn throttle(&self, bytes: usize) {
+        let limit = self.rate_limit();
+        if limit == 0 {
+            return;
+        }
+        let wait = {
+            let mut next_slot = match self.inner.next_slot.lock() {
+                Ok(n) => n,
+                Err(e) => e.into_inner(),
+            };
+            let now = Instant::now();
+            let start = match *next_slot {
+                Some(n) if n > now => n,
+                _ => now,
+            };
+            let end = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+            *next_slot = Some(end);
+            end - now
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_released() {
+        let control = DownloadControl::new();
+        control.hold();
+
+        let c = control.clone();
+        let waiter = tokio::spawn(async move { c.wait_released().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        control.release();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_wakes_held() {
+        let control = DownloadControl::new();
+        control.hold();
+
+        let c = control.clone();
+        let waiter = tokio::spawn(async move {
+            c.wait_released().await;
+            c.wait_stopped().await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        control.stop();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(control.is_held());
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let control = DownloadControl::new();
+        control.set_rate_limit(100_000);
+
+        let start = Instant::now();
+        for _ in 0..4 {
+            control.throttle(5_000).await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
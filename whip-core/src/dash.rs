@@ -0,0 +1,692 @@
+use reqwest::{header, Client, Url};
+use roxmltree::{Document, Node};
+
+use crate::{
+    download::DownloadTask,
+    errors::WhipError,
+    stream::{fetch_text, join_url, parse_url, Segment, VariantSelector},
+};
+
+/// Size of the byte ranges a SegmentBase resource is split into
+const BASE_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A parsed MPD manifest. Only the first period is taken into account.
+#[derive(Debug)]
+pub struct Manifest {
+    /// Presentation duration in seconds
+    pub duration: Option<f64>,
+    pub adaptation_sets: Vec<AdaptationSet>,
+}
+
+/// A group of interchangeable representations (e.g. the video qualities).
+#[derive(Debug)]
+pub struct AdaptationSet {
+    /// "video", "audio", "text"...
+    pub content_type: String,
+    pub representations: Vec<Representation>,
+}
+
+#[derive(Debug)]
+pub struct Representation {
+    pub id: String,
+    /// Bit rate in bits per second
+    pub bandwidth: u64,
+    /// Width and height in pixels
+    pub resolution: Option<(u32, u32)>,
+    pub mime_type: String,
+    pub codecs: Option<String>,
+    pub segments: SegmentSource,
+}
+
+/// Where the media of a representation comes from.
+#[derive(Debug)]
+pub enum SegmentSource {
+    /// Explicit list of segments (SegmentTemplate, SegmentList),
+    /// the initialization segment comes first
+    Segments(Vec<SegmentRef>),
+    /// A single resource (SegmentBase), downloaded in byte ranges
+    Base(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentRef {
+    pub url: String,
+    /// Inclusive byte range of the segment within the resource at `url`
+    pub byte_range: Option<(u64, u64)>,
+}
+
+/// A representation picked for download, ready to be handed to a `StreamDownloader`.
+#[derive(Debug)]
+pub struct Track {
+    pub content_type: String,
+    /// Extension of the assembled file
+    pub extension: String,
+    pub segments: Vec<Segment>,
+}
+
+impl Track {
+    /// Name of the assembled file of the track
+    pub fn file_name(&self, stem: &str) -> String {
+        format!("{}.{}.{}", stem, self.content_type, self.extension)
+    }
+}
+
+/// SegmentTemplate attributes, inherited from the adaptation set
+/// and overridden by the representation.
+#[derive(Debug, Default, Clone)]
+struct Template {
+    media: Option<String>,
+    initialization: Option<String>,
+    start_number: Option<u64>,
+    timescale: Option<u64>,
+    duration: Option<u64>,
+    /// (start time, duration, repeat count) of the S elements
+    timeline: Option<Vec<(Option<u64>, u64, i64)>>,
+}
+
+impl Template {
+    fn from_node(node: Node) -> Self {
+        let timeline = node
+            .children()
+            .find(|n| n.has_tag_name("SegmentTimeline"))
+            .map(|t| {
+                t.children()
+                    .filter(|n| n.has_tag_name("S"))
+                    .map(|s| {
+                        (
+                            s.attribute("t").and_then(|v| v.parse().ok()),
+                            s.attribute("d").and_then(|v| v.parse().ok()).unwrap_or(0),
+                            s.attribute("r").and_then(|v| v.parse().ok()).unwrap_or(0),
+                        )
+                    })
+                    .collect()
+            });
+        Template {
+            media: node.attribute("media").map(String::from),
+            initialization: node.attribute("initialization").map(String::from),
+            start_number: node.attribute("startNumber").and_then(|v| v.parse().ok()),
+            timescale: node.attribute("timescale").and_then(|v| v.parse().ok()),
+            duration: node.attribute("duration").and_then(|v| v.parse().ok()),
+            timeline,
+        }
+    }
+
+    fn merge(&self, other: &Template) -> Template {
+        Template {
+            media: other.media.clone().or_else(|| self.media.clone()),
+            initialization: other
+                .initialization
+                .clone()
+                .or_else(|| self.initialization.clone()),
+            start_number: other.start_number.or(self.start_number),
+            timescale: other.timescale.or(self.timescale),
+            duration: other.duration.or(self.duration),
+            timeline: other.timeline.clone().or_else(|| self.timeline.clone()),
+        }
+    }
+}
+
+/// Checks if a download task points to a DASH manifest.
+pub fn is_dash(task: &DownloadTask) -> bool {
+    if task.meta.content_type.to_lowercase().contains("dash+xml") {
+        return true;
+    }
+    let path = task.file_url.split('?').next().unwrap_or("");
+    path.to_lowercase().ends_with(".mpd")
+}
+
+/// Name the tracks of a manifest are saved under (without the track suffix).
+pub fn output_file_stem(manifest_name: &str) -> String {
+    match manifest_name.rfind('.') {
+        Some(index) if manifest_name[index..].eq_ignore_ascii_case(".mpd") => {
+            manifest_name[..index].to_string()
+        }
+        _ => manifest_name.to_string(),
+    }
+}
+
+/// Parses an MPD manifest, resolving segment urls against `manifest_url`.
+pub fn parse_manifest(content: &str, manifest_url: &Url) -> Result<Manifest, WhipError> {
+    let document = match Document::parse(content) {
+        Ok(d) => d,
+        Err(e) => return Err(WhipError::Parse(format!("Invalid MPD manifest : {}", e))),
+    };
+    let mpd = document.root_element();
+    if !mpd.has_tag_name("MPD") {
+        return Err(WhipError::Parse("Not an MPD manifest".to_string()));
+    }
+
+    let mut base = resolve_base_url(manifest_url, mpd)?;
+    let mut duration = mpd
+        .attribute("mediaPresentationDuration")
+        .and_then(parse_duration);
+
+    let period = match mpd.children().find(|n| n.has_tag_name("Period")) {
+        Some(p) => p,
+        None => return Err(WhipError::Parse("Manifest has no period".to_string())),
+    };
+    base = resolve_base_url(&base, period)?;
+    if let Some(d) = period.attribute("duration").and_then(parse_duration) {
+        duration = Some(d);
+    }
+
+    let mut adaptation_sets = Vec::new();
+    for set in period
+        .children()
+        .filter(|n| n.has_tag_name("AdaptationSet"))
+    {
+        let set_base = resolve_base_url(&base, set)?;
+        let set_template = child(set, "SegmentTemplate")
+            .map(Template::from_node)
+            .unwrap_or_default();
+
+        let mut representations = Vec::new();
+        for rep in set.children().filter(|n| n.has_tag_name("Representation")) {
+            let rep_base = resolve_base_url(&set_base, rep)?;
+            let id = rep.attribute("id").unwrap_or("").to_string();
+            let bandwidth = rep
+                .attribute("bandwidth")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0);
+            let width = attribute(rep, set, "width").and_then(|w| w.parse().ok());
+            let height = attribute(rep, set, "height").and_then(|h| h.parse().ok());
+
+            let segments =
+                if let Some(list) = child(rep, "SegmentList").or(child(set, "SegmentList")) {
+                    SegmentSource::Segments(list_segments(list, &rep_base)?)
+                } else if let Some(t) = child(rep, "SegmentTemplate") {
+                    let template = set_template.merge(&Template::from_node(t));
+                    SegmentSource::Segments(template_segments(
+                        &template, &id, bandwidth, &rep_base, duration,
+                    )?)
+                } else if set_template.media.is_some() {
+                    SegmentSource::Segments(template_segments(
+                        &set_template,
+                        &id,
+                        bandwidth,
+                        &rep_base,
+                        duration,
+                    )?)
+                } else {
+                    SegmentSource::Base(rep_base.to_string())
+                };
+
+            representations.push(Representation {
+                id,
+                bandwidth,
+                resolution: width.zip(height),
+                mime_type: attribute(rep, set, "mimeType").unwrap_or("").to_string(),
+                codecs: attribute(rep, set, "codecs").map(String::from),
+                segments,
+            });
+        }
+
+        let content_type = set
+            .attribute("contentType")
+            .map(String::from)
+            .or_else(|| {
+                representations
+                    .iter()
+                    .map(|r| r.mime_type.as_str())
+                    .chain(set.attribute("mimeType"))
+                    .find_map(|m| m.split('/').next().filter(|t| !t.is_empty()))
+                    .map(String::from)
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+
+        adaptation_sets.push(AdaptationSet {
+            content_type,
+            representations,
+        });
+    }
+
+    Ok(Manifest {
+        duration,
+        adaptation_sets,
+    })
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+/// Reads an attribute of a representation, falling back to its adaptation set
+fn attribute<'a>(rep: Node<'a, '_>, set: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    rep.attribute(name).or_else(|| set.attribute(name))
+}
+
+fn resolve_base_url(base: &Url, node: Node) -> Result<Url, WhipError> {
+    match child(node, "BaseURL").and_then(|b| b.text()) {
+        Some(url) => join_url(base, url.trim()),
+        None => Ok(base.clone()),
+    }
+}
+
+/// Parses an ISO 8601 duration (PT1H2M3.5S) into seconds
+fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.strip_prefix('P')?;
+    let (date, time) = match value.split_once('T') {
+        Some((d, t)) => (d, t),
+        None => (value, ""),
+    };
+    let mut seconds = 0f64;
+    let mut number = String::new();
+    for c in date.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+        } else {
+            let n: f64 = number.parse().ok()?;
+            number.clear();
+            // Years and months have no fixed length, manifests don't use them
+            seconds += match c {
+                'D' => n * 86400f64,
+                _ => return None,
+            };
+        }
+    }
+    for c in time.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+        } else {
+            let n: f64 = number.parse().ok()?;
+            number.clear();
+            seconds += match c {
+                'H' => n * 3600f64,
+                'M' => n * 60f64,
+                'S' => n,
+                _ => return None,
+            };
+        }
+    }
+    Some(seconds)
+}
+
+/// Parses a "start-end" byte range
+fn parse_range(value: &str) -> Option<(u64, u64)> {
+    let (start, end) = value.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+/// Replaces the $identifiers$ of a SegmentTemplate url
+fn fill_template(template: &str, rep_id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let mut result = String::new();
+    let mut parts = template.split('$');
+    if let Some(first) = parts.next() {
+        result.push_str(first);
+    }
+    let mut inside = true;
+    for part in parts {
+        if !inside {
+            result.push_str(part);
+            inside = true;
+            continue;
+        }
+        inside = false;
+        if part.is_empty() {
+            result.push('$');
+            continue;
+        }
+        let (name, format) = match part.split_once('%') {
+            Some((n, f)) => (n, Some(f)),
+            None => (part, None),
+        };
+        let value = match name {
+            "RepresentationID" => {
+                result.push_str(rep_id);
+                continue;
+            }
+            "Number" => number,
+            "Bandwidth" => bandwidth,
+            "Time" => time,
+            _ => {
+                result.push('$');
+                result.push_str(part);
+                result.push('$');
+                continue;
+            }
+        };
+        // Only the %0[width]d format tag is allowed by the spec
+        let width = format
+            .and_then(|f| f.trim_end_matches('d').trim_start_matches('0').parse().ok())
+            .unwrap_or(0);
+        result.push_str(&format!("{:0width$}", value, width = width));
+    }
+    result
+}
+
+fn template_segments(
+    template: &Template,
+    rep_id: &str,
+    bandwidth: u64,
+    base: &Url,
+    duration: Option<f64>,
+) -> Result<Vec<SegmentRef>, WhipError> {
+    let media = match &template.media {
+        Some(m) => m,
+        None => {
+            return Err(WhipError::Parse(
+                "SegmentTemplate has no media attribute".to_string(),
+            ))
+        }
+    };
+    let timescale = template.timescale.unwrap_or(1).max(1);
+    let mut number = template.start_number.unwrap_or(1);
+    let mut segments = Vec::new();
+
+    if let Some(init) = &template.initialization {
+        segments.push(SegmentRef {
+            url: join_url(base, &fill_template(init, rep_id, bandwidth, number, 0))?.to_string(),
+            byte_range: None,
+        });
+    }
+
+    if let Some(timeline) = &template.timeline {
+        let end_time = duration.map(|d| (d * timescale as f64) as u64);
+        let mut time = 0;
+        for (i, (start, d, repeat)) in timeline.iter().enumerate() {
+            if let Some(t) = start {
+                time = *t;
+            }
+            // A negative repeat count lasts until the next S element or the end of the period
+            let count = if *repeat >= 0 {
+                *repeat as u64 + 1
+            } else {
+                let until = timeline
+                    .get(i + 1)
+                    .and_then(|next| next.0)
+                    .or(end_time)
+                    .unwrap_or(time);
+                if *d == 0 {
+                    0
+                } else {
+                    until.saturating_sub(time).div_ceil(*d)
+                }
+            };
+            for _ in 0..count {
+                segments.push(SegmentRef {
+                    url: join_url(base, &fill_template(media, rep_id, bandwidth, number, time))?
+                        .to_string(),
+                    byte_range: None,
+                });
+                number += 1;
+                time += d;
+            }
+        }
+    } else {
+        let segment_ticks = template.duration.unwrap_or(0);
+        let segment_duration = match segment_ticks {
+            d if d > 0 => d as f64 / timescale as f64,
+            _ => {
+                return Err(WhipError::Parse(
+                    "SegmentTemplate has neither a duration nor a timeline".to_string(),
+                ))
+            }
+        };
+        let count = match duration {
+            Some(d) => (d / segment_duration).ceil() as u64,
+            None => {
+                return Err(WhipError::Parse(
+                    "Can't count segments of a manifest without duration".to_string(),
+                ))
+            }
+        };
+        for i in 0..count {
+            let time = i * segment_ticks;
+            segments.push(SegmentRef {
+                url: join_url(base, &fill_template(media, rep_id, bandwidth, number, time))?
+                    .to_string(),
+                byte_range: None,
+            });
+            number += 1;
+        }
+    }
+
+    Ok(segments)
+}
+
+fn list_segments(list: Node, base: &Url) -> Result<Vec<SegmentRef>, WhipError> {
+    let mut segments = Vec::new();
+    if let Some(init) = child(list, "Initialization") {
+        segments.push(SegmentRef {
+            url: match init.attribute("sourceURL") {
+                Some(url) => join_url(base, url)?.to_string(),
+                None => base.to_string(),
+            },
+            byte_range: init.attribute("range").and_then(parse_range),
+        });
+    }
+    for s in list.children().filter(|n| n.has_tag_name("SegmentURL")) {
+        segments.push(SegmentRef {
+            url: match s.attribute("media") {
+                Some(url) => join_url(base, url)?.to_string(),
+                None => base.to_string(),
+            },
+            byte_range: s.attribute("mediaRange").and_then(parse_range),
+        });
+    }
+    Ok(segments)
+}
+
+fn extension(mime_type: &str) -> &'static str {
+    match mime_type {
+        "audio/mp4" => "m4a",
+        "video/webm" | "audio/webm" => "webm",
+        "text/vtt" => "vtt",
+        "application/ttml+xml" => "ttml",
+        _ => "mp4",
+    }
+}
+
+/// Fetches a manifest and returns the tracks to download: the video
+/// representation picked by `selector` and the best audio representation.
+pub async fn get_tracks(
+    manifest_url: &str,
+    selector: &VariantSelector,
+) -> Result<Vec<Track>, WhipError> {
+    let client = Client::new();
+    let url = parse_url(manifest_url)?;
+    let manifest = parse_manifest(&fetch_text(&client, &url).await?, &url)?;
+
+    let mut tracks = Vec::new();
+    for (content_type, selector) in [("video", selector), ("audio", &VariantSelector::Highest)] {
+        // Take the first adaptation set of each kind
+        let set = match manifest
+            .adaptation_sets
+            .iter()
+            .find(|s| s.content_type == content_type)
+        {
+            Some(s) => s,
+            None => continue,
+        };
+        let rep = match selector.select(&set.representations, |r| (r.bandwidth, r.resolution)) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let refs = match &rep.segments {
+            SegmentSource::Segments(s) => s.clone(),
+            SegmentSource::Base(url) => base_segments(&client, url).await?,
+        };
+        tracks.push(Track {
+            content_type: content_type.to_string(),
+            extension: extension(&rep.mime_type).to_string(),
+            segments: refs
+                .into_iter()
+                .enumerate()
+                .map(|(i, r)| Segment {
+                    id: i as u64,
+                    url: r.url,
+                    byte_range: r.byte_range,
+                    key: None,
+                    fallback_urls: Vec::new(),
+                    checksum: None,
+                })
+                .collect(),
+        });
+    }
+
+    if tracks.is_empty() {
+        return Err(WhipError::Parse(
+            "No video or audio representation found in manifest".to_string(),
+        ));
+    }
+    Ok(tracks)
+}
+
+/// Splits a SegmentBase resource into byte ranges so it can be fetched in parallel
+async fn base_segments(client: &Client, url: &str) -> Result<Vec<SegmentRef>, WhipError> {
+    let content_length = match client
+        .head(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        Ok(response) => response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok())
+            .unwrap_or(0),
+        Err(e) => return Err(WhipError::request(url, e)),
+    };
+
+    if content_length == 0 {
+        return Ok(vec![SegmentRef {
+            url: url.to_string(),
+            byte_range: None,
+        }]);
+    }
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    while start < content_length {
+        let end = (start + BASE_SEGMENT_SIZE).min(content_length) - 1;
+        segments.push(SegmentRef {
+            url: url.to_string(),
+            byte_range: Some((start, end)),
+        });
+        start = end + 1;
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE_MPD: &str = r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT20S">
+  <Period>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate media="$RepresentationID$/seg-$Number%05d$.m4s" initialization="$RepresentationID$/init.mp4" startNumber="1" timescale="1000" duration="4000"/>
+      <Representation id="480p" bandwidth="1000000" width="854" height="480"/>
+      <Representation id="720p" bandwidth="3000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4">
+      <Representation id="audio" bandwidth="128000">
+        <SegmentTemplate media="audio/$Time$.m4s" initialization="audio/init.mp4" timescale="10">
+          <SegmentTimeline>
+            <S t="0" d="50" r="1"/>
+            <S d="100"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    const LIST_MPD: &str = r#"<MPD mediaPresentationDuration="PT10S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="v" bandwidth="500000">
+        <BaseURL>video.mp4</BaseURL>
+        <SegmentList>
+          <Initialization range="0-999"/>
+          <SegmentURL mediaRange="1000-4999"/>
+          <SegmentURL mediaRange="5000-8999"/>
+        </SegmentList>
+      </Representation>
+      <Representation id="b" bandwidth="800000">
+        <BaseURL>base.mp4</BaseURL>
+        <SegmentBase indexRange="800-1200"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    fn manifest_url() -> Url {
+        Url::parse("https://example.com/talk/manifest.mpd").unwrap()
+    }
+
+    #[test]
+    fn test_parse_segment_template() {
+        let manifest = parse_manifest(TEMPLATE_MPD, &manifest_url()).unwrap();
+
+        assert_eq!(manifest.duration, Some(20f64));
+        let video = &manifest.adaptation_sets[0];
+        assert_eq!(video.content_type, "video");
+        if let SegmentSource::Segments(segments) = &video.representations[1].segments {
+            assert_eq!(segments.len(), 6);
+            assert_eq!(segments[0].url, "https://example.com/talk/720p/init.mp4");
+            assert_eq!(
+                segments[1].url,
+                "https://example.com/talk/720p/seg-00001.m4s"
+            );
+            assert_eq!(
+                segments[5].url,
+                "https://example.com/talk/720p/seg-00005.m4s"
+            );
+        } else {
+            panic!("Expected explicit segments");
+        }
+    }
+
+    #[test]
+    fn test_parse_segment_timeline() {
+        let manifest = parse_manifest(TEMPLATE_MPD, &manifest_url()).unwrap();
+
+        let audio = &manifest.adaptation_sets[1];
+        assert_eq!(audio.content_type, "audio");
+        if let SegmentSource::Segments(segments) = &audio.representations[0].segments {
+            let urls: Vec<&str> = segments.iter().map(|s| s.url.as_str()).collect();
+            assert_eq!(
+                urls,
+                vec![
+                    "https://example.com/talk/audio/init.mp4",
+                    "https://example.com/talk/audio/0.m4s",
+                    "https://example.com/talk/audio/50.m4s",
+                    "https://example.com/talk/audio/100.m4s",
+                ]
+            );
+        } else {
+            panic!("Expected explicit segments");
+        }
+    }
+
+    #[test]
+    fn test_parse_segment_list_and_base() {
+        let manifest = parse_manifest(LIST_MPD, &manifest_url()).unwrap();
+
+        let reps = &manifest.adaptation_sets[0].representations;
+        if let SegmentSource::Segments(segments) = &reps[0].segments {
+            assert_eq!(segments.len(), 3);
+            assert_eq!(segments[0].url, "https://example.com/talk/media/video.mp4");
+            assert_eq!(segments[0].byte_range, Some((0, 999)));
+            assert_eq!(segments[2].byte_range, Some((5000, 8999)));
+        } else {
+            panic!("Expected explicit segments");
+        }
+        match &reps[1].segments {
+            SegmentSource::Base(url) => assert_eq!(url, "https://example.com/talk/media/base.mp4"),
+            _ => panic!("Expected a SegmentBase resource"),
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT1S"), Some(86401f64));
+        assert_eq!(parse_duration("1H"), None);
+    }
+}
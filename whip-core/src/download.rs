@@ -1,4 +1,11 @@
-use reqwest::header;
+use std::sync::{Arc, Mutex};
+
+use reqwest::{
+    header::{self, HeaderMap},
+    redirect, StatusCode,
+};
+
+use crate::{checksum::Checksum, errors::WhipError};
 
 /// A representation of a download task.
 #[derive(Debug, Clone)]
@@ -6,10 +13,14 @@ pub struct DownloadTask {
     pub file_url: String,
     pub percentage_completed: f64,
     pub meta: DownloadMeta,
+    /// Extra headers sent with every request (cookies, authorization...)
+    pub headers: Vec<(String, String)>,
+    /// Expected digest of the complete file
+    pub checksum: Option<Checksum>,
 }
 
 /// Basic information on the file to download.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DownloadMeta {
     pub content_length: u64,
     pub supports_resume: bool,
@@ -17,90 +28,245 @@ pub struct DownloadMeta {
     pub file_name: String,
 }
 
+/// A redirect followed while probing a url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Status of the response that redirected
+    pub status: u16,
+    /// Where it redirected to
+    pub url: String,
+}
+
+/// What the server tells about a url, found without downloading it
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    /// Url that was probed
+    pub url: String,
+    /// Url the redirects led to
+    pub final_url: String,
+    pub redirects: Vec<Redirect>,
+    /// Status of the last response
+    pub status: u16,
+    /// False when the server refused the HEAD request, the rest of the
+    /// report then comes from a GET of the first byte
+    pub head_supported: bool,
+    pub meta: DownloadMeta,
+    /// Accept-Ranges header
+    pub accept_ranges: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub server: Option<String>,
+}
+
 /// Representation of a part of the file to download.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DownloadPart {
-    pub id: u8,
+    /// Position of the part in the file, parts are joined in id order
+    pub id: u32,
     pub start_byte: u64,
     pub end_byte: u64,
     pub file_url: String,
 }
 
+/// Parses a "Name: value" header line
+pub fn parse_header(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), value.trim().to_string()))
+}
+
 impl DownloadTask {
-    pub async fn new(url: String) -> Result<Self, String> {
-        if let Ok(download_meta) = Self::get_file_info(&url).await {
-            return Ok(DownloadTask {
-                file_url: url,
-                percentage_completed: 0f64,
-                meta: download_meta,
+    pub async fn new(url: String) -> Result<Self, WhipError> {
+        Self::with_headers(url, Vec::new()).await
+    }
+
+    /// Profiles a download, sending `headers` with the request.
+    pub async fn with_headers(
+        url: String,
+        headers: Vec<(String, String)>,
+    ) -> Result<Self, WhipError> {
+        let report = Self::get_file_info(&url, &headers).await?;
+        Ok(Self::from_probe(report, headers))
+    }
+
+    /// Task of the url of a probe, `headers` being the ones it was probed with
+    pub fn from_probe(report: ProbeReport, headers: Vec<(String, String)>) -> Self {
+        DownloadTask {
+            file_url: report.url,
+            percentage_completed: 0f64,
+            meta: report.meta,
+            headers,
+            checksum: None,
+        }
+    }
+
+    /// Asks the server about `url` with a HEAD request sending `headers`,
+    /// following and recording the redirects. Servers that refuse HEAD are
+    /// asked for the first byte of the file with a GET.
+    pub async fn get_file_info(
+        url: &str,
+        headers: &[(String, String)],
+    ) -> Result<ProbeReport, WhipError> {
+        let redirects = Arc::new(Mutex::new(Vec::new()));
+        let followed = redirects.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > 10 {
+                return attempt.error("too many redirects");
+            }
+            let mut followed = match followed.lock() {
+                Ok(f) => f,
+                Err(e) => e.into_inner(),
+            };
+            followed.push(Redirect {
+                status: attempt.status().as_u16(),
+                url: attempt.url().to_string(),
+            });
+            attempt.follow()
+        });
+        let client = match reqwest::Client::builder().redirect(policy).build() {
+            Ok(c) => c,
+            Err(e) => return Err(WhipError::request(url, e)),
+        };
+        let send = |mut req: reqwest::RequestBuilder| {
+            for (name, value) in headers {
+                req = req.header(name, value);
+            }
+            req.send()
+        };
+        let mut response = match send(client.head(url)).await {
+            Ok(r) => r,
+            Err(e) => return Err(WhipError::request(url, e)),
+        };
+        // Some servers don't implement HEAD, the headers of their error page
+        // aren't the file's so its first byte is asked for instead
+        let head_unsupported = [StatusCode::METHOD_NOT_ALLOWED, StatusCode::NOT_IMPLEMENTED];
+        let head_supported = !head_unsupported.contains(&response.status());
+        if !head_supported {
+            match redirects.lock() {
+                Ok(mut r) => r.clear(),
+                Err(e) => e.into_inner().clear(),
+            }
+            response = match send(client.get(url).header(header::RANGE, "bytes=0-0")).await {
+                Ok(r) => r,
+                Err(e) => return Err(WhipError::request(url, e)),
+            };
+        }
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(WhipError::Http {
+                status: status.as_u16(),
+                url: url.to_string(),
             });
         }
-        Err(String::from("Error getting file info"))
+        let response_headers = response.headers();
+        let header_value = |name| {
+            response_headers
+                .get(name)
+                .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let redirects = match redirects.lock() {
+            Ok(r) => r.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        Ok(ProbeReport {
+            url: url.to_string(),
+            final_url: response.url().to_string(),
+            redirects,
+            status: status.as_u16(),
+            head_supported,
+            meta: Self::read_meta(url, response_headers),
+            accept_ranges: header_value(header::ACCEPT_RANGES),
+            etag: header_value(header::ETAG),
+            last_modified: header_value(header::LAST_MODIFIED),
+            server: header_value(header::SERVER),
+        })
     }
 
-    /// Gets some basic informations on the file to download.
+    /// Gets some basic informations on the file to download from the
+    /// headers of the response to a request of `url`.
     /// File size, file name, content type and check if we
     /// can make partial downloads.
-    async fn get_file_info(url: &str) -> Result<DownloadMeta, ()> {
-        let client = reqwest::Client::new();
-        if let Ok(response) = client.head(url).send().await {
-            let mut meta = DownloadMeta {
-                content_length: 0,
-                supports_resume: false,
-                content_type: String::new(),
-                file_name: String::new(),
-            };
+    pub(crate) fn read_meta(url: &str, headers: &HeaderMap) -> DownloadMeta {
+        let mut meta = DownloadMeta {
+            content_length: 0,
+            supports_resume: false,
+            content_type: String::new(),
+            file_name: String::new(),
+        };
 
-            // Get size (Bytes)
-            if let Some(content_length) = response.headers().get(header::CONTENT_LENGTH) {
-                if !content_length.is_empty() {
-                    meta.content_length = String::from(content_length.to_str().unwrap())
-                        .parse::<u64>()
-                        .unwrap_or(meta.content_length);
-                }
+        // Get size (Bytes)
+        if let Some(content_length) = headers.get(header::CONTENT_LENGTH) {
+            if !content_length.is_empty() {
+                meta.content_length = content_length
+                    .to_str()
+                    .ok()
+                    .and_then(|l| l.trim().parse::<u64>().ok())
+                    .unwrap_or(meta.content_length);
             }
+        }
 
-            // Get content type
-            if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
-                if !content_type.is_empty() {
-                    meta.content_type =
-                        String::from(content_type.to_str().unwrap_or(&meta.content_type).trim())
-                }
+        // A ranged response, "bytes 0-0/size", tells the size of the whole file
+        let mut ranged = false;
+        if let Some(content_range) = headers.get(header::CONTENT_RANGE) {
+            let size = content_range
+                .to_str()
+                .ok()
+                .and_then(|r| r.trim().strip_prefix("bytes "))
+                .and_then(|r| r.split_once('/'))
+                .and_then(|(_, size)| size.parse::<u64>().ok());
+            if let Some(size) = size {
+                meta.content_length = size;
+                ranged = true;
             }
+        }
 
-            // Check if supports partial download
-            if let Some(accept_ranges) = response.headers().get(header::ACCEPT_RANGES) {
-                if !accept_ranges.is_empty() && meta.content_length > 0 {
-                    meta.supports_resume = true;
-                }
+        // Get content type
+        if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
+            if !content_type.is_empty() {
+                meta.content_type =
+                    String::from(content_type.to_str().unwrap_or(&meta.content_type).trim())
+            }
+        }
+
+        // Check if supports partial download, "none" says it doesn't
+        if let Some(accept_ranges) = headers.get(header::ACCEPT_RANGES) {
+            if !accept_ranges.is_empty() && accept_ranges != "none" && meta.content_length > 0 {
+                meta.supports_resume = true;
             }
+        }
+        if ranged && meta.content_length > 0 {
+            meta.supports_resume = true;
+        }
 
-            // Get file name (Might not be present)
-            if let Some(content_disposition) = response.headers().get(header::CONTENT_DISPOSITION) {
-                if !content_disposition.is_empty() {
-                    let cd = String::from(content_disposition.to_str().unwrap_or("").trim());
-                    if cd.to_lowercase().contains("filename=") {
-                        let cd_parts = cd.split(';');
-                        for p in cd_parts {
-                            if let Some(index) = p.find("filename=") {
-                                meta.file_name = p[index + "filename=".len()..].to_string();
-                                break;
-                            }
+        // Get file name (Might not be present)
+        if let Some(content_disposition) = headers.get(header::CONTENT_DISPOSITION) {
+            if !content_disposition.is_empty() {
+                let cd = String::from(content_disposition.to_str().unwrap_or("").trim());
+                if cd.to_lowercase().contains("filename=") {
+                    let cd_parts = cd.split(';');
+                    for p in cd_parts {
+                        if let Some(index) = p.find("filename=") {
+                            meta.file_name = p[index + "filename=".len()..].to_string();
+                            break;
                         }
                     }
                 }
             }
+        }
 
-            if meta.file_name.is_empty() {
-                meta.file_name = Self::get_file_name_from_url(url).unwrap();
-            }
+        if meta.file_name.is_empty() {
+            meta.file_name = Self::get_file_name_from_url(url).unwrap();
+        }
 
-            meta.file_name = meta.file_name.replace('\"', "");
-            meta.file_name = meta.file_name.replace("/", "");
+        meta.file_name = meta.file_name.replace('\"', "");
+        meta.file_name = meta.file_name.replace("/", "");
 
-            return Ok(meta);
-        }
-        Err(())
+        meta
     }
 
     /// Gets a file name from a dowload url
@@ -118,8 +284,9 @@ impl DownloadTask {
         Ok(String::from("Unknown_File"))
     }
 
-    /// Returns the download parts to download
-    pub fn get_download_parts(&self, mut thread_count: u64) -> Vec<DownloadPart> {
+    /// Splits the file into `part_count` parts of at least 1MB each
+    pub fn get_download_parts(&self, part_count: u32) -> Vec<DownloadPart> {
+        let mut part_count = part_count.max(1) as u64;
         let mut download_parts = Vec::new();
 
         if self.meta.content_length == 0 || !self.meta.supports_resume {
@@ -132,18 +299,16 @@ impl DownloadTask {
             return download_parts;
         }
 
-        while self.meta.content_length / thread_count < 1000000 && thread_count > 1 {
-            thread_count -= 1;
-        }
+        part_count = part_count.min((self.meta.content_length / 1000000).max(1));
 
-        let part_size = self.meta.content_length / thread_count;
+        let part_size = self.meta.content_length / part_count;
 
-        for i in 0..thread_count {
+        for i in 0..part_count {
             let start = i * part_size;
             download_parts.push(DownloadPart {
-                id: i as u8,
+                id: i as u32,
                 start_byte: start,
-                end_byte: if i + 1 != thread_count {
+                end_byte: if i + 1 != part_count {
                     start + part_size - 1
                 } else {
                     self.meta.content_length
@@ -190,6 +355,82 @@ mod tests {
         assert_eq!(result, String::from("Unknown_File"));
     }
 
+    #[test]
+    fn test_read_meta() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, "3000000".parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, " application/zip ".parse().unwrap());
+        headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"report.zip\"".parse().unwrap(),
+        );
+        let meta = DownloadTask::read_meta("https://hello.com/download?id=3", &headers);
+        assert_eq!(meta.content_length, 3000000);
+        assert_eq!(meta.content_type, "application/zip");
+        assert_eq!(meta.file_name, "report.zip");
+        assert!(meta.supports_resume);
+
+        headers.insert(header::ACCEPT_RANGES, "none".parse().unwrap());
+        headers.remove(header::CONTENT_DISPOSITION);
+        let meta = DownloadTask::read_meta("https://hello.com/files/disk.img", &headers);
+        assert_eq!(meta.file_name, "disk.img");
+        assert!(!meta.supports_resume);
+
+        // The answer to a GET of the first byte
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, "1".parse().unwrap());
+        headers.insert(header::CONTENT_RANGE, "bytes 0-0/5000".parse().unwrap());
+        let meta = DownloadTask::read_meta("https://hello.com/files/disk.img", &headers);
+        assert_eq!(meta.content_length, 5000);
+        assert!(meta.supports_resume);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_LENGTH,
+            header::HeaderValue::from_bytes(b"30\xff").unwrap(),
+        );
+        let meta = DownloadTask::read_meta("https://hello.com/files/disk.img", &headers);
+        assert_eq!(meta.content_length, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_info_without_head() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let response = if request.starts_with("head ") {
+                    "HTTP/1.1 405 Method Not Allowed\r\nContent-Type: text/html\r\nContent-Length: 150\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n"
+                } else if request.contains("range: bytes=0-0") {
+                    "HTTP/1.1 206 Partial Content\r\nContent-Type: application/octet-stream\r\nContent-Length: 1\r\nContent-Range: bytes 0-0/5000\r\nConnection: close\r\n\r\na"
+                } else {
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        let url = format!("http://127.0.0.1:{}/disk.img", port);
+        let report = DownloadTask::get_file_info(&url, &[]).await.unwrap();
+        assert!(!report.head_supported);
+        assert_eq!(report.status, 206);
+        assert_eq!(report.meta.content_length, 5000);
+        assert_eq!(report.meta.content_type, "application/octet-stream");
+        assert!(report.meta.supports_resume);
+    }
+
     #[test]
     fn test_get_download_parts_1() {
         let task = DownloadTask {
@@ -203,6 +444,8 @@ mod tests {
                 content_type: String::from("application/zip"),
                 file_name: String::from("bugza.zip"),
             },
+            headers: Vec::new(),
+            checksum: None,
         };
 
         let result = task.get_download_parts(4);
@@ -221,6 +464,8 @@ mod tests {
                 content_type: String::from("application/x-gzip"),
                 file_name: String::from("go1.18.3.linux-amd64.tar.gz"),
             },
+            headers: Vec::new(),
+            checksum: None,
         };
 
         let result = task.get_download_parts(4);
@@ -240,10 +485,35 @@ mod tests {
                 content_type: String::from("application/zip"),
                 file_name: String::from("smallFile.zip"),
             },
+            headers: Vec::new(),
+            checksum: None,
         };
 
         let result = task.get_download_parts(4);
 
         assert_eq!(result.len(), 1);
     }
+
+    #[test]
+    fn test_get_download_parts_4() {
+        let task = DownloadTask {
+            file_url: String::from("https://hello.com/disk.img"),
+            percentage_completed: 0f64,
+            meta: DownloadMeta {
+                content_length: 4_000_000_000,
+                supports_resume: true,
+                content_type: String::from("application/octet-stream"),
+                file_name: String::from("disk.img"),
+            },
+            headers: Vec::new(),
+            checksum: None,
+        };
+
+        let result = task.get_download_parts(3000);
+
+        assert_eq!(result.len(), 3000);
+        assert_eq!(result[2999].id, 2999);
+        assert_eq!(result[2999].start_byte, result[2998].end_byte + 1);
+        assert_eq!(result[2999].end_byte, task.meta.content_length);
+    }
 }
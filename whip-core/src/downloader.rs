@@ -1,12 +1,13 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, VecDeque},
     fs::remove_file,
     io::SeekFrom,
-    path::{PathBuf, MAIN_SEPARATOR},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
+use fs2::FileExt;
 use futures::{join, AsyncReadExt, AsyncWriteExt, StreamExt};
 use tokio::{
     fs,
@@ -18,10 +19,13 @@ use tokio::{
 use reqwest::{header, Client, Response, StatusCode};
 
 use crate::{
+    checkpoint::{Checkpoint, Checkpointer, PartCheckpoint, PartState, PartTracker, SpeedMeter},
+    control::DownloadControl,
     download::{DownloadPart, DownloadTask},
     errors::WhipError,
     event::{CompleteStats, Event},
-    storage::{FileStorage, MemoryStorage, Storage},
+    progress::{Progress, ProgressMeter},
+    storage::{check_disk_space, FileStorage, MemoryStorage, Storage},
 };
 
 #[derive(Debug)]
@@ -35,9 +39,9 @@ pub enum SessionState {
 #[derive(Debug)]
 pub struct Downloader<P>
 where
-    P: std::marker::Send + std::marker::Sync + FnMut(f64) -> () + 'static,
+    P: std::marker::Send + std::marker::Sync + FnMut(&Progress) + 'static,
 {
-    /// Current download progress
+    /// Bytes downloaded so far
     progress: f64,
     /// Current state of the session
     state: SessionState,
@@ -49,29 +53,50 @@ where
     pub task: DownloadTask,
     /// Callback for getting download progress updates
     pub on_progress_change: P,
+    /// Time between two calls of on_progress_change
+    pub progress_interval: Duration,
     /// Callback for getting final file path on completion
     pub on_complete: fn(String) -> (),
+    /// Callback for the errors of parts failing after the first one,
+    /// which download returns
     pub on_error: fn(WhipError) -> (),
+    /// First error of a part
+    error: Option<WhipError>,
     /// Status of the current download session
     completed: bool,
     /// Use in memory storage to store each part (Takes precedence over temp_dir)
     pub use_in_memory_storage: bool,
-    /// Parts that have completed successfully
-    completed_downloads: HashMap<u8, CompleteStats>,
-    /// Max number of threads to use
-    max_threads: u8,
+    /// Reserve the space of the final file before downloading (fallocate on Linux)
+    pub preallocate: bool,
+    /// Parts that have completed successfully, ordered by id
+    completed_downloads: BTreeMap<u32, CompleteStats>,
+    /// Max number of parts downloaded at the same time
+    max_threads: u32,
+    /// Number of parts to split the file into
+    part_count: u32,
     /// Total number of download parts
-    total_download_parts: u8,
+    total_download_parts: u32,
     /// Maximum retry request for a file part
     max_retries: u8,
     retry_download: bool,
+    /// Holds and throttles the parts from outside of the session
+    control: DownloadControl,
+    /// Receives the progress of the session at a regular interval
+    pub checkpointer: Option<Checkpointer>,
+    /// Progress of each part
+    parts: PartTracker,
+    /// Bytes each part had from a previous session when it started
+    resumed: BTreeMap<u32, u64>,
+    /// Measures the speeds of the progress snapshots
+    meter: ProgressMeter,
 }
 
 impl<P> Downloader<P>
 where
-    P: std::marker::Send + std::marker::Sync + FnMut(f64) -> () + 'static,
+    P: std::marker::Send + std::marker::Sync + FnMut(&Progress) + 'static,
 {
     /// Creates a download
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         task: DownloadTask,
         output_dir: String,
@@ -80,7 +105,8 @@ where
         on_complete: fn(String) -> (),
         on_error: fn(WhipError) -> (),
         use_in_memory_storage: bool,
-        max_threads: u8,
+        max_threads: u32,
+        part_count: u32,
         max_retries: u8,
     ) -> Result<Self, WhipError> {
         let output_path = PathBuf::from(output_dir);
@@ -102,20 +128,32 @@ where
             temp_dir: temp_path,
             task,
             on_progress_change,
+            progress_interval: Duration::from_millis(200),
             use_in_memory_storage,
+            preallocate: false,
             state: SessionState::Download,
-            completed_downloads: HashMap::new(),
-            max_threads: max_threads,
+            completed_downloads: BTreeMap::new(),
+            max_threads: max_threads.max(1),
+            part_count,
             on_complete,
             on_error,
-            total_download_parts: max_threads,
+            error: None,
+            total_download_parts: part_count,
             max_retries,
             retry_download: false,
+            control: DownloadControl::new(),
+            checkpointer: None,
+            parts: PartTracker::new(),
+            resumed: BTreeMap::new(),
+            meter: ProgressMeter::new(),
         })
     }
 
-    /// Restore the state of a download session.
-
+    /// Restore the state of a download session. `part_count` has to be the
+    /// one the session was created with, so the temporary parts line up.
+    /// `progress` is in bytes, not counting the temporary parts: their
+    /// size is added once they're opened again.
+    #[allow(clippy::too_many_arguments)]
     pub fn restore(
         progress: f64,
         task: DownloadTask,
@@ -125,7 +163,8 @@ where
         on_complete: fn(String) -> (),
         on_error: fn(WhipError) -> (),
         use_in_memory_storage: bool,
-        max_threads: u8,
+        max_threads: u32,
+        part_count: u32,
         max_retries: u8,
     ) -> Downloader<P> {
         Downloader {
@@ -135,15 +174,24 @@ where
             temp_dir: PathBuf::from(temp_dir),
             task,
             on_progress_change,
+            progress_interval: Duration::from_millis(200),
             on_complete,
             on_error,
+            error: None,
             completed: false,
             use_in_memory_storage,
-            completed_downloads: HashMap::new(),
-            max_threads,
-            total_download_parts: max_threads,
+            preallocate: false,
+            completed_downloads: BTreeMap::new(),
+            max_threads: max_threads.max(1),
+            part_count,
+            total_download_parts: part_count,
             max_retries,
             retry_download: false,
+            control: DownloadControl::new(),
+            checkpointer: None,
+            parts: PartTracker::new(),
+            resumed: BTreeMap::new(),
+            meter: ProgressMeter::new(),
         }
     }
 
@@ -155,39 +203,182 @@ where
         self.state = SessionState::Download;
     }
 
+    /// Handle to hold, release and throttle the download while it runs.
+    /// Unlike pause, holding doesn't end the download.
+    pub fn control(&self) -> DownloadControl {
+        self.control.clone()
+    }
+
+    /// Progress of the parts, updated while the download runs
+    pub fn parts(&self) -> PartTracker {
+        self.parts.clone()
+    }
+
     /// Logic for downloading the file. Returns the number of bytes downloaded
-    /// if download was succesful and an error if otherwise.
+    /// if download was succesful and the error of the first failed part if otherwise.
+    /// Fails before any request is made if the disks can't hold the file.
     pub async fn download(mut self) -> Result<f64, WhipError> {
+        self.reserve_disk_space()?;
         let client = Arc::from(reqwest::Client::new());
-        let parts = self.task.get_download_parts(self.max_threads as u64);
-        self.total_download_parts = parts.len() as u8;
-        self.total_download_parts = parts.len() as u8;
+        let parts = self.task.get_download_parts(self.part_count);
+        self.total_download_parts = parts.len() as u32;
+        self.parts.reset(
+            parts
+                .iter()
+                .map(|p| PartCheckpoint {
+                    id: p.id,
+                    start_byte: p.start_byte,
+                    end_byte: p.end_byte,
+                    bytes_done: 0,
+                    state: PartState::Pending,
+                })
+                .collect(),
+        );
+        let workers = self.max_threads.min(self.total_download_parts);
         let session = Arc::from(Mutex::from(self));
+        let queue = Arc::new(Mutex::new(
+            parts.into_iter().collect::<VecDeque<DownloadPart>>(),
+        ));
+
+        let control = session.lock().await.control.clone();
 
         let mut join_handles = Vec::new();
-        for mut p in parts.into_iter() {
+        for _ in 0..workers {
             let s = session.clone();
             let c = client.clone();
+            let q = queue.clone();
+            let control = control.clone();
             let h = task::spawn(async move {
-                if let Err(e) = Downloader::download_part(&s, c, &mut p).await {
-                    let mut ses = s.lock().await;
-                    ses.retry_download = true;
-                    (ses.on_error)(e);
-                    ses.completed = false;
-                };
+                loop {
+                    control.wait_released().await;
+                    if control.is_stopped() {
+                        break;
+                    }
+                    if let SessionState::Pause = s.lock().await.state {
+                        break;
+                    }
+                    let part = match q.lock().await.pop_front() {
+                        Some(p) => p,
+                        None => break,
+                    };
+                    let mut p = part.clone();
+                    match Downloader::download_part(&s, c.clone(), &mut p).await {
+                        Ok(()) if control.is_stopped() => {
+                            let ses = s.lock().await;
+                            if !ses.completed_downloads.contains_key(&part.id) {
+                                ses.parts.update(part.id, |p| p.state = PartState::Pending);
+                            }
+                        }
+                        // Cut by a hold, it starts again from what it wrote once released
+                        Ok(()) if control.is_held() => {
+                            let ses = s.lock().await;
+                            if !ses.completed_downloads.contains_key(&part.id) {
+                                ses.parts.update(part.id, |p| p.state = PartState::Pending);
+                                q.lock().await.push_front(part);
+                            }
+                        }
+                        Ok(()) => {}
+                        Err(e) => {
+                            let mut ses = s.lock().await;
+                            ses.parts.update(part.id, |p| p.state = PartState::Failed);
+                            ses.retry_download = true;
+                            ses.completed = false;
+                            match ses.error {
+                                None => ses.error = Some(e),
+                                Some(_) => (ses.on_error)(e),
+                            }
+                        }
+                    };
+                }
             });
             join_handles.push(h);
         }
 
+        let checkpointer = session.lock().await.checkpointer.clone();
+        let ticker = checkpointer.map(|checkpointer| {
+            let s = session.clone();
+            task::spawn(async move {
+                let mut speed = SpeedMeter::new();
+                let mut interval = tokio::time::interval(checkpointer.interval);
+                loop {
+                    interval.tick().await;
+                    let checkpoint = s.lock().await.checkpoint(&checkpointer, &mut speed);
+                    if !checkpointer.send(checkpoint) {
+                        break;
+                    }
+                }
+            })
+        });
+
+        let reporter = {
+            let s = session.clone();
+            let period = s.lock().await.progress_interval;
+            task::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    s.lock().await.report_progress();
+                }
+            })
+        };
+
+        let mut joined = Ok(());
         for j in join_handles {
             let res = join!(j);
             if let Err(e) = res.0 {
-                return Err(WhipError::Unknown(e.to_string()));
+                joined = Err(WhipError::Unknown(e.to_string()));
+                break;
             }
         }
 
-        let progress = session.lock().await.progress;
-        Ok(progress)
+        // The last checkpoint tells where the session stopped, the sender goes with it
+        if let Some(t) = ticker {
+            t.abort();
+            let _ = t.await;
+        }
+        reporter.abort();
+        let _ = reporter.await;
+        let mut session = session.lock().await;
+        // Completion was reported already
+        if !session.completed {
+            session.report_progress();
+        }
+        if let Some(checkpointer) = session.checkpointer.take() {
+            let mut checkpoint = session.checkpoint(&checkpointer, &mut SpeedMeter::new());
+            checkpoint.speed = 0f64;
+            checkpointer.send(checkpoint);
+        }
+
+        joined?;
+        if control.is_stopped() && !session.completed {
+            return Err(WhipError::Interrupted);
+        }
+        if let Some(e) = session.error.take() {
+            return Err(e);
+        }
+        Ok(session.progress)
+    }
+
+    /// Progress of the session for `checkpointer`
+    fn checkpoint(&self, checkpointer: &Checkpointer, speed: &mut SpeedMeter) -> Checkpoint {
+        let bytes_done = self.parts.bytes_done();
+        Checkpoint {
+            task_id: checkpointer.task_id,
+            bytes_done,
+            total_bytes: self.task.meta.content_length,
+            speed: speed.sample(bytes_done),
+            parts: self.parts.snapshot(),
+        }
+    }
+
+    /// Calls on_progress_change with a snapshot of the session
+    fn report_progress(&mut self) {
+        let progress = self.meter.measure(
+            self.parts.snapshot(),
+            &self.resumed,
+            self.task.meta.content_length,
+        );
+        (self.on_progress_change)(&progress);
     }
 
     async fn download_part(
@@ -200,6 +391,8 @@ where
         ));
 
         let mut sess = session.lock().await;
+        sess.parts
+            .update(download_part.id, |p| p.state = PartState::Downloading);
 
         if !sess.use_in_memory_storage {
             if let Some(value) = sess.setup_file_storage(&mut storage, download_part).await {
@@ -216,25 +409,25 @@ where
 
         let task = sess.task.clone();
         let max_retries = sess.max_retries;
+        let part_path = sess.part_path(download_part.id);
+        let control = sess.control.clone();
+        // Bytes the part had before this call, resumed from its temporary file
+        let parts = sess.parts.clone();
+        let resumed = match parts.get(download_part.id) {
+            Some(p) => p.bytes_done,
+            None => 0,
+        };
         drop(sess);
 
-        let mut response: Response;
+        let response: Response;
         let mut retries = 0;
         let mut interval = tokio::time::interval(Duration::from_secs(10));
         interval.tick().await;
 
         loop {
-            if retries > max_retries {
-                return Err(WhipError::NetWork("Max retries reached".to_string()));
-            }
-
-            response = match request_file(&client, &task, download_part).await {
-                Ok(r) => r,
-                Err(value) => return value,
-            };
-
-            if ![StatusCode::OK, StatusCode::PARTIAL_CONTENT].contains(&response.status()) {
-                if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let result = match request_file(&client, &task, download_part).await {
+                Ok(r) if r.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    // Wait for another part to be done before asking again
                     loop {
                         interval.tick().await;
                         let sess = session.lock().await;
@@ -242,55 +435,107 @@ where
                             break;
                         }
                     }
-                } else {
-                    return Err(WhipError::NetWork(
-                        response
-                            .status()
-                            .canonical_reason()
-                            .unwrap_or("Error fetching file from server")
-                            .to_string(),
-                    ));
+                    Err(WhipError::Http {
+                        status: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+                        url: task.file_url.to_owned(),
+                    })
                 }
-            } else {
-                if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
-                    if content_type.to_str().unwrap_or("").contains(&"text/html") {
-                        return Err(WhipError::NetWork(
-                            "Download link expired or link doesn't point to a file. Update link to resume download".to_string(),
-                        ));
+                Ok(r) => check_response(&task, download_part, r),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(r) => {
+                    response = r;
+                    break;
+                }
+                Err(e) if e.is_retryable() && retries < max_retries => {
+                    retries += 1;
+                    if !matches!(e, WhipError::Http { status: 429, .. }) {
+                        interval.tick().await;
                     }
-                };
-                break;
+                }
+                Err(e) => return Err(e),
             }
-            retries += 1;
         }
 
         let mut bytes_stream = response.bytes_stream();
+        // Progress reported by this call, taken back if a hold drops data kept in memory
+        let mut reported = 0f64;
+        let mut written = 0u64;
 
-        while let Some(data) = bytes_stream.next().await {
-            if let Ok(bytes) = data {
-                let bytes_length = bytes.len();
-                match storage {
-                    Storage::InMemory(ref mut s) => {
-                        if let Err(e) = s.cursor.write_all(&bytes).await {
-                            return Err(WhipError::Storage(e.to_string()));
-                        }
+        // Set when a hold or a stop cuts the part before its end
+        let mut cut = false;
+
+        loop {
+            let data = tokio::select! {
+                data = bytes_stream.next() => data,
+                // A stop doesn't wait for a slow server to send more
+                _ = control.wait_stopped() => {
+                    cut = true;
+                    None
+                }
+            };
+            let bytes = match data {
+                Some(Ok(b)) => b,
+                // What was written so far is kept, the part resumes from there
+                Some(Err(e)) => return Err(WhipError::request(&task.file_url, e)),
+                None => break,
+            };
+            let bytes_length = bytes.len();
+            written += bytes_length as u64;
+            parts.update(download_part.id, |p| p.bytes_done = resumed + written);
+            match storage {
+                Storage::InMemory(ref mut s) => {
+                    if let Err(e) = s.cursor.write_all(&bytes).await {
+                        return Err(WhipError::Unknown(e.to_string()));
                     }
+                }
 
-                    Storage::File(ref mut f) => {
-                        if let Err(e) = f.file.write_all(&bytes).await {
-                            return Err(WhipError::Storage(e.to_string()));
+                Storage::File(ref mut f) => {
+                    if let Err(e) = f.file.write_all(&bytes).await {
+                        let e = WhipError::io(&part_path, e);
+                        if let WhipError::DiskFull { .. } = e {
+                            // Keep what was written so far, the task resumes once space is freed
+                            session.lock().await.pause();
                         }
+                        return Err(e);
+                    }
+                }
+            };
+            if let Ok(mut s) = session.try_lock() {
+                s.on_event(Event::ProgressChanged(bytes_length as f64))
+                    .await
+                    .unwrap();
+                reported += bytes_length as f64;
+                if let SessionState::Pause = s.state {
+                    return Ok(());
+                }
+            }
+            control.throttle(bytes_length).await;
+            if control.is_held() || control.is_stopped() {
+                cut = true;
+                break;
+            }
+        }
+
+        if cut {
+            match storage {
+                Storage::InMemory(_) => {
+                    session.lock().await.progress -= reported;
+                    parts.update(download_part.id, |p| p.bytes_done = resumed);
+                }
+                // Written down for good before the process may exit
+                Storage::File(ref mut f) => {
+                    if let Err(e) = f.file.flush().await {
+                        return Err(WhipError::io(&part_path, e));
                     }
-                };
-                if let Ok(mut s) = session.try_lock() {
-                    s.on_event(Event::ProgressChanged(bytes_length as f64))
-                        .await
-                        .unwrap();
-                    if let SessionState::Pause = s.state {
-                        return Ok(());
+                    if let Err(e) = f.file.sync_all().await {
+                        return Err(WhipError::io(&part_path, e));
                     }
                 }
             }
+            return Ok(());
         }
 
         session
@@ -310,13 +555,7 @@ where
         storage: &mut Storage,
         download_part: &mut DownloadPart,
     ) -> Option<Result<(), WhipError>> {
-        let mut temp_file_path = PathBuf::new();
-        temp_file_path.push(&self.temp_dir);
-        temp_file_path.push(format!(
-            "{filename}.{part_id}",
-            filename = &self.task.meta.file_name,
-            part_id = download_part.id
-        ));
+        let temp_file_path = self.part_path(download_part.id);
 
         let mut append = false;
 
@@ -325,10 +564,15 @@ where
                 self.on_event(Event::ProgressChanged(metadata.len() as f64))
                     .await
                     .unwrap();
+                self.parts
+                    .update(download_part.id, |p| p.bytes_done = metadata.len());
+                self.resumed
+                    .entry(download_part.id)
+                    .or_insert_with(|| metadata.len());
                 if metadata.len() >= (download_part.end_byte - download_part.start_byte) {
                     return Some(Ok(()));
                 }
-                download_part.start_byte = metadata.len();
+                download_part.start_byte += metadata.len();
                 append = true;
             }
         }
@@ -342,13 +586,7 @@ where
             .await
         {
             Ok(file) => file,
-            Err(e) => {
-                return Some(Err(WhipError::Storage(format!(
-                    "{} : {}",
-                    e.to_string(),
-                    temp_file_path.to_string_lossy().to_string()
-                ))));
-            }
+            Err(e) => return Some(Err(WhipError::io(temp_file_path, e))),
         };
 
         *storage = Storage::File(FileStorage { file });
@@ -359,17 +597,33 @@ where
         match event {
             Event::ProgressChanged(progress) => {
                 self.progress += progress;
-                (self.on_progress_change)(
-                    (self.progress / self.task.meta.content_length as f64) * 100f64,
-                );
             }
             Event::Complete(stats) => {
                 self.retry_download = true;
+                self.parts
+                    .update(stats.part_id, |p| p.state = PartState::Completed);
                 self.completed_downloads.insert(stats.part_id, stats);
-                if self.completed_downloads.len() >= self.total_download_parts.into() {
+                if self.completed_downloads.len() >= self.total_download_parts as usize {
                     let f_name = self.concatenate_files().await?;
-                    self.progress = self.task.meta.content_length as f64;
-                    (self.on_progress_change)(100f64);
+                    if let Some(checksum) = self.task.checksum.clone() {
+                        if !checksum.verify_file(&f_name).await? {
+                            // The parts are corrupt, the next attempt has to start over
+                            if let Err(e) = fs::remove_file(&f_name).await {
+                                eprintln!("{} : {}", e, f_name.to_string_lossy());
+                            }
+                            self.remove_temp_files();
+                            self.completed = false;
+                            self.progress = 0f64;
+                            return Err(WhipError::ChecksumMismatch {
+                                algorithm: checksum.algorithm,
+                                resource: f_name.to_string_lossy().to_string(),
+                            });
+                        }
+                    }
+                    // The size of some files is only known once they're downloaded
+                    self.progress =
+                        self.task.meta.content_length.max(self.parts.bytes_done()) as f64;
+                    self.report_progress();
                     (self.on_complete)(f_name.to_string_lossy().to_string());
                 }
             }
@@ -377,45 +631,142 @@ where
         Ok(())
     }
 
-    async fn concatenate_files(&mut self) -> Result<PathBuf, WhipError> {
+    fn final_file_path(&self) -> PathBuf {
         let mut f_path = PathBuf::new();
         f_path.push(&self.output_dir);
         f_path.push(&self.task.meta.file_name);
-        if let Ok(mut file) = fs::OpenOptions::new()
+        f_path
+    }
+
+    /// Checks the space left on the temporary and output directories
+    /// against what remains to download, then preallocates the final file
+    /// if asked to. Downloads of unknown size are let through.
+    fn reserve_disk_space(&self) -> Result<(), WhipError> {
+        let content_length = self.task.meta.content_length;
+        if content_length == 0 {
+            return Ok(());
+        }
+
+        let f_path = self.final_file_path();
+        // A previous session may have preallocated the final file already
+        let allocated = f_path.metadata().map(|m| m.len()).unwrap_or(0);
+        let temp_dir = if self.use_in_memory_storage {
+            None
+        } else {
+            Some(self.temp_dir.as_path())
+        };
+        check_disk_space(
+            temp_dir,
+            content_length.saturating_sub(self.bytes_on_disk()),
+            &self.output_dir,
+            content_length.saturating_sub(allocated),
+        )?;
+
+        if self.preallocate {
+            let file = match std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&f_path)
+            {
+                Ok(f) => f,
+                Err(e) => return Err(WhipError::io(f_path, e)),
+            };
+            if let Err(e) = file.allocate(content_length) {
+                return Err(WhipError::io(f_path, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of the temporary parts a previous session left, which the
+    /// download goes on from
+    fn bytes_on_disk(&self) -> u64 {
+        if self.use_in_memory_storage || !self.task.meta.supports_resume {
+            return 0;
+        }
+        (0..self.part_count)
+            .filter_map(|id| self.part_path(id).metadata().ok())
+            .map(|m| m.len())
+            .sum()
+    }
+
+    /// Path of the temporary file of a part
+    fn part_path(&self, part_id: u32) -> PathBuf {
+        let mut f_path = PathBuf::new();
+        f_path.push(&self.temp_dir);
+        f_path.push(format!(
+            "{filename}.{part_id}",
+            filename = &self.task.meta.file_name,
+            part_id = part_id
+        ));
+        f_path
+    }
+
+    /// Removes the parts stored in the temporary directory
+    fn remove_temp_files(&self) {
+        if self.use_in_memory_storage {
+            return;
+        }
+        for i in 0..self.total_download_parts {
+            let f_path = self.part_path(i);
+            if let Err(e) = remove_file(&f_path) {
+                eprintln!("{} : {}", e, f_path.to_string_lossy());
+            };
+        }
+    }
+
+    async fn concatenate_files(&mut self) -> Result<PathBuf, WhipError> {
+        let f_path = self.final_file_path();
+        // Truncating would release the preallocated space, trim the file once written instead
+        let mut file = match fs::OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(!self.preallocate)
             .open(&f_path)
             .await
         {
-            for i in 0..self.completed_downloads.len() {
-                let mut buffer = Vec::new();
-                match self
-                    .completed_downloads
-                    .get_mut(&(i as u8))
-                    .unwrap()
-                    .storage
-                {
-                    Storage::File(ref mut fs) => {
-                        fs.file.seek(SeekFrom::Start(0)).await.unwrap();
-                        fs.file.read_to_end(&mut buffer).await.unwrap();
-                    }
-                    Storage::InMemory(ref mut ms) => {
-                        ms.cursor.set_position(0);
-                        ms.cursor.read_to_end(&mut buffer).await.unwrap();
-                    }
+            Ok(file) => file,
+            Err(e) => return Err(WhipError::io(f_path, e)),
+        };
+        let mut written = 0;
+        let ids: Vec<u32> = self.completed_downloads.keys().copied().collect();
+        for id in ids {
+            let part_path = self.part_path(id);
+            let stats = match self.completed_downloads.get_mut(&id) {
+                Some(s) => s,
+                None => continue,
+            };
+            let mut buffer = Vec::new();
+            let read = match stats.storage {
+                Storage::File(ref mut fs) => match fs.file.seek(SeekFrom::Start(0)).await {
+                    Ok(_) => fs.file.read_to_end(&mut buffer).await,
+                    Err(e) => Err(e),
+                },
+                Storage::InMemory(ref mut ms) => {
+                    ms.cursor.set_position(0);
+                    ms.cursor.read_to_end(&mut buffer).await
                 }
-                if !buffer.is_empty() {
-                    if let Err(e) = file.write_all(&buffer).await {
-                        return Err(WhipError::Storage(e.to_string()));
+            };
+            if let Err(e) = read {
+                return Err(WhipError::io(part_path, e));
+            }
+            if !buffer.is_empty() {
+                if let Err(e) = file.write_all(&buffer).await {
+                    let e = WhipError::io(&f_path, e);
+                    if let WhipError::DiskFull { .. } = e {
+                        self.pause();
                     }
+                    return Err(e);
                 }
+                written += buffer.len() as u64;
             }
-            self.completed = true;
-            return Ok(f_path);
         }
-        Err(WhipError::Storage(
-            "Error creating download file".to_string(),
-        ))
+        if let Err(e) = file.set_len(written).await {
+            return Err(WhipError::io(f_path, e));
+        }
+        self.completed = true;
+        Ok(f_path)
     }
 }
 
@@ -423,8 +774,11 @@ async fn request_file(
     client: &Arc<Client>,
     task: &DownloadTask,
     download_part: &mut DownloadPart,
-) -> Result<reqwest::Response, Result<(), WhipError>> {
+) -> Result<reqwest::Response, WhipError> {
     let mut req = client.get(&task.file_url);
+    for (name, value) in task.headers.iter() {
+        req = req.header(name, value);
+    }
     if task.meta.supports_resume {
         req = req.header(
             header::RANGE,
@@ -437,23 +791,89 @@ async fn request_file(
     }
     let response = match req.send().await {
         Ok(r) => r,
-        Err(e) => return Err(Err(WhipError::NetWork(e.to_string()))),
+        Err(e) => return Err(WhipError::request(&task.file_url, e)),
     };
     Ok(response)
 }
 
+/// Makes sure a response holds the requested part of the file
+fn check_response(
+    task: &DownloadTask,
+    download_part: &DownloadPart,
+    response: Response,
+) -> Result<Response, WhipError> {
+    let url = task.file_url.to_owned();
+    if ![StatusCode::OK, StatusCode::PARTIAL_CONTENT].contains(&response.status()) {
+        return Err(WhipError::Http {
+            status: response.status().as_u16(),
+            url,
+        });
+    }
+    if let Some(content_type) = response.headers().get(header::CONTENT_TYPE) {
+        if content_type.to_str().unwrap_or("").contains("text/html") {
+            return Err(WhipError::LinkExpired { url });
+        }
+    };
+    // The whole file would be written where only a part of it belongs
+    let is_partial =
+        download_part.start_byte > 0 || download_part.end_byte + 1 < task.meta.content_length;
+    if task.meta.supports_resume && is_partial && response.status() == StatusCode::OK {
+        return Err(WhipError::RangeNotSupported { url });
+    }
+    Ok(response)
+}
+
 impl<F> Drop for Downloader<F>
 where
-    F: std::marker::Send + std::marker::Sync + FnMut(f64) -> () + 'static,
+    F: std::marker::Send + std::marker::Sync + FnMut(&Progress) + 'static,
 {
     fn drop(&mut self) {
         if self.completed {
-            for i in 0..self.total_download_parts {
-                let f_path = format!("{temp_dir}{sep}{fn}.{id}", temp_dir=self.temp_dir.to_string_lossy().to_string(),fn=self.task.meta.file_name, id=i, sep=MAIN_SEPARATOR);
-                if let Err(e) = remove_file(&f_path) {
-                    eprintln!("{} : {}", e.to_string(), f_path);
-                };
-            }
+            self.remove_temp_files();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::DownloadMeta;
+
+    #[test]
+    fn test_bytes_on_disk() {
+        let dir = std::env::temp_dir().join(format!("whip-bytes-on-disk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bin.0"), vec![0u8; 300]).unwrap();
+        std::fs::write(dir.join("a.bin.2"), vec![0u8; 200]).unwrap();
+        let task = DownloadTask {
+            file_url: String::from("http://example.com/a.bin"),
+            percentage_completed: 50f64,
+            meta: DownloadMeta {
+                content_length: 1000,
+                supports_resume: true,
+                content_type: String::new(),
+                file_name: String::from("a.bin"),
+            },
+            headers: Vec::new(),
+            checksum: None,
+        };
+
+        // The percentage stored with the task isn't a number of bytes
+        let downloader = Downloader::restore(
+            0f64,
+            task,
+            dir.to_string_lossy().to_string(),
+            dir.to_string_lossy().to_string(),
+            |_: &Progress| {},
+            |_| {},
+            |_| {},
+            false,
+            3,
+            3,
+            0,
+        );
+        assert_eq!(downloader.bytes_on_disk(), 500);
+        drop(downloader);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
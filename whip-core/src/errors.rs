@@ -1,18 +1,225 @@
-use std::fmt;
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use crate::{checksum::HashAlgorithm, storage::is_disk_full};
 
 #[derive(Debug)]
 pub enum WhipError {
+    /// The server answered with an error status
+    Http {
+        status: u16,
+        url: String,
+    },
+    /// The server took too long to answer
+    Timeout {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// The host name of the url couldn't be resolved
+    Dns {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// The secure connection couldn't be set up (bad certificate, handshake...)
+    Tls {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// Any other failure to reach the server or to read its answer
+    Connection {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// A part of the file was asked for but the server sent all of it
+    RangeNotSupported {
+        url: String,
+    },
+    /// The link leads to a web page instead of the file
+    LinkExpired {
+        url: String,
+    },
+    /// Downloaded data doesn't match its expected digest
+    ChecksumMismatch {
+        algorithm: HashAlgorithm,
+        resource: String,
+    },
+    /// No space left on the device or quota exceeded
+    DiskFull {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The file can't be stored (missing directory, not enough space...)
     Storage(String),
-    NetWork(String),
+    /// A playlist, manifest, metalink file or url couldn't be understood
+    Parse(String),
+    /// The download was stopped before the end, what was received is kept
+    Interrupted,
+    /// Another process is working on the task
+    Locked {
+        task_id: u64,
+        path: PathBuf,
+    },
     Unknown(String),
 }
 
+impl WhipError {
+    /// Classifies the error of a request to `url`.
+    pub fn request(url: &str, source: reqwest::Error) -> Self {
+        let url = url.to_string();
+        if let Some(status) = source.status() {
+            return WhipError::Http {
+                status: status.as_u16(),
+                url,
+            };
+        }
+        if source.is_timeout() {
+            return WhipError::Timeout { url, source };
+        }
+        // reqwest doesn't expose these, the messages of the underlying errors tell them apart
+        let chain = error_chain(&source).to_lowercase();
+        if chain.contains("dns error") || chain.contains("failed to lookup address") {
+            WhipError::Dns { url, source }
+        } else if ["tls", "ssl", "certificate", "handshake"]
+            .iter()
+            .any(|k| chain.contains(k))
+        {
+            WhipError::Tls { url, source }
+        } else {
+            WhipError::Connection { url, source }
+        }
+    }
+
+    /// Wraps an I/O error on `path`, a full disk gets its own variant.
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        let path = path.into();
+        if is_disk_full(&source) {
+            WhipError::DiskFull { path, source }
+        } else {
+            WhipError::Io { path, source }
+        }
+    }
+
+    /// Checks if trying again later may succeed without changing anything.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WhipError::Http { status, .. } => *status >= 500 || *status == 408 || *status == 429,
+            WhipError::Timeout { .. }
+            | WhipError::Connection { .. }
+            | WhipError::ChecksumMismatch { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Messages of an error and all its sources
+fn error_chain(e: &dyn Error) -> String {
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        chain.push_str(" : ");
+        chain.push_str(&s.to_string());
+        source = s.source();
+    }
+    chain
+}
+
 impl fmt::Display for WhipError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            WhipError::Http { status, url } => {
+                let reason = reqwest::StatusCode::from_u16(*status)
+                    .ok()
+                    .and_then(|s| s.canonical_reason())
+                    .unwrap_or("");
+                write!(f, "HTTP Error : {} {} : {}", status, reason, url)
+            }
+            WhipError::Timeout { url, .. } => write!(f, "Network Error : timed out : {}", url),
+            WhipError::Dns { url, .. } => {
+                write!(f, "Network Error : can't resolve host name : {}", url)
+            }
+            WhipError::Tls { url, source } => {
+                write!(f, "Network Error : secure connection failed ({}) : {}", source, url)
+            }
+            WhipError::Connection { url, source } => {
+                write!(f, "Network Error : {} : {}", source, url)
+            }
+            WhipError::RangeNotSupported { url } => write!(
+                f,
+                "Network Error : server doesn't support partial downloads : {}",
+                url
+            ),
+            WhipError::LinkExpired { url } => write!(
+                f,
+                "Network Error : Download link expired or link doesn't point to a file. Update link to resume download : {}",
+                url
+            ),
+            WhipError::ChecksumMismatch {
+                algorithm,
+                resource,
+            } => write!(f, "Checksum mismatch ({}) : {}", algorithm, resource),
+            WhipError::DiskFull { path, source } => write!(
+                f,
+                "Storage Error : {} : {}",
+                source,
+                path.to_string_lossy()
+            ),
+            WhipError::Io { path, source } => write!(
+                f,
+                "Storage Error : {} : {}",
+                source,
+                path.to_string_lossy()
+            ),
             WhipError::Storage(e) => write!(f, "Storage Error : {}", e),
-            WhipError::NetWork(e) => write!(f, "Network Error : {}", e),
+            WhipError::Parse(e) => write!(f, "Parse Error : {}", e),
+            WhipError::Interrupted => write!(f, "Download interrupted"),
+            WhipError::Locked { task_id, path } => write!(
+                f,
+                "Task {} is in use by another process : {}",
+                task_id,
+                path.to_string_lossy()
+            ),
             WhipError::Unknown(e) => write!(f, "Unknown Error : {}", e),
         }
     }
 }
+
+impl Error for WhipError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WhipError::Timeout { source, .. }
+            | WhipError::Dns { source, .. }
+            | WhipError::Tls { source, .. }
+            | WhipError::Connection { source, .. } => Some(source),
+            WhipError::DiskFull { source, .. } | WhipError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        let http = |status| WhipError::Http {
+            status,
+            url: String::from("https://example.com/file.zip"),
+        };
+
+        assert!(http(503).is_retryable());
+        assert!(http(429).is_retryable());
+        assert!(!http(404).is_retryable());
+        assert!(
+            !WhipError::io("/tmp/file.zip", io::Error::from(io::ErrorKind::StorageFull))
+                .is_retryable()
+        );
+        assert!(matches!(
+            WhipError::io("/tmp/file.zip", io::Error::from(io::ErrorKind::StorageFull)),
+            WhipError::DiskFull { .. }
+        ));
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::errors::WhipError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Detects the format from the first bytes of the file (at least 262
+    /// for plain tar files), falling back to the content type the server sent.
+    pub fn detect(magic: &[u8], content_type: &str) -> Option<Self> {
+        if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            return Some(ArchiveFormat::Zip);
+        }
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            return Some(ArchiveFormat::TarGz);
+        }
+        if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            return Some(ArchiveFormat::TarXz);
+        }
+        if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Some(ArchiveFormat::TarZst);
+        }
+        if magic.len() >= 262 && &magic[257..262] == b"ustar" {
+            return Some(ArchiveFormat::Tar);
+        }
+
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        match content_type.as_str() {
+            "application/zip" | "application/x-zip-compressed" => Some(ArchiveFormat::Zip),
+            "application/x-tar" => Some(ArchiveFormat::Tar),
+            "application/gzip" | "application/x-gzip" | "application/x-compressed-tar" => {
+                Some(ArchiveFormat::TarGz)
+            }
+            "application/x-xz" | "application/x-xz-compressed-tar" => Some(ArchiveFormat::TarXz),
+            "application/zstd" | "application/x-zstd" | "application/x-zstd-compressed-tar" => {
+                Some(ArchiveFormat::TarZst)
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads the start of the archive to detect its format
+    pub fn of_file(path: &Path, content_type: &str) -> Result<Option<Self>, WhipError> {
+        let mut file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(WhipError::io(path, e)),
+        };
+        let mut magic = Vec::with_capacity(512);
+        if let Err(e) = file.by_ref().take(512).read_to_end(&mut magic) {
+            return Err(WhipError::io(path, e));
+        }
+        Ok(ArchiveFormat::detect(&magic, content_type))
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Zip => write!(f, "zip"),
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::TarGz => write!(f, "tar.gz"),
+            ArchiveFormat::TarXz => write!(f, "tar.xz"),
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// Number of files written
+    pub files: u64,
+    /// Total size of the files written
+    pub bytes: u64,
+    /// Entries left out because they would land outside the target directory
+    pub skipped: Vec<String>,
+}
+
+/// Name of the directory an archive is extracted into by default, the
+/// file name without its volume number and archive extensions.
+pub fn extract_dir_name(file_name: &str) -> String {
+    let mut name = file_name;
+    if let Some((base, _)) = volume_number(name) {
+        name = base;
+    }
+    let lower = name.to_lowercase();
+    for ext in [
+        ".tar.gz", ".tar.xz", ".tar.zst", ".tgz", ".txz", ".tzst", ".tar", ".zip",
+    ] {
+        if lower.ends_with(ext) && lower.len() > ext.len() {
+            return name[..name.len() - ext.len()].to_string();
+        }
+    }
+    format!("{}.d", name)
+}
+
+/// Splits "archive.zip.003" into ("archive.zip", 3)
+pub fn volume_number(file_name: &str) -> Option<(&str, u32)> {
+    let (base, number) = file_name.rsplit_once('.')?;
+    if number.len() != 3 || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((base, number.parse().ok()?))
+}
+
+/// Lists the volumes of a split archive (archive.zip.001, archive.zip.002...)
+/// found next to one of them, in order. Files that aren't volumes are their
+/// own single volume.
+pub fn volumes(path: &Path) -> Vec<PathBuf> {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let base = match volume_number(&file_name) {
+        Some((base, _)) => base.to_string(),
+        None => return vec![path.to_path_buf()],
+    };
+
+    let mut volumes = Vec::new();
+    for i in 1..1000 {
+        let volume = path.with_file_name(format!("{}.{:03}", base, i));
+        if !volume.is_file() {
+            break;
+        }
+        volumes.push(volume);
+    }
+    volumes
+}
+
+/// Joins an archive entry name to the target directory, None for absolute
+/// names and names going up with "..".
+pub fn safe_path(target: &Path, entry_name: &Path) -> Option<PathBuf> {
+    let mut path = target.to_path_buf();
+    let mut has_name = false;
+    for component in entry_name.components() {
+        match component {
+            Component::Normal(c) => {
+                path.push(c);
+                has_name = true;
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if has_name {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Extracts an archive, given as its volumes in order, into `target`.
+/// Runs on a blocking thread.
+pub async fn extract(
+    volumes: Vec<PathBuf>,
+    format: ArchiveFormat,
+    target: PathBuf,
+) -> Result<ExtractReport, WhipError> {
+    match tokio::task::spawn_blocking(move || extract_blocking(&volumes, format, &target)).await {
+        Ok(res) => res,
+        Err(e) => Err(WhipError::Unknown(e.to_string())),
+    }
+}
+
+fn extract_blocking(
+    volumes: &[PathBuf],
+    format: ArchiveFormat,
+    target: &Path,
+) -> Result<ExtractReport, WhipError> {
+    if let Err(e) = fs::create_dir_all(target) {
+        return Err(WhipError::io(target, e));
+    }
+    let reader = Volumes::open(volumes)?;
+    let archive_name = volumes
+        .first()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let parse_error = |e: &dyn fmt::Display| WhipError::Parse(format!("{} : {}", archive_name, e));
+
+    match format {
+        ArchiveFormat::Zip => extract_zip(reader, target, parse_error),
+        ArchiveFormat::Tar => extract_tar(reader, target, parse_error),
+        ArchiveFormat::TarGz => extract_tar(
+            flate2::read::MultiGzDecoder::new(reader),
+            target,
+            parse_error,
+        ),
+        ArchiveFormat::TarXz => extract_tar(xz2::read::XzDecoder::new(reader), target, parse_error),
+        ArchiveFormat::TarZst => match zstd::stream::read::Decoder::new(reader) {
+            Ok(decoder) => extract_tar(decoder, target, parse_error),
+            Err(e) => Err(parse_error(&e)),
+        },
+    }
+}
+
+fn extract_zip<F>(
+    reader: Volumes,
+    target: &Path,
+    parse_error: F,
+) -> Result<ExtractReport, WhipError>
+where
+    F: Fn(&dyn fmt::Display) -> WhipError,
+{
+    let mut archive = match zip::ZipArchive::new(reader) {
+        Ok(a) => a,
+        Err(e) => return Err(parse_error(&e)),
+    };
+    let mut report = ExtractReport::default();
+
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(e) => e,
+            Err(e) => return Err(parse_error(&e)),
+        };
+        let path = match safe_path(target, Path::new(entry.name())) {
+            Some(p) => p,
+            None => {
+                report.skipped.push(entry.name().to_string());
+                continue;
+            }
+        };
+
+        if entry.is_dir() {
+            if let Err(e) = fs::create_dir_all(&path) {
+                return Err(WhipError::io(path, e));
+            }
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return Err(WhipError::io(parent, e));
+            }
+        }
+        let mut file = match fs::File::create(&path) {
+            Ok(f) => f,
+            Err(e) => return Err(WhipError::io(path, e)),
+        };
+        match io::copy(&mut entry, &mut file) {
+            Ok(n) => report.bytes += n,
+            Err(e) => return Err(WhipError::io(path, e)),
+        }
+        #[cfg(target_family = "unix")]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777));
+        }
+        report.files += 1;
+    }
+
+    Ok(report)
+}
+
+fn extract_tar<R, F>(reader: R, target: &Path, parse_error: F) -> Result<ExtractReport, WhipError>
+where
+    R: Read,
+    F: Fn(&dyn fmt::Display) -> WhipError,
+{
+    let mut archive = tar::Archive::new(reader);
+    let entries = match archive.entries() {
+        Ok(e) => e,
+        Err(e) => return Err(parse_error(&e)),
+    };
+    let mut report = ExtractReport::default();
+
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(e) => e,
+            Err(e) => return Err(parse_error(&e)),
+        };
+        let name = match entry.path() {
+            Ok(p) => p.to_path_buf(),
+            Err(e) => return Err(parse_error(&e)),
+        };
+        if safe_path(target, &name).is_none() {
+            report.skipped.push(name.to_string_lossy().to_string());
+            continue;
+        }
+
+        // unpack_in also refuses to write through links pointing outside of target
+        let size = entry.header().size().unwrap_or(0);
+        let is_file = entry.header().entry_type().is_file();
+        match entry.unpack_in(target) {
+            Ok(true) => {
+                if is_file {
+                    report.files += 1;
+                    report.bytes += size;
+                }
+            }
+            Ok(false) => report.skipped.push(name.to_string_lossy().to_string()),
+            Err(e) => return Err(parse_error(&e)),
+        }
+    }
+
+    Ok(report)
+}
+
+/// The volumes of a split archive read as one file
+struct Volumes {
+    files: Vec<(fs::File, u64)>,
+    position: u64,
+    len: u64,
+}
+
+impl Volumes {
+    fn open(paths: &[PathBuf]) -> Result<Self, WhipError> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in paths.iter() {
+            let file = match fs::File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(WhipError::io(path, e)),
+            };
+            let size = match file.metadata() {
+                Ok(m) => m.len(),
+                Err(e) => return Err(WhipError::io(path, e)),
+            };
+            len += size;
+            files.push((file, size));
+        }
+        Ok(Volumes {
+            files,
+            position: 0,
+            len,
+        })
+    }
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (file, size) in self.files.iter_mut() {
+            if self.position < start + *size {
+                file.seek(SeekFrom::Start(self.position - start))?;
+                let max = ((start + *size - self.position) as usize).min(buf.len());
+                let n = file.read(&mut buf[..max])?;
+                self.position += n as u64;
+                return Ok(n);
+            }
+            start += *size;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) => p as i128,
+            SeekFrom::End(p) => self.len as i128 + p as i128,
+            SeekFrom::Current(p) => self.position as i128 + p as i128,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the archive",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            ArchiveFormat::detect(b"PK\x03\x04rest", ""),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0], "text/plain"),
+            Some(ArchiveFormat::TarZst)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(b"????", "application/x-xz; charset=binary"),
+            Some(ArchiveFormat::TarXz)
+        );
+        assert_eq!(ArchiveFormat::detect(b"<html>", "text/html"), None);
+    }
+
+    #[test]
+    fn test_safe_path() {
+        let target = Path::new("/tmp/out");
+
+        assert_eq!(
+            safe_path(target, Path::new("./dir/file.txt")),
+            Some(PathBuf::from("/tmp/out/dir/file.txt"))
+        );
+        assert_eq!(safe_path(target, Path::new("../etc/passwd")), None);
+        assert_eq!(safe_path(target, Path::new("dir/../../file.txt")), None);
+        assert_eq!(safe_path(target, Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn test_extract_dir_name() {
+        assert_eq!(extract_dir_name("linux-5.19.tar.xz"), "linux-5.19");
+        assert_eq!(extract_dir_name("data.zip.002"), "data");
+        assert_eq!(extract_dir_name("archive.bin"), "archive.bin.d");
+    }
+
+    #[test]
+    fn test_volumes_read() {
+        let dir = std::env::temp_dir().join(format!("whip-volumes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.zip.001"), b"abc").unwrap();
+        fs::write(dir.join("data.zip.002"), b"defg").unwrap();
+
+        let paths = volumes(&dir.join("data.zip.002"));
+        let mut reader = Volumes::open(&paths).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        let mut end = String::new();
+        reader.read_to_string(&mut end).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(paths.len(), 2);
+        assert_eq!(content, "abcdefg");
+        assert_eq!(end, "fg");
+    }
+}
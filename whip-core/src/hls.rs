@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+
+use reqwest::{Client, Url};
+
+use crate::{
+    download::DownloadTask,
+    errors::WhipError,
+    stream::{fetch_text, join_url, parse_url, Segment, SegmentKey, VariantSelector},
+};
+
+/// A parsed m3u8 playlist.
+#[derive(Debug)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+/// Playlist listing the available renditions of a stream.
+#[derive(Debug)]
+pub struct MasterPlaylist {
+    pub variants: Vec<Variant>,
+}
+
+/// A rendition of the stream listed in a master playlist.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub uri: String,
+    /// Peak bit rate in bits per second
+    pub bandwidth: u64,
+    /// Width and height in pixels
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+}
+
+/// Playlist listing the segments of a single rendition.
+#[derive(Debug)]
+pub struct MediaPlaylist {
+    pub target_duration: u64,
+    pub media_sequence: u64,
+    pub segments: Vec<MediaSegment>,
+    /// Whether the playlist is complete (no more segments will be added)
+    pub end_list: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaSegment {
+    pub uri: String,
+    /// Duration in seconds
+    pub duration: f64,
+    pub sequence: u64,
+    /// Inclusive byte range of the segment within the resource at `uri`
+    pub byte_range: Option<(u64, u64)>,
+    pub key: Option<HlsKey>,
+    /// Initialization section the segment has to be preceded by
+    pub map: Option<MediaMap>,
+}
+
+/// Initialization section of an EXT-X-MAP tag, fMP4 streams can't be
+/// played without it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaMap {
+    pub uri: String,
+    /// Inclusive byte range of the section within the resource at `uri`
+    pub byte_range: Option<(u64, u64)>,
+    /// Encryption of the section, from the EXT-X-KEY tag before it
+    pub key: Option<HlsKey>,
+}
+
+impl MediaMap {
+    /// Extension of the file the stream is joined into, ts for transport
+    /// stream sections and mp4 for the others (fMP4, CMAF)
+    pub fn extension(&self) -> &'static str {
+        let path = self.uri.split('?').next().unwrap_or("");
+        if path.to_lowercase().ends_with(".ts") {
+            "ts"
+        } else {
+            "mp4"
+        }
+    }
+}
+
+/// Encryption applied to the segments following an EXT-X-KEY tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HlsKey {
+    pub method: String,
+    pub uri: Option<String>,
+    pub iv: Option<[u8; 16]>,
+}
+
+/// Checks if a download task points to an HLS playlist.
+pub fn is_hls(task: &DownloadTask) -> bool {
+    let content_type = task.meta.content_type.to_lowercase();
+    if content_type.contains("mpegurl") {
+        return true;
+    }
+    let path = task.file_url.split('?').next().unwrap_or("");
+    path.to_lowercase().ends_with(".m3u8")
+}
+
+/// Segments of the rendition of a playlist picked for download
+#[derive(Debug)]
+pub struct HlsStream {
+    pub segments: Vec<Segment>,
+    /// Extension of the file the segments are joined into
+    pub extension: &'static str,
+}
+
+/// Name of the file a playlist is saved to once its segments are joined.
+/// It's a transport stream until the playlist tells otherwise, see
+/// [`with_extension`].
+pub fn output_file_name(playlist_name: &str) -> String {
+    let stem = match playlist_name.rfind('.') {
+        Some(index) if playlist_name[index..].eq_ignore_ascii_case(".m3u8") => {
+            &playlist_name[..index]
+        }
+        _ => playlist_name,
+    };
+    format!("{}.ts", stem)
+}
+
+/// Swaps the .ts extension of a name given by [`output_file_name`] for `extension`
+pub fn with_extension(file_name: &str, extension: &str) -> String {
+    match file_name.strip_suffix(".ts") {
+        Some(stem) => format!("{}.{}", stem, extension),
+        None => file_name.to_string(),
+    }
+}
+
+/// Parses the content of a m3u8 file.
+pub fn parse_playlist(content: &str) -> Result<Playlist, WhipError> {
+    let mut lines = content.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        return Err(WhipError::Parse("Not a m3u8 playlist".to_string()));
+    }
+
+    let mut variants = Vec::new();
+    let mut segments = Vec::new();
+    let mut target_duration = 0;
+    let mut media_sequence = 0;
+    let mut end_list = false;
+
+    let mut pending_variant: Option<Variant> = None;
+    let mut pending_duration: Option<f64> = None;
+    let mut pending_range: Option<(u64, u64)> = None;
+    let mut last_range_end = 0u64;
+    let mut current_key: Option<HlsKey> = None;
+    let mut current_map: Option<MediaMap> = None;
+
+    for line in lines {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let attrs = parse_attributes(attrs);
+            pending_variant = Some(Variant {
+                uri: String::new(),
+                bandwidth: attrs
+                    .get("BANDWIDTH")
+                    .and_then(|b| b.parse().ok())
+                    .unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").and_then(|r| parse_resolution(r)),
+                codecs: attrs.get("CODECS").cloned(),
+            });
+        } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            target_duration = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            media_sequence = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let duration = value.split(',').next().unwrap_or("0");
+            pending_duration = Some(duration.parse().unwrap_or(0f64));
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            let mut parts = value.splitn(2, '@');
+            let length: u64 = parts.next().and_then(|l| l.parse().ok()).unwrap_or(0);
+            let offset = parts
+                .next()
+                .and_then(|o| o.parse().ok())
+                .unwrap_or(last_range_end);
+            if length > 0 {
+                pending_range = Some((offset, offset + length - 1));
+                last_range_end = offset + length;
+            }
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = parse_attributes(attrs);
+            let method = attrs
+                .get("METHOD")
+                .cloned()
+                .unwrap_or_else(|| "NONE".to_string());
+            current_key = if method == "NONE" {
+                None
+            } else {
+                Some(HlsKey {
+                    method,
+                    uri: attrs.get("URI").cloned(),
+                    iv: attrs.get("IV").and_then(|iv| parse_iv(iv)),
+                })
+            };
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            let attrs = parse_attributes(attrs);
+            current_map = attrs.get("URI").map(|uri| MediaMap {
+                uri: uri.to_owned(),
+                // length[@offset], from the start of the resource when there's no offset
+                byte_range: attrs.get("BYTERANGE").and_then(|r| {
+                    let mut parts = r.splitn(2, '@');
+                    let length: u64 = parts.next()?.parse().ok()?;
+                    let offset: u64 = parts.next().map_or(Some(0), |o| o.parse().ok())?;
+                    Some(length)
+                        .filter(|l| *l > 0)
+                        .map(|l| (offset, offset + l - 1))
+                }),
+                key: current_key.clone(),
+            });
+        } else if line == "#EXT-X-ENDLIST" {
+            end_list = true;
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(mut variant) = pending_variant.take() {
+            variant.uri = line.to_string();
+            variants.push(variant);
+        } else {
+            segments.push(MediaSegment {
+                uri: line.to_string(),
+                duration: pending_duration.take().unwrap_or(0f64),
+                sequence: media_sequence + segments.len() as u64,
+                byte_range: pending_range.take(),
+                key: current_key.clone(),
+                map: current_map.clone(),
+            });
+        }
+    }
+
+    if !variants.is_empty() {
+        return Ok(Playlist::Master(MasterPlaylist { variants }));
+    }
+    Ok(Playlist::Media(MediaPlaylist {
+        target_duration,
+        media_sequence,
+        segments,
+        end_list,
+    }))
+}
+
+/// Parses an attribute list (KEY=VALUE,KEY="QUOTED,VALUE")
+fn parse_attributes(attrs: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = attrs;
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(i) => i,
+            None => break,
+        };
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+        let value;
+        if let Some(stripped) = rest.strip_prefix('"') {
+            let end = stripped.find('"').unwrap_or(stripped.len());
+            value = stripped[..end].to_string();
+            rest = stripped.get(end + 1..).unwrap_or("");
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start_matches(',');
+        result.insert(key, value);
+    }
+    result
+}
+
+fn parse_resolution(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))?;
+    let number = u128::from_str_radix(hex, 16).ok()?;
+    Some(number.to_be_bytes())
+}
+
+impl MasterPlaylist {
+    /// Picks a variant according to the selector.
+    pub fn select_variant(&self, selector: &VariantSelector) -> Option<&Variant> {
+        selector.select(&self.variants, |v| (v.bandwidth, v.resolution))
+    }
+}
+
+/// Fetches a playlist, follows the selected variant if it's a master
+/// playlist and returns the segments (with their decryption keys) to download.
+/// Initialization sections are downloaded as segments ahead of the ones they
+/// apply to.
+pub async fn get_segments(
+    playlist_url: &str,
+    selector: &VariantSelector,
+) -> Result<HlsStream, WhipError> {
+    let client = Client::new();
+    let mut url = parse_url(playlist_url)?;
+    let mut playlist = parse_playlist(&fetch_text(&client, &url).await?)?;
+
+    if let Playlist::Master(master) = &playlist {
+        let variant = match master.select_variant(selector) {
+            Some(v) => v,
+            None => return Err(WhipError::Parse("No variant found in playlist".to_string())),
+        };
+        url = join_url(&url, &variant.uri)?;
+        playlist = parse_playlist(&fetch_text(&client, &url).await?)?;
+    }
+
+    let media = match playlist {
+        Playlist::Media(m) => m,
+        Playlist::Master(_) => {
+            return Err(WhipError::Parse(
+                "Variant playlist points to another master playlist".to_string(),
+            ))
+        }
+    };
+
+    let mut keys: HashMap<String, [u8; 16]> = HashMap::new();
+    let mut segments = Vec::new();
+    let mut last_map: Option<&MediaMap> = None;
+    for s in media.segments.iter() {
+        if let Some(map) = &s.map {
+            if last_map != Some(map) {
+                segments.push(Segment {
+                    id: segments.len() as u64,
+                    url: join_url(&url, &map.uri)?.to_string(),
+                    byte_range: map.byte_range,
+                    key: segment_key(&client, &url, &map.key, s.sequence, &mut keys).await?,
+                    fallback_urls: Vec::new(),
+                    checksum: None,
+                });
+                last_map = Some(map);
+            }
+        }
+        segments.push(Segment {
+            id: segments.len() as u64,
+            url: join_url(&url, &s.uri)?.to_string(),
+            byte_range: s.byte_range,
+            key: segment_key(&client, &url, &s.key, s.sequence, &mut keys).await?,
+            fallback_urls: Vec::new(),
+            checksum: None,
+        });
+    }
+
+    let extension = match media.segments.iter().find_map(|s| s.map.as_ref()) {
+        Some(map) => map.extension(),
+        None => "ts",
+    };
+    Ok(HlsStream {
+        segments,
+        extension,
+    })
+}
+
+/// Key to decrypt a segment encrypted with `key` with, fetched once per
+/// key url into `keys`. The media sequence is the IV when there's none.
+async fn segment_key(
+    client: &Client,
+    playlist_url: &Url,
+    key: &Option<HlsKey>,
+    sequence: u64,
+    keys: &mut HashMap<String, [u8; 16]>,
+) -> Result<Option<SegmentKey>, WhipError> {
+    let key = match key {
+        None => return Ok(None),
+        Some(k) if k.method == "AES-128" => k,
+        Some(k) => {
+            return Err(WhipError::Parse(format!(
+                "Unsupported encryption method : {}",
+                k.method
+            )))
+        }
+    };
+    let key_url = match &key.uri {
+        Some(uri) => join_url(playlist_url, uri)?,
+        None => return Err(WhipError::Parse("Key has no URI".to_string())),
+    };
+    if !keys.contains_key(key_url.as_str()) {
+        let bytes = fetch_key(client, &key_url).await?;
+        keys.insert(key_url.to_string(), bytes);
+    }
+    Ok(Some(SegmentKey {
+        key: keys[key_url.as_str()],
+        iv: key.iv.unwrap_or_else(|| (sequence as u128).to_be_bytes()),
+    }))
+}
+
+async fn fetch_key(client: &Client, url: &Url) -> Result<[u8; 16], WhipError> {
+    let bytes = match client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        Ok(response) => match response.bytes().await {
+            Ok(b) => b,
+            Err(e) => return Err(WhipError::request(url.as_str(), e)),
+        },
+        Err(e) => return Err(WhipError::request(url.as_str(), e)),
+    };
+    match bytes[..].try_into() {
+        Ok(key) => Ok(key),
+        Err(_) => Err(WhipError::Parse(format!(
+            "Invalid AES-128 key of {} bytes : {}",
+            bytes.len(),
+            url
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1280x720
+mid/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=7680000,RESOLUTION=1920x1080
+hi/index.m3u8
+";
+
+    const MEDIA: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x00000000000000000000000000000001
+#EXTINF:9.009,
+first.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:9.009,
+second.ts
+#EXT-X-BYTERANGE:1000@200
+#EXTINF:3.003,
+third.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn test_parse_master_playlist() {
+        let playlist = parse_playlist(MASTER).unwrap();
+
+        if let Playlist::Master(master) = playlist {
+            assert_eq!(master.variants.len(), 3);
+            assert_eq!(master.variants[0].uri, "low/index.m3u8");
+            assert_eq!(master.variants[0].resolution, Some((640, 360)));
+            assert_eq!(
+                master.variants[0].codecs,
+                Some(String::from("avc1.4d401e,mp4a.40.2"))
+            );
+        } else {
+            panic!("Expected a master playlist");
+        }
+    }
+
+    #[test]
+    fn test_parse_media_playlist() {
+        let playlist = parse_playlist(MEDIA).unwrap();
+
+        if let Playlist::Media(media) = playlist {
+            assert!(media.end_list);
+            assert_eq!(media.segments.len(), 3);
+            assert_eq!(media.segments[0].sequence, 5);
+            assert_eq!(media.segments[0].key.as_ref().unwrap().iv.unwrap()[15], 1);
+            assert!(media.segments[1].key.is_none());
+            assert_eq!(media.segments[2].byte_range, Some((200, 1199)));
+        } else {
+            panic!("Expected a media playlist");
+        }
+    }
+
+    #[test]
+    fn test_parse_media_map() {
+        let content = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:4.0,
+seg1.m4s
+#EXTINF:4.0,
+seg2.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-MAP:URI=\"ad/init.mp4\"
+#EXTINF:2.0,
+ad/seg1.m4s
+#EXT-X-ENDLIST
+";
+        let media = match parse_playlist(content).unwrap() {
+            Playlist::Media(m) => m,
+            _ => panic!("Expected a media playlist"),
+        };
+        let map = media.segments[0].map.as_ref().unwrap();
+        assert_eq!(map.uri, "init.mp4");
+        assert_eq!(map.byte_range, Some((0, 719)));
+        assert_eq!(map.extension(), "mp4");
+        assert_eq!(media.segments[1].map.as_ref(), Some(map));
+        let ad = media.segments[2].map.as_ref().unwrap();
+        assert_eq!(ad.uri, "ad/init.mp4");
+        assert_eq!(ad.byte_range, None);
+
+        let media = match parse_playlist(MEDIA).unwrap() {
+            Playlist::Media(m) => m,
+            _ => panic!("Expected a media playlist"),
+        };
+        assert!(media.segments.iter().all(|s| s.map.is_none()));
+
+        assert_eq!(with_extension("talk.ts", "mp4"), "talk.mp4");
+        assert_eq!(with_extension("talk.mkv", "mp4"), "talk.mkv");
+    }
+
+    #[tokio::test]
+    async fn test_get_segments_with_map() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let playlist = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:4.0,\nseg1.m4s\n#EXTINF:4.0,\nseg2.m4s\n#EXT-X-ENDLIST\n";
+            for mut stream in listener.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    playlist.len(),
+                    playlist
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        let url = format!("http://127.0.0.1:{}/talk/index.m3u8", port);
+        let stream = get_segments(&url, &VariantSelector::Highest).await.unwrap();
+        assert_eq!(stream.extension, "mp4");
+        let urls = stream
+            .segments
+            .iter()
+            .map(|s| (s.id, s.url.rsplit('/').next().unwrap().to_string()))
+            .collect::<Vec<(u64, String)>>();
+        assert_eq!(
+            urls,
+            vec![
+                (0, String::from("init.mp4")),
+                (1, String::from("seg1.m4s")),
+                (2, String::from("seg2.m4s")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_variant() {
+        let master = match parse_playlist(MASTER).unwrap() {
+            Playlist::Master(m) => m,
+            _ => panic!("Expected a master playlist"),
+        };
+
+        let highest = master.select_variant(&VariantSelector::Highest).unwrap();
+        assert_eq!(highest.uri, "hi/index.m3u8");
+
+        let capped = master
+            .select_variant(&VariantSelector::MaxBandwidth(3000000))
+            .unwrap();
+        assert_eq!(capped.uri, "mid/index.m3u8");
+
+        let by_resolution = master
+            .select_variant(&VariantSelector::Resolution(1280, 720))
+            .unwrap();
+        assert_eq!(by_resolution.uri, "mid/index.m3u8");
+    }
+
+    #[test]
+    fn test_is_hls() {
+        let mut task = DownloadTask {
+            file_url: String::from("https://example.com/talk/index.m3u8?token=1"),
+            percentage_completed: 0f64,
+            meta: crate::download::DownloadMeta {
+                content_length: 0,
+                supports_resume: false,
+                content_type: String::new(),
+                file_name: String::from("index.m3u8"),
+            },
+            headers: Vec::new(),
+            checksum: None,
+        };
+        assert!(is_hls(&task));
+
+        task.file_url = String::from("https://example.com/talk/video.mp4");
+        assert!(!is_hls(&task));
+    }
+}
@@ -0,0 +1,292 @@
+use std::{fmt, path::PathBuf, process::Stdio, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::{Child, Command},
+};
+
+use crate::{checksum::Checksum, errors::WhipError};
+
+/// Hook output kept beyond this is dropped
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Complete,
+    Failure,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookEvent::Complete => write!(f, "complete"),
+            HookEvent::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// What a hook is told about the task, passed to it as `WHIP_*`
+/// environment variables.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub task_id: u64,
+    pub file_path: PathBuf,
+    pub url: String,
+    pub file_size: u64,
+    pub content_type: String,
+    /// Digest of the downloaded file, complete hooks only
+    pub checksum: Option<Checksum>,
+    /// Why the download failed, failure hooks only
+    pub error: Option<String>,
+}
+
+impl HookContext {
+    fn env(&self, event: HookEvent) -> Vec<(&'static str, String)> {
+        vec![
+            ("WHIP_EVENT", event.to_string()),
+            ("WHIP_TASK_ID", self.task_id.to_string()),
+            (
+                "WHIP_FILE_PATH",
+                self.file_path.to_string_lossy().to_string(),
+            ),
+            ("WHIP_URL", self.url.to_owned()),
+            ("WHIP_FILE_SIZE", self.file_size.to_string()),
+            ("WHIP_CONTENT_TYPE", self.content_type.to_owned()),
+            (
+                "WHIP_HASH",
+                self.checksum
+                    .as_ref()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+            ),
+            ("WHIP_ERROR", self.error.to_owned().unwrap_or_default()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HookOutput {
+    /// None when the command was killed
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// Standard output followed by standard error
+    pub output: String,
+}
+
+impl HookOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// A shell command run when a download completes or fails
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub command: String,
+    /// The command and the processes it started are killed if it runs
+    /// longer than this
+    pub timeout: Duration,
+}
+
+impl Hook {
+    pub fn new(command: &str, timeout: Duration) -> Self {
+        Hook {
+            command: command.to_string(),
+            timeout,
+        }
+    }
+
+    /// Runs the command through the shell and waits for it, at most `timeout`.
+    pub async fn run(
+        &self,
+        event: HookEvent,
+        context: &HookContext,
+    ) -> Result<HookOutput, WhipError> {
+        let mut command = shell_command(&self.command);
+        command
+            .envs(context.env(event))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = match command.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(WhipError::Unknown(format!(
+                    "Can't run hook {} : {}",
+                    self.command, e
+                )))
+            }
+        };
+        // The group outlives the shell while what it started in the background runs
+        let group = child.id();
+
+        // Read as it comes, what was printed before a timeout is kept
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        // Background processes keep the output open, it ends with the last of them
+        let waited = tokio::time::timeout(self.timeout, async {
+            let (status, _, _) = tokio::join!(
+                child.wait(),
+                read_output(stdout, &mut out),
+                read_output(stderr, &mut err)
+            );
+            status
+        })
+        .await;
+
+        let (exit_code, timed_out) = match waited {
+            Ok(Ok(status)) => (status.code(), false),
+            Ok(Err(e)) => {
+                return Err(WhipError::Unknown(format!(
+                    "Can't run hook {} : {}",
+                    self.command, e
+                )))
+            }
+            Err(_) => {
+                kill_group(&mut child, group);
+                let _ = child.wait().await;
+                (None, true)
+            }
+        };
+
+        let mut text = String::from_utf8_lossy(&out).to_string();
+        text.push_str(&String::from_utf8_lossy(&err));
+        if text.len() > MAX_OUTPUT_LEN {
+            let mut end = MAX_OUTPUT_LEN;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        Ok(HookOutput {
+            exit_code,
+            timed_out,
+            output: text,
+        })
+    }
+}
+
+/// Reads `pipe` to its end into `output`, keeping at most MAX_OUTPUT_LEN bytes
+async fn read_output<R: AsyncRead + Unpin>(pipe: Option<R>, output: &mut Vec<u8>) {
+    let mut pipe = match pipe {
+        Some(p) => p,
+        None => return,
+    };
+    let mut buffer = [0u8; 4096];
+    loop {
+        match pipe.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) if output.len() < MAX_OUTPUT_LEN => output.extend_from_slice(&buffer[..n]),
+            Ok(_) => {}
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn shell_command(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let mut c = std::process::Command::new("sh");
+    // A group of its own, killing it gets what the hook started too
+    c.arg("-c").arg(command).process_group(0);
+    Command::from(c)
+}
+
+/// Kills the hook and every process of its group `group`
+#[cfg(target_family = "unix")]
+fn kill_group(child: &mut Child, group: Option<u32>) {
+    match group {
+        // SAFETY: killpg only sends a signal
+        Some(id) => unsafe {
+            libc::killpg(id as libc::pid_t, libc::SIGKILL);
+        },
+        None => {
+            let _ = child.start_kill();
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn kill_group(child: &mut Child, _group: Option<u32>) {
+    let _ = child.start_kill();
+}
+
+#[cfg(target_family = "windows")]
+fn shell_command(command: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(command);
+    c
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            task_id: 7,
+            file_path: PathBuf::from("/tmp/file.zip"),
+            url: String::from("https://example.com/file.zip"),
+            file_size: 1024,
+            content_type: String::from("application/zip"),
+            checksum: Checksum::parse("md5=900150983cd24fb0d6963f7d28e17f72"),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let hook = Hook::new(
+            "echo $WHIP_EVENT $WHIP_TASK_ID $WHIP_FILE_SIZE $WHIP_HASH; exit 3",
+            Duration::from_secs(10),
+        );
+
+        let output = hook.run(HookEvent::Complete, &context()).await.unwrap();
+
+        assert_eq!(output.exit_code, Some(3));
+        assert!(!output.timed_out);
+        assert_eq!(
+            output.output,
+            "complete 7 1024 md5=900150983cd24fb0d6963f7d28e17f72\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout() {
+        let hook = Hook::new("sleep 5", Duration::from_millis(100));
+
+        let output = hook.run(HookEvent::Failure, &context()).await.unwrap();
+
+        assert!(output.timed_out);
+        assert!(!output.success());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_run_timeout_kills_group() {
+        let hook = Hook::new("sleep 60 & echo $!", Duration::from_millis(500));
+
+        let started = std::time::Instant::now();
+        let output = hook.run(HookEvent::Complete, &context()).await.unwrap();
+
+        // The shell is done at once, the sleep holding its output is what times out
+        assert!(output.timed_out);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let pid = output.output.trim();
+        assert!(pid.parse::<u32>().is_ok());
+        // Gone, or a zombie left for init to reap
+        let mut killed = false;
+        for _ in 0..50 {
+            match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                Ok(stat) if !stat.contains(") Z ") => std::thread::sleep(Duration::from_millis(20)),
+                _ => {
+                    killed = true;
+                    break;
+                }
+            }
+        }
+        assert!(killed);
+    }
+}
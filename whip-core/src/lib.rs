@@ -1,5 +1,17 @@
+pub mod checkpoint;
+pub mod checksum;
+pub mod control;
+pub mod dash;
 pub mod download;
 pub mod downloader;
 pub mod errors;
 mod event;
-mod storage;
+pub mod extract;
+pub mod hls;
+pub mod hooks;
+pub mod metalink;
+pub mod mirror;
+pub mod progress;
+pub mod schedule;
+pub mod storage;
+pub mod stream;
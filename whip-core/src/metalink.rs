@@ -0,0 +1,342 @@
+use std::path::Path;
+
+use reqwest::Client;
+use roxmltree::{Document, Node};
+use tokio::fs;
+
+use crate::{
+    checksum::{Checksum, HashAlgorithm},
+    download::{DownloadMeta, DownloadTask},
+    errors::WhipError,
+    stream::{fetch_text, parse_url, Segment},
+};
+
+/// Size of the chunks a file without piece hashes is split into
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A parsed Metalink (v3 or RFC 5854 v4) document.
+#[derive(Debug)]
+pub struct Metalink {
+    pub files: Vec<MetalinkFile>,
+}
+
+#[derive(Debug)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    /// Digests of the whole file
+    pub hashes: Vec<Checksum>,
+    pub pieces: Option<Pieces>,
+    /// Sources of the file, sorted by priority
+    pub mirrors: Vec<Mirror>,
+}
+
+/// Digests of consecutive fixed size chunks of a file.
+#[derive(Debug)]
+pub struct Pieces {
+    pub length: u64,
+    pub hashes: Vec<Checksum>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub url: String,
+    /// 1 is the most preferred (RFC 5854 semantics)
+    pub priority: u32,
+    /// ISO 3166-1 alpha-2 country code of the mirror
+    pub location: Option<String>,
+}
+
+/// Checks if a download source (url or local path) is a metalink file.
+pub fn is_metalink(source: &str) -> bool {
+    let path = source.split('?').next().unwrap_or("").to_lowercase();
+    path.ends_with(".meta4") || path.ends_with(".metalink")
+}
+
+/// Checks if a content type is a metalink one.
+pub fn is_metalink_content_type(content_type: &str) -> bool {
+    content_type.to_lowercase().contains("metalink")
+}
+
+/// Reads a metalink file from a local path or an url.
+pub async fn load(source: &str) -> Result<Metalink, WhipError> {
+    let content = if Path::new(source).is_file() {
+        match fs::read_to_string(source).await {
+            Ok(c) => c,
+            Err(e) => return Err(WhipError::io(source, e)),
+        }
+    } else {
+        fetch_text(&Client::new(), &parse_url(source)?).await?
+    };
+    parse_metalink(&content)
+}
+
+/// Parses a metalink document. Version 3 preferences (higher is better)
+/// are converted to version 4 priorities (lower is better).
+pub fn parse_metalink(content: &str) -> Result<Metalink, WhipError> {
+    let document = match Document::parse(content) {
+        Ok(d) => d,
+        Err(e) => return Err(WhipError::Parse(format!("Invalid metalink file : {}", e))),
+    };
+    let root = document.root_element();
+    if !root.has_tag_name("metalink") {
+        return Err(WhipError::Parse("Not a metalink file".to_string()));
+    }
+
+    let files = root
+        .descendants()
+        .filter(|n| n.has_tag_name("file"))
+        .map(parse_file)
+        .filter(|f| !f.mirrors.is_empty())
+        .collect::<Vec<MetalinkFile>>();
+
+    if files.is_empty() {
+        return Err(WhipError::Parse(
+            "Metalink file doesn't list any downloadable file".to_string(),
+        ));
+    }
+    Ok(Metalink { files })
+}
+
+fn parse_file(file: Node) -> MetalinkFile {
+    // v3 nests hashes in <verification> and urls in <resources>
+    let containers: Vec<Node> = std::iter::once(file)
+        .chain(
+            file.children()
+                .filter(|n| n.has_tag_name("verification") || n.has_tag_name("resources")),
+        )
+        .collect();
+    let elements = || containers.iter().flat_map(|c| c.children());
+
+    let hashes = elements()
+        .filter(|n| n.has_tag_name("hash"))
+        .filter_map(parse_hash)
+        .collect();
+
+    let pieces = elements().find(|n| n.has_tag_name("pieces")).and_then(|p| {
+        let algorithm = HashAlgorithm::from_name(p.attribute("type")?)?;
+        let length = p.attribute("length")?.parse().ok()?;
+        let mut hashes: Vec<(usize, Checksum)> = p
+            .children()
+            .filter(|n| n.has_tag_name("hash"))
+            .enumerate()
+            .map(|(i, h)| {
+                let index = h
+                    .attribute("piece")
+                    .and_then(|i| i.parse().ok())
+                    .unwrap_or(i);
+                (index, Checksum::new(algorithm, h.text().unwrap_or("")))
+            })
+            .collect();
+        hashes.sort_by_key(|(i, _)| *i);
+        Some(Pieces {
+            length,
+            hashes: hashes.into_iter().map(|(_, h)| h).collect(),
+        })
+    });
+
+    let mut mirrors: Vec<Mirror> = elements()
+        .filter(|n| n.has_tag_name("url"))
+        .filter(|n| {
+            // v3 can list torrents and ftp mirrors, only keep what we can fetch
+            let scheme = n.attribute("type").unwrap_or("http");
+            scheme == "http" || scheme == "https"
+        })
+        .filter_map(|n| {
+            let url = n.text()?.trim().to_string();
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return None;
+            }
+            let priority = match (n.attribute("priority"), n.attribute("preference")) {
+                (Some(p), _) => p.parse().unwrap_or(999999),
+                (None, Some(p)) => 101u32.saturating_sub(p.parse().unwrap_or(0)),
+                (None, None) => 999999,
+            };
+            Some(Mirror {
+                url,
+                priority,
+                location: n.attribute("location").map(|l| l.to_lowercase()),
+            })
+        })
+        .collect();
+    mirrors.sort_by_key(|m| m.priority);
+
+    let name = file.attribute("name").unwrap_or("Unknown_File");
+    MetalinkFile {
+        // Names may contain directories, only keep the file name
+        name: name.rsplit('/').next().unwrap_or(name).to_string(),
+        size: elements()
+            .find(|n| n.has_tag_name("size"))
+            .and_then(|s| s.text())
+            .and_then(|s| s.trim().parse().ok()),
+        hashes,
+        pieces,
+        mirrors,
+    }
+}
+
+fn parse_hash(node: Node) -> Option<Checksum> {
+    let algorithm = HashAlgorithm::from_name(node.attribute("type")?)?;
+    Some(Checksum::new(algorithm, node.text()?))
+}
+
+impl MetalinkFile {
+    /// Mirrors sorted by priority, the ones in `location` first
+    pub fn ranked_mirrors(&self, location: Option<&str>) -> Vec<&Mirror> {
+        let mut mirrors: Vec<&Mirror> = self.mirrors.iter().collect();
+        if let Some(location) = location {
+            let location = location.to_lowercase();
+            mirrors.sort_by_key(|m| (m.location.as_deref() != Some(location.as_str()), m.priority));
+        }
+        mirrors
+    }
+
+    /// Builds the download task of the file. The task is identified by the
+    /// mirror with the highest priority, whatever the preferred location.
+    pub fn to_download_task(&self) -> DownloadTask {
+        DownloadTask {
+            file_url: self.mirrors[0].url.to_owned(),
+            percentage_completed: 0f64,
+            meta: DownloadMeta {
+                content_length: self.size.unwrap_or(0),
+                supports_resume: self.size.is_some(),
+                content_type: String::new(),
+                file_name: self.name.to_owned(),
+            },
+            headers: Vec::new(),
+            checksum: Checksum::strongest(&self.hashes).cloned(),
+        }
+    }
+
+    /// Splits the file into pieces spread over the mirrors. Pieces with a
+    /// hash are verified as they land and re-fetched from the next mirror
+    /// when they don't match.
+    pub fn segments(&self, location: Option<&str>) -> Vec<Segment> {
+        let mirrors: Vec<String> = self
+            .ranked_mirrors(location)
+            .iter()
+            .map(|m| m.url.to_owned())
+            .collect();
+
+        let size = match self.size {
+            Some(s) if s > 0 => s,
+            _ => {
+                return vec![Segment {
+                    id: 0,
+                    url: mirrors[0].to_owned(),
+                    byte_range: None,
+                    key: None,
+                    fallback_urls: mirrors[1..].to_vec(),
+                    checksum: None,
+                }]
+            }
+        };
+
+        let (length, hashes) = match &self.pieces {
+            Some(p) if p.length > 0 => (p.length, p.hashes.as_slice()),
+            _ => (CHUNK_SIZE, &[][..]),
+        };
+
+        let mut segments = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let id = segments.len();
+            let end = (start + length).min(size) - 1;
+            // Rotate the mirrors so the pieces are spread across them
+            let first = id % mirrors.len();
+            let mut sources = mirrors[first..].to_vec();
+            sources.extend_from_slice(&mirrors[..first]);
+            segments.push(Segment {
+                id: id as u64,
+                url: sources.remove(0),
+                byte_range: Some((start, end)),
+                key: None,
+                fallback_urls: sources,
+                checksum: hashes.get(id).cloned(),
+            });
+            start = end + 1;
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const META4: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="example.iso">
+    <size>10</size>
+    <hash type="sha-256">07E79AFC33C931F735EFA648026C57DFDB32522505668D8147B754B6438451DE</hash>
+    <pieces length="4" type="sha-1">
+      <hash>011c945f30ce2cbafc452f39840f025693339c42</hash>
+      <hash>fea7f657f56a2a448da7d4b535ee5e279caf3d9a</hash>
+      <hash>b6692ea5df920cad691c20319a6fffd7a4a766b8</hash>
+    </pieces>
+    <url location="de" priority="2">https://de.example.com/example.iso</url>
+    <url location="us" priority="1">https://us.example.com/example.iso</url>
+    <url priority="3">ftp://ftp.example.com/example.iso</url>
+  </file>
+  <file name="docs/readme.txt">
+    <url>https://example.com/readme.txt</url>
+  </file>
+</metalink>"#;
+
+    const METALINK_V3: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="example.iso">
+      <size>1024</size>
+      <verification>
+        <hash type="md5">900150983cd24fb0d6963f7d28e17f72</hash>
+      </verification>
+      <resources>
+        <url type="http" location="fr" preference="90">http://fr.example.com/example.iso</url>
+        <url type="http" preference="100">http://main.example.com/example.iso</url>
+        <url type="bittorrent" preference="100">http://example.com/example.iso.torrent</url>
+      </resources>
+    </file>
+  </files>
+</metalink>"#;
+
+    #[test]
+    fn test_parse_metalink_v4() {
+        let metalink = parse_metalink(META4).unwrap();
+
+        assert_eq!(metalink.files.len(), 2);
+        let file = &metalink.files[0];
+        assert_eq!(file.size, Some(10));
+        assert_eq!(file.hashes[0].algorithm, HashAlgorithm::Sha256);
+        assert_eq!(file.pieces.as_ref().unwrap().hashes.len(), 3);
+        assert_eq!(file.mirrors.len(), 2);
+        assert_eq!(file.mirrors[0].url, "https://us.example.com/example.iso");
+        assert_eq!(metalink.files[1].name, "readme.txt");
+    }
+
+    #[test]
+    fn test_parse_metalink_v3() {
+        let metalink = parse_metalink(METALINK_V3).unwrap();
+
+        let file = &metalink.files[0];
+        assert_eq!(file.hashes[0].algorithm, HashAlgorithm::Md5);
+        assert_eq!(file.mirrors.len(), 2);
+        assert_eq!(file.mirrors[0].url, "http://main.example.com/example.iso");
+        assert_eq!(file.mirrors[1].location, Some(String::from("fr")));
+    }
+
+    #[test]
+    fn test_segments() {
+        let metalink = parse_metalink(META4).unwrap();
+        let file = &metalink.files[0];
+
+        let segments = file.segments(Some("DE"));
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].url, "https://de.example.com/example.iso");
+        assert_eq!(segments[1].url, "https://us.example.com/example.iso");
+        assert_eq!(segments[2].byte_range, Some((8, 9)));
+        assert!(segments[0].checksum.as_ref().unwrap().verify(b"1111"));
+        assert_eq!(segments[0].fallback_urls.len(), 1);
+    }
+}
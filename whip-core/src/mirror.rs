@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    time::Duration,
+};
+
+use reqwest::{header, Client, StatusCode, Url};
+
+use crate::{
+    download::{DownloadMeta, DownloadTask},
+    errors::WhipError,
+    stream::parse_url,
+};
+
+/// User agent sent while crawling and matched against robots.txt groups
+pub const USER_AGENT: &str = "whip";
+
+/// Limits of a mirroring session.
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    /// How many links away from the start page to go
+    pub max_depth: u32,
+    /// Hosts links may point to, the start url's host is always allowed
+    pub allowed_domains: Vec<String>,
+    /// Only urls whose path starts with this prefix are followed.
+    /// Defaults to the directory of the start url.
+    pub path_prefix: Option<String>,
+    /// Pause between two requests
+    pub delay: Duration,
+    pub respect_robots: bool,
+}
+
+/// A file found while crawling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub url: String,
+    /// Path of the file relative to the output directory (host/remote/path)
+    pub local_path: PathBuf,
+    /// What the server told about the file when it was found
+    pub meta: DownloadMeta,
+    /// Body of an html page, already fetched for its links
+    pub page: Option<String>,
+}
+
+/// Rules of a robots.txt file applying to whip.
+#[derive(Debug, Default)]
+pub struct Robots {
+    allow: Vec<String>,
+    disallow: Vec<String>,
+    pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Parses a robots.txt file, keeping the group for whip if there's
+    /// one or the `*` group otherwise.
+    pub fn parse(content: &str) -> Self {
+        let mut groups: HashMap<String, Robots> = HashMap::new();
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (field, value) = match line.split_once(':') {
+                Some((f, v)) => (f.trim().to_lowercase(), v.trim()),
+                None => continue,
+            };
+            match field.as_str() {
+                "user-agent" => {
+                    // A user-agent line after rules starts a new group
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" | "crawl-delay" => {
+                    in_rules = true;
+                    for agent in agents.iter() {
+                        let group = groups.entry(agent.to_owned()).or_default();
+                        match field.as_str() {
+                            "allow" if !value.is_empty() => group.allow.push(value.to_string()),
+                            "disallow" if !value.is_empty() => {
+                                group.disallow.push(value.to_string())
+                            }
+                            "crawl-delay" => {
+                                group.crawl_delay =
+                                    value.parse::<f64>().ok().map(Duration::from_secs_f64)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        groups
+            .remove(USER_AGENT)
+            .or_else(|| groups.remove("*"))
+            .unwrap_or_default()
+    }
+
+    /// Checks if a path may be fetched. The longest matching rule wins,
+    /// allow wins ties.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let longest = |rules: &Vec<String>| {
+            rules
+                .iter()
+                .filter(|r| rule_matches(r, path))
+                .map(|r| r.len())
+                .max()
+        };
+        match (longest(&self.allow), longest(&self.disallow)) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(a), Some(d)) => a >= d,
+        }
+    }
+}
+
+/// Matches a robots.txt rule supporting the `*` wildcard and the `$` end anchor
+fn rule_matches(rule: &str, path: &str) -> bool {
+    let (rule, anchored) = match rule.strip_suffix('$') {
+        Some(r) => (r, true),
+        None => (rule, false),
+    };
+    let parts: Vec<&str> = rule.split('*').collect();
+    if !path.starts_with(parts[0]) {
+        return false;
+    }
+    let mut position = parts[0].len();
+    for (i, part) in parts.iter().enumerate().skip(1) {
+        // An anchored rule's last part has to match the end of the path
+        if anchored && i == parts.len() - 1 {
+            return path.len() >= position + part.len() && path.ends_with(part);
+        }
+        match path[position..].find(part) {
+            Some(index) => position += index + part.len(),
+            None => return false,
+        }
+    }
+    !anchored || position == path.len()
+}
+
+/// Extracts the href and src attributes of an HTML page, resolved against `base`.
+/// Fragments are dropped and non http(s) links ignored.
+pub fn extract_links(html: &str, base: &Url) -> Vec<Url> {
+    // ASCII lowercasing keeps the byte offsets of the original page
+    let lower = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+
+    for attribute in ["href", "src"] {
+        let mut start = 0;
+        while let Some(index) = lower[start..].find(attribute) {
+            let mut position = start + index + attribute.len();
+            start = position;
+            // Make sure it's an attribute name and not part of another word
+            let preceding = lower[..start - attribute.len()].chars().last();
+            if !matches!(preceding, Some(c) if c.is_whitespace()) {
+                continue;
+            }
+            let rest = &html[position..];
+            let trimmed = rest.trim_start();
+            if !trimmed.starts_with('=') {
+                continue;
+            }
+            position += rest.len() - trimmed.len() + 1;
+            let value_start = html[position..].trim_start();
+            let value = match value_start.chars().next() {
+                Some(quote @ ('"' | '\'')) => value_start[1..].split(quote).next().unwrap_or(""),
+                Some(_) => value_start
+                    .split(|c: char| c.is_whitespace() || c == '>')
+                    .next()
+                    .unwrap_or(""),
+                None => "",
+            };
+            let value = value.trim().replace("&amp;", "&");
+            if value.is_empty() {
+                continue;
+            }
+            if let Ok(mut url) = base.join(&value) {
+                if url.scheme() == "http" || url.scheme() == "https" {
+                    url.set_fragment(None);
+                    links.push(url);
+                }
+            }
+        }
+    }
+
+    links
+}
+
+/// Checks if a page is an Apache/nginx (or python http.server) directory listing.
+pub fn is_autoindex(html: &str) -> bool {
+    let lower = html.to_ascii_lowercase();
+    lower.contains("<title>index of ") || lower.contains("<title>directory listing for ")
+}
+
+/// Path a url is stored under, relative to the output directory
+pub fn local_path(url: &Url) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(url.host_str().unwrap_or("unknown_host"));
+    let segments: Vec<&str> = url.path_segments().map(|s| s.collect()).unwrap_or_default();
+    for segment in segments.iter() {
+        if segment.is_empty() || *segment == "." || *segment == ".." {
+            continue;
+        }
+        path.push(segment);
+    }
+    if url.path().ends_with('/') || segments.iter().all(|s| s.is_empty()) {
+        path.push("index.html");
+    }
+    path
+}
+
+/// Walks a website from a start url, within the limits of the options,
+/// and returns the files to download.
+pub struct Crawler {
+    client: Client,
+    options: MirrorOptions,
+    start: Url,
+    path_prefix: String,
+    robots: HashMap<String, Robots>,
+}
+
+impl Crawler {
+    pub fn new(start_url: &str, options: MirrorOptions) -> Result<Self, WhipError> {
+        let start = parse_url(start_url)?;
+        let path_prefix = match &options.path_prefix {
+            Some(p) => p.to_owned(),
+            None => match start.path().rfind('/') {
+                Some(index) => start.path()[..index + 1].to_string(),
+                None => String::from("/"),
+            },
+        };
+        let client = match Client::builder().user_agent(USER_AGENT).build() {
+            Ok(c) => c,
+            Err(e) => return Err(WhipError::Unknown(e.to_string())),
+        };
+        Ok(Crawler {
+            client,
+            options,
+            start,
+            path_prefix,
+            robots: HashMap::new(),
+        })
+    }
+
+    /// Checks the domain and path limits
+    fn in_scope(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or("");
+        let allowed_host = Some(host) == self.start.host_str()
+            || self
+                .options
+                .allowed_domains
+                .iter()
+                .any(|d| host == d || host.ends_with(&format!(".{}", d)));
+        allowed_host && url.path().starts_with(&self.path_prefix)
+    }
+
+    /// Fetches (once per host) and applies robots.txt
+    async fn is_allowed(&mut self, url: &Url) -> bool {
+        if !self.options.respect_robots {
+            return true;
+        }
+        let origin = url.origin().ascii_serialization();
+        if !self.robots.contains_key(&origin) {
+            let robots = match self
+                .client
+                .get(format!("{}/robots.txt", origin))
+                .send()
+                .await
+            {
+                Ok(response) if response.status() == StatusCode::OK => {
+                    Robots::parse(&response.text().await.unwrap_or_default())
+                }
+                _ => Robots::default(),
+            };
+            self.robots.insert(origin.to_owned(), robots);
+        }
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path = format!("{}?{}", path, query);
+        }
+        self.robots[&origin].is_allowed(&path)
+    }
+
+    async fn wait(&self, url: &Url) {
+        let crawl_delay = self
+            .robots
+            .get(&url.origin().ascii_serialization())
+            .and_then(|r| r.crawl_delay)
+            .unwrap_or_default();
+        tokio::time::sleep(self.options.delay.max(crawl_delay)).await;
+    }
+
+    /// Crawls the website. `on_resource` is called for every file found.
+    pub async fn crawl<F>(mut self, mut on_resource: F) -> Result<Vec<Resource>, WhipError>
+    where
+        F: FnMut(&Resource),
+    {
+        let mut resources = Vec::new();
+        let mut visited: HashSet<Url> = HashSet::new();
+        let mut queue: VecDeque<(Url, u32)> = VecDeque::new();
+        queue.push_back((self.start.clone(), 0));
+        visited.insert(self.start.clone());
+
+        while let Some((url, depth)) = queue.pop_front() {
+            if !self.is_allowed(&url).await {
+                continue;
+            }
+            if depth > 0 {
+                self.wait(&url).await;
+            }
+
+            let response = match self.client.head(url.clone()).send().await {
+                Ok(r) => r,
+                Err(e) => {
+                    // The start page has to be reachable, other links may be dead
+                    if depth == 0 {
+                        return Err(WhipError::request(url.as_str(), e));
+                    }
+                    continue;
+                }
+            };
+            if !response.status().is_success() {
+                if depth == 0 {
+                    return Err(WhipError::Http {
+                        status: response.status().as_u16(),
+                        url: url.to_string(),
+                    });
+                }
+                continue;
+            }
+            let is_html = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|c| c.to_str().ok())
+                .map(|c| c.contains("text/html"))
+                .unwrap_or(false);
+            // Redirects may land outside the limits
+            let url = response.url().clone();
+            if !self.in_scope(&url) {
+                continue;
+            }
+
+            let meta = DownloadTask::read_meta(url.as_str(), response.headers());
+            let mut page = None;
+            let mut save = true;
+            if is_html {
+                self.wait(&url).await;
+                let html = match self.client.get(url.clone()).send().await {
+                    Ok(r) => r.text().await.unwrap_or_default(),
+                    Err(_) => continue,
+                };
+                // Listings only matter for the links they hold
+                save = !is_autoindex(&html);
+                if depth < self.options.max_depth {
+                    for mut link in extract_links(&html, &url) {
+                        // Autoindex sort links (?C=N;O=D) point to the same listing
+                        if link.query().map(|q| q.starts_with("C=")).unwrap_or(false) {
+                            link.set_query(None);
+                        }
+                        if self.in_scope(&link) && visited.insert(link.clone()) {
+                            queue.push_back((link, depth + 1));
+                        }
+                    }
+                }
+                page = Some(html);
+            }
+
+            if save {
+                let resource = Resource {
+                    local_path: local_path(&url),
+                    url: url.to_string(),
+                    meta,
+                    page,
+                };
+                on_resource(&resource);
+                resources.push(resource);
+            }
+        }
+
+        Ok(resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_links() {
+        let base = Url::parse("https://example.com/docs/guide/").unwrap();
+        let html = r#"<html><body>
+            <a href="intro.html#top">Intro</a>
+            <A HREF='../api/index.html'>API</A>
+            <img src=logo.png alt="">
+            <a href="mailto:someone@example.com">Mail</a>
+            <a data-href="ignored.html">Not a link</a>
+            <a href="https://other.com/page?a=1&amp;b=2">Other</a>
+        </body></html>"#;
+
+        let links: Vec<String> = extract_links(html, &base)
+            .iter()
+            .map(|u| u.to_string())
+            .collect();
+
+        assert_eq!(
+            links,
+            vec![
+                "https://example.com/docs/guide/intro.html",
+                "https://example.com/docs/api/index.html",
+                "https://other.com/page?a=1&b=2",
+                "https://example.com/docs/guide/logo.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_robots() {
+        let robots = Robots::parse(
+            "User-agent: googlebot\nDisallow: /\n\nUser-agent: *\nDisallow: /private/\nAllow: /private/public*.html$\nCrawl-delay: 2\n",
+        );
+
+        assert!(robots.is_allowed("/docs/index.html"));
+        assert!(!robots.is_allowed("/private/secret.html"));
+        assert!(robots.is_allowed("/private/public-notes.html"));
+        assert!(!robots.is_allowed("/private/public-notes.html.bak"));
+        assert_eq!(robots.crawl_delay, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_local_path() {
+        let file = Url::parse("https://example.com/pub/linux/../kernel/v5.x/README").unwrap();
+        let dir = Url::parse("https://example.com/pub/docs/").unwrap();
+
+        assert_eq!(
+            local_path(&file),
+            PathBuf::from("example.com/pub/kernel/v5.x/README")
+        );
+        assert_eq!(
+            local_path(&dir),
+            PathBuf::from("example.com/pub/docs/index.html")
+        );
+    }
+
+    #[test]
+    fn test_is_autoindex() {
+        assert!(is_autoindex(
+            "<html><head><title>Index of /pub/</title></head></html>"
+        ));
+        assert!(!is_autoindex(
+            "<html><head><title>User guide</title></head></html>"
+        ));
+    }
+}
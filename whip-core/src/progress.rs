@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::checkpoint::{PartCheckpoint, PartState};
+
+/// Speeds are measured over this much time
+const SPEED_WINDOW: Duration = Duration::from_secs(3);
+
+/// Where a part of a download stands, with the speed of its connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartProgress {
+    pub id: u32,
+    pub start_byte: u64,
+    pub end_byte: u64,
+    /// Bytes of the part, 0 when the size of the download is unknown
+    pub size: u64,
+    /// Bytes of the part received so far, resumed ones included
+    pub bytes_done: u64,
+    pub state: PartState,
+    /// Bytes per second received by the connection of the part
+    pub speed: f64,
+}
+
+impl PartProgress {
+    /// None when the size of the part is unknown
+    pub fn percentage(&self) -> Option<f64> {
+        if self.size == 0 {
+            return None;
+        }
+        Some((self.bytes_done as f64 / self.size as f64 * 100f64).min(100f64))
+    }
+}
+
+/// Snapshot of a download, handed to the progress callback of a downloader
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Bytes received so far, resumed ones included
+    pub downloaded_bytes: u64,
+    /// None when the server didn't tell the size of the file
+    pub total_bytes: Option<u64>,
+    /// Bytes per second over the last few seconds
+    pub speed: f64,
+    /// Bytes per second since the session started, resumed bytes left out
+    pub average_speed: f64,
+    /// Time since the session started
+    pub elapsed: Duration,
+    /// Time left at the current speed, None when the size or the speed is unknown
+    pub eta: Option<Duration>,
+    pub parts: Vec<PartProgress>,
+}
+
+impl Progress {
+    /// None when the size of the file is unknown
+    pub fn percentage(&self) -> Option<f64> {
+        let total = self.total_bytes?;
+        Some((self.downloaded_bytes as f64 / total as f64 * 100f64).min(100f64))
+    }
+
+    /// Whether every part is downloaded
+    pub fn is_complete(&self) -> bool {
+        !self.parts.is_empty() && self.parts.iter().all(|p| p.state == PartState::Completed)
+    }
+}
+
+/// Rate of a growing byte count over a sliding window
+#[derive(Debug, Default)]
+struct Rate {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Rate {
+    /// Bytes per second between the oldest reading of the window and this one
+    fn sample(&mut self, now: Instant, bytes: u64) -> f64 {
+        self.samples.push_back((now, bytes));
+        // The oldest reading kept is the last one taken before the window
+        while self.samples.len() > 2 && self.samples[1].0 + SPEED_WINDOW <= now {
+            self.samples.pop_front();
+        }
+        match self.samples.front() {
+            Some((at, first)) if now > *at => {
+                bytes.saturating_sub(*first) as f64 / (now - *at).as_secs_f64()
+            }
+            _ => 0f64,
+        }
+    }
+}
+
+/// Turns the parts of a download into progress snapshots, measuring the
+/// speeds between two of them
+#[derive(Debug)]
+pub struct ProgressMeter {
+    started: Instant,
+    total: Rate,
+    parts: BTreeMap<u32, Rate>,
+}
+
+impl Default for ProgressMeter {
+    fn default() -> Self {
+        ProgressMeter {
+            started: Instant::now(),
+            total: Rate::default(),
+            parts: BTreeMap::new(),
+        }
+    }
+}
+
+impl ProgressMeter {
+    pub fn new() -> Self {
+        ProgressMeter::default()
+    }
+
+    /// Progress of `parts` out of `total_bytes`, 0 when unknown. `resumed`
+    /// holds the bytes the parts had from a previous session, they count as
+    /// downloaded but not in the speeds.
+    pub fn measure(
+        &mut self,
+        parts: Vec<PartCheckpoint>,
+        resumed: &BTreeMap<u32, u64>,
+        total_bytes: u64,
+    ) -> Progress {
+        self.measure_at(Instant::now(), parts, resumed, total_bytes)
+    }
+
+    fn measure_at(
+        &mut self,
+        now: Instant,
+        parts: Vec<PartCheckpoint>,
+        resumed: &BTreeMap<u32, u64>,
+        total_bytes: u64,
+    ) -> Progress {
+        let mut downloaded_bytes = 0;
+        let mut received = 0;
+        let parts: Vec<PartProgress> = parts
+            .into_iter()
+            .map(|p| {
+                let part_received = p
+                    .bytes_done
+                    .saturating_sub(resumed.get(&p.id).copied().unwrap_or(0));
+                downloaded_bytes += p.bytes_done;
+                received += part_received;
+                let speed = self
+                    .parts
+                    .entry(p.id)
+                    .or_default()
+                    .sample(now, part_received);
+                PartProgress {
+                    id: p.id,
+                    start_byte: p.start_byte,
+                    end_byte: p.end_byte,
+                    size: if total_bytes == 0 {
+                        0
+                    } else {
+                        (p.end_byte + 1)
+                            .min(total_bytes)
+                            .saturating_sub(p.start_byte)
+                    },
+                    bytes_done: p.bytes_done,
+                    state: p.state,
+                    speed: if p.state == PartState::Downloading {
+                        speed
+                    } else {
+                        0f64
+                    },
+                }
+            })
+            .collect();
+
+        let speed = self.total.sample(now, received);
+        let elapsed = now.saturating_duration_since(self.started);
+        let average_speed = if elapsed.is_zero() {
+            0f64
+        } else {
+            received as f64 / elapsed.as_secs_f64()
+        };
+        let total_bytes = if total_bytes == 0 {
+            None
+        } else {
+            Some(total_bytes)
+        };
+        let eta = total_bytes.and_then(|total| {
+            let remaining = total.saturating_sub(downloaded_bytes);
+            let rate = if speed > 0f64 { speed } else { average_speed };
+            if remaining == 0 {
+                Some(Duration::ZERO)
+            } else if rate > 0f64 {
+                Some(Duration::from_secs_f64(remaining as f64 / rate))
+            } else {
+                None
+            }
+        });
+
+        Progress {
+            downloaded_bytes,
+            total_bytes,
+            speed,
+            average_speed,
+            elapsed,
+            eta,
+            parts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(id: u32, start_byte: u64, end_byte: u64, bytes_done: u64) -> PartCheckpoint {
+        PartCheckpoint {
+            id,
+            start_byte,
+            end_byte,
+            bytes_done,
+            state: PartState::Downloading,
+        }
+    }
+
+    #[test]
+    fn test_measure() {
+        let mut meter = ProgressMeter::new();
+        let start = meter.started;
+        // Part 1 had 500 bytes from a previous session
+        let resumed = BTreeMap::from([(1, 500)]);
+
+        let progress = meter.measure_at(
+            start,
+            vec![part(0, 0, 999, 0), part(1, 1000, 2000, 500)],
+            &resumed,
+            2000,
+        );
+        assert_eq!(progress.downloaded_bytes, 500);
+        assert_eq!(progress.parts[0].size, 1000);
+        assert_eq!(progress.parts[1].size, 1000);
+        assert_eq!(progress.percentage(), Some(25f64));
+        assert_eq!(progress.eta, None);
+
+        let progress = meter.measure_at(
+            start + Duration::from_secs(2),
+            vec![part(0, 0, 999, 400), part(1, 1000, 2000, 700)],
+            &resumed,
+            2000,
+        );
+        assert_eq!(progress.parts[0].speed, 200f64);
+        assert_eq!(progress.parts[1].speed, 100f64);
+        assert_eq!(progress.speed, 300f64);
+        assert_eq!(progress.average_speed, 300f64);
+        assert_eq!(progress.eta, Some(Duration::from_secs(3)));
+
+        // Only the readings of the last seconds make the speed
+        let progress = meter.measure_at(
+            start + Duration::from_secs(6),
+            vec![part(0, 0, 999, 400), part(1, 1000, 2000, 700)],
+            &resumed,
+            2000,
+        );
+        assert_eq!(progress.speed, 0f64);
+        assert_eq!(progress.average_speed, 100f64);
+        assert_eq!(progress.eta, Some(Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn test_unknown_size() {
+        let mut meter = ProgressMeter::new();
+        let start = meter.started;
+        let progress = meter.measure_at(
+            start + Duration::from_secs(1),
+            vec![part(0, 0, 0, 4096)],
+            &BTreeMap::new(),
+            0,
+        );
+        assert_eq!(progress.total_bytes, None);
+        assert_eq!(progress.percentage(), None);
+        assert_eq!(progress.parts[0].percentage(), None);
+        assert_eq!(progress.eta, None);
+        assert_eq!(progress.average_speed, 4096f64);
+    }
+}
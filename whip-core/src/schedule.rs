@@ -0,0 +1,357 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+use tokio::task::JoinHandle;
+
+use crate::{control::DownloadControl, storage::format_size};
+
+/// How much a download may use during a part of the day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bandwidth {
+    Unlimited,
+    /// Bytes per second
+    Limited(u64),
+    Paused,
+}
+
+impl Bandwidth {
+    /// Parses "unlimited", "pause" or a rate in bytes per second with an
+    /// optional K, M or G suffix (500K, 2M...)
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        match value.to_lowercase().as_str() {
+            "unlimited" => return Ok(Bandwidth::Unlimited),
+            "pause" | "paused" => return Ok(Bandwidth::Paused),
+            _ => {}
+        }
+        match parse_rate(value) {
+            Some(rate) if rate > 0 => Ok(Bandwidth::Limited(rate)),
+            _ => Err(format!(
+                "Invalid bandwidth {}, expected unlimited, pause or a rate like 500K",
+                value
+            )),
+        }
+    }
+
+    /// Sets the control of a download to this bandwidth
+    pub fn apply(&self, control: &DownloadControl) {
+        match self {
+            Bandwidth::Unlimited => {
+                control.set_rate_limit(0);
+                control.release();
+            }
+            Bandwidth::Limited(rate) => {
+                control.set_rate_limit(*rate);
+                control.release();
+            }
+            Bandwidth::Paused => control.hold(),
+        }
+    }
+
+    /// This bandwidth lowered to `max_rate` bytes per second, unchanged
+    /// when `max_rate` is 0
+    pub fn capped(self, max_rate: u64) -> Self {
+        match self {
+            _ if max_rate == 0 => self,
+            Bandwidth::Unlimited => Bandwidth::Limited(max_rate),
+            Bandwidth::Limited(rate) => Bandwidth::Limited(rate.min(max_rate)),
+            Bandwidth::Paused => Bandwidth::Paused,
+        }
+    }
+}
+
+/// Parses a rate in bytes per second (1048576, 1024K, 1M...)
+pub fn parse_rate(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix("B/S").unwrap_or(&value);
+    let value = value.strip_suffix('B').unwrap_or(value);
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number: f64 = number.trim().parse().ok()?;
+    if number < 0f64 {
+        return None;
+    }
+    Some((number * multiplier as f64) as u64)
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bandwidth::Unlimited => write!(f, "unlimited"),
+            Bandwidth::Limited(rate) => write!(f, "{}", rate),
+            Bandwidth::Paused => write!(f, "pause"),
+        }
+    }
+}
+
+/// A daily time range, it wraps around midnight when it ends before it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    /// Minutes since midnight
+    pub start: u32,
+    pub end: u32,
+    pub bandwidth: Bandwidth,
+}
+
+impl Window {
+    /// Parses "22:00-06:00" or "22:00-06:00=500K", windows are unlimited by default
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (range, bandwidth) = match value.split_once('=') {
+            Some((r, b)) => (r, Bandwidth::parse(b)?),
+            None => (value, Bandwidth::Unlimited),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((s, e)) => (parse_minutes(s)?, parse_minutes(e)?),
+            None => {
+                return Err(format!(
+                    "Invalid window {}, expected START-END like 22:00-06:00",
+                    value
+                ))
+            }
+        };
+        Ok(Window {
+            start,
+            end,
+            bandwidth,
+        })
+    }
+
+    pub fn contains(&self, minute: u32) -> bool {
+        match self.start.cmp(&self.end) {
+            std::cmp::Ordering::Less => self.start <= minute && minute < self.end,
+            std::cmp::Ordering::Greater => minute >= self.start || minute < self.end,
+            // Same start and end covers the whole day
+            std::cmp::Ordering::Equal => true,
+        }
+    }
+}
+
+fn parse_minutes(value: &str) -> Result<u32, String> {
+    match NaiveTime::parse_from_str(value.trim(), "%H:%M") {
+        Ok(t) => Ok(t.hour() * 60 + t.minute()),
+        Err(_) => Err(format!("Invalid time {}, expected HH:MM", value.trim())),
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}={}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60,
+            self.bandwidth
+        )
+    }
+}
+
+/// Bandwidth of a download through the day: the first window containing
+/// the time applies, `otherwise` outside of all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+    pub otherwise: Bandwidth,
+}
+
+impl Schedule {
+    pub fn bandwidth_at(&self, minute: u32) -> Bandwidth {
+        self.windows
+            .iter()
+            .find(|w| w.contains(minute))
+            .map(|w| w.bandwidth)
+            .unwrap_or(self.otherwise)
+    }
+
+    /// Parses the stored form, windows and the bandwidth outside of them
+    /// separated by semicolons (22:00-06:00=unlimited;otherwise=pause)
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut windows = Vec::new();
+        let mut otherwise = Bandwidth::Paused;
+        for item in value.split(';').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+            match item.strip_prefix("otherwise=") {
+                Some(b) => otherwise = Bandwidth::parse(b)?,
+                None => windows.push(Window::parse(item)?),
+            }
+        }
+        if windows.is_empty() {
+            return Err(String::from("A schedule needs at least one window"));
+        }
+        Ok(Schedule { windows, otherwise })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for window in self.windows.iter() {
+            write!(f, "{};", window)?;
+        }
+        write!(f, "otherwise={}", self.otherwise)
+    }
+}
+
+/// Parses when a download may start: "22:30" (the next time it's 22:30),
+/// "2026-10-19 22:30" or an RFC 3339 date.
+pub fn parse_start_time(value: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    let value = value.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(value) {
+        return Ok(d.with_timezone(&Local));
+    }
+    let naive = if let Ok(d) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M") {
+        d
+    } else if let Ok(d) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        d.and_hms(0, 0, 0)
+    } else if let Ok(t) = NaiveTime::parse_from_str(value, "%H:%M") {
+        let today = now.naive_local().date().and_time(t);
+        if today > now.naive_local() {
+            today
+        } else {
+            today + chrono::Duration::days(1)
+        }
+    } else {
+        return Err(format!(
+            "Invalid start time {}, expected HH:MM, YYYY-MM-DD HH:MM or an RFC 3339 date",
+            value
+        ));
+    };
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(d) => Ok(d),
+        None => Err(format!("{} doesn't exist in the local time zone", value)),
+    }
+}
+
+/// When and how fast a download may run
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    /// The download is paused until then
+    pub start_after: Option<DateTime<Local>>,
+    pub schedule: Option<Schedule>,
+    /// Highest rate in bytes per second whatever the schedule says, 0 for none
+    pub max_rate: u64,
+}
+
+impl Scheduler {
+    pub fn is_empty(&self) -> bool {
+        self.start_after.is_none() && self.schedule.is_none() && self.max_rate == 0
+    }
+
+    pub fn bandwidth_at(&self, now: DateTime<Local>) -> Bandwidth {
+        if let Some(start_after) = self.start_after {
+            if now < start_after {
+                return Bandwidth::Paused;
+            }
+        }
+        let bandwidth = match &self.schedule {
+            Some(s) => s.bandwidth_at(now.hour() * 60 + now.minute()),
+            None => Bandwidth::Unlimited,
+        };
+        bandwidth.capped(self.max_rate)
+    }
+
+    /// Applies the bandwidth of the current time to `control` right away,
+    /// then again every second from a background task until it's aborted.
+    /// `on_change` gets every new bandwidth, the first one included.
+    pub fn start<F>(self, control: DownloadControl, mut on_change: F) -> JoinHandle<()>
+    where
+        F: FnMut(Bandwidth) + Send + 'static,
+    {
+        let mut current = self.bandwidth_at(Local::now());
+        current.apply(&control);
+        on_change(current);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let bandwidth = self.bandwidth_at(Local::now());
+                if bandwidth != current {
+                    bandwidth.apply(&control);
+                    on_change(bandwidth);
+                    current = bandwidth;
+                }
+            }
+        })
+    }
+}
+
+/// Tells what a download does under a bandwidth, for logs
+pub fn describe(bandwidth: Bandwidth) -> String {
+    match bandwidth {
+        Bandwidth::Unlimited => String::from("Running at full speed"),
+        Bandwidth::Limited(rate) => format!("Running at up to {}/s", format_size(rate)),
+        Bandwidth::Paused => String::from("Paused by schedule"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::parse("22:00-06:00;12:00-13:00=500K;otherwise=pause").unwrap();
+
+        assert_eq!(schedule.bandwidth_at(23 * 60), Bandwidth::Unlimited);
+        assert_eq!(schedule.bandwidth_at(2 * 60), Bandwidth::Unlimited);
+        assert_eq!(schedule.bandwidth_at(6 * 60), Bandwidth::Paused);
+        assert_eq!(
+            schedule.bandwidth_at(12 * 60 + 30),
+            Bandwidth::Limited(500 * 1024)
+        );
+        assert_eq!(Schedule::parse(&schedule.to_string()).unwrap(), schedule);
+        assert!(Schedule::parse("otherwise=pause").is_err());
+        assert!(Schedule::parse("25:00-06:00").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Some(1000));
+        assert_eq!(parse_rate("1.5M"), Some(1572864));
+        assert_eq!(parse_rate("500KB/s"), Some(512000));
+        assert_eq!(parse_rate("fast"), None);
+    }
+
+    #[test]
+    fn test_parse_start_time() {
+        let now = Local.ymd(2026, 10, 18).and_hms(23, 0, 0);
+
+        assert_eq!(
+            parse_start_time("22:30", now).unwrap(),
+            Local.ymd(2026, 10, 19).and_hms(22, 30, 0)
+        );
+        assert_eq!(
+            parse_start_time("23:30", now).unwrap(),
+            Local.ymd(2026, 10, 18).and_hms(23, 30, 0)
+        );
+        assert!(parse_start_time("tonight", now).is_err());
+    }
+
+    #[test]
+    fn test_scheduler_start_after() {
+        let now = Local.ymd(2026, 10, 18).and_hms(23, 0, 0);
+        let scheduler = Scheduler {
+            start_after: Some(now + chrono::Duration::hours(1)),
+            schedule: None,
+            max_rate: 0,
+        };
+
+        assert_eq!(scheduler.bandwidth_at(now), Bandwidth::Paused);
+        assert_eq!(
+            scheduler.bandwidth_at(now + chrono::Duration::hours(2)),
+            Bandwidth::Unlimited
+        );
+
+        let capped = Scheduler {
+            max_rate: 1000,
+            ..scheduler
+        };
+        assert_eq!(
+            capped.bandwidth_at(now + chrono::Duration::hours(2)),
+            Bandwidth::Limited(1000)
+        );
+    }
+}
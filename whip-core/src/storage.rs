@@ -1,6 +1,16 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use fs2::FileExt;
 use futures::io::Cursor;
 use tokio::fs::File;
 
+use crate::errors::WhipError;
+
 #[derive(Debug)]
 pub enum Storage {
     InMemory(MemoryStorage),
@@ -15,9 +25,7 @@ pub struct MemoryStorage {
 impl MemoryStorage {
     pub fn new(size: u64) -> Self {
         MemoryStorage {
-            cursor: Cursor::new(Vec::with_capacity(
-                size.try_into().unwrap_or(usize::max_value()),
-            )),
+            cursor: Cursor::new(Vec::with_capacity(size.try_into().unwrap_or(usize::MAX))),
         }
     }
 }
@@ -26,3 +34,210 @@ impl MemoryStorage {
 pub struct FileStorage {
     pub file: File,
 }
+
+/// Checks if a write failed because the disk (or the user's quota) is full
+pub fn is_disk_full(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// Checks that `temp_dir` can hold `temp_bytes` more bytes and `output_dir`
+/// `output_bytes` more. When both live on the same file system, it has to
+/// hold the temporary parts and the final file at once.
+pub fn check_disk_space(
+    temp_dir: Option<&Path>,
+    temp_bytes: u64,
+    output_dir: &Path,
+    output_bytes: u64,
+) -> Result<(), WhipError> {
+    let mut needed = vec![(output_dir, output_bytes)];
+    if let Some(temp_dir) = temp_dir {
+        if same_file_system(temp_dir, output_dir) {
+            needed[0].1 += temp_bytes;
+        } else {
+            needed.push((temp_dir, temp_bytes));
+        }
+    }
+
+    for (dir, bytes) in needed.into_iter() {
+        let available = match fs2::available_space(dir) {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(WhipError::Storage(format!(
+                    "{} : {}",
+                    e,
+                    dir.to_string_lossy()
+                )))
+            }
+        };
+        if available < bytes {
+            return Err(WhipError::Storage(format!(
+                "Not enough disk space on {} : {} needed, {} available",
+                dir.to_string_lossy(),
+                format_size(bytes),
+                format_size(available)
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file_system(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => true,
+    }
+}
+
+/// Without a device id, assume the worst
+#[cfg(not(unix))]
+fn same_file_system(_a: &Path, _b: &Path) -> bool {
+    true
+}
+
+/// Exclusive lock on a task, held while one process works on it. The
+/// system releases it when the process ends, even killed, so it never
+/// goes stale. Dropping it releases it.
+#[derive(Debug)]
+pub struct TaskLock {
+    file: std::fs::File,
+    pub path: PathBuf,
+}
+
+impl TaskLock {
+    /// Lock file of the task `task_id` in `dir`
+    pub fn file_path(dir: &Path, task_id: u64) -> PathBuf {
+        dir.join(format!("task-{}.lock", task_id))
+    }
+
+    /// Locks the task `task_id` with a lock file in `dir`, the directory
+    /// holding its temporary files. Fails right away if another process
+    /// holds it.
+    pub fn acquire(dir: &Path, task_id: u64) -> Result<Self, WhipError> {
+        let path = TaskLock::file_path(dir, task_id);
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(f) => f,
+            Err(e) => return Err(WhipError::io(path, e)),
+        };
+        if file.try_lock_exclusive().is_err() {
+            return Err(WhipError::Locked { task_id, path });
+        }
+        // A request left by an earlier session doesn't pause this one
+        let _ = std::fs::remove_file(TaskLock::pause_path(dir, task_id));
+        // Lets other processes tell who is downloading the task
+        if let Err(e) = file
+            .set_len(0)
+            .and_then(|_| file.write_all(process::id().to_string().as_bytes()))
+        {
+            return Err(WhipError::io(path, e));
+        }
+        Ok(TaskLock { file, path })
+    }
+
+    /// File asking the process holding the task `task_id` in `dir` to pause it
+    pub fn pause_path(dir: &Path, task_id: u64) -> PathBuf {
+        dir.join(format!("task-{}.pause", task_id))
+    }
+
+    /// Asks the process holding the task `task_id` in `dir` to pause it,
+    /// and only it. The holder checks for the request while it downloads.
+    pub fn request_pause(dir: &Path, task_id: u64) -> Result<(), WhipError> {
+        let path = TaskLock::pause_path(dir, task_id);
+        match std::fs::File::create(&path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(WhipError::io(path, e)),
+        }
+    }
+
+    /// Whether a pause of the task `task_id` in `dir` was requested
+    pub fn pause_requested(dir: &Path, task_id: u64) -> bool {
+        TaskLock::pause_path(dir, task_id).exists()
+    }
+
+    /// Id of the process holding the lock of the task `task_id` in `dir`,
+    /// None when no process does
+    pub fn holder(dir: &Path, task_id: u64) -> Option<u32> {
+        let mut file = std::fs::File::open(TaskLock::file_path(dir, task_id)).ok()?;
+        if file.try_lock_shared().is_ok() {
+            let _ = file.unlock();
+            return None;
+        }
+        let mut pid = String::new();
+        file.read_to_string(&mut pid).ok()?;
+        pid.trim().parse().ok()
+    }
+}
+
+impl Drop for TaskLock {
+    fn drop(&mut self) {
+        // The request is answered once the task is let go of
+        let _ = std::fs::remove_file(self.path.with_extension("pause"));
+        let _ = self.file.unlock();
+    }
+}
+
+/// Human readable size, e.g. 1.5 GiB
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024f64 && unit + 1 < units.len() {
+        size /= 1024f64;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_disk_space() {
+        let dir = std::env::temp_dir();
+
+        assert!(check_disk_space(Some(&dir), 1, &dir, 1).is_ok());
+        assert!(check_disk_space(None, 0, &dir, u64::MAX).is_err());
+        assert_eq!(format_size(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn test_task_lock() {
+        // Left to this run alone, removed at the end
+        let dir = std::env::temp_dir().join(format!("whip-task-lock-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let lock = TaskLock::acquire(&dir, 4242).unwrap();
+        assert_eq!(TaskLock::holder(&dir, 4242), Some(process::id()));
+        assert!(matches!(
+            TaskLock::acquire(&dir, 4242),
+            Err(WhipError::Locked { task_id: 4242, .. })
+        ));
+        TaskLock::request_pause(&dir, 4242).unwrap();
+        assert!(TaskLock::pause_requested(&dir, 4242));
+        drop(lock);
+        assert_eq!(TaskLock::holder(&dir, 4242), None);
+        assert!(!TaskLock::pause_requested(&dir, 4242));
+
+        // Requests nobody answered don't pause the next session
+        TaskLock::request_pause(&dir, 4242).unwrap();
+        let lock = TaskLock::acquire(&dir, 4242).unwrap();
+        assert!(!TaskLock::pause_requested(&dir, 4242));
+        drop(lock);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
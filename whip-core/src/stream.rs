@@ -0,0 +1,655 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use futures::join;
+use reqwest::{header, Client, Response, StatusCode, Url};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex, task};
+
+use crate::{
+    checkpoint::{Checkpoint, Checkpointer, PartCheckpoint, PartState, SpeedMeter},
+    checksum::Checksum,
+    control::DownloadControl,
+    errors::WhipError,
+    progress::{Progress, ProgressMeter},
+};
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// A single piece of a segmented stream (HLS, DASH...).
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Position of the segment in the final file
+    pub id: u64,
+    pub url: String,
+    /// Inclusive byte range to request, if the segment is a slice of a bigger resource
+    pub byte_range: Option<(u64, u64)>,
+    /// Key to decrypt the segment with once downloaded
+    pub key: Option<SegmentKey>,
+    /// Other sources serving the same bytes, tried in turn when a request fails
+    pub fallback_urls: Vec<String>,
+    /// Expected digest of the (decrypted) segment
+    pub checksum: Option<Checksum>,
+}
+
+impl Segment {
+    /// Sources of the segment, in the order they should be tried
+    fn sources(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.url).chain(self.fallback_urls.iter())
+    }
+
+    /// Decrypts the downloaded data and verifies its checksum
+    fn check(&self, mut data: Vec<u8>) -> Result<Vec<u8>, WhipError> {
+        if let Some(key) = &self.key {
+            let len = key.decrypt(&mut data)?;
+            data.truncate(len);
+        }
+        if let Some(checksum) = &self.checksum {
+            if !checksum.verify(&data) {
+                return Err(WhipError::ChecksumMismatch {
+                    algorithm: checksum.algorithm,
+                    resource: format!("segment {} ({})", self.id, self.url),
+                });
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// AES-128 key and initialisation vector of an encrypted segment.
+#[derive(Debug, Clone)]
+pub struct SegmentKey {
+    pub key: [u8; 16],
+    pub iv: [u8; 16],
+}
+
+impl SegmentKey {
+    /// Decrypts an AES-128-CBC (PKCS7 padded) segment.
+    pub fn decrypt(&self, data: &mut [u8]) -> Result<usize, WhipError> {
+        match Aes128CbcDec::new(&self.key.into(), &self.iv.into()).decrypt_padded_mut::<Pkcs7>(data)
+        {
+            Ok(plain) => Ok(plain.len()),
+            Err(_) => Err(WhipError::Parse(
+                "Error decrypting segment, invalid key or padding".to_string(),
+            )),
+        }
+    }
+}
+
+/// Criteria used to pick a rendition (HLS variant, DASH representation) of a stream.
+#[derive(Debug, Clone)]
+pub enum VariantSelector {
+    /// Rendition with the highest bandwidth
+    Highest,
+    /// Rendition with the lowest bandwidth
+    Lowest,
+    /// Rendition with the highest bandwidth not exceeding the value (bits per second)
+    MaxBandwidth(u64),
+    /// Rendition with the given resolution, or the closest one below it
+    Resolution(u32, u32),
+}
+
+impl VariantSelector {
+    /// Picks an item according to the selector. `info` returns the
+    /// bandwidth and resolution of an item.
+    pub fn select<'a, T, F>(&self, items: &'a [T], info: F) -> Option<&'a T>
+    where
+        F: Fn(&T) -> (u64, Option<(u32, u32)>),
+    {
+        let lowest = || items.iter().min_by_key(|i| info(i).0);
+        match self {
+            VariantSelector::Highest => items.iter().max_by_key(|i| info(i).0),
+            VariantSelector::Lowest => lowest(),
+            VariantSelector::MaxBandwidth(max) => items
+                .iter()
+                .filter(|i| info(i).0 <= *max)
+                .max_by_key(|i| info(i).0)
+                .or_else(lowest),
+            VariantSelector::Resolution(width, height) => items
+                .iter()
+                .filter(|i| match info(i).1 {
+                    Some((w, h)) => w <= *width && h <= *height,
+                    None => false,
+                })
+                .max_by_key(|i| (info(i).1, info(i).0))
+                .or_else(lowest),
+        }
+    }
+}
+
+/// Downloads the segments of a stream in parallel and joins them
+/// into a single file. Segments already present in the temporary
+/// directory are skipped, which makes interrupted streams resumable.
+/// Progress snapshots have a part per segment.
+pub struct StreamDownloader<P>
+where
+    P: std::marker::Send + std::marker::Sync + FnMut(&Progress) + 'static,
+{
+    /// Segments left to download
+    queue: VecDeque<Segment>,
+    /// Progress of each segment by id, its byte range is the one it's
+    /// requested with
+    segments: BTreeMap<u64, PartCheckpoint>,
+    /// Bytes each segment had from a previous session
+    resumed: BTreeMap<u32, u64>,
+    /// Measures the speeds of the progress snapshots
+    meter: ProgressMeter,
+    /// Directory to store the file. The path has to exist.
+    pub output_dir: PathBuf,
+    /// Temporary directory to store the segments. The path has to exist.
+    pub temp_dir: PathBuf,
+    /// Name of the final file
+    pub file_name: String,
+    /// Callback for getting download progress updates
+    pub on_progress_change: P,
+    /// Time between two calls of on_progress_change
+    pub progress_interval: Duration,
+    /// Max number of threads to use
+    max_threads: u32,
+    /// Maximum retry request for a segment
+    max_retries: u8,
+    /// Holds and throttles the segments from outside of the session
+    control: DownloadControl,
+    /// Bytes of the segments stored in the temporary directory
+    bytes_done: u64,
+    /// Sends the progress at a regular interval so it survives the process
+    pub checkpointer: Option<Checkpointer>,
+    /// Bytes the task received before this stream, from the tracks
+    /// downloaded ahead of it
+    pub bytes_before: u64,
+    /// Size of the task, 0 when unknown like for most streams
+    pub total_bytes: u64,
+}
+
+impl<P> StreamDownloader<P>
+where
+    P: std::marker::Send + std::marker::Sync + FnMut(&Progress) + 'static,
+{
+    pub fn new(
+        segments: Vec<Segment>,
+        output_dir: String,
+        temp_dir: String,
+        file_name: String,
+        on_progress_change: P,
+        max_threads: u32,
+        max_retries: u8,
+    ) -> Result<Self, WhipError> {
+        let output_path = PathBuf::from(output_dir);
+        if !output_path.is_dir() {
+            return Err(WhipError::Storage(
+                "Output directory doesn't exist or path leads to a file".to_string(),
+            ));
+        }
+        let temp_path = PathBuf::from(temp_dir);
+        if !temp_path.is_dir() {
+            return Err(WhipError::Storage(
+                "Temporary directory doesn't exist".to_string(),
+            ));
+        }
+        Ok(StreamDownloader {
+            queue: segments.into(),
+            segments: BTreeMap::new(),
+            resumed: BTreeMap::new(),
+            meter: ProgressMeter::new(),
+            output_dir: output_path,
+            temp_dir: temp_path,
+            file_name,
+            on_progress_change,
+            progress_interval: Duration::from_millis(200),
+            max_threads: max_threads.max(1),
+            max_retries,
+            control: DownloadControl::new(),
+            bytes_done: 0,
+            checkpointer: None,
+            bytes_before: 0,
+            total_bytes: 0,
+        })
+    }
+
+    /// Handle to hold, release and throttle the download while it runs
+    pub fn control(&self) -> DownloadControl {
+        self.control.clone()
+    }
+
+    /// Path of the temporary file of a segment
+    fn segment_path(&self, id: u64) -> PathBuf {
+        let mut path = PathBuf::new();
+        path.push(&self.temp_dir);
+        path.push(format!("{}.seg{}", self.file_name, id));
+        path
+    }
+
+    /// Downloads all the segments and returns the path of the final file.
+    pub async fn download(mut self) -> Result<PathBuf, WhipError> {
+        let ids: Vec<u64> = self.queue.iter().map(|s| s.id).collect();
+        let queue = std::mem::take(&mut self.queue);
+        for segment in queue.into_iter() {
+            let (start_byte, end_byte) = segment.byte_range.unwrap_or((0, 0));
+            let mut part = PartCheckpoint {
+                id: segment.id as u32,
+                start_byte,
+                end_byte,
+                bytes_done: 0,
+                state: PartState::Pending,
+            };
+            match self.segment_path(segment.id).metadata() {
+                Ok(m) if m.is_file() => {
+                    part.bytes_done = m.len();
+                    part.state = PartState::Completed;
+                    self.bytes_done += m.len();
+                    self.resumed.insert(part.id, m.len());
+                }
+                _ => self.queue.push_back(segment.clone()),
+            }
+            self.segments.insert(segment.id, part);
+        }
+        self.report_progress();
+
+        let client = Arc::from(reqwest::Client::new());
+        let session = Arc::from(Mutex::from(self));
+
+        let mut join_handles = Vec::new();
+        for _ in 0..session.lock().await.max_threads {
+            let s = session.clone();
+            let c = client.clone();
+            join_handles.push(task::spawn(
+                async move { StreamDownloader::worker(&s, c).await },
+            ));
+        }
+
+        let checkpointer = session.lock().await.checkpointer.clone();
+        let ticker = checkpointer.map(|checkpointer| {
+            let s = session.clone();
+            task::spawn(async move {
+                let mut speed = SpeedMeter::new();
+                let mut interval = tokio::time::interval(checkpointer.interval);
+                loop {
+                    interval.tick().await;
+                    let checkpoint = s.lock().await.checkpoint(&checkpointer, &mut speed);
+                    if !checkpointer.send(checkpoint) {
+                        break;
+                    }
+                }
+            })
+        });
+
+        let reporter = {
+            let s = session.clone();
+            let period = s.lock().await.progress_interval;
+            task::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    s.lock().await.report_progress();
+                }
+            })
+        };
+
+        let mut error = None;
+        for j in join_handles {
+            match join!(j).0 {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error = Some(e),
+                Err(e) => error = Some(WhipError::Unknown(e.to_string())),
+            }
+        }
+
+        // The last checkpoint tells where the stream stopped, the sender goes with it
+        if let Some(t) = ticker {
+            t.abort();
+            let _ = t.await;
+        }
+        reporter.abort();
+        let _ = reporter.await;
+        {
+            let mut sess = session.lock().await;
+            sess.report_progress();
+            if let Some(checkpointer) = sess.checkpointer.take() {
+                let mut checkpoint = sess.checkpoint(&checkpointer, &mut SpeedMeter::new());
+                checkpoint.speed = 0f64;
+                checkpointer.send(checkpoint);
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        {
+            let sess = session.lock().await;
+            if sess.control.is_stopped() && !sess.queue.is_empty() {
+                return Err(WhipError::Interrupted);
+            }
+        }
+
+        let session = match Arc::try_unwrap(session) {
+            Ok(s) => s.into_inner(),
+            Err(_) => {
+                return Err(WhipError::Unknown(
+                    "Stream session still in use".to_string(),
+                ))
+            }
+        };
+        session.concatenate_segments(&ids).await
+    }
+
+    /// Pulls segments from the queue until it's empty.
+    async fn worker(
+        session: &Arc<Mutex<StreamDownloader<P>>>,
+        client: Arc<Client>,
+    ) -> Result<(), WhipError> {
+        let control = session.lock().await.control.clone();
+        loop {
+            control.wait_released().await;
+            // Segments are written whole, the ones left are fetched when resuming
+            if control.is_stopped() {
+                return Ok(());
+            }
+            let (segment, path, max_retries) = {
+                let mut sess = session.lock().await;
+                match sess.queue.pop_front() {
+                    Some(s) => {
+                        let path = sess.segment_path(s.id);
+                        sess.update(s.id, |p| p.state = PartState::Downloading);
+                        (s, path, sess.max_retries)
+                    }
+                    None => return Ok(()),
+                }
+            };
+
+            let mut retries = 0;
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            interval.tick().await;
+            let data = loop {
+                let result =
+                    match StreamDownloader::fetch(session, &client, &segment, retries as usize)
+                        .await
+                    {
+                        Ok(data) => segment.check(data),
+                        Err(e) => Err(e),
+                    };
+                match result {
+                    Ok(d) => break d,
+                    // Another source may have what this one lacks
+                    Err(e)
+                        if retries >= max_retries
+                            || (!e.is_retryable() && segment.fallback_urls.is_empty()) =>
+                    {
+                        let mut sess = session.lock().await;
+                        sess.queue.clear();
+                        sess.update(segment.id, |p| p.state = PartState::Failed);
+                        return Err(e);
+                    }
+                    Err(e) => {
+                        retries += 1;
+                        // Moving on to another source doesn't have to wait,
+                        // asking one of them again does
+                        let source_count = segment.fallback_urls.len() + 1;
+                        if e.is_retryable() && (retries as usize).is_multiple_of(source_count) {
+                            interval.tick().await;
+                        }
+                    }
+                }
+            };
+
+            control.throttle(data.len()).await;
+
+            // Write under a temporary name so a partial segment is never
+            // mistaken for a complete one when resuming.
+            let partial_path = PathBuf::from(format!("{}.part", path.to_string_lossy()));
+            if let Err(e) = fs::write(&partial_path, &data).await {
+                return Err(WhipError::io(partial_path, e));
+            }
+            if let Err(e) = fs::rename(&partial_path, &path).await {
+                return Err(WhipError::io(path, e));
+            }
+
+            let mut sess = session.lock().await;
+            sess.bytes_done += data.len() as u64;
+            sess.update(segment.id, |p| {
+                p.bytes_done = data.len() as u64;
+                p.state = PartState::Completed;
+            });
+        }
+    }
+
+    /// Fetches the whole body of a segment, counting its bytes as they come.
+    /// Each attempt moves on to the next source.
+    async fn fetch(
+        session: &Arc<Mutex<StreamDownloader<P>>>,
+        client: &Arc<Client>,
+        segment: &Segment,
+        attempt: usize,
+    ) -> Result<Vec<u8>, WhipError> {
+        let source_count = segment.fallback_urls.len() + 1;
+        let url = segment.sources().nth(attempt % source_count).unwrap();
+        let mut response = request_segment(client, segment, url).await?;
+        // What a failed attempt received is thrown away
+        session
+            .lock()
+            .await
+            .update(segment.id, |p| p.bytes_done = 0);
+        let mut data = Vec::new();
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    data.extend_from_slice(&chunk);
+                    let received = data.len() as u64;
+                    session
+                        .lock()
+                        .await
+                        .update(segment.id, |p| p.bytes_done = received);
+                }
+                Ok(None) => return Ok(data),
+                Err(e) => return Err(WhipError::request(url, e)),
+            }
+        }
+    }
+
+    /// Applies `f` to the progress of the segment `id`
+    fn update<F: FnOnce(&mut PartCheckpoint)>(&mut self, id: u64, f: F) {
+        if let Some(part) = self.segments.get_mut(&id) {
+            f(part);
+        }
+    }
+
+    /// Progress of the stream for `checkpointer`, segments aren't parts
+    fn checkpoint(&self, checkpointer: &Checkpointer, speed: &mut SpeedMeter) -> Checkpoint {
+        let bytes_done = self.bytes_before + self.bytes_done;
+        Checkpoint {
+            task_id: checkpointer.task_id,
+            bytes_done,
+            total_bytes: self.total_bytes,
+            speed: speed.sample(bytes_done),
+            parts: Vec::new(),
+        }
+    }
+
+    /// Calls on_progress_change with a snapshot of the stream, the tracks
+    /// downloaded ahead of it count as downloaded
+    fn report_progress(&mut self) {
+        let mut progress = self.meter.measure(
+            self.segments.values().cloned().collect(),
+            &self.resumed,
+            self.total_bytes,
+        );
+        progress.downloaded_bytes += self.bytes_before;
+        (self.on_progress_change)(&progress);
+    }
+
+    /// Joins the segments in order and removes them from the temporary directory.
+    async fn concatenate_segments(&self, ids: &[u64]) -> Result<PathBuf, WhipError> {
+        let mut f_path = PathBuf::new();
+        f_path.push(&self.output_dir);
+        f_path.push(&self.file_name);
+        // The file only gets its final name once every segment is in,
+        // so an existing output file always means a complete stream.
+        let partial_path = PathBuf::from(format!("{}.part", f_path.to_string_lossy()));
+
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial_path)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) => return Err(WhipError::io(partial_path, e)),
+        };
+
+        for id in ids {
+            let segment_path = self.segment_path(*id);
+            let buffer = match fs::read(&segment_path).await {
+                Ok(b) => b,
+                Err(e) => return Err(WhipError::io(segment_path, e)),
+            };
+            if let Err(e) = file.write_all(&buffer).await {
+                return Err(WhipError::io(partial_path, e));
+            }
+        }
+        if let Err(e) = file.flush().await {
+            return Err(WhipError::io(partial_path, e));
+        }
+        if let Err(e) = fs::rename(&partial_path, &f_path).await {
+            return Err(WhipError::io(f_path, e));
+        }
+
+        for id in ids {
+            let segment_path = self.segment_path(*id);
+            if let Err(e) = fs::remove_file(&segment_path).await {
+                eprintln!("{} : {}", e, segment_path.to_string_lossy());
+            }
+        }
+
+        Ok(f_path)
+    }
+}
+
+pub(crate) fn parse_url(url: &str) -> Result<Url, WhipError> {
+    match Url::parse(url) {
+        Ok(u) => Ok(u),
+        Err(e) => Err(WhipError::Parse(format!("{} : {}", e, url))),
+    }
+}
+
+pub(crate) fn join_url(base: &Url, uri: &str) -> Result<Url, WhipError> {
+    match base.join(uri) {
+        Ok(u) => Ok(u),
+        Err(e) => Err(WhipError::Parse(format!("{} : {}", e, uri))),
+    }
+}
+
+pub(crate) async fn fetch_text(client: &Client, url: &Url) -> Result<String, WhipError> {
+    match client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        Ok(response) => match response.text().await {
+            Ok(text) => Ok(text),
+            Err(e) => Err(WhipError::request(url.as_str(), e)),
+        },
+        Err(e) => Err(WhipError::request(url.as_str(), e)),
+    }
+}
+
+/// Requests a segment from `url`, one of its sources
+async fn request_segment(
+    client: &Arc<Client>,
+    segment: &Segment,
+    url: &str,
+) -> Result<Response, WhipError> {
+    let mut req = client.get(url);
+    if let Some((start, end)) = segment.byte_range {
+        req = req.header(
+            header::RANGE,
+            format!("bytes={start}-{end}", start = start, end = end),
+        );
+    }
+    let response = match req.send().await {
+        Ok(r) => r,
+        Err(e) => return Err(WhipError::request(url, e)),
+    };
+    if ![StatusCode::OK, StatusCode::PARTIAL_CONTENT].contains(&response.status()) {
+        return Err(WhipError::Http {
+            status: response.status().as_u16(),
+            url: url.to_owned(),
+        });
+    }
+    if segment.byte_range.is_some() && response.status() == StatusCode::OK {
+        return Err(WhipError::RangeNotSupported {
+            url: url.to_owned(),
+        });
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(id: u64) -> Segment {
+        Segment {
+            id,
+            url: format!("http://127.0.0.1:9/{}", id),
+            byte_range: None,
+            key: None,
+            fallback_urls: Vec::new(),
+            checksum: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_checkpoint() {
+        // Every segment is already in, nothing is requested
+        let dir = std::env::temp_dir().join(format!("whip-stream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("s.ts.seg0"), b"abc").unwrap();
+        std::fs::write(dir.join("s.ts.seg1"), b"defgh").unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let dir_name = dir.to_string_lossy().to_string();
+        let reported = Arc::new(std::sync::Mutex::new(None));
+        let last_progress = reported.clone();
+        let mut downloader = StreamDownloader::new(
+            vec![segment(0), segment(1)],
+            dir_name.clone(),
+            dir_name,
+            String::from("s.ts"),
+            move |p: &Progress| *last_progress.lock().unwrap() = Some(p.clone()),
+            2,
+            0,
+        )
+        .unwrap();
+        downloader.checkpointer = Some(Checkpointer::new(
+            7,
+            std::time::Duration::from_secs(60),
+            sender,
+        ));
+        downloader.bytes_before = 100;
+
+        let path = downloader.download().await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abcdefgh");
+        let mut last = None;
+        while let Some(checkpoint) = receiver.recv().await {
+            last = Some(checkpoint);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.task_id, 7);
+        assert_eq!(last.bytes_done, 108);
+        assert_eq!(last.total_bytes, 0);
+        assert_eq!(last.speed, 0f64);
+
+        // Segments are the parts of the snapshots
+        let progress = reported.lock().unwrap().clone().unwrap();
+        assert!(progress.is_complete());
+        assert_eq!(progress.parts.len(), 2);
+        assert_eq!(progress.parts[1].bytes_done, 5);
+        assert_eq!(progress.downloaded_bytes, 108);
+        assert_eq!(progress.total_bytes, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
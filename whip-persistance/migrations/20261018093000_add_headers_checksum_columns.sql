@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD headers TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD checksum TEXT DEFAULT "";
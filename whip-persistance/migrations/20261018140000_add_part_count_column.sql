@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD part_count INTEGER DEFAULT 0;
@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD on_complete_hook TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD on_failure_hook TEXT DEFAULT "";
+CREATE TABLE Hook_Run (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    task_id INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    command TEXT NOT NULL,
+    exit_code INTEGER,
+    timed_out INTEGER DEFAULT 0,
+    output TEXT DEFAULT "",
+    date_created TEXT NOT NULL
+);
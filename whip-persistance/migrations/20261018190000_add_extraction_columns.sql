@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD extracted_path TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD extraction_result TEXT DEFAULT "";
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD start_after TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD schedule TEXT DEFAULT "";
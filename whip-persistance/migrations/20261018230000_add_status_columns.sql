@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD status TEXT DEFAULT "queued";
+ALTER TABLE Download_Task ADD last_error TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD attempt_count INTEGER DEFAULT 0;
+ALTER TABLE Download_Task ADD started_at TEXT DEFAULT "";
+ALTER TABLE Download_Task ADD finished_at TEXT DEFAULT "";
+-- Unfinished tasks can't be told apart anymore, they're resumable so call them paused
+UPDATE Download_Task SET status = "completed" WHERE percentage_completed >= 100;
+UPDATE Download_Task SET status = "paused" WHERE percentage_completed > 0 AND percentage_completed < 100;
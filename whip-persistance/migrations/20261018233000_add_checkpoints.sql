@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD bytes_done INTEGER DEFAULT 0;
+ALTER TABLE Download_Task ADD speed REAL DEFAULT 0;
+ALTER TABLE Download_Task ADD checkpoint_at TEXT DEFAULT "";
+CREATE TABLE Download_Part (
+    task_id INTEGER NOT NULL,
+    part_id INTEGER NOT NULL,
+    start_byte INTEGER NOT NULL,
+    end_byte INTEGER NOT NULL,
+    bytes_done INTEGER NOT NULL DEFAULT 0,
+    state TEXT NOT NULL,
+    PRIMARY KEY (task_id, part_id)
+);
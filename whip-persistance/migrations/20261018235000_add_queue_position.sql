@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE Download_Task ADD queue_position INTEGER DEFAULT 0;
+UPDATE Download_Task SET queue_position = id;
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- Lowercased host of file_url, the way url_host works it out: after the
+-- scheme, before the path, without the user and the port
+ALTER TABLE Download_Task ADD host TEXT;
+UPDATE Download_Task SET host = lower(
+    CASE WHEN substr(host_port, 1, 1) = '[' AND instr(host_port, ']') > 0
+        THEN substr(host_port, 1, instr(host_port, ']'))
+        ELSE substr(host_port, 1, instr(host_port || ':', ':') - 1) END)
+FROM (SELECT id AS task_id, substr(authority, length(rtrim(authority, replace(authority, '@', ''))) + 1) AS host_port
+    FROM (SELECT id, substr(rest, 1, instr(replace(replace(rest, '?', '/'), '#', '/') || '/', '/') - 1) AS authority
+        FROM (SELECT id, CASE WHEN instr(file_url, '://') > 0 THEN substr(file_url, instr(file_url, '://') + 3) ELSE file_url END AS rest
+            FROM Download_Task)))
+WHERE id = task_id;
+CREATE INDEX Download_Task_host ON Download_Task (host);
@@ -0,0 +1,54 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle};
+use whip_core::checkpoint::Checkpoint;
+
+use crate::models::DownloadTaskRepository;
+
+/// Writes the checkpoints of running downloads to `repository`. Only the
+/// latest checkpoint of each task is kept, they're all written together
+/// every `flush_interval`. The task ends once every sender is dropped and
+/// what they sent is written.
+pub fn spawn_checkpoint_writer<R>(
+    repository: R,
+    mut receiver: UnboundedReceiver<Checkpoint>,
+    flush_interval: Duration,
+) -> JoinHandle<()>
+where
+    R: DownloadTaskRepository + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let mut pending: BTreeMap<u64, Checkpoint> = BTreeMap::new();
+        let mut interval = tokio::time::interval(flush_interval);
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Some(checkpoint) => {
+                        pending.insert(checkpoint.task_id, checkpoint);
+                    }
+                    None => {
+                        flush(&repository, &mut pending).await;
+                        break;
+                    }
+                },
+                _ = interval.tick() => flush(&repository, &mut pending).await,
+            }
+        }
+    })
+}
+
+async fn flush<R>(repository: &R, pending: &mut BTreeMap<u64, Checkpoint>)
+where
+    R: DownloadTaskRepository + Send + Sync,
+{
+    if pending.is_empty() {
+        return;
+    }
+    let checkpoints = std::mem::take(pending).into_values().collect::<Vec<_>>();
+    // The next checkpoints carry the whole progress again, nothing to retry
+    if let Err(e) = repository.save_checkpoints(&checkpoints).await {
+        eprintln!("{}", e);
+    }
+}
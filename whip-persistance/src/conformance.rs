@@ -0,0 +1,479 @@
+//! Behaviour every `DownloadTaskRepository` shares, checked against each of them
+
+use chrono::prelude::*;
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint, PartState},
+    checksum::Checksum,
+    download::{DownloadMeta, DownloadTask},
+    hooks::{HookEvent, HookOutput},
+};
+
+use crate::{
+    models::{DownloadFilter, DownloadStatus, DownloadTaskRepository},
+    search::{SortKey, TaskQuery},
+};
+
+/// Runs every check on `repository`, which must hold no task
+pub async fn check<R: DownloadTaskRepository>(repository: &R) {
+    insert_and_get(repository).await;
+    update(repository).await;
+    checkpoints(repository).await;
+    filters_and_queue(repository).await;
+    search(repository).await;
+    hook_runs(repository).await;
+    import(repository).await;
+    remove(repository).await;
+}
+
+/// Inserts a task of 1000 bytes in 2 parts, returns its id
+pub async fn insert<R: DownloadTaskRepository>(repository: &R, url: &str) -> u64 {
+    let task = DownloadTask {
+        file_url: url.to_string(),
+        percentage_completed: 0f64,
+        meta: DownloadMeta {
+            content_length: 1000,
+            supports_resume: true,
+            content_type: String::from("application/octet-stream"),
+            file_name: url.rsplit('/').next().unwrap_or(url).to_string(),
+        },
+        headers: vec![(String::from("Referer"), String::from("http://example.com"))],
+        checksum: Checksum::parse("md5=900150983cd24fb0d6963f7d28e17f72"),
+    };
+    repository
+        .insert_task(
+            &task,
+            String::from("/tmp/whip"),
+            String::from("/tmp/out"),
+            String::from("4"),
+            2,
+        )
+        .await
+        .unwrap()
+}
+
+fn part(id: u32, start_byte: u64, end_byte: u64, bytes_done: u64) -> PartCheckpoint {
+    PartCheckpoint {
+        id,
+        start_byte,
+        end_byte,
+        bytes_done,
+        state: if bytes_done > end_byte - start_byte {
+            PartState::Completed
+        } else {
+            PartState::Downloading
+        },
+    }
+}
+
+async fn insert_and_get<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/a.iso").await;
+    let task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    assert_eq!(task.id, id);
+    assert_eq!(task.file_name, "a.iso");
+    assert_eq!(task.file_size, 1000);
+    assert!(task.supports_resume);
+    assert_eq!(task.temp_files_path, "/tmp/whip");
+    assert_eq!(task.final_file_path, "/tmp/out");
+    assert_eq!(task.max_threads, 4);
+    assert_eq!(task.part_count, 2);
+    assert_eq!(task.content_type, "application/octet-stream");
+    assert_eq!(task.headers[0].0, "Referer");
+    assert_eq!(
+        task.checksum.unwrap().value,
+        "900150983cd24fb0d6963f7d28e17f72"
+    );
+    assert_eq!(task.status, DownloadStatus::Queued);
+    assert_eq!(
+        task.date_created,
+        Utc::today().format("%Y-%m-%d").to_string()
+    );
+    assert_eq!(task.attempt_count, 0);
+    assert_eq!(task.bytes_done, 0);
+    assert!(task.on_complete_hook.is_none() && task.started_at.is_none());
+
+    let by_url = repository.get_task_by_url("http://example.com/a.iso").await;
+    assert_eq!(by_url.unwrap().unwrap().id, id);
+    assert!(repository
+        .get_task_by_url("http://example.com/missing")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repository
+        .get_task_by_id(id as i64 + 100)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repository.get_parts(id as i64).await.unwrap().is_empty());
+
+    let next = insert(repository, "http://example.com/b.iso").await;
+    assert!(next > id);
+}
+
+async fn update<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/update.iso").await;
+    let mut task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    task.set_status(DownloadStatus::Downloading).unwrap();
+    task.fail("HTTP Error : 503").unwrap();
+    task.file_name = String::from("renamed.iso");
+    task.on_complete_hook = Some(String::from("echo done"));
+    task.start_after = Some(Local::now());
+    task.temp_files_path = String::from("/tmp/whip/7");
+    task.source = Some(String::from("/tmp/files.meta4"));
+    // Fields the commands don't change are left as they are
+    task.content_type = String::from("text/plain");
+    let returned = repository.update_task(task).await.unwrap();
+    assert_eq!(returned.file_name, "renamed.iso");
+
+    let task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    assert_eq!(task.file_name, "renamed.iso");
+    assert_eq!(task.status, DownloadStatus::Failed);
+    assert_eq!(task.last_error.as_deref(), Some("HTTP Error : 503"));
+    assert_eq!(task.attempt_count, 1);
+    assert!(task.started_at.is_some() && task.finished_at.is_some());
+    assert!(task.start_after.is_some());
+    assert_eq!(task.on_complete_hook.as_deref(), Some("echo done"));
+    assert_eq!(task.temp_files_path, "/tmp/whip/7");
+    assert_eq!(task.source.as_deref(), Some("/tmp/files.meta4"));
+    assert_eq!(task.content_type, "application/octet-stream");
+}
+
+async fn checkpoints<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/checkpoint.iso").await;
+    let stale = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    let checkpoint = Checkpoint {
+        task_id: id,
+        bytes_done: 750,
+        total_bytes: 1000,
+        speed: 2048f64,
+        parts: vec![part(1, 500, 999, 250), part(0, 0, 499, 500)],
+    };
+    repository.save_checkpoints(&[checkpoint]).await.unwrap();
+
+    let task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    assert_eq!(task.bytes_done, 750);
+    assert_eq!(task.speed, 2048f64);
+    assert_eq!(task.percentage_completed, 75f64);
+    assert!(task.checkpoint_at.is_some());
+    let parts = repository.get_parts(id as i64).await.unwrap();
+    assert_eq!(parts, vec![part(0, 0, 499, 500), part(1, 500, 999, 250)]);
+
+    // Parts are replaced one by one, unknown sizes keep the percentage
+    let checkpoint = Checkpoint {
+        task_id: id,
+        bytes_done: 900,
+        total_bytes: 0,
+        speed: 0f64,
+        parts: vec![part(1, 500, 999, 400)],
+    };
+    repository.save_checkpoints(&[checkpoint]).await.unwrap();
+    let task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    assert_eq!(task.bytes_done, 900);
+    assert_eq!(task.percentage_completed, 75f64);
+    let parts = repository.get_parts(id as i64).await.unwrap();
+    assert_eq!(parts, vec![part(0, 0, 499, 500), part(1, 500, 999, 400)]);
+
+    // The progress belongs to the checkpoints, updates don't undo it
+    repository.update_task(stale).await.unwrap();
+    let task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    assert_eq!(task.bytes_done, 900);
+    assert_eq!(task.percentage_completed, 75f64);
+}
+
+async fn filters_and_queue<R: DownloadTaskRepository>(repository: &R) {
+    let first = insert(repository, "http://example.com/queue/1.iso").await;
+    let second = insert(repository, "http://example.com/queue/2.iso").await;
+    let mut task = repository
+        .get_task_by_id(second as i64)
+        .await
+        .unwrap()
+        .unwrap();
+    task.set_status(DownloadStatus::Downloading).unwrap();
+    task.set_status(DownloadStatus::Completed).unwrap();
+    repository.update_task(task).await.unwrap();
+
+    let ids = |tasks: Vec<crate::models::DownloadTaskEntity>| {
+        tasks
+            .into_iter()
+            .filter(|t| t.file_url.contains("/queue/"))
+            .map(|t| t.id)
+            .collect::<Vec<u64>>()
+    };
+    let all = repository.get_tasks(DownloadFilter::All).await.unwrap();
+    let mut sorted = all.iter().map(|t| t.id).collect::<Vec<u64>>();
+    sorted.sort();
+    assert_eq!(all.iter().map(|t| t.id).collect::<Vec<u64>>(), sorted);
+    assert_eq!(ids(all), vec![first, second]);
+    let completed = repository.get_tasks(DownloadFilter::Completed).await;
+    assert_eq!(ids(completed.unwrap()), vec![second]);
+    let in_progress = repository.get_tasks(DownloadFilter::InProgress).await;
+    assert_eq!(ids(in_progress.unwrap()), vec![first]);
+    let queued = repository
+        .get_tasks(DownloadFilter::Status(DownloadStatus::Queued))
+        .await;
+    assert_eq!(ids(queued.unwrap()), vec![first]);
+
+    // New tasks go to the end of the queue
+    let first_task = repository.get_task_by_id(first as i64).await.unwrap();
+    let second_task = repository.get_task_by_id(second as i64).await.unwrap();
+    assert!(second_task.unwrap().queue_position > first_task.unwrap().queue_position);
+    repository
+        .set_queue_position(second as i64, -1)
+        .await
+        .unwrap();
+    let query = TaskQuery {
+        url: Some(String::from("/queue/")),
+        sort: SortKey::Queue,
+        ..Default::default()
+    };
+    let page = repository.search_tasks(&query).await.unwrap();
+    assert_eq!(page.total, 2);
+    assert_eq!(ids(page.tasks), vec![second, first]);
+    let third = insert(repository, "http://example.com/queue/3.iso").await;
+    let third = repository.get_task_by_id(third as i64).await.unwrap();
+    let first_task = repository.get_task_by_id(first as i64).await.unwrap();
+    assert!(third.unwrap().queue_position > first_task.unwrap().queue_position);
+}
+
+async fn search<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/search/base.iso").await;
+    let base = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    let tasks = [
+        (
+            "https://user@cdn.Debian.org:8443/search/debian.iso",
+            "debian.iso",
+            4000,
+            "2026-10-11",
+        ),
+        (
+            "https://example.com/search/notes_1.txt",
+            "notes_1.txt",
+            0,
+            "2026-10-12 08:00",
+        ),
+        (
+            "https://media.example.com/search/Movie.mkv",
+            "Movie.mkv",
+            2000,
+            "2026-10-13",
+        ),
+        (
+            "http://[::1]:8080/search/song.mp3",
+            "song.mp3",
+            5,
+            "2026-10-14",
+        ),
+    ];
+    let mut ids = Vec::new();
+    for (url, file_name, file_size, date_created) in tasks {
+        let mut task = base.clone();
+        task.file_url = url.to_string();
+        task.file_name = file_name.to_string();
+        task.file_size = file_size;
+        task.date_created = date_created.to_string();
+        task.content_type = if file_name.ends_with(".mkv") {
+            String::from("video/x-matroska")
+        } else {
+            String::from("application/octet-stream")
+        };
+        ids.push(repository.import_task(&task, &[]).await.unwrap());
+    }
+    let search = |query: TaskQuery| async move {
+        let query = TaskQuery {
+            url: Some(String::from("/search/")),
+            ..query
+        };
+        let page = repository.search_tasks(&query).await.unwrap();
+        let found = page.tasks.iter().map(|t| t.id).collect::<Vec<u64>>();
+        (page.total, found)
+    };
+    let [debian, notes, movie, song] = [ids[0], ids[1], ids[2], ids[3]];
+
+    let query = TaskQuery {
+        host: Some(String::from("example.COM")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (3, vec![id, notes, movie]));
+    let query = TaskQuery {
+        host: Some(String::from("debian.org")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (1, vec![debian]));
+    let query = TaskQuery {
+        host: Some(String::from("[::1]")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (1, vec![song]));
+    let query = TaskQuery {
+        name: Some(String::from("*.ISO")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (2, vec![id, debian]));
+    // Wildcards of the database are plain characters
+    let query = TaskQuery {
+        name: Some(String::from("s_1")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (1, vec![notes]));
+    let query = TaskQuery {
+        content_type: Some(String::from("Video/")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (1, vec![movie]));
+    let query = TaskQuery {
+        min_size: Some(10),
+        max_size: Some(3000),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (2, vec![id, movie]));
+    let query = TaskQuery {
+        created_since: Some(String::from("2026-10-12")),
+        created_until: Some(String::from("2026-10-13")),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (2, vec![notes, movie]));
+    let query = TaskQuery {
+        sort: SortKey::Size,
+        descending: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        search(query).await,
+        (5, vec![debian, movie, id, song, notes])
+    );
+    let query = TaskQuery {
+        sort: SortKey::Name,
+        offset: 1,
+        limit: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (5, vec![debian, movie]));
+    let query = TaskQuery {
+        offset: 10,
+        ..Default::default()
+    };
+    assert_eq!(search(query).await, (5, vec![]));
+}
+
+async fn hook_runs<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/hooks.iso").await;
+    let other = insert(repository, "http://example.com/hooks-other.iso").await;
+    let output = |exit_code: Option<i32>, text: &str| HookOutput {
+        exit_code,
+        timed_out: exit_code.is_none(),
+        output: text.to_string(),
+    };
+    let first = repository
+        .insert_hook_run(id, HookEvent::Complete, "echo one", &output(Some(0), "one"))
+        .await
+        .unwrap();
+    repository
+        .insert_hook_run(
+            other,
+            HookEvent::Complete,
+            "echo other",
+            &output(Some(0), ""),
+        )
+        .await
+        .unwrap();
+    let second = repository
+        .insert_hook_run(id, HookEvent::Failure, "sleep 60", &output(None, ""))
+        .await
+        .unwrap();
+    assert!(second > first);
+
+    let runs = repository.get_hook_runs(id as i64).await.unwrap();
+    assert_eq!(
+        runs.iter().map(|r| r.id).collect::<Vec<u64>>(),
+        vec![first, second]
+    );
+    assert_eq!(runs[0].event, HookEvent::Complete.to_string());
+    assert_eq!(runs[0].command, "echo one");
+    assert_eq!(runs[0].exit_code, Some(0));
+    assert_eq!(runs[0].output, "one");
+    assert!(!runs[0].timed_out);
+    assert!(runs[1].timed_out && runs[1].exit_code.is_none());
+    assert!(!runs[0].date_created.is_empty());
+}
+
+async fn import<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/export.iso").await;
+    let mut task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+    task.set_status(DownloadStatus::Downloading).unwrap();
+    task.set_status(DownloadStatus::Paused).unwrap();
+    task.date_created = String::from("2020-01-02");
+    task.bytes_done = 500;
+    task.percentage_completed = 50f64;
+    task.on_failure_hook = Some(String::from("notify-send failed"));
+    task.file_url = String::from("http://example.com/import.iso");
+    let parts = vec![part(0, 0, 499, 500), part(1, 500, 999, 0)];
+
+    let imported = repository.import_task(&task, &parts).await.unwrap();
+    assert!(imported > id);
+    let stored = repository
+        .get_task_by_id(imported as i64)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.file_url, "http://example.com/import.iso");
+    assert_eq!(stored.status, DownloadStatus::Paused);
+    assert_eq!(stored.date_created, "2020-01-02");
+    assert_eq!(stored.bytes_done, 500);
+    assert_eq!(stored.percentage_completed, 50f64);
+    assert_eq!(stored.attempt_count, 1);
+    assert_eq!(
+        stored.on_failure_hook.as_deref(),
+        Some("notify-send failed")
+    );
+    assert_eq!(stored.headers, task.headers);
+    assert_eq!(stored.checksum, task.checksum);
+    assert_eq!(repository.get_parts(imported as i64).await.unwrap(), parts);
+
+    let last = repository
+        .get_tasks(DownloadFilter::All)
+        .await
+        .unwrap()
+        .into_iter()
+        .max_by_key(|t| t.queue_position)
+        .unwrap();
+    assert_eq!(last.id, imported);
+}
+
+async fn remove<R: DownloadTaskRepository>(repository: &R) {
+    let id = insert(repository, "http://example.com/remove.iso").await;
+    let checkpoint = Checkpoint {
+        task_id: id,
+        bytes_done: 10,
+        total_bytes: 1000,
+        speed: 0f64,
+        parts: vec![part(0, 0, 999, 10)],
+    };
+    repository.save_checkpoints(&[checkpoint]).await.unwrap();
+    let output = HookOutput {
+        exit_code: Some(1),
+        timed_out: false,
+        output: String::new(),
+    };
+    repository
+        .insert_hook_run(id, HookEvent::Failure, "false", &output)
+        .await
+        .unwrap();
+
+    repository.remove_task(id as i64).await.unwrap();
+    assert!(repository
+        .get_task_by_id(id as i64)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(repository.get_parts(id as i64).await.unwrap().is_empty());
+    assert!(repository
+        .get_hook_runs(id as i64)
+        .await
+        .unwrap()
+        .is_empty());
+    // Removing it again does nothing
+    repository.remove_task(id as i64).await.unwrap();
+
+    // Ids aren't given twice
+    let next = insert(repository, "http://example.com/remove.iso").await;
+    assert!(next > id);
+}
@@ -1,16 +1,57 @@
-use std::fmt;
+use std::{error::Error, fmt};
+
+#[cfg(feature = "sqlite")]
+use sqlx::migrate::MigrateError;
 
 #[derive(Debug)]
 pub enum DatabaseError {
-    Connection(String),
+    #[cfg(feature = "sqlite")]
+    Connection(sqlx::Error),
+    #[cfg(feature = "sqlite")]
+    Migration(MigrateError),
+    /// A query failed, `action` tells what it was for
+    #[cfg(feature = "sqlite")]
+    Query {
+        action: String,
+        source: sqlx::Error,
+    },
     Operation(String),
 }
 
+#[cfg(feature = "sqlite")]
+impl DatabaseError {
+    pub fn query(action: &str, source: sqlx::Error) -> Self {
+        DatabaseError::Query {
+            action: action.to_string(),
+            source,
+        }
+    }
+}
+
 impl fmt::Display for DatabaseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            DatabaseError::Connection(e) => write!(f, "{}", e),
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Connection(e) => write!(f, "Error connecting to database : {}", e),
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Migration(e) => write!(f, "Error migrating database : {}", e),
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Query { action, source } => write!(f, "{} : {}", action, source),
             DatabaseError::Operation(e) => write!(f, "{}", e),
         }
     }
 }
+
+impl Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Connection(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Migration(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            DatabaseError::Query { source, .. } => Some(source),
+            DatabaseError::Operation(_) => None,
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint, PartState},
+    download::DownloadTask,
+    hooks::{HookEvent, HookOutput},
+};
+
+use crate::{
+    errors::DatabaseError,
+    memory::Store,
+    models::{
+        decode_checksum, decode_headers, decode_schedule, decode_time, encode_checksum,
+        encode_headers, encode_schedule, encode_time, DownloadFilter, DownloadStatus,
+        DownloadTaskEntity, DownloadTaskRepository, HookRunEntity,
+    },
+    search::{TaskPage, TaskQuery},
+};
+
+/// Version of the file layout, files of later versions aren't read
+const FILE_VERSION: u32 = 1;
+
+/// Repository keeping everything in a single JSON file, for small setups
+/// without sqlite. The file is read for every call and written again by
+/// those changing it. A lock file next to it keeps other processes from
+/// writing at the same time.
+#[derive(Debug, Clone)]
+pub struct JsonFileRepository {
+    path: Arc<PathBuf>,
+}
+
+impl JsonFileRepository {
+    /// Opens the repository at `path`, creating the file and its directory
+    /// when they don't exist
+    pub async fn open(path: PathBuf) -> Result<Self, DatabaseError> {
+        let repository = JsonFileRepository {
+            path: Arc::new(path),
+        };
+        // Unreadable files are reported now rather than by the first command
+        repository.write(|_| Ok(())).await?;
+        Ok(repository)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs `read` on the content of the file
+    async fn read<T, F>(&self, read: F) -> Result<T, DatabaseError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Store) -> T + Send + 'static,
+    {
+        let path = self.path.clone();
+        // Files are read and locked with blocking calls
+        let result = tokio::task::spawn_blocking(move || {
+            let lock = lock(&path, false)?;
+            let store = load(&path)?;
+            drop(lock);
+            Ok(read(&store))
+        })
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(e) => Err(DatabaseError::Operation(e.to_string())),
+        }
+    }
+
+    /// Runs `write` on the content of the file and writes it back
+    async fn write<T, F>(&self, write: F) -> Result<T, DatabaseError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Store) -> Result<T, DatabaseError> + Send + 'static,
+    {
+        let path = self.path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let lock = lock(&path, true)?;
+            let mut store = load(&path)?;
+            let value = write(&mut store)?;
+            save(&path, &store)?;
+            drop(lock);
+            Ok(value)
+        })
+        .await;
+        match result {
+            Ok(result) => result,
+            Err(e) => Err(DatabaseError::Operation(e.to_string())),
+        }
+    }
+}
+
+fn file_error(path: &Path, action: &str, message: impl ToString) -> DatabaseError {
+    DatabaseError::Operation(format!(
+        "Error {} {} : {}",
+        action,
+        path.to_string_lossy(),
+        message.to_string()
+    ))
+}
+
+/// Locks the file at `path` through a lock file next to it, shared by
+/// readers. Dropping the file releases it.
+fn lock(path: &Path, exclusive: bool) -> Result<fs::File, DatabaseError> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(file_error(dir, "creating", e));
+        }
+    }
+    let lock_path = PathBuf::from(format!("{}.lock", path.to_string_lossy()));
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+    {
+        Ok(f) => f,
+        Err(e) => return Err(file_error(&lock_path, "opening", e)),
+    };
+    let locked = if exclusive {
+        file.lock_exclusive()
+    } else {
+        file.lock_shared()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(e) => Err(file_error(&lock_path, "locking", e)),
+    }
+}
+
+fn load(path: &Path) -> Result<Store, DatabaseError> {
+    let content = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Store::default()),
+        Err(e) => return Err(file_error(path, "reading", e)),
+    };
+    let file: StoreFile = match serde_json::from_slice(&content) {
+        Ok(f) => f,
+        Err(e) => return Err(file_error(path, "reading", e)),
+    };
+    if file.version > FILE_VERSION {
+        return Err(file_error(
+            path,
+            "reading",
+            format!("version {} is too recent", file.version),
+        ));
+    }
+    Ok(file.into())
+}
+
+/// Writes a copy of the file first, so it's never left half written. The
+/// copy reaches the disk before it replaces the file, and the rename does
+/// before returning, so a crash leaves one or the other.
+fn save(path: &Path, store: &Store) -> Result<(), DatabaseError> {
+    let content = match serde_json::to_vec_pretty(&StoreFile::from(store)) {
+        Ok(c) => c,
+        Err(e) => return Err(file_error(path, "writing", e)),
+    };
+    let copy = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+    let written = fs::File::create(&copy)
+        .and_then(|mut file| file.write_all(&content).and_then(|_| file.sync_all()));
+    if let Err(e) = written {
+        return Err(file_error(&copy, "writing", e));
+    }
+    if let Err(e) = fs::rename(&copy, path) {
+        return Err(file_error(path, "writing", e));
+    }
+    sync_parent(path).map_err(|e| file_error(path, "writing", e))
+}
+
+/// Makes the entries of the directory of `path` durable, renames included
+#[cfg(target_family = "unix")]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to be synced, renames are durable once done
+#[cfg(not(target_family = "unix"))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Content of the file. Values are written the way the sqlite database
+/// stores them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoreFile {
+    version: u32,
+    last_task_id: u64,
+    last_hook_run_id: u64,
+    tasks: Vec<StoredTask>,
+    hook_runs: Vec<StoredHookRun>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoredTask {
+    id: u64,
+    file_name: String,
+    file_size: u64,
+    file_url: String,
+    supports_resume: bool,
+    temp_files_path: String,
+    final_file_path: String,
+    max_threads: u32,
+    part_count: u32,
+    percentage_completed: f64,
+    date_created: String,
+    content_type: String,
+    /// "Name: value"
+    headers: Vec<String>,
+    checksum: Option<String>,
+    on_complete_hook: Option<String>,
+    on_failure_hook: Option<String>,
+    extracted_path: Option<String>,
+    extraction_result: Option<String>,
+    start_after: Option<String>,
+    schedule: Option<String>,
+    status: String,
+    last_error: Option<String>,
+    attempt_count: u32,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    bytes_done: u64,
+    speed: f64,
+    checkpoint_at: Option<String>,
+    queue_position: i64,
+    source: Option<String>,
+    parts: Vec<StoredPart>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoredPart {
+    id: u32,
+    start_byte: u64,
+    end_byte: u64,
+    bytes_done: u64,
+    state: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoredHookRun {
+    id: u64,
+    task_id: u64,
+    event: String,
+    command: String,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    output: String,
+    date_created: String,
+}
+
+/// Unset values are left out rather than stored empty
+fn optional(value: String) -> Option<String> {
+    Some(value).filter(|v| !v.is_empty())
+}
+
+impl From<&Store> for StoreFile {
+    fn from(store: &Store) -> Self {
+        let tasks = store
+            .tasks
+            .values()
+            .map(|t| StoredTask {
+                id: t.id,
+                file_name: t.file_name.to_owned(),
+                file_size: t.file_size,
+                file_url: t.file_url.to_owned(),
+                supports_resume: t.supports_resume,
+                temp_files_path: t.temp_files_path.to_owned(),
+                final_file_path: t.final_file_path.to_owned(),
+                max_threads: t.max_threads,
+                part_count: t.part_count,
+                percentage_completed: t.percentage_completed,
+                date_created: t.date_created.to_owned(),
+                content_type: t.content_type.to_owned(),
+                headers: encode_headers(&t.headers)
+                    .lines()
+                    .map(|h| h.to_string())
+                    .collect(),
+                checksum: optional(encode_checksum(&t.checksum)),
+                on_complete_hook: t.on_complete_hook.to_owned(),
+                on_failure_hook: t.on_failure_hook.to_owned(),
+                extracted_path: t.extracted_path.to_owned(),
+                extraction_result: t.extraction_result.to_owned(),
+                start_after: optional(encode_time(&t.start_after)),
+                schedule: optional(encode_schedule(&t.schedule)),
+                status: t.status.as_str().to_string(),
+                last_error: t.last_error.to_owned(),
+                attempt_count: t.attempt_count,
+                started_at: optional(encode_time(&t.started_at)),
+                finished_at: optional(encode_time(&t.finished_at)),
+                bytes_done: t.bytes_done,
+                speed: t.speed,
+                checkpoint_at: optional(encode_time(&t.checkpoint_at)),
+                queue_position: t.queue_position,
+                source: t.source.to_owned(),
+                parts: store
+                    .get_parts(t.id as i64)
+                    .into_iter()
+                    .map(|p| StoredPart {
+                        id: p.id,
+                        start_byte: p.start_byte,
+                        end_byte: p.end_byte,
+                        bytes_done: p.bytes_done,
+                        state: p.state.as_str().to_string(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        let hook_runs = store
+            .hook_runs
+            .iter()
+            .map(|r| StoredHookRun {
+                id: r.id,
+                task_id: r.task_id,
+                event: r.event.to_owned(),
+                command: r.command.to_owned(),
+                exit_code: r.exit_code,
+                timed_out: r.timed_out,
+                output: r.output.to_owned(),
+                date_created: r.date_created.to_owned(),
+            })
+            .collect();
+
+        StoreFile {
+            version: FILE_VERSION,
+            last_task_id: store.last_task_id,
+            last_hook_run_id: store.last_hook_run_id,
+            tasks,
+            hook_runs,
+        }
+    }
+}
+
+impl From<StoreFile> for Store {
+    fn from(file: StoreFile) -> Self {
+        let mut store = Store {
+            last_task_id: file.last_task_id,
+            last_hook_run_id: file.last_hook_run_id,
+            ..Default::default()
+        };
+        let time = |t: Option<String>| decode_time(&t.unwrap_or_default());
+
+        for t in file.tasks.into_iter() {
+            store.parts.insert(
+                t.id,
+                t.parts
+                    .into_iter()
+                    .map(|p| {
+                        let part = PartCheckpoint {
+                            id: p.id,
+                            start_byte: p.start_byte,
+                            end_byte: p.end_byte,
+                            bytes_done: p.bytes_done,
+                            state: PartState::parse(&p.state).unwrap_or(PartState::Pending),
+                        };
+                        (p.id, part)
+                    })
+                    .collect(),
+            );
+            // Edited files may have lost the counters
+            store.last_task_id = store.last_task_id.max(t.id);
+            let task = DownloadTaskEntity {
+                id: t.id,
+                file_name: t.file_name,
+                file_size: t.file_size,
+                file_url: t.file_url,
+                supports_resume: t.supports_resume,
+                temp_files_path: t.temp_files_path,
+                final_file_path: t.final_file_path,
+                max_threads: t.max_threads,
+                part_count: if t.part_count > 0 {
+                    t.part_count
+                } else {
+                    t.max_threads
+                },
+                percentage_completed: t.percentage_completed,
+                date_created: t.date_created,
+                content_type: t.content_type,
+                headers: decode_headers(&t.headers.join("\n")),
+                checksum: decode_checksum(&t.checksum.unwrap_or_default()),
+                on_complete_hook: t.on_complete_hook,
+                on_failure_hook: t.on_failure_hook,
+                extracted_path: t.extracted_path,
+                extraction_result: t.extraction_result,
+                start_after: time(t.start_after),
+                schedule: decode_schedule(&t.schedule.unwrap_or_default()),
+                status: DownloadStatus::parse(&t.status).unwrap_or(DownloadStatus::Queued),
+                last_error: t.last_error,
+                attempt_count: t.attempt_count,
+                started_at: time(t.started_at),
+                finished_at: time(t.finished_at),
+                bytes_done: t.bytes_done,
+                speed: t.speed,
+                checkpoint_at: time(t.checkpoint_at),
+                queue_position: t.queue_position,
+                source: t.source,
+            };
+            store.tasks.insert(task.id, task);
+        }
+        for r in file.hook_runs.into_iter() {
+            store.last_hook_run_id = store.last_hook_run_id.max(r.id);
+            store.hook_runs.push(HookRunEntity {
+                id: r.id,
+                task_id: r.task_id,
+                event: r.event,
+                command: r.command,
+                exit_code: r.exit_code,
+                timed_out: r.timed_out,
+                output: r.output,
+                date_created: r.date_created,
+            });
+        }
+        store
+    }
+}
+
+#[async_trait]
+impl DownloadTaskRepository for JsonFileRepository {
+    async fn insert_task(
+        &self,
+        task: &DownloadTask,
+        temp_files_path: String,
+        final_file_path: String,
+        thread_count: String,
+        part_count: u32,
+    ) -> Result<u64, DatabaseError> {
+        let task = task.clone();
+        self.write(move |store| {
+            store.insert_task(
+                &task,
+                temp_files_path,
+                final_file_path,
+                thread_count,
+                part_count,
+            )
+        })
+        .await
+    }
+
+    async fn import_task(
+        &self,
+        task: &DownloadTaskEntity,
+        parts: &[PartCheckpoint],
+    ) -> Result<u64, DatabaseError> {
+        let (task, parts) = (task.clone(), parts.to_vec());
+        self.write(move |store| Ok(store.import_task(&task, &parts)))
+            .await
+    }
+
+    async fn get_tasks(
+        &self,
+        filter: DownloadFilter,
+    ) -> Result<Vec<DownloadTaskEntity>, DatabaseError> {
+        self.read(move |store| store.get_tasks(filter)).await
+    }
+
+    async fn search_tasks(&self, query: &TaskQuery) -> Result<TaskPage, DatabaseError> {
+        let tasks = self.get_tasks(query.filter).await?;
+        Ok(query.apply(tasks))
+    }
+
+    async fn get_task_by_id(&self, id: i64) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        self.read(move |store| store.get_task_by_id(id)).await
+    }
+
+    async fn get_task_by_url(
+        &self,
+        url: &str,
+    ) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        let url = url.to_string();
+        self.read(move |store| store.get_task_by_url(&url)).await
+    }
+
+    async fn update_task(
+        &self,
+        task: DownloadTaskEntity,
+    ) -> Result<DownloadTaskEntity, DatabaseError> {
+        self.write(move |store| {
+            store.update_task(&task);
+            Ok(task)
+        })
+        .await
+    }
+
+    async fn remove_task(&self, id: i64) -> Result<(), DatabaseError> {
+        self.write(move |store| {
+            store.remove_task(id);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_hook_run(
+        &self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> Result<u64, DatabaseError> {
+        let (command, output) = (command.to_string(), output.clone());
+        self.write(move |store| Ok(store.insert_hook_run(task_id, event, &command, &output)))
+            .await
+    }
+
+    async fn get_hook_runs(&self, task_id: i64) -> Result<Vec<HookRunEntity>, DatabaseError> {
+        self.read(move |store| store.get_hook_runs(task_id)).await
+    }
+
+    async fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> Result<(), DatabaseError> {
+        let checkpoints = checkpoints.to_vec();
+        self.write(move |store| {
+            store.save_checkpoints(&checkpoints);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_parts(&self, task_id: i64) -> Result<Vec<PartCheckpoint>, DatabaseError> {
+        self.read(move |store| store.get_parts(task_id)).await
+    }
+
+    async fn set_queue_position(&self, id: i64, queue_position: i64) -> Result<(), DatabaseError> {
+        self.write(move |store| {
+            store.set_queue_position(id, queue_position);
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    fn file_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("whip-{}-{}.json", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let path = file_path("conformance");
+        let _ = fs::remove_file(&path);
+        conformance::check(&JsonFileRepository::open(path.clone()).await.unwrap()).await;
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let path = file_path("reopen");
+        let _ = fs::remove_file(&path);
+        let repository = JsonFileRepository::open(path.clone()).await.unwrap();
+        let removed = conformance::insert(&repository, "http://example.com/a.iso").await;
+        repository.remove_task(removed as i64).await.unwrap();
+        let id = conformance::insert(&repository, "http://example.com/b.iso").await;
+        let mut task = repository.get_task_by_id(id as i64).await.unwrap().unwrap();
+        task.schedule = decode_schedule("22:00-06:00");
+        task.set_status(DownloadStatus::Downloading).unwrap();
+        repository.update_task(task).await.unwrap();
+
+        let reopened = JsonFileRepository::open(path.clone()).await.unwrap();
+        let tasks = reopened.get_tasks(DownloadFilter::All).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, id);
+        assert_eq!(tasks[0].status, DownloadStatus::Downloading);
+        assert_eq!(
+            encode_schedule(&tasks[0].schedule),
+            encode_schedule(&decode_schedule("22:00-06:00"))
+        );
+        assert!(tasks[0].schedule.is_some());
+        assert!(tasks[0].started_at.is_some());
+        // Ids of removed tasks aren't given again
+        let next = conformance::insert(&reopened, "http://example.com/c.iso").await;
+        assert_eq!(next, id + 1);
+
+        fs::write(&path, "{ \"tasks\": [").unwrap();
+        assert!(JsonFileRepository::open(path.clone()).await.is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,24 +1,32 @@
+#[cfg(feature = "sqlite")]
 use errors::DatabaseError;
+#[cfg(feature = "sqlite")]
 use sqlx::SqlitePool;
 
+pub mod checkpoint;
+#[cfg(test)]
+mod conformance;
 pub mod errors;
+pub mod json_file;
+pub mod memory;
 pub mod models;
+#[cfg(feature = "sqlite")]
 pub mod queries;
+pub mod repository;
+pub mod search;
 
+#[cfg(feature = "sqlite")]
 pub async fn get_database_pool(database_uri: String) -> Result<SqlitePool, DatabaseError> {
-    if let Ok(pool) = SqlitePool::connect(&database_uri).await {
-        if let Ok(mut conn) = pool.acquire().await {
-            if let Err(e) = sqlx::migrate!().run(&mut conn).await {
-                return Err(DatabaseError::Operation(e.to_string()));
-            };
-            return Ok(pool);
-        }
-        return Err(DatabaseError::Operation(
-            "Error acquiring database connection".to_string(),
-        ));
-    }
-
-    Err(DatabaseError::Connection(
-        "Error connecting to database".to_string(),
-    ))
+    let pool = match SqlitePool::connect(&database_uri).await {
+        Ok(pool) => pool,
+        Err(e) => return Err(DatabaseError::Connection(e)),
+    };
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return Err(DatabaseError::Connection(e)),
+    };
+    if let Err(e) = sqlx::migrate!().run(&mut conn).await {
+        return Err(DatabaseError::Migration(e));
+    };
+    Ok(pool)
 }
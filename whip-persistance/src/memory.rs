@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint},
+    download::DownloadTask,
+    hooks::{HookEvent, HookOutput},
+};
+
+use crate::{
+    errors::DatabaseError,
+    models::{DownloadFilter, DownloadTaskEntity, DownloadTaskRepository, HookRunEntity},
+    search::{TaskPage, TaskQuery},
+};
+
+/// Tasks, parts and hook runs of a repository, kept the way the sqlite
+/// database keeps them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Store {
+    pub tasks: BTreeMap<u64, DownloadTaskEntity>,
+    /// Parts of each task, by task id then part id
+    pub parts: BTreeMap<u64, BTreeMap<u32, PartCheckpoint>>,
+    pub hook_runs: Vec<HookRunEntity>,
+    /// Ids aren't given twice, even once their task is removed
+    pub last_task_id: u64,
+    pub last_hook_run_id: u64,
+}
+
+impl Store {
+    pub fn insert_task(
+        &mut self,
+        task: &DownloadTask,
+        temp_files_path: String,
+        final_file_path: String,
+        thread_count: String,
+        part_count: u32,
+    ) -> Result<u64, DatabaseError> {
+        let max_threads = match thread_count.parse::<u32>() {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(DatabaseError::Operation(format!(
+                    "Error inserting download task : {} isn't a number of threads",
+                    thread_count
+                )))
+            }
+        };
+        self.last_task_id += 1;
+        let entity = DownloadTaskEntity {
+            id: self.last_task_id,
+            file_name: task.meta.file_name.to_owned(),
+            file_size: task.meta.content_length,
+            file_url: task.file_url.to_owned(),
+            supports_resume: task.meta.supports_resume,
+            temp_files_path,
+            final_file_path,
+            max_threads,
+            // Tasks created before part counts were stored used one part per thread
+            part_count: if part_count > 0 {
+                part_count
+            } else {
+                max_threads
+            },
+            percentage_completed: task.percentage_completed,
+            date_created: Utc::today().format("%Y-%m-%d").to_string(),
+            content_type: task.meta.content_type.to_owned(),
+            headers: task.headers.to_owned(),
+            checksum: task.checksum.to_owned(),
+            queue_position: self.last_queue_position() + 1,
+            ..Default::default()
+        };
+        self.tasks.insert(entity.id, entity);
+        Ok(self.last_task_id)
+    }
+
+    pub fn import_task(&mut self, task: &DownloadTaskEntity, parts: &[PartCheckpoint]) -> u64 {
+        self.last_task_id += 1;
+        let mut entity = task.clone();
+        entity.id = self.last_task_id;
+        if entity.part_count == 0 {
+            entity.part_count = entity.max_threads;
+        }
+        entity.speed = 0f64;
+        entity.queue_position = self.last_queue_position() + 1;
+        self.tasks.insert(entity.id, entity);
+        self.parts.insert(
+            self.last_task_id,
+            parts.iter().map(|p| (p.id, p.clone())).collect(),
+        );
+        self.last_task_id
+    }
+
+    pub fn get_tasks(&self, filter: DownloadFilter) -> Vec<DownloadTaskEntity> {
+        self.tasks
+            .values()
+            .filter(|t| filter.matches(t.status))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_task_by_id(&self, id: i64) -> Option<DownloadTaskEntity> {
+        self.tasks.get(&(id as u64)).cloned()
+    }
+
+    pub fn get_task_by_url(&self, url: &str) -> Option<DownloadTaskEntity> {
+        self.tasks.values().find(|t| t.file_url == url).cloned()
+    }
+
+    /// Only what the commands change is written, the progress is left to
+    /// the checkpoints
+    pub fn update_task(&mut self, task: &DownloadTaskEntity) {
+        let stored = match self.tasks.get_mut(&task.id) {
+            Some(t) => t,
+            None => return,
+        };
+        stored.file_name = task.file_name.to_owned();
+        stored.file_url = task.file_url.to_owned();
+        stored.file_size = task.file_size;
+        stored.final_file_path = task.final_file_path.to_owned();
+        stored.on_complete_hook = task.on_complete_hook.to_owned();
+        stored.on_failure_hook = task.on_failure_hook.to_owned();
+        stored.extracted_path = task.extracted_path.to_owned();
+        stored.extraction_result = task.extraction_result.to_owned();
+        stored.start_after = task.start_after;
+        stored.schedule = task.schedule.to_owned();
+        stored.status = task.status;
+        stored.last_error = task.last_error.to_owned();
+        stored.attempt_count = task.attempt_count;
+        stored.started_at = task.started_at;
+        stored.finished_at = task.finished_at;
+        stored.temp_files_path = task.temp_files_path.to_owned();
+        stored.queue_position = task.queue_position;
+        stored.source = task.source.to_owned();
+    }
+
+    pub fn remove_task(&mut self, id: i64) {
+        let id = id as u64;
+        self.tasks.remove(&id);
+        self.hook_runs.retain(|r| r.task_id != id);
+        self.parts.remove(&id);
+    }
+
+    pub fn insert_hook_run(
+        &mut self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> u64 {
+        self.last_hook_run_id += 1;
+        self.hook_runs.push(HookRunEntity {
+            id: self.last_hook_run_id,
+            task_id,
+            event: event.to_string(),
+            command: command.to_string(),
+            exit_code: output.exit_code,
+            timed_out: output.timed_out,
+            output: output.output.to_owned(),
+            date_created: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        });
+        self.last_hook_run_id
+    }
+
+    pub fn get_hook_runs(&self, task_id: i64) -> Vec<HookRunEntity> {
+        self.hook_runs
+            .iter()
+            .filter(|r| r.task_id == task_id as u64)
+            .cloned()
+            .collect()
+    }
+
+    pub fn save_checkpoints(&mut self, checkpoints: &[Checkpoint]) {
+        let now = Local::now();
+        for checkpoint in checkpoints.iter() {
+            let task = match self.tasks.get_mut(&checkpoint.task_id) {
+                Some(t) => t,
+                None => continue,
+            };
+            task.bytes_done = checkpoint.bytes_done;
+            task.speed = checkpoint.speed;
+            task.checkpoint_at = Some(now);
+            // Downloads of unknown size keep the percentage they had
+            if checkpoint.total_bytes > 0 {
+                task.percentage_completed = checkpoint.percentage();
+            }
+            let parts = self.parts.entry(checkpoint.task_id).or_default();
+            for part in checkpoint.parts.iter() {
+                parts.insert(part.id, part.clone());
+            }
+        }
+    }
+
+    pub fn get_parts(&self, task_id: i64) -> Vec<PartCheckpoint> {
+        self.parts
+            .get(&(task_id as u64))
+            .map(|parts| parts.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_queue_position(&mut self, id: i64, queue_position: i64) {
+        if let Some(task) = self.tasks.get_mut(&(id as u64)) {
+            task.queue_position = queue_position;
+        }
+    }
+
+    fn last_queue_position(&self) -> i64 {
+        self.tasks
+            .values()
+            .map(|t| t.queue_position)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Repository keeping everything in memory, for tests and programs
+/// embedding whip. Clones share the same tasks.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRepository {
+    store: Arc<Mutex<Store>>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        MemoryRepository::default()
+    }
+
+    fn store(&self) -> std::sync::MutexGuard<'_, Store> {
+        // A panic while holding the lock can't leave the store half written
+        match self.store.lock() {
+            Ok(store) => store,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[async_trait]
+impl DownloadTaskRepository for MemoryRepository {
+    async fn insert_task(
+        &self,
+        task: &DownloadTask,
+        temp_files_path: String,
+        final_file_path: String,
+        thread_count: String,
+        part_count: u32,
+    ) -> Result<u64, DatabaseError> {
+        self.store().insert_task(
+            task,
+            temp_files_path,
+            final_file_path,
+            thread_count,
+            part_count,
+        )
+    }
+
+    async fn import_task(
+        &self,
+        task: &DownloadTaskEntity,
+        parts: &[PartCheckpoint],
+    ) -> Result<u64, DatabaseError> {
+        Ok(self.store().import_task(task, parts))
+    }
+
+    async fn get_tasks(
+        &self,
+        filter: DownloadFilter,
+    ) -> Result<Vec<DownloadTaskEntity>, DatabaseError> {
+        Ok(self.store().get_tasks(filter))
+    }
+
+    async fn search_tasks(&self, query: &TaskQuery) -> Result<TaskPage, DatabaseError> {
+        let tasks = self.store().get_tasks(query.filter);
+        Ok(query.apply(tasks))
+    }
+
+    async fn get_task_by_id(&self, id: i64) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        Ok(self.store().get_task_by_id(id))
+    }
+
+    async fn get_task_by_url(
+        &self,
+        url: &str,
+    ) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        Ok(self.store().get_task_by_url(url))
+    }
+
+    async fn update_task(
+        &self,
+        task: DownloadTaskEntity,
+    ) -> Result<DownloadTaskEntity, DatabaseError> {
+        self.store().update_task(&task);
+        Ok(task)
+    }
+
+    async fn remove_task(&self, id: i64) -> Result<(), DatabaseError> {
+        self.store().remove_task(id);
+        Ok(())
+    }
+
+    async fn insert_hook_run(
+        &self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> Result<u64, DatabaseError> {
+        Ok(self
+            .store()
+            .insert_hook_run(task_id, event, command, output))
+    }
+
+    async fn get_hook_runs(&self, task_id: i64) -> Result<Vec<HookRunEntity>, DatabaseError> {
+        Ok(self.store().get_hook_runs(task_id))
+    }
+
+    async fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> Result<(), DatabaseError> {
+        self.store().save_checkpoints(checkpoints);
+        Ok(())
+    }
+
+    async fn get_parts(&self, task_id: i64) -> Result<Vec<PartCheckpoint>, DatabaseError> {
+        Ok(self.store().get_parts(task_id))
+    }
+
+    async fn set_queue_position(&self, id: i64, queue_position: i64) -> Result<(), DatabaseError> {
+        self.store().set_queue_position(id, queue_position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        conformance::check(&MemoryRepository::new()).await;
+    }
+}
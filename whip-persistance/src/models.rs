@@ -1,9 +1,21 @@
+use std::fmt;
+
 use async_trait::async_trait;
-use whip_core::download::{DownloadMeta, DownloadTask};
+use chrono::{DateTime, Local};
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint},
+    checksum::Checksum,
+    download::{parse_header, DownloadMeta, DownloadTask},
+    hooks::{HookEvent, HookOutput},
+    schedule::Schedule,
+};
 
-use crate::errors::DatabaseError;
+use crate::{
+    errors::DatabaseError,
+    search::{TaskPage, TaskQuery},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct DownloadTaskEntity {
     pub id: u64,
     pub file_name: String,
@@ -15,13 +27,91 @@ pub struct DownloadTaskEntity {
     /// Destination for final file
     pub final_file_path: String,
     /// Maximum number of threads to use if possible
-    pub max_threads: u64,
+    pub max_threads: u32,
+    /// Number of parts the file is split into
+    pub part_count: u32,
     pub percentage_completed: f64,
     pub date_created: String,
     pub content_type: String,
+    /// Extra headers sent with every request
+    pub headers: Vec<(String, String)>,
+    /// Expected digest of the complete file
+    pub checksum: Option<Checksum>,
+    /// Command run once the file is downloaded
+    pub on_complete_hook: Option<String>,
+    /// Command run when the download fails
+    pub on_failure_hook: Option<String>,
+    /// Directory the downloaded archive was extracted into
+    pub extracted_path: Option<String>,
+    /// Outcome of the last extraction of the archive
+    pub extraction_result: Option<String>,
+    /// The download waits until then
+    pub start_after: Option<DateTime<Local>>,
+    /// Bandwidth allowed through the day
+    pub schedule: Option<Schedule>,
+    pub status: DownloadStatus,
+    /// Why the last attempt failed
+    pub last_error: Option<String>,
+    /// Number of times the download was started
+    pub attempt_count: u32,
+    /// When the last attempt started
+    pub started_at: Option<DateTime<Local>>,
+    /// When the task completed, failed or was cancelled
+    pub finished_at: Option<DateTime<Local>>,
+    /// Bytes received as of the last checkpoint
+    pub bytes_done: u64,
+    /// Bytes per second as of the last checkpoint, 0 once stopped
+    pub speed: f64,
+    /// When the last checkpoint was written
+    pub checkpoint_at: Option<DateTime<Local>>,
+    /// Rank of the task in the queue, lower ones are started first
+    pub queue_position: i64,
+    /// Metalink file the task was listed in, it's resumed from it
+    pub source: Option<String>,
 }
 
 impl DownloadTaskEntity {
+    /// Moves the task to another state, counting attempts and keeping
+    /// track of when they start and end. Staying in the same state is allowed.
+    pub fn set_status(&mut self, status: DownloadStatus) -> Result<(), DatabaseError> {
+        if self.status == status {
+            return Ok(());
+        }
+        if !self.status.can_become(status) {
+            return Err(DatabaseError::Operation(format!(
+                "Task {} can't go from {} to {}",
+                self.id, self.status, status
+            )));
+        }
+
+        match status {
+            DownloadStatus::Downloading => {
+                // Verifying only goes back to downloading to fetch bad data again
+                if self.status != DownloadStatus::Verifying {
+                    self.attempt_count += 1;
+                    self.started_at = Some(Local::now());
+                }
+                self.finished_at = None;
+            }
+            DownloadStatus::Completed | DownloadStatus::Failed | DownloadStatus::Cancelled => {
+                self.finished_at = Some(Local::now());
+            }
+            _ => {}
+        }
+        if status != DownloadStatus::Failed && status != DownloadStatus::Paused {
+            self.last_error = None;
+        }
+        self.status = status;
+        Ok(())
+    }
+
+    /// Marks the task as failed because of `error`
+    pub fn fail(&mut self, error: &str) -> Result<(), DatabaseError> {
+        self.set_status(DownloadStatus::Failed)?;
+        self.last_error = Some(error.to_string());
+        Ok(())
+    }
+
     pub fn to_download_task(&self) -> DownloadTask {
         DownloadTask {
             file_url: self.file_url.to_owned(),
@@ -32,14 +122,170 @@ impl DownloadTaskEntity {
                 content_type: self.content_type.to_owned(),
                 file_name: self.file_name.to_owned(),
             },
+            headers: self.headers.to_owned(),
+            checksum: self.checksum.to_owned(),
+        }
+    }
+}
+
+/// Stores headers as one "Name: value" line each
+pub fn encode_headers(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn decode_headers(headers: &str) -> Vec<(String, String)> {
+    headers.lines().filter_map(parse_header).collect()
+}
+
+pub fn encode_checksum(checksum: &Option<Checksum>) -> String {
+    checksum.as_ref().map(|c| c.to_string()).unwrap_or_default()
+}
+
+pub fn decode_checksum(checksum: &str) -> Option<Checksum> {
+    Checksum::parse(checksum)
+}
+
+/// A hook command that ran for a task, with what it printed
+#[derive(Debug, Clone)]
+pub struct HookRunEntity {
+    pub id: u64,
+    pub task_id: u64,
+    pub event: String,
+    pub command: String,
+    /// None when the command was killed
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub output: String,
+    pub date_created: String,
+}
+
+/// Stores times in RFC 3339
+pub fn encode_time(time: &Option<DateTime<Local>>) -> String {
+    time.map(|d| d.to_rfc3339()).unwrap_or_default()
+}
+
+pub fn decode_time(time: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|d| d.with_timezone(&Local))
+}
+
+pub fn encode_schedule(schedule: &Option<Schedule>) -> String {
+    schedule.as_ref().map(|s| s.to_string()).unwrap_or_default()
+}
+
+pub fn decode_schedule(schedule: &str) -> Option<Schedule> {
+    Schedule::parse(schedule).ok()
+}
+
+/// Empty text columns are stored for unset optional values
+pub fn decode_optional(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.is_empty())
+}
+
+/// Where a task is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownloadStatus {
+    /// Waiting for its turn
+    #[default]
+    Queued,
+    /// Looking up the file, playlist or manifest before downloading
+    Probing,
+    Downloading,
+    /// Stopped before the end, resumable
+    Paused,
+    /// Checking the downloaded data against its expected digest
+    Verifying,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl DownloadStatus {
+    pub const ALL: [DownloadStatus; 8] = [
+        DownloadStatus::Queued,
+        DownloadStatus::Probing,
+        DownloadStatus::Downloading,
+        DownloadStatus::Paused,
+        DownloadStatus::Verifying,
+        DownloadStatus::Completed,
+        DownloadStatus::Failed,
+        DownloadStatus::Cancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Queued => "queued",
+            DownloadStatus::Probing => "probing",
+            DownloadStatus::Downloading => "downloading",
+            DownloadStatus::Paused => "paused",
+            DownloadStatus::Verifying => "verifying",
+            DownloadStatus::Completed => "completed",
+            DownloadStatus::Failed => "failed",
+            DownloadStatus::Cancelled => "cancelled",
         }
     }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        DownloadStatus::ALL
+            .iter()
+            .find(|s| s.as_str() == value)
+            .copied()
+    }
+
+    /// Still to be completed, failed or cancelled
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self,
+            DownloadStatus::Completed | DownloadStatus::Failed | DownloadStatus::Cancelled
+        )
+    }
+
+    /// Transitions of the state machine
+    pub fn can_become(&self, next: DownloadStatus) -> bool {
+        use DownloadStatus::*;
+        match self {
+            Queued => matches!(next, Probing | Downloading | Paused | Cancelled | Failed),
+            Probing => matches!(next, Queued | Downloading | Paused | Failed | Cancelled),
+            Downloading => matches!(next, Paused | Verifying | Completed | Failed | Cancelled),
+            Paused => matches!(next, Queued | Probing | Downloading | Failed | Cancelled),
+            Verifying => matches!(next, Downloading | Completed | Failed | Cancelled),
+            // Finished tasks can only be started over
+            Completed | Failed | Cancelled => matches!(next, Queued | Probing | Downloading),
+        }
+    }
+}
+
+impl fmt::Display for DownloadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum DownloadFilter {
     Completed,
+    /// Tasks that are neither completed, failed nor cancelled
     InProgress,
+    #[default]
     All,
+    Status(DownloadStatus),
+}
+
+impl DownloadFilter {
+    /// Value matched against the status column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadFilter::Completed => DownloadStatus::Completed.as_str(),
+            DownloadFilter::InProgress => "in_progress",
+            DownloadFilter::All => "all",
+            DownloadFilter::Status(s) => s.as_str(),
+        }
+    }
 }
 
 #[async_trait]
@@ -50,19 +296,58 @@ pub trait DownloadTaskRepository {
         temp_files_path: String,
         final_file_path: String,
         thread_count: String,
+        part_count: u32,
+    ) -> Result<u64, DatabaseError>;
+    /// Stores a task as it is, with its parts and date of creation, at the
+    /// end of the queue. Its id is ignored, the new one is returned
+    async fn import_task(
+        &self,
+        task: &DownloadTaskEntity,
+        parts: &[PartCheckpoint],
     ) -> Result<u64, DatabaseError>;
     async fn get_tasks(
         &self,
         filter: DownloadFilter,
     ) -> Result<Vec<DownloadTaskEntity>, DatabaseError>;
+    /// Tasks matching `query`, sorted and paginated
+    async fn search_tasks(&self, query: &TaskQuery) -> Result<TaskPage, DatabaseError>;
     async fn get_task_by_id(&self, id: i64) -> Result<Option<DownloadTaskEntity>, DatabaseError>;
-    async fn get_task_by_url(
-        &self,
-        url: &String,
-    ) -> Result<Option<DownloadTaskEntity>, DatabaseError>;
+    async fn get_task_by_url(&self, url: &str)
+        -> Result<Option<DownloadTaskEntity>, DatabaseError>;
     async fn update_task(
         &self,
         task: DownloadTaskEntity,
     ) -> Result<DownloadTaskEntity, DatabaseError>;
     async fn remove_task(&self, id: i64) -> Result<(), DatabaseError>;
+    async fn insert_hook_run(
+        &self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> Result<u64, DatabaseError>;
+    async fn get_hook_runs(&self, task_id: i64) -> Result<Vec<HookRunEntity>, DatabaseError>;
+    /// Stores the progress and parts of the checkpoints, all or none
+    async fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> Result<(), DatabaseError>;
+    /// Parts of a task as of its last checkpoint, by id
+    async fn get_parts(&self, task_id: i64) -> Result<Vec<PartCheckpoint>, DatabaseError>;
+    /// Moves a task in the queue without touching the rest of it, which
+    /// the process downloading it may be changing
+    async fn set_queue_position(&self, id: i64, queue_position: i64) -> Result<(), DatabaseError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_status() {
+        assert!(DownloadStatus::Queued.can_become(DownloadStatus::Downloading));
+        assert!(DownloadStatus::Downloading.can_become(DownloadStatus::Paused));
+        assert!(!DownloadStatus::Queued.can_become(DownloadStatus::Completed));
+        assert!(!DownloadStatus::Completed.can_become(DownloadStatus::Paused));
+        for status in DownloadStatus::ALL.iter() {
+            assert_eq!(DownloadStatus::parse(status.as_str()), Some(*status));
+        }
+    }
 }
@@ -1,12 +1,21 @@
 use async_trait::async_trait;
 use chrono::prelude::*;
-use sqlx::SqlitePool;
-use whip_core::download::DownloadTask;
+use sqlx::{sqlite::SqliteRow, FromRow, QueryBuilder, Row, Sqlite, SqlitePool};
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint, PartState},
+    download::DownloadTask,
+    hooks::{HookEvent, HookOutput},
+};
 
 use crate::models::DownloadFilter;
+use crate::search::{url_host, SortKey, TaskPage, TaskQuery};
 use crate::{
     errors::DatabaseError,
-    models::{DownloadTaskEntity, DownloadTaskRepository},
+    models::{
+        decode_checksum, decode_headers, decode_optional, decode_schedule, decode_time,
+        encode_checksum, encode_headers, encode_schedule, encode_time, DownloadStatus,
+        DownloadTaskEntity, DownloadTaskRepository, HookRunEntity,
+    },
 };
 
 #[async_trait]
@@ -17,113 +26,168 @@ impl DownloadTaskRepository for SqlitePool {
         temp_files_path: String,
         final_file_path: String,
         thread_count: String,
+        part_count: u32,
     ) -> Result<u64, DatabaseError> {
         let content_length = task.meta.content_length as i64;
         let today = Utc::today().to_string().to_lowercase().replace("utc", "");
+        let headers = encode_headers(&task.headers);
+        let checksum = encode_checksum(&task.checksum);
+        let host = url_host(&task.file_url).to_lowercase();
 
-        if let Ok(res) = sqlx::query!(r#"Insert Into Download_Task (file_name, file_size, file_url, supports_resume, temp_files_path, final_file_path, thread_count, percentage_completed, date_created, content_type) VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10)"#, task.meta.file_name, content_length, task.file_url, task.meta.supports_resume, temp_files_path, final_file_path, thread_count, task.percentage_completed, today, task.meta.content_type)
+        match sqlx::query!(r#"Insert Into Download_Task (file_name, file_size, file_url, supports_resume, temp_files_path, final_file_path, thread_count, percentage_completed, date_created, content_type, headers, checksum, part_count, host, queue_position) VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,(SELECT COALESCE(MAX(queue_position), 0) + 1 FROM Download_Task))"#, task.meta.file_name, content_length, task.file_url, task.meta.supports_resume, temp_files_path, final_file_path, thread_count, task.percentage_completed, today, task.meta.content_type, headers, checksum, part_count, host)
             .execute(self)
             .await
         {
-            return Ok(res.last_insert_rowid() as u64);
+            Ok(res) => Ok(res.last_insert_rowid() as u64),
+            Err(e) => Err(DatabaseError::query("Error inserting download task", e)),
+        }
+    }
+
+    async fn import_task(
+        &self,
+        task: &DownloadTaskEntity,
+        parts: &[PartCheckpoint],
+    ) -> Result<u64, DatabaseError> {
+        let mut tx = match self.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(DatabaseError::query("Error importing download task", e)),
         };
-        Err(DatabaseError::Operation(
-            "Error inserting download task".to_string(),
-        ))
+        let file_size = task.file_size as i64;
+        let thread_count = task.max_threads.to_string();
+        let headers = encode_headers(&task.headers);
+        let checksum = encode_checksum(&task.checksum);
+        let on_complete_hook = task.on_complete_hook.to_owned().unwrap_or_default();
+        let on_failure_hook = task.on_failure_hook.to_owned().unwrap_or_default();
+        let extracted_path = task.extracted_path.to_owned().unwrap_or_default();
+        let extraction_result = task.extraction_result.to_owned().unwrap_or_default();
+        let start_after = encode_time(&task.start_after);
+        let schedule = encode_schedule(&task.schedule);
+        let status = task.status.as_str();
+        let last_error = task.last_error.to_owned().unwrap_or_default();
+        let started_at = encode_time(&task.started_at);
+        let finished_at = encode_time(&task.finished_at);
+        let bytes_done = task.bytes_done as i64;
+        let checkpoint_at = encode_time(&task.checkpoint_at);
+        let source = task.source.to_owned().unwrap_or_default();
+        let host = url_host(&task.file_url).to_lowercase();
+
+        let id = match sqlx::query!(r#"INSERT INTO Download_Task (file_name, file_size, file_url, supports_resume, temp_files_path, final_file_path, thread_count, percentage_completed, date_created, content_type, headers, checksum, part_count, on_complete_hook, on_failure_hook, extracted_path, extraction_result, start_after, schedule, status, last_error, attempt_count, started_at, finished_at, bytes_done, checkpoint_at, source, host, queue_position) VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16,?17,?18,?19,?20,?21,?22,?23,?24,?25,?26,?27,?28,(SELECT COALESCE(MAX(queue_position), 0) + 1 FROM Download_Task))"#, task.file_name, file_size, task.file_url, task.supports_resume, task.temp_files_path, task.final_file_path, thread_count, task.percentage_completed, task.date_created, task.content_type, headers, checksum, task.part_count, on_complete_hook, on_failure_hook, extracted_path, extraction_result, start_after, schedule, status, last_error, task.attempt_count, started_at, finished_at, bytes_done, checkpoint_at, source, host)
+            .execute(&mut tx)
+            .await
+        {
+            Ok(res) => res.last_insert_rowid(),
+            Err(e) => return Err(DatabaseError::query("Error importing download task", e)),
+        };
+
+        for part in parts.iter() {
+            let start_byte = part.start_byte as i64;
+            let end_byte = part.end_byte as i64;
+            let bytes_done = part.bytes_done as i64;
+            let state = part.state.as_str();
+            if let Err(e) = sqlx::query!("INSERT INTO Download_Part (task_id, part_id, start_byte, end_byte, bytes_done, state) VALUES (?1,?2,?3,?4,?5,?6)", id, part.id, start_byte, end_byte, bytes_done, state)
+                .execute(&mut tx)
+                .await
+            {
+                return Err(DatabaseError::query("Error importing download part", e));
+            }
+        }
+
+        match tx.commit().await {
+            Ok(()) => Ok(id as u64),
+            Err(e) => Err(DatabaseError::query("Error importing download task", e)),
+        }
     }
 
     async fn get_tasks(
         &self,
         filter: DownloadFilter,
     ) -> Result<Vec<DownloadTaskEntity>, DatabaseError> {
-        let mut upper_limit = 100f64;
-        let mut lower_limit = 0;
-
-        match filter {
-            DownloadFilter::Completed => lower_limit = 100,
-            DownloadFilter::InProgress => upper_limit = 99.999,
-            _ => {}
-        }
+        let filter = filter.as_str();
 
-        if let Ok(download_task_entities) = sqlx::query!(
-            r#"SELECT * FROM Download_Task WHERE percentage_completed >= ?1 and percentage_completed <= ?2"#, lower_limit, upper_limit
+        match sqlx::query_as!(
+            TaskRow,
+            r#"SELECT * FROM Download_Task WHERE ?1 = 'all' OR status = ?1 OR (?1 = 'in_progress' AND status IN ('queued', 'probing', 'downloading', 'paused', 'verifying'))"#, filter
         )
-        .map(|r| DownloadTaskEntity {
-            id: r.id as u64,
-            file_name: r.file_name,
-            file_size: r.file_size.unwrap_or(0) as u64,
-            file_url: r.file_url,
-            supports_resume: r.supports_resume.unwrap_or(0) > 1,
-            temp_files_path: r.temp_files_path,
-            final_file_path: r.final_file_path,
-            max_threads: r.thread_count as u64,
-            percentage_completed: r.percentage_completed.unwrap_or(0f64),
-            date_created: r.date_created,
-            content_type: r.content_type.unwrap_or("".to_string())
-        })
+        .map(DownloadTaskEntity::from)
         .fetch_all(self)
         .await
         {
-            return Ok(download_task_entities);
+            Ok(download_task_entities) => Ok(download_task_entities),
+            Err(e) => Err(DatabaseError::query("Error fetching download tasks from database", e)),
+        }
+    }
+
+    async fn search_tasks(&self, query: &TaskQuery) -> Result<TaskPage, DatabaseError> {
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM Download_Task");
+        push_conditions(&mut count, query);
+        let total: i64 = match count.build().fetch_one(self).await {
+            Ok(row) => row.get(0),
+            Err(e) => return Err(DatabaseError::query("Error counting download tasks", e)),
         };
-        Err(DatabaseError::Operation(
-            "Error fetching download tasks from database".to_string(),
-        ))
+
+        let mut select = QueryBuilder::<Sqlite>::new("SELECT * FROM Download_Task");
+        push_conditions(&mut select, query);
+        let direction = if query.descending { "DESC" } else { "ASC" };
+        select.push(format!(
+            " ORDER BY {} {}, id {}",
+            sort_column(query.sort),
+            direction,
+            direction
+        ));
+        // SQLite only takes an offset after a limit, -1 is none
+        select.push(" LIMIT ");
+        select.push_bind(query.limit.map(|l| l as i64).unwrap_or(-1));
+        select.push(" OFFSET ");
+        select.push_bind(query.offset as i64);
+
+        match select
+            .build()
+            .try_map(|r: SqliteRow| TaskRow::from_row(&r))
+            .map(DownloadTaskEntity::from)
+            .fetch_all(self)
+            .await
+        {
+            Ok(tasks) => Ok(TaskPage {
+                tasks,
+                total: total as usize,
+            }),
+            Err(e) => Err(DatabaseError::query("Error searching download tasks", e)),
+        }
     }
 
     async fn get_task_by_id(&self, id: i64) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
-        if let Ok(download_task_entity) =
-            sqlx::query!(r#"SELECT * FROM Download_Task WHERE id = ?1"#, id)
-                .map(|r| DownloadTaskEntity {
-                    id: r.id as u64,
-                    file_name: r.file_name,
-                    file_size: r.file_size.unwrap_or(0) as u64,
-                    file_url: r.file_url,
-                    supports_resume: r.supports_resume.unwrap_or(0) >= 1,
-                    temp_files_path: r.temp_files_path,
-                    final_file_path: r.final_file_path,
-                    max_threads: r.thread_count as u64,
-                    percentage_completed: r.percentage_completed.unwrap_or(0f64),
-                    date_created: r.date_created,
-                    content_type: r.content_type.unwrap_or("".to_string()),
-                })
-                .fetch_optional(self)
-                .await
+        match sqlx::query_as!(TaskRow, r#"SELECT * FROM Download_Task WHERE id = ?1"#, id)
+            .map(DownloadTaskEntity::from)
+            .fetch_optional(self)
+            .await
         {
-            return Ok(download_task_entity);
-        };
-        Err(DatabaseError::Operation(
-            "Error fetching download task from database".to_string(),
-        ))
+            Ok(download_task_entity) => Ok(download_task_entity),
+            Err(e) => Err(DatabaseError::query(
+                "Error fetching download task from database",
+                e,
+            )),
+        }
     }
 
     async fn get_task_by_url(
         &self,
-        url: &String,
+        url: &str,
     ) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
-        if let Ok(download_task_entity) =
-            sqlx::query!(r#"SELECT * FROM Download_Task WHERE file_url = ?1"#, url)
-                .map(|r| DownloadTaskEntity {
-                    id: r.id as u64,
-                    file_name: r.file_name,
-                    file_size: r.file_size.unwrap_or(0) as u64,
-                    file_url: r.file_url,
-                    supports_resume: r.supports_resume.unwrap_or(0) >= 1,
-                    temp_files_path: r.temp_files_path,
-                    final_file_path: r.final_file_path,
-                    max_threads: r.thread_count as u64,
-                    percentage_completed: r.percentage_completed.unwrap_or(0f64),
-                    date_created: r.date_created,
-                    content_type: r.content_type.unwrap_or("".to_string()),
-                })
-                .fetch_optional(self)
-                .await
+        match sqlx::query_as!(
+            TaskRow,
+            r#"SELECT * FROM Download_Task WHERE file_url = ?1"#,
+            url
+        )
+        .map(DownloadTaskEntity::from)
+        .fetch_optional(self)
+        .await
         {
-            return Ok(download_task_entity);
-        };
-        Err(DatabaseError::Operation(
-            "Error fetching download task from database".to_string(),
-        ))
+            Ok(download_task_entity) => Ok(download_task_entity),
+            Err(e) => Err(DatabaseError::query(
+                "Error fetching download task from database",
+                e,
+            )),
+        }
     }
 
     async fn update_task(
@@ -132,23 +196,368 @@ impl DownloadTaskRepository for SqlitePool {
     ) -> Result<DownloadTaskEntity, DatabaseError> {
         let id = task.id as i64;
         let file_size = task.file_size as i64;
+        let on_complete_hook = task.on_complete_hook.to_owned().unwrap_or_default();
+        let on_failure_hook = task.on_failure_hook.to_owned().unwrap_or_default();
+        let extracted_path = task.extracted_path.to_owned().unwrap_or_default();
+        let extraction_result = task.extraction_result.to_owned().unwrap_or_default();
+        let start_after = encode_time(&task.start_after);
+        let schedule = encode_schedule(&task.schedule);
+        let status = task.status.as_str();
+        let last_error = task.last_error.to_owned().unwrap_or_default();
+        let started_at = encode_time(&task.started_at);
+        let finished_at = encode_time(&task.finished_at);
+        let source = task.source.to_owned().unwrap_or_default();
+        let host = url_host(&task.file_url).to_lowercase();
 
-        if sqlx::query!("UPDATE Download_Task SET file_name=?1, file_url=?2, file_size=?3, percentage_completed=?4, final_file_path=?5 WHERE id = ?6", task.file_name, task.file_url, file_size, task.percentage_completed, task.final_file_path, id).execute(self).await.is_ok() {
-            return Ok(task);
-        };
-        Err(DatabaseError::Operation(
-            "Error updating download task".to_string(),
-        ))
+        match sqlx::query!("UPDATE Download_Task SET file_name=?1, file_url=?2, file_size=?3, final_file_path=?4, on_complete_hook=?5, on_failure_hook=?6, extracted_path=?7, extraction_result=?8, start_after=?9, schedule=?10, status=?11, last_error=?12, attempt_count=?13, started_at=?14, finished_at=?15, temp_files_path=?16, queue_position=?17, source=?18, host=?19 WHERE id = ?20", task.file_name, task.file_url, file_size, task.final_file_path, on_complete_hook, on_failure_hook, extracted_path, extraction_result, start_after, schedule, status, last_error, task.attempt_count, started_at, finished_at, task.temp_files_path, task.queue_position, source, host, id).execute(self).await {
+            Ok(_) => Ok(task),
+            Err(e) => Err(DatabaseError::query("Error updating download task", e)),
+        }
     }
 
     async fn remove_task(&self, id: i64) -> Result<(), DatabaseError> {
+        // Its hook runs and parts go with it, or nothing does
+        let mut tx = match self.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(DatabaseError::query("Error removing download task", e)),
+        };
         if let Err(e) = sqlx::query!("DELETE FROM Download_Task WHERE id = ?1", id)
+            .execute(&mut tx)
+            .await
+        {
+            return Err(DatabaseError::query("Error removing download task", e));
+        };
+        if let Err(e) = sqlx::query!("DELETE FROM Hook_Run WHERE task_id = ?1", id)
+            .execute(&mut tx)
+            .await
+        {
+            return Err(DatabaseError::query("Error removing hook runs", e));
+        };
+        if let Err(e) = sqlx::query!("DELETE FROM Download_Part WHERE task_id = ?1", id)
+            .execute(&mut tx)
+            .await
+        {
+            return Err(DatabaseError::query("Error removing download parts", e));
+        };
+
+        match tx.commit().await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(DatabaseError::query("Error removing download task", e)),
+        }
+    }
+
+    async fn insert_hook_run(
+        &self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> Result<u64, DatabaseError> {
+        let task_id = task_id as i64;
+        let event = event.to_string();
+        let now = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+
+        match sqlx::query!(r#"INSERT INTO Hook_Run (task_id, event, command, exit_code, timed_out, output, date_created) VALUES (?1,?2,?3,?4,?5,?6,?7)"#, task_id, event, command, output.exit_code, output.timed_out, output.output, now)
             .execute(self)
             .await
         {
-            return Err(DatabaseError::Operation(e.to_string()));
+            Ok(res) => Ok(res.last_insert_rowid() as u64),
+            Err(e) => Err(DatabaseError::query("Error inserting hook run", e)),
+        }
+    }
+
+    async fn get_hook_runs(&self, task_id: i64) -> Result<Vec<HookRunEntity>, DatabaseError> {
+        match sqlx::query!(
+            r#"SELECT * FROM Hook_Run WHERE task_id = ?1 ORDER BY id"#,
+            task_id
+        )
+        .map(|r| HookRunEntity {
+            id: r.id as u64,
+            task_id: r.task_id as u64,
+            event: r.event,
+            command: r.command,
+            exit_code: r.exit_code.map(|c| c as i32),
+            timed_out: r.timed_out.unwrap_or(0) >= 1,
+            output: r.output.unwrap_or_default(),
+            date_created: r.date_created,
+        })
+        .fetch_all(self)
+        .await
+        {
+            Ok(runs) => Ok(runs),
+            Err(e) => Err(DatabaseError::query(
+                "Error fetching hook runs from database",
+                e,
+            )),
+        }
+    }
+
+    async fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> Result<(), DatabaseError> {
+        let mut tx = match self.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return Err(DatabaseError::query("Error saving checkpoints", e)),
         };
+        let now = encode_time(&Some(Local::now()));
+
+        for checkpoint in checkpoints.iter() {
+            let task_id = checkpoint.task_id as i64;
+            let bytes_done = checkpoint.bytes_done as i64;
+            // Downloads of unknown size keep the percentage they had
+            let percentage = if checkpoint.total_bytes > 0 {
+                Some(checkpoint.percentage())
+            } else {
+                None
+            };
+            if let Err(e) = sqlx::query!("UPDATE Download_Task SET bytes_done=?1, speed=?2, checkpoint_at=?3, percentage_completed=COALESCE(?4, percentage_completed) WHERE id = ?5", bytes_done, checkpoint.speed, now, percentage, task_id)
+                .execute(&mut tx)
+                .await
+            {
+                return Err(DatabaseError::query("Error saving checkpoint", e));
+            }
+
+            for part in checkpoint.parts.iter() {
+                let start_byte = part.start_byte as i64;
+                let end_byte = part.end_byte as i64;
+                let bytes_done = part.bytes_done as i64;
+                let state = part.state.as_str();
+                if let Err(e) = sqlx::query!("INSERT OR REPLACE INTO Download_Part (task_id, part_id, start_byte, end_byte, bytes_done, state) VALUES (?1,?2,?3,?4,?5,?6)", task_id, part.id, start_byte, end_byte, bytes_done, state)
+                    .execute(&mut tx)
+                    .await
+                {
+                    return Err(DatabaseError::query("Error saving part checkpoint", e));
+                }
+            }
+        }
+
+        match tx.commit().await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(DatabaseError::query("Error saving checkpoints", e)),
+        }
+    }
+
+    async fn get_parts(&self, task_id: i64) -> Result<Vec<PartCheckpoint>, DatabaseError> {
+        match sqlx::query!(
+            r#"SELECT * FROM Download_Part WHERE task_id = ?1 ORDER BY part_id"#,
+            task_id
+        )
+        .map(|r| PartCheckpoint {
+            id: r.part_id as u32,
+            start_byte: r.start_byte as u64,
+            end_byte: r.end_byte as u64,
+            bytes_done: r.bytes_done as u64,
+            state: PartState::parse(&r.state).unwrap_or(PartState::Pending),
+        })
+        .fetch_all(self)
+        .await
+        {
+            Ok(parts) => Ok(parts),
+            Err(e) => Err(DatabaseError::query(
+                "Error fetching download parts from database",
+                e,
+            )),
+        }
+    }
 
-        Ok(())
+    async fn set_queue_position(&self, id: i64, queue_position: i64) -> Result<(), DatabaseError> {
+        match sqlx::query!(
+            "UPDATE Download_Task SET queue_position = ?1 WHERE id = ?2",
+            queue_position,
+            id
+        )
+        .execute(self)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(DatabaseError::query("Error moving download task", e)),
+        }
+    }
+}
+
+/// A row of Download_Task
+#[derive(FromRow)]
+struct TaskRow {
+    id: i64,
+    file_name: String,
+    file_size: Option<i64>,
+    file_url: String,
+    supports_resume: Option<i64>,
+    temp_files_path: String,
+    final_file_path: String,
+    thread_count: i64,
+    percentage_completed: Option<f64>,
+    date_created: String,
+    content_type: Option<String>,
+    headers: Option<String>,
+    checksum: Option<String>,
+    part_count: Option<i64>,
+    on_complete_hook: Option<String>,
+    on_failure_hook: Option<String>,
+    extracted_path: Option<String>,
+    extraction_result: Option<String>,
+    start_after: Option<String>,
+    schedule: Option<String>,
+    status: Option<String>,
+    last_error: Option<String>,
+    attempt_count: Option<i64>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    bytes_done: Option<i64>,
+    speed: Option<f64>,
+    checkpoint_at: Option<String>,
+    queue_position: Option<i64>,
+    source: Option<String>,
+    /// Only searches read it
+    #[allow(dead_code)]
+    host: Option<String>,
+}
+
+impl From<TaskRow> for DownloadTaskEntity {
+    fn from(r: TaskRow) -> Self {
+        DownloadTaskEntity {
+            id: r.id as u64,
+            file_name: r.file_name,
+            file_size: r.file_size.unwrap_or(0) as u64,
+            file_url: r.file_url,
+            supports_resume: r.supports_resume.unwrap_or(0) >= 1,
+            temp_files_path: r.temp_files_path,
+            final_file_path: r.final_file_path,
+            max_threads: r.thread_count as u32,
+            // Tasks created before part counts were stored used one part per thread
+            part_count: match r.part_count {
+                Some(c) if c > 0 => c as u32,
+                _ => r.thread_count as u32,
+            },
+            percentage_completed: r.percentage_completed.unwrap_or(0f64),
+            date_created: r.date_created,
+            content_type: r.content_type.unwrap_or_default(),
+            headers: decode_headers(&r.headers.unwrap_or_default()),
+            checksum: decode_checksum(&r.checksum.unwrap_or_default()),
+            on_complete_hook: decode_optional(r.on_complete_hook),
+            on_failure_hook: decode_optional(r.on_failure_hook),
+            extracted_path: decode_optional(r.extracted_path),
+            extraction_result: decode_optional(r.extraction_result),
+            start_after: decode_time(&r.start_after.unwrap_or_default()),
+            schedule: decode_schedule(&r.schedule.unwrap_or_default()),
+            status: DownloadStatus::parse(&r.status.unwrap_or_default())
+                .unwrap_or(DownloadStatus::Queued),
+            last_error: decode_optional(r.last_error),
+            attempt_count: r.attempt_count.unwrap_or(0) as u32,
+            started_at: decode_time(&r.started_at.unwrap_or_default()),
+            finished_at: decode_time(&r.finished_at.unwrap_or_default()),
+            bytes_done: r.bytes_done.unwrap_or(0) as u64,
+            speed: r.speed.unwrap_or(0f64),
+            checkpoint_at: decode_time(&r.checkpoint_at.unwrap_or_default()),
+            queue_position: r.queue_position.unwrap_or(0),
+            source: decode_optional(r.source),
+        }
+    }
+}
+
+/// Adds the criteria of `query` as a WHERE clause, the way `TaskQuery::matches` checks them
+fn push_conditions(builder: &mut QueryBuilder<Sqlite>, query: &TaskQuery) {
+    builder.push(" WHERE (");
+    builder.push_bind(query.filter.as_str());
+    builder.push(" = 'all' OR status = ");
+    builder.push_bind(query.filter.as_str());
+    builder.push(" OR (");
+    builder.push_bind(query.filter.as_str());
+    builder.push(" = 'in_progress' AND status IN ('queued', 'probing', 'downloading', 'paused', 'verifying')))");
+
+    if let Some(name) = &query.name {
+        builder.push(r" AND file_name LIKE ");
+        builder.push_bind(name_pattern(name));
+        builder.push(r" ESCAPE '\'");
+    }
+    if let Some(url) = &query.url {
+        builder.push(r" AND file_url LIKE ");
+        builder.push_bind(format!("%{}%", escape_like(url)));
+        builder.push(r" ESCAPE '\'");
+    }
+    if let Some(host) = &query.host {
+        // Hosts are stored lowercased
+        builder.push(" AND (host = ");
+        builder.push_bind(host.to_lowercase());
+        builder.push(r" OR host LIKE ");
+        builder.push_bind(format!("%.{}", escape_like(&host.to_lowercase())));
+        builder.push(r" ESCAPE '\')");
+    }
+    if let Some(content_type) = &query.content_type {
+        builder.push(" AND content_type LIKE ");
+        builder.push_bind(format!("{}%", escape_like(content_type)));
+        builder.push(r" ESCAPE '\'");
+    }
+    if let Some(since) = &query.created_since {
+        builder.push(" AND date_created >= ");
+        builder.push_bind(since.to_owned());
+    }
+    if let Some(until) = &query.created_until {
+        // Dates may carry a time after the day
+        builder.push(" AND substr(date_created, 1, length(");
+        builder.push_bind(until.to_owned());
+        builder.push(")) <= ");
+        builder.push_bind(until.to_owned());
+    }
+    if query.min_size.is_some() || query.max_size.is_some() {
+        builder.push(" AND file_size > 0");
+    }
+    if let Some(min) = query.min_size {
+        builder.push(" AND file_size >= ");
+        builder.push_bind(min as i64);
+    }
+    if let Some(max) = query.max_size {
+        builder.push(" AND file_size <= ");
+        builder.push_bind(max as i64);
+    }
+}
+
+/// Column of the tasks `key` sorts by
+fn sort_column(key: SortKey) -> &'static str {
+    match key {
+        SortKey::Id => "id",
+        SortKey::Name => "lower(file_name)",
+        SortKey::Size => "COALESCE(file_size, 0)",
+        SortKey::Progress => "COALESCE(percentage_completed, 0)",
+        SortKey::Status => "status",
+        SortKey::Date => "date_created",
+        SortKey::Host => "host",
+        SortKey::Url => "file_url",
+        SortKey::Queue => "COALESCE(queue_position, 0)",
+    }
+}
+
+/// LIKE pattern of a file name pattern: a glob when it has * or ?, else a part of it
+fn name_pattern(name: &str) -> String {
+    let escaped = escape_like(name);
+    if name.contains(['*', '?']) {
+        escaped.replace('*', "%").replace('?', "_")
+    } else {
+        format!("%{}%", escaped)
+    }
+}
+
+/// Escapes the wildcards of LIKE, with \ as the escape character
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('%', r"\%")
+        .replace('_', r"\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use crate::conformance;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        // Every connection to :memory: opens a database of its own
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        conformance::check(&pool).await;
     }
 }
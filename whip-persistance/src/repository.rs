@@ -0,0 +1,192 @@
+use std::{fmt, path::PathBuf};
+
+use async_trait::async_trait;
+#[cfg(feature = "sqlite")]
+use sqlx::SqlitePool;
+use whip_core::{
+    checkpoint::{Checkpoint, PartCheckpoint},
+    download::DownloadTask,
+    hooks::{HookEvent, HookOutput},
+};
+
+#[cfg(feature = "sqlite")]
+use crate::get_database_pool;
+use crate::{
+    errors::DatabaseError,
+    json_file::JsonFileRepository,
+    memory::MemoryRepository,
+    models::{DownloadFilter, DownloadTaskEntity, DownloadTaskRepository, HookRunEntity},
+    search::{TaskPage, TaskQuery},
+};
+
+/// Kinds of storage for the tasks. Sqlite is only there when whip is built
+/// with the sqlite feature, it's the default then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    /// A single JSON file
+    Json,
+    /// Nothing is kept once the process ends
+    Memory,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Sqlite, Backend::Json, Backend::Memory];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "sqlite",
+            Backend::Json => "json",
+            Backend::Memory => "memory",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Backend::ALL
+            .iter()
+            .find(|b| b.as_str().eq_ignore_ascii_case(value))
+            .copied()
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(feature = "sqlite") {
+            Backend::Sqlite
+        } else {
+            Backend::Json
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// One of the repositories, picked when whip starts
+#[derive(Debug, Clone)]
+pub enum Repository {
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqlitePool),
+    Memory(MemoryRepository),
+    JsonFile(JsonFileRepository),
+}
+
+impl Repository {
+    /// Opens the repository of `backend` at `location`: the url of the
+    /// sqlite database or the path of the JSON file. Memory ones have none.
+    pub async fn open(backend: Backend, location: &str) -> Result<Self, DatabaseError> {
+        match backend {
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => Ok(Repository::Sqlite(
+                get_database_pool(location.to_string()).await?,
+            )),
+            #[cfg(not(feature = "sqlite"))]
+            Backend::Sqlite => Err(DatabaseError::Operation(String::from(
+                "whip was built without the sqlite backend, pick the json or memory one",
+            ))),
+            Backend::Json => Ok(Repository::JsonFile(
+                JsonFileRepository::open(PathBuf::from(location)).await?,
+            )),
+            Backend::Memory => Ok(Repository::Memory(MemoryRepository::new())),
+        }
+    }
+}
+
+/// Runs `$call` on the repository behind `$self`, bound to `$repository`
+macro_rules! each {
+    ($self:ident, $repository:ident => $call:expr) => {
+        match $self {
+            #[cfg(feature = "sqlite")]
+            Repository::Sqlite($repository) => $call,
+            Repository::Memory($repository) => $call,
+            Repository::JsonFile($repository) => $call,
+        }
+    };
+}
+
+#[async_trait]
+impl DownloadTaskRepository for Repository {
+    async fn insert_task(
+        &self,
+        task: &DownloadTask,
+        temp_files_path: String,
+        final_file_path: String,
+        thread_count: String,
+        part_count: u32,
+    ) -> Result<u64, DatabaseError> {
+        each!(self, r => {
+            r.insert_task(task, temp_files_path, final_file_path, thread_count, part_count)
+                .await
+        })
+    }
+
+    async fn import_task(
+        &self,
+        task: &DownloadTaskEntity,
+        parts: &[PartCheckpoint],
+    ) -> Result<u64, DatabaseError> {
+        each!(self, r => r.import_task(task, parts).await)
+    }
+
+    async fn get_tasks(
+        &self,
+        filter: DownloadFilter,
+    ) -> Result<Vec<DownloadTaskEntity>, DatabaseError> {
+        each!(self, r => r.get_tasks(filter).await)
+    }
+
+    async fn search_tasks(&self, query: &TaskQuery) -> Result<TaskPage, DatabaseError> {
+        each!(self, r => r.search_tasks(query).await)
+    }
+
+    async fn get_task_by_id(&self, id: i64) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        each!(self, r => r.get_task_by_id(id).await)
+    }
+
+    async fn get_task_by_url(
+        &self,
+        url: &str,
+    ) -> Result<Option<DownloadTaskEntity>, DatabaseError> {
+        each!(self, r => r.get_task_by_url(url).await)
+    }
+
+    async fn update_task(
+        &self,
+        task: DownloadTaskEntity,
+    ) -> Result<DownloadTaskEntity, DatabaseError> {
+        each!(self, r => r.update_task(task).await)
+    }
+
+    async fn remove_task(&self, id: i64) -> Result<(), DatabaseError> {
+        each!(self, r => r.remove_task(id).await)
+    }
+
+    async fn insert_hook_run(
+        &self,
+        task_id: u64,
+        event: HookEvent,
+        command: &str,
+        output: &HookOutput,
+    ) -> Result<u64, DatabaseError> {
+        each!(self, r => r.insert_hook_run(task_id, event, command, output).await)
+    }
+
+    async fn get_hook_runs(&self, task_id: i64) -> Result<Vec<HookRunEntity>, DatabaseError> {
+        each!(self, r => r.get_hook_runs(task_id).await)
+    }
+
+    async fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> Result<(), DatabaseError> {
+        each!(self, r => r.save_checkpoints(checkpoints).await)
+    }
+
+    async fn get_parts(&self, task_id: i64) -> Result<Vec<PartCheckpoint>, DatabaseError> {
+        each!(self, r => r.get_parts(task_id).await)
+    }
+
+    async fn set_queue_position(&self, id: i64, queue_position: i64) -> Result<(), DatabaseError> {
+        each!(self, r => r.set_queue_position(id, queue_position).await)
+    }
+}
@@ -0,0 +1,307 @@
+use std::cmp::Ordering;
+
+use crate::models::{DownloadFilter, DownloadStatus, DownloadTaskEntity};
+
+/// Column the tasks are sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Id,
+    Name,
+    Size,
+    Progress,
+    Status,
+    /// Day the task was created
+    Date,
+    Host,
+    Url,
+    /// Rank in the queue
+    Queue,
+}
+
+/// Which tasks to list and in what order. Unset criteria match every task.
+#[derive(Debug, Clone, Default)]
+pub struct TaskQuery {
+    pub filter: DownloadFilter,
+    /// File name, with * and ? wildcards, or a part of it. Case insensitive
+    pub name: Option<String>,
+    /// Part of the url. Case insensitive
+    pub url: Option<String>,
+    /// Host of the url, its subdomains match too
+    pub host: Option<String>,
+    /// Content type or its start, like video/
+    pub content_type: Option<String>,
+    /// Created on or after that day, YYYY-MM-DD
+    pub created_since: Option<String>,
+    /// Created on or before that day, YYYY-MM-DD
+    pub created_until: Option<String>,
+    /// Bytes, tasks of unknown size are left out when set
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub sort: SortKey,
+    pub descending: bool,
+    /// Tasks skipped once sorted
+    pub offset: usize,
+    /// Most tasks returned, all of them when None
+    pub limit: Option<usize>,
+}
+
+/// A page of the tasks matching a query
+#[derive(Debug, Clone, Default)]
+pub struct TaskPage {
+    pub tasks: Vec<DownloadTaskEntity>,
+    /// Tasks matching the query, before the offset and limit
+    pub total: usize,
+}
+
+impl TaskQuery {
+    /// Whether `task` meets every criterion
+    pub fn matches(&self, task: &DownloadTaskEntity) -> bool {
+        if !self.filter.matches(task.status) {
+            return false;
+        }
+        if let Some(name) = &self.name {
+            if !matches_name(name, &task.file_name) {
+                return false;
+            }
+        }
+        if let Some(url) = &self.url {
+            if !task.file_url.to_lowercase().contains(&url.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(host) = &self.host {
+            let host = host.to_lowercase();
+            let task_host = url_host(&task.file_url).to_lowercase();
+            if task_host != host && !task_host.ends_with(&format!(".{}", host)) {
+                return false;
+            }
+        }
+        if let Some(content_type) = &self.content_type {
+            let content_type = content_type.to_lowercase();
+            if !task.content_type.to_lowercase().starts_with(&content_type) {
+                return false;
+            }
+        }
+        if let Some(since) = &self.created_since {
+            if task.date_created.as_str() < since.as_str() {
+                return false;
+            }
+        }
+        if let Some(until) = &self.created_until {
+            // Dates may carry a time after the day
+            if task
+                .date_created
+                .get(..until.len())
+                .unwrap_or(&task.date_created)
+                > until.as_str()
+            {
+                return false;
+            }
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            if task.file_size == 0 {
+                return false;
+            }
+            if self.min_size.is_some_and(|min| task.file_size < min)
+                || self.max_size.is_some_and(|max| task.file_size > max)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The page of `tasks` the query asks for
+    pub fn apply(&self, tasks: Vec<DownloadTaskEntity>) -> TaskPage {
+        let mut tasks: Vec<DownloadTaskEntity> =
+            tasks.into_iter().filter(|t| self.matches(t)).collect();
+        tasks.sort_by(|a, b| {
+            let ordering = compare(self.sort, a, b).then(a.id.cmp(&b.id));
+            if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        let total = tasks.len();
+        let tasks = tasks
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        TaskPage { tasks, total }
+    }
+}
+
+fn compare(key: SortKey, a: &DownloadTaskEntity, b: &DownloadTaskEntity) -> Ordering {
+    match key {
+        SortKey::Id => a.id.cmp(&b.id),
+        SortKey::Name => a.file_name.to_lowercase().cmp(&b.file_name.to_lowercase()),
+        SortKey::Size => a.file_size.cmp(&b.file_size),
+        SortKey::Progress => a
+            .percentage_completed
+            .partial_cmp(&b.percentage_completed)
+            .unwrap_or(Ordering::Equal),
+        SortKey::Status => a.status.as_str().cmp(b.status.as_str()),
+        SortKey::Date => a.date_created.cmp(&b.date_created),
+        SortKey::Host => url_host(&a.file_url)
+            .to_lowercase()
+            .cmp(&url_host(&b.file_url).to_lowercase()),
+        SortKey::Url => a.file_url.cmp(&b.file_url),
+        SortKey::Queue => a.queue_position.cmp(&b.queue_position),
+    }
+}
+
+impl DownloadFilter {
+    /// Whether tasks in `status` pass the filter
+    pub fn matches(&self, status: DownloadStatus) -> bool {
+        match self {
+            DownloadFilter::All => true,
+            DownloadFilter::Completed => status == DownloadStatus::Completed,
+            DownloadFilter::InProgress => !matches!(
+                status,
+                DownloadStatus::Completed | DownloadStatus::Failed | DownloadStatus::Cancelled
+            ),
+            DownloadFilter::Status(s) => status == *s,
+        }
+    }
+}
+
+/// Host of `url`, without the user or the port
+pub fn url_host(url: &str) -> &str {
+    let rest = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    // IPv6 addresses are bracketed, their colons aren't a port
+    if let Some(end) = host.strip_prefix('[').and_then(|h| h.find(']')) {
+        return &host[..end + 2];
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Whether `name` matches `pattern`: a glob when it has * or ?, else a part of it
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    if !pattern.contains(['*', '?']) {
+        return name.contains(&pattern);
+    }
+    glob(
+        &pattern.chars().collect::<Vec<char>>(),
+        &name.chars().collect::<Vec<char>>(),
+    )
+}
+
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    // Backtracks to the last star on a mismatch
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(id: u64, file_name: &str, file_url: &str, file_size: u64) -> DownloadTaskEntity {
+        DownloadTaskEntity {
+            id,
+            file_name: file_name.to_string(),
+            file_url: file_url.to_string(),
+            file_size,
+            date_created: format!("2026-10-{:02}", 10 + id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matches_name() {
+        assert!(matches_name("*.ISO", "debian-12.iso"));
+        assert!(matches_name("debian-??.iso", "debian-12.iso"));
+        assert!(!matches_name("debian-?.iso", "debian-12.iso"));
+        assert!(matches_name("bian", "debian-12.iso"));
+        assert!(matches_name("*a*b*", "xaxxbx"));
+        assert!(!matches_name("*a*b", "xaxxbx"));
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(
+            url_host("https://user@cdn.example.com:8443/a?b"),
+            "cdn.example.com"
+        );
+        assert_eq!(url_host("http://[::1]:8080/file"), "[::1]");
+        assert_eq!(url_host("ftp://example.org"), "example.org");
+    }
+
+    #[test]
+    fn test_apply() {
+        let tasks = vec![
+            task(
+                1,
+                "debian.iso",
+                "https://cdn.debian.org/debian.iso",
+                4_000_000_000,
+            ),
+            task(2, "notes.txt", "https://example.com/notes.txt", 0),
+            task(
+                3,
+                "movie.mkv",
+                "https://media.example.com/movie.mkv",
+                2_000_000_000,
+            ),
+            task(4, "song.mp3", "http://example.com:8080/song.mp3", 5_000_000),
+        ];
+
+        let query = TaskQuery {
+            host: Some(String::from("example.com")),
+            ..Default::default()
+        };
+        let ids = |page: TaskPage| page.tasks.iter().map(|t| t.id).collect::<Vec<u64>>();
+        assert_eq!(ids(query.apply(tasks.clone())), vec![2, 3, 4]);
+
+        let query = TaskQuery {
+            min_size: Some(1_000_000),
+            sort: SortKey::Size,
+            descending: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(query.apply(tasks.clone())), vec![1, 3, 4]);
+
+        let query = TaskQuery {
+            created_since: Some(String::from("2026-10-12")),
+            created_until: Some(String::from("2026-10-13")),
+            ..Default::default()
+        };
+        assert_eq!(ids(query.apply(tasks.clone())), vec![2, 3]);
+
+        let query = TaskQuery {
+            sort: SortKey::Name,
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = query.apply(tasks);
+        assert_eq!(page.total, 4);
+        assert_eq!(ids(page), vec![3, 2]);
+    }
+}